@@ -1,48 +1,92 @@
-use serde::{Deserialize, Serialize};
-use std::io::{self, BufRead, Write};
-
-#[derive(Serialize, Deserialize, Debug)]
-struct Message {
-    src: String,
-    dest: String,
-    body: Body,
-}
+// Stdout carries the Maelstrom protocol, only the message writer may touch it.
+#![deny(clippy::print_stdout)]
+
+mod message;
+
+use crate::message::{Body, Envelope, Message};
+use clap::Parser;
+use serde::Deserialize;
+use serde_json::Value;
+use std::io::{self, BufRead, Stdout, Write};
+use tracing::{info_span, warn};
+
+/// Maelstrom error codes, see https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors
+const NODE_NOT_FOUND: u64 = 1;
+const NOT_SUPPORTED: u64 = 10;
+const TEMPORARILY_UNAVAILABLE: u64 = 11;
+const MALFORMED_REQUEST: u64 = 12;
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "type")]
-enum Body {
-    #[serde(rename = "init")]
-    Init {
-        msg_id: u64,
-        node_id: String,
-        node_ids: Vec<String>,
-    },
-    #[serde(rename = "init_ok")]
-    InitOk { in_reply_to: u64 },
-    #[serde(rename = "echo")]
-    Echo { msg_id: u64, echo: String },
-    #[serde(rename = "echo_ok")]
-    EchoOk {
-        msg_id: u64,
-        in_reply_to: u64,
-        echo: String,
-    },
-    #[serde(rename = "error")]
-    Error {
-        in_reply_to: u64,
-        code: u64,
-        text: String,
-    },
+/// The echo node has nothing to tune but its log filter, also read from `RUST_LOG`.
+#[derive(Parser, Debug)]
+struct Config {
+    /// Log filter, e.g. `debug` or `ch01_echo=trace`.
+    #[arg(long, env = "RUST_LOG", default_value = "info", value_parser = logging::parse_filter)]
+    log_level: String,
 }
 
 fn main() {
+    let config = Config::parse();
+    logging::init(&config.log_level);
+
     let stdin = io::stdin();
     let mut stdout = io::stdout();
     let mut node_id = String::new();
 
     for line in stdin.lock().lines() {
-        let input: Message = serde_json::from_str(&line.unwrap()).unwrap();
-        match input.body {
+        let input: Envelope = match serde_json::from_str(&line.unwrap()) {
+            Ok(input) => input,
+            Err(e) => {
+                warn!("Malformed message: {}", e);
+                continue;
+            }
+        };
+
+        let msg_id = input.body.get("msg_id").and_then(Value::as_u64);
+        let body_type = input.body.get("type").and_then(Value::as_str).unwrap_or("");
+
+        let span = info_span!("message", node_id = %node_id, msg_id, body_type = %body_type);
+        let _enter = span.enter();
+
+        if !node_id.is_empty() && input.dest != node_id {
+            reject(
+                &mut stdout,
+                &node_id,
+                &input,
+                msg_id,
+                NODE_NOT_FOUND,
+                format!("This is node {}, not {}", node_id, input.dest),
+            );
+            continue;
+        }
+
+        if node_id.is_empty() && body_type != "init" {
+            reject(
+                &mut stdout,
+                &node_id,
+                &input,
+                msg_id,
+                TEMPORARILY_UNAVAILABLE,
+                "Node is not initialised yet".to_string(),
+            );
+            continue;
+        }
+
+        let body = match Body::deserialize(&input.body) {
+            Ok(body) => body,
+            Err(e) => {
+                reject(
+                    &mut stdout,
+                    &node_id,
+                    &input,
+                    msg_id,
+                    MALFORMED_REQUEST,
+                    e.to_string(),
+                );
+                continue;
+            }
+        };
+
+        match body {
             Body::Init {
                 msg_id,
                 node_id: id,
@@ -56,9 +100,7 @@ fn main() {
                         in_reply_to: msg_id,
                     },
                 };
-                let output_json = serde_json::to_string(&output).unwrap();
-                writeln!(stdout, "{}", output_json).unwrap();
-                stdout.flush().unwrap();
+                send(&mut stdout, &output);
             }
             Body::Echo { msg_id, echo } => {
                 let output = Message {
@@ -70,21 +112,65 @@ fn main() {
                         echo,
                     },
                 };
-                let output_json = serde_json::to_string(&output).unwrap();
-                writeln!(stdout, "{}", output_json).unwrap();
-                stdout.flush().unwrap();
+                send(&mut stdout, &output);
             }
             Body::Error {
                 in_reply_to,
                 code,
                 text,
             } => {
-                eprintln!(
-                    "Error received (in_reply_to: {}, code: {}, text: {})",
-                    in_reply_to, code, text
-                );
+                warn!(in_reply_to, code, "Error received: {}", text);
             }
-            _ => (),
+            _ => reject(
+                &mut stdout,
+                &node_id,
+                &input,
+                msg_id,
+                NOT_SUPPORTED,
+                format!("Unsupported message type: {}", body_type),
+            ),
         }
     }
 }
+
+fn send(stdout: &mut Stdout, output: &Message) {
+    let output_json = serde_json::to_string(output).unwrap();
+    writeln!(stdout, "{}", output_json).unwrap();
+    stdout.flush().unwrap();
+}
+
+/// Answers a request with an error. Messages without a `msg_id` expect no
+/// reply, and replies are never answered, so two nodes cannot bounce errors
+/// back and forth.
+fn reject(
+    stdout: &mut Stdout,
+    node_id: &str,
+    input: &Envelope,
+    msg_id: Option<u64>,
+    code: u64,
+    text: String,
+) {
+    let msg_id = match msg_id {
+        Some(msg_id) if input.body.get("in_reply_to").is_none() => msg_id,
+        _ => {
+            warn!(code, "Dropped message: {}", text);
+            return;
+        }
+    };
+
+    let output = Message {
+        // Before init we do not know our own id yet
+        src: if node_id.is_empty() {
+            input.dest.clone()
+        } else {
+            node_id.to_string()
+        },
+        dest: input.src.clone(),
+        body: Body::Error {
+            in_reply_to: msg_id,
+            code,
+            text,
+        },
+    };
+    send(stdout, &output);
+}
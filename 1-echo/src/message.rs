@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Message {
+    pub src: String,
+    pub dest: String,
+    pub body: Body,
+}
+
+/// A message whose body was not parsed yet, so we can still answer bodies we do not understand.
+#[derive(Deserialize, Debug)]
+pub struct Envelope {
+    pub src: String,
+    pub dest: String,
+    pub body: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+pub enum Body {
+    #[serde(rename = "init")]
+    Init {
+        msg_id: u64,
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    #[serde(rename = "init_ok")]
+    InitOk { in_reply_to: u64 },
+    #[serde(rename = "echo")]
+    Echo { msg_id: u64, echo: String },
+    #[serde(rename = "echo_ok")]
+    EchoOk {
+        msg_id: u64,
+        in_reply_to: u64,
+        echo: String,
+    },
+    #[serde(rename = "error")]
+    Error {
+        in_reply_to: u64,
+        code: u64,
+        text: String,
+    },
+    #[serde(other)]
+    Unknown,
+}
@@ -0,0 +1,65 @@
+//! Feeds every `tests/golden/*.in` transcript to the node and compares its
+//! stdout with the matching `.out` file. Run with `UPDATE_GOLDEN=1` to rewrite
+//! the expected output after an intended change.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+fn run_node(input: &[u8]) -> String {
+    let mut node = Command::new(env!("CARGO_BIN_EXE_ch01-echo"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    node.stdin.take().unwrap().write_all(input).unwrap();
+
+    let output = node.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "Node exited with {}",
+        output.status
+    );
+
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn golden_transcripts() {
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    let mut transcripts = 0;
+
+    for entry in fs::read_dir(&golden).unwrap() {
+        let input_path = entry.unwrap().path();
+
+        if input_path
+            .extension()
+            .is_none_or(|extension| extension != "in")
+        {
+            continue;
+        }
+
+        let output_path = input_path.with_extension("out");
+        let actual = run_node(&fs::read(&input_path).unwrap());
+
+        if update {
+            fs::write(&output_path, &actual).unwrap();
+        } else {
+            let expected = fs::read_to_string(&output_path).unwrap();
+            assert_eq!(
+                actual,
+                expected,
+                "Transcript {} does not match",
+                input_path.display()
+            );
+        }
+
+        transcripts += 1;
+    }
+
+    assert!(transcripts > 0, "No transcripts in {}", golden.display());
+}
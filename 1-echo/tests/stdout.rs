@@ -0,0 +1,56 @@
+//! Feeds every message type through the node and checks that everything it
+//! writes to stdout is a protocol message, diagnostics belong on stderr.
+
+#[allow(dead_code)]
+#[path = "../src/message.rs"]
+mod message;
+
+use message::{Body, Message};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const MESSAGES: &[&str] = &[
+    r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"before init"}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":2,"node_id":"n1","node_ids":["n1","n2"]}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"init_ok","in_reply_to":2}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":3,"echo":"hello"}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"echo_ok","msg_id":4,"in_reply_to":3,"echo":"hello"}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"error","in_reply_to":3,"code":11,"text":"busy"}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"generate","msg_id":5}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":6}}"#,
+    r#"{"src":"c1","dest":"n2","body":{"type":"echo","msg_id":7,"echo":"not for us"}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"gossip"}}"#,
+    r#"not json at all"#,
+];
+
+#[test]
+fn stdout_only_carries_messages() {
+    let mut node = Command::new(env!("CARGO_BIN_EXE_ch01-echo"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut stdin = node.stdin.take().unwrap();
+    for message in MESSAGES {
+        writeln!(stdin, "{}", message).unwrap();
+    }
+    drop(stdin);
+
+    let output = node.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert!(!stdout.is_empty(), "Node did not answer anything");
+
+    for line in stdout.lines() {
+        let message: Message = serde_json::from_str(line)
+            .unwrap_or_else(|e| panic!("{:?} is not a message: {}", line, e));
+
+        assert!(
+            !matches!(message.body, Body::Unknown),
+            "{:?} has an unknown body type",
+            line
+        );
+    }
+}
@@ -0,0 +1,20 @@
+use crate::generator::Strategy;
+use crate::snowflake::ClockRegression;
+use clap::Parser;
+
+/// How the node makes IDs. Flags may also come from the environment, e.g.
+/// `ID_STRATEGY=snowflake`.
+#[derive(Parser, Debug, Clone)]
+pub(crate) struct Config {
+    /// How IDs are made: uuid7, uuid4, snowflake, counter, hlc or lease.
+    #[arg(long, env = "ID_STRATEGY", default_value = "uuid7")]
+    pub(crate) id_strategy: Strategy,
+
+    /// What snowflake IDs do when the clock goes backwards: wait or borrow.
+    #[arg(long, env = "ID_CLOCK_REGRESSION", default_value = "wait")]
+    pub(crate) id_clock_regression: ClockRegression,
+
+    /// Log filter, e.g. `debug` or `ch02_unique_id=trace`.
+    #[arg(long, env = "RUST_LOG", default_value = "info", value_parser = logging::parse_filter)]
+    pub(crate) log_level: String,
+}
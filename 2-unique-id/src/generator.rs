@@ -0,0 +1,326 @@
+use crate::lease::Lease;
+use crate::message::Id;
+use crate::snowflake::{ClockRegression, Snowflake};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid7::V7Generator;
+
+const HLC_LOGICAL_BITS: u64 = 16;
+
+/// Which `IdGenerator` answers `generate` requests, taken from `--id-strategy` or `ID_STRATEGY`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Strategy {
+    Uuid7,
+    Uuid4,
+    Snowflake,
+    Counter,
+    Hlc,
+    /// Dense integers from blocks leased in `lin-kv`, see `Lease`.
+    Lease,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(strategy: &str) -> Result<Self, Self::Err> {
+        match strategy {
+            "uuid7" => Ok(Strategy::Uuid7),
+            "uuid4" => Ok(Strategy::Uuid4),
+            "snowflake" => Ok(Strategy::Snowflake),
+            "counter" => Ok(Strategy::Counter),
+            "hlc" => Ok(Strategy::Hlc),
+            "lease" => Ok(Strategy::Lease),
+            _ => Err(format!("Unknown ID strategy: {}", strategy)),
+        }
+    }
+}
+
+pub(crate) trait IdGenerator: Send {
+    fn generate(&mut self) -> Id;
+
+    /// Makes the IDs issued from now on sort after `id`, one a client got from
+    /// another node. Only clocks which merge remote timestamps can promise that.
+    fn observe(&mut self, _id: &Id) {}
+}
+
+/// What answers `generate` requests on a node.
+pub(crate) enum Source {
+    /// IDs made on the spot.
+    Local(Box<dyn IdGenerator>),
+    /// IDs from blocks leased in `lin-kv`, which may have to wait for a round-trip.
+    Leased(Lease),
+}
+
+impl Source {
+    /// Builds the source for `strategy` once the node knows its id, fails if
+    /// the strategy cannot work with that id.
+    pub(crate) fn new(
+        strategy: Strategy,
+        node_id: &str,
+        on_regression: ClockRegression,
+    ) -> Result<Source, String> {
+        let generator: Box<dyn IdGenerator> = match strategy {
+            Strategy::Uuid7 => Box::new(Uuid7Generator::new()),
+            Strategy::Uuid4 => Box::new(Uuid4Generator),
+            Strategy::Snowflake => Box::new(Snowflake::new(node_id, on_regression)?),
+            Strategy::Counter => Box::new(CounterGenerator::new(node_id)),
+            Strategy::Hlc => Box::new(HlcGenerator::new(node_id)),
+            Strategy::Lease => return Ok(Source::Leased(Lease::new())),
+        };
+
+        Ok(Source::Local(generator))
+    }
+}
+
+/// Time-ordered random UUIDs, monotonic per generator rather than per thread like `uuid7::uuid7`.
+pub(crate) struct Uuid7Generator(V7Generator<StdRng>);
+
+impl Uuid7Generator {
+    pub(crate) fn new() -> Uuid7Generator {
+        Uuid7Generator(V7Generator::new(StdRng::from_entropy()))
+    }
+}
+
+impl IdGenerator for Uuid7Generator {
+    fn generate(&mut self) -> Id {
+        Id::Uuid(self.0.generate())
+    }
+}
+
+/// Fully random UUIDs, unique by probability only and without any order.
+pub(crate) struct Uuid4Generator;
+
+impl IdGenerator for Uuid4Generator {
+    fn generate(&mut self) -> Id {
+        Id::Uuid(uuid7::uuid4())
+    }
+}
+
+impl IdGenerator for Snowflake {
+    fn generate(&mut self) -> Id {
+        Id::Number(Snowflake::generate(self))
+    }
+}
+
+/// The node id with a local counter, unique as long as node ids are.
+pub(crate) struct CounterGenerator {
+    node: String,
+    counter: u64,
+}
+
+impl CounterGenerator {
+    pub(crate) fn new(node_id: &str) -> CounterGenerator {
+        CounterGenerator {
+            node: node_id.to_string(),
+            counter: 0,
+        }
+    }
+}
+
+impl IdGenerator for CounterGenerator {
+    fn generate(&mut self) -> Id {
+        self.counter += 1;
+
+        Id::Scoped {
+            node: self.node.clone(),
+            value: self.counter,
+        }
+    }
+}
+
+/// Hybrid logical clock timestamps: wall clock milliseconds in the high bits
+/// and a logical counter in the low bits, which keeps them increasing when
+/// the wall clock stalls or goes backwards.
+pub(crate) struct HlcGenerator {
+    node: String,
+    physical: u64,
+    logical: u64,
+}
+
+impl HlcGenerator {
+    pub(crate) fn new(node_id: &str) -> HlcGenerator {
+        HlcGenerator {
+            node: node_id.to_string(),
+            physical: 0,
+            logical: 0,
+        }
+    }
+}
+
+impl IdGenerator for HlcGenerator {
+    /// The receive rule of the hybrid logical clock: our clock moves up to the
+    /// remote timestamp, so the next `generate` ticks past it.
+    fn observe(&mut self, id: &Id) {
+        if let Id::Scoped { value, .. } = id {
+            let physical = value >> HLC_LOGICAL_BITS;
+            let logical = value & ((1 << HLC_LOGICAL_BITS) - 1);
+
+            if (physical, logical) > (self.physical, self.logical) {
+                self.physical = physical;
+                self.logical = logical;
+            }
+        }
+    }
+
+    fn generate(&mut self) -> Id {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System clock is before the UNIX epoch")
+            .as_millis() as u64;
+
+        if now > self.physical {
+            self.physical = now;
+            self.logical = 0;
+        } else {
+            self.logical += 1;
+
+            if self.logical >> HLC_LOGICAL_BITS > 0 {
+                self.physical += 1;
+                self.logical = 0;
+            }
+        }
+
+        Id::Scoped {
+            node: self.node.clone(),
+            value: (self.physical << HLC_LOGICAL_BITS) | self.logical,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    const NODES: usize = 10;
+    const CLIENTS_PER_NODE: usize = 4;
+    const REQUESTS_PER_CLIENT: usize = 500;
+
+    /// Whether the IDs a single node hands out are strictly increasing.
+    fn is_monotonic(strategy: Strategy) -> bool {
+        !matches!(strategy, Strategy::Uuid4)
+    }
+
+    /// A node's generator and the IDs it issued, in order.
+    type SimulatedNode = Arc<Mutex<(Box<dyn IdGenerator>, Vec<Id>)>>;
+
+    /// Runs concurrent clients against simulated nodes, each node answering
+    /// one `generate` at a time like the stdin loop does, and checks the IDs.
+    fn check(strategy: Strategy) {
+        let nodes: Vec<SimulatedNode> = (0..NODES)
+            .map(|n| {
+                let Ok(Source::Local(generator)) =
+                    Source::new(strategy, &format!("n{}", n), ClockRegression::Wait)
+                else {
+                    panic!("{:?} IDs are not made locally", strategy);
+                };
+                Arc::new(Mutex::new((generator, vec![])))
+            })
+            .collect();
+
+        let clients: Vec<_> = nodes
+            .iter()
+            .flat_map(|node| (0..CLIENTS_PER_NODE).map(move |_| node.clone()))
+            .map(|node| {
+                thread::spawn(move || {
+                    for _ in 0..REQUESTS_PER_CLIENT {
+                        let mut node = node.lock().unwrap();
+                        let id = node.0.generate();
+                        node.1.push(id);
+                    }
+                })
+            })
+            .collect();
+
+        for client in clients {
+            client.join().unwrap();
+        }
+
+        let mut unique = HashSet::new();
+
+        for node in nodes.iter() {
+            let issued = &node.lock().unwrap().1;
+
+            assert_eq!(issued.len(), CLIENTS_PER_NODE * REQUESTS_PER_CLIENT);
+
+            if is_monotonic(strategy) {
+                assert!(
+                    issued.windows(2).all(|pair| pair[0] < pair[1]),
+                    "{:?} IDs are not monotonic within a node",
+                    strategy
+                );
+            }
+
+            unique.extend(issued.iter().cloned());
+        }
+
+        assert_eq!(
+            unique.len(),
+            NODES * CLIENTS_PER_NODE * REQUESTS_PER_CLIENT,
+            "{:?} issued duplicate IDs",
+            strategy
+        );
+    }
+
+    #[test]
+    fn uuid7_ids_are_unique_and_monotonic() {
+        check(Strategy::Uuid7);
+    }
+
+    #[test]
+    fn uuid4_ids_are_unique() {
+        check(Strategy::Uuid4);
+    }
+
+    #[test]
+    fn snowflake_ids_are_unique_and_monotonic() {
+        check(Strategy::Snowflake);
+    }
+
+    #[test]
+    fn counter_ids_are_unique_and_monotonic() {
+        check(Strategy::Counter);
+    }
+
+    #[test]
+    fn hlc_ids_are_unique_and_monotonic() {
+        check(Strategy::Hlc);
+    }
+
+    #[test]
+    fn hlc_ids_sort_after_observed_remote_ids() {
+        let mut ahead = HlcGenerator::new("n1");
+        ahead.physical = u64::MAX >> HLC_LOGICAL_BITS >> 1;
+        let remote = ahead.generate();
+
+        let mut behind = HlcGenerator::new("n2");
+        behind.generate();
+        behind.observe(&remote);
+
+        let (Id::Scoped { value: local, .. }, Id::Scoped { value: remote, .. }) =
+            (behind.generate(), remote)
+        else {
+            panic!("HLC IDs are scoped to their node");
+        };
+        assert!(local > remote);
+    }
+
+    #[test]
+    fn hlc_ignores_older_remote_ids() {
+        let mut hlc = HlcGenerator::new("n1");
+        let before = hlc.generate();
+
+        hlc.observe(&Id::Scoped {
+            node: "n2".to_string(),
+            value: 1 << HLC_LOGICAL_BITS,
+        });
+        hlc.observe(&Id::Number(u64::MAX));
+
+        assert!(hlc.generate() > before);
+        assert!(hlc.physical < u64::MAX >> HLC_LOGICAL_BITS);
+    }
+}
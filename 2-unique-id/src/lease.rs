@@ -0,0 +1,412 @@
+use crate::message::{Body, Id, Message};
+use std::collections::VecDeque;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+const LIN_KV: &str = "lin-kv";
+/// Key in `lin-kv` holding the first ID no node leased yet.
+const HIGH_WATER_MARK: &str = "id-high-water-mark";
+const BLOCK_SIZE: u64 = 1000;
+/// Remaining IDs in the current block at which we start leasing the next one.
+const PREFETCH_AT: u64 = BLOCK_SIZE / 4;
+/// How long a `lin-kv` request may go unanswered before it is sent again.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+const KEY_DOES_NOT_EXIST: u64 = 20;
+const PRECONDITION_FAILED: u64 = 22;
+
+/// Where the lease of the next block stands.
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    Idle,
+    /// Waiting for the reply to a `read` of the high-water mark.
+    Reading {
+        msg_id: u64,
+    },
+    /// Waiting for the reply to a `cas` moving the high-water mark from `from` one block up.
+    Claiming {
+        msg_id: u64,
+        from: u64,
+    },
+}
+
+/// Dense integer IDs handed out from blocks leased in `lin-kv`, so `generate`
+/// only waits on the network when a block ran out before the next one arrived.
+#[derive(Debug)]
+pub(crate) struct Lease {
+    block: Range<u64>,
+    next_block: Option<Range<u64>>,
+    state: State,
+    /// When the request of `state` is given up on and sent again.
+    deadline: Option<Instant>,
+    next_msg_id: u64,
+    /// Clients and `msg_id`s of `generate` requests waiting for a block.
+    waiting: VecDeque<(String, u64)>,
+}
+
+impl Lease {
+    pub(crate) fn new() -> Lease {
+        Lease {
+            block: 0..0,
+            next_block: None,
+            state: State::Idle,
+            deadline: None,
+            next_msg_id: 0,
+            waiting: VecDeque::new(),
+        }
+    }
+
+    /// Leases the first block right away, before any client asks for an ID.
+    pub(crate) fn start(&mut self, node_id: &str) -> Vec<Message> {
+        self.prefetch(node_id).into_iter().collect()
+    }
+
+    /// Answers a `generate` request, or queues it until the next block arrives.
+    pub(crate) fn generate(&mut self, node_id: &str, client: String, msg_id: u64) -> Vec<Message> {
+        self.waiting.push_back((client, msg_id));
+        self.serve(node_id)
+    }
+
+    pub(crate) fn receive_read_ok(
+        &mut self,
+        node_id: &str,
+        in_reply_to: u64,
+        value: u64,
+    ) -> Vec<Message> {
+        if self.state
+            != (State::Reading {
+                msg_id: in_reply_to,
+            })
+        {
+            return vec![];
+        }
+
+        vec![self.claim(node_id, value)]
+    }
+
+    pub(crate) fn receive_cas_ok(&mut self, node_id: &str, in_reply_to: u64) -> Vec<Message> {
+        let State::Claiming { msg_id, from } = self.state else {
+            return vec![];
+        };
+
+        if msg_id != in_reply_to {
+            return vec![];
+        }
+
+        self.state = State::Idle;
+        self.deadline = None;
+        self.next_block = Some(from..from + BLOCK_SIZE);
+        debug!("Leased IDs {} to {}", from, from + BLOCK_SIZE - 1);
+
+        self.serve(node_id)
+    }
+
+    pub(crate) fn receive_error(
+        &mut self,
+        node_id: &str,
+        in_reply_to: u64,
+        code: u64,
+    ) -> Vec<Message> {
+        match self.state {
+            // The key does not exist yet, nobody leased anything
+            State::Reading { msg_id } if msg_id == in_reply_to && code == KEY_DOES_NOT_EXIST => {
+                vec![self.claim(node_id, 0)]
+            }
+            // Another node moved the high-water mark first, read where it is now
+            State::Claiming { msg_id, .. }
+                if msg_id == in_reply_to && code == PRECONDITION_FAILED =>
+            {
+                debug!("Another node leased the block first, retrying");
+
+                self.state = State::Idle;
+                self.prefetch(node_id).into_iter().collect()
+            }
+            // `lin-kv` could not serve the request, `tick` sends it again at the deadline
+            // rather than hammering a busy service
+            State::Reading { msg_id } | State::Claiming { msg_id, .. } if msg_id == in_reply_to => {
+                warn!(code, "Leasing a block failed, retrying after the timeout");
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Sends the pending request again once its reply is overdue. A `cas`
+    /// which did land after all makes the repeated one fail, the block it
+    /// claimed is skipped and the next one is leased instead.
+    pub(crate) fn tick(&mut self, node_id: &str, now: Instant) -> Vec<Message> {
+        if self.deadline.is_none_or(|deadline| now < deadline) {
+            return vec![];
+        }
+
+        match self.state {
+            State::Idle => vec![],
+            State::Reading { .. } => {
+                warn!("Reading the high-water mark timed out, retrying");
+
+                self.state = State::Idle;
+                self.prefetch(node_id).into_iter().collect()
+            }
+            State::Claiming { from, .. } => {
+                warn!("Leasing a block timed out, retrying");
+
+                vec![self.claim(node_id, from)]
+            }
+        }
+    }
+
+    /// Answers waiting requests while there are IDs left and prefetches the next block.
+    fn serve(&mut self, node_id: &str) -> Vec<Message> {
+        let mut outgoing = vec![];
+
+        while !self.waiting.is_empty() {
+            if self.block.is_empty() {
+                match self.next_block.take() {
+                    Some(next_block) => self.block = next_block,
+                    None => break,
+                }
+            }
+
+            let (client, msg_id) = self.waiting.pop_front().unwrap();
+            let id = self.block.next().unwrap();
+
+            outgoing.push(Message {
+                src: node_id.to_string(),
+                dest: client,
+                body: Body::GenerateOk {
+                    msg_id,
+                    in_reply_to: msg_id,
+                    id: Id::Number(id),
+                },
+            });
+        }
+
+        if self.next_block.is_none() && (self.block.end - self.block.start) <= PREFETCH_AT {
+            outgoing.extend(self.prefetch(node_id));
+        }
+
+        outgoing
+    }
+
+    fn prefetch(&mut self, node_id: &str) -> Option<Message> {
+        if self.state != State::Idle {
+            return None;
+        }
+
+        self.next_msg_id += 1;
+        self.state = State::Reading {
+            msg_id: self.next_msg_id,
+        };
+        self.deadline = Some(Instant::now() + REQUEST_TIMEOUT);
+
+        Some(Message {
+            src: node_id.to_string(),
+            dest: LIN_KV.to_string(),
+            body: Body::Read {
+                msg_id: self.next_msg_id,
+                key: HIGH_WATER_MARK.to_string(),
+            },
+        })
+    }
+
+    fn claim(&mut self, node_id: &str, from: u64) -> Message {
+        self.next_msg_id += 1;
+        self.state = State::Claiming {
+            msg_id: self.next_msg_id,
+            from,
+        };
+        self.deadline = Some(Instant::now() + REQUEST_TIMEOUT);
+
+        Message {
+            src: node_id.to_string(),
+            dest: LIN_KV.to_string(),
+            body: Body::Cas {
+                msg_id: self.next_msg_id,
+                key: HIGH_WATER_MARK.to_string(),
+                from,
+                to: from + BLOCK_SIZE,
+                create_if_not_exists: true,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPORARILY_UNAVAILABLE: u64 = 11;
+
+    /// The `msg_id` of the single `read` of the high-water mark in `outgoing`.
+    fn read(outgoing: &[Message]) -> u64 {
+        match outgoing {
+            [Message {
+                dest,
+                body: Body::Read { msg_id, key },
+                ..
+            }] if dest == LIN_KV && key == HIGH_WATER_MARK => *msg_id,
+            _ => panic!("Expected a read of the high-water mark, got {:?}", outgoing),
+        }
+    }
+
+    /// The `msg_id` and `from` of the single `cas` of the high-water mark in `outgoing`.
+    fn cas(outgoing: &[Message]) -> (u64, u64) {
+        match outgoing {
+            [Message {
+                body: Body::Cas {
+                    msg_id, from, to, ..
+                },
+                ..
+            }] if *to == from + BLOCK_SIZE => (*msg_id, *from),
+            _ => panic!("Expected a cas of the high-water mark, got {:?}", outgoing),
+        }
+    }
+
+    fn ids(outgoing: &[Message]) -> Vec<u64> {
+        outgoing
+            .iter()
+            .filter_map(|m| match m.body {
+                Body::GenerateOk {
+                    id: Id::Number(id), ..
+                } => Some(id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// A lease holding the block starting at `from`.
+    fn leased(from: u64) -> Lease {
+        let mut lease = Lease::new();
+        let msg_id = read(&lease.start("n1"));
+        let (msg_id, _) = cas(&lease.receive_read_ok("n1", msg_id, from));
+        lease.receive_cas_ok("n1", msg_id);
+        lease
+    }
+
+    #[test]
+    fn the_first_block_is_created_when_nobody_leased_one() {
+        let mut lease = Lease::new();
+        let msg_id = read(&lease.start("n1"));
+
+        let outgoing = lease.receive_error("n1", msg_id, KEY_DOES_NOT_EXIST);
+
+        assert_eq!(cas(&outgoing).1, 0);
+    }
+
+    #[test]
+    fn requests_wait_for_the_block_and_get_dense_ids() {
+        let mut lease = Lease::new();
+        let msg_id = read(&lease.start("n1"));
+        assert!(lease.generate("n1", "c1".to_string(), 1).is_empty());
+        assert!(lease.generate("n1", "c2".to_string(), 1).is_empty());
+
+        let (msg_id, _) = cas(&lease.receive_read_ok("n1", msg_id, 3000));
+        let outgoing = lease.receive_cas_ok("n1", msg_id);
+
+        assert_eq!(ids(&outgoing), [3000, 3001]);
+        assert_eq!(ids(&lease.generate("n1", "c1".to_string(), 2)), [3002]);
+    }
+
+    #[test]
+    fn the_next_block_is_prefetched_once_few_ids_are_left() {
+        let mut lease = leased(0);
+
+        for n in 0..BLOCK_SIZE - PREFETCH_AT - 1 {
+            let outgoing = lease.generate("n1", "c1".to_string(), n);
+            assert_eq!(outgoing.len(), 1, "Prefetched too early, at {}", n);
+        }
+
+        let outgoing = lease.generate("n1", "c1".to_string(), 0);
+        assert_eq!(ids(&outgoing), [BLOCK_SIZE - PREFETCH_AT - 1]);
+        read(&outgoing[1..]);
+    }
+
+    #[test]
+    fn ids_continue_from_the_prefetched_block() {
+        let mut lease = leased(0);
+        let outgoing: Vec<Message> = (0..BLOCK_SIZE - PREFETCH_AT)
+            .flat_map(|n| lease.generate("n1", "c1".to_string(), n))
+            .collect();
+        let msg_id = read(&outgoing[outgoing.len() - 1..]);
+        let (msg_id, _) = cas(&lease.receive_read_ok("n1", msg_id, 5000));
+        lease.receive_cas_ok("n1", msg_id);
+
+        let outgoing: Vec<Message> = (0..PREFETCH_AT + 1)
+            .flat_map(|n| lease.generate("n1", "c1".to_string(), n))
+            .collect();
+
+        assert_eq!(ids(&outgoing[outgoing.len() - 2..]), [BLOCK_SIZE - 1, 5000]);
+    }
+
+    #[test]
+    fn a_conflicting_cas_reads_the_high_water_mark_again() {
+        let mut lease = Lease::new();
+        let msg_id = read(&lease.start("n1"));
+        let (msg_id, _) = cas(&lease.receive_read_ok("n1", msg_id, 0));
+
+        // Another node leased the block first
+        let msg_id = read(&lease.receive_error("n1", msg_id, PRECONDITION_FAILED));
+        let (_, from) = cas(&lease.receive_read_ok("n1", msg_id, BLOCK_SIZE));
+
+        assert_eq!(from, BLOCK_SIZE);
+    }
+
+    #[test]
+    fn an_unavailable_lin_kv_is_retried_at_the_deadline() {
+        let mut lease = Lease::new();
+        let first = read(&lease.start("n1"));
+
+        assert!(lease
+            .receive_error("n1", first, TEMPORARILY_UNAVAILABLE)
+            .is_empty());
+        assert!(lease.tick("n1", Instant::now()).is_empty());
+        let msg_id = read(&lease.tick("n1", Instant::now() + REQUEST_TIMEOUT));
+        let (first, _) = cas(&lease.receive_read_ok("n1", msg_id, 4000));
+
+        assert!(lease
+            .receive_error("n1", first, TEMPORARILY_UNAVAILABLE)
+            .is_empty());
+        assert!(lease.tick("n1", Instant::now()).is_empty());
+        let (second, from) = cas(&lease.tick("n1", Instant::now() + REQUEST_TIMEOUT));
+        assert_ne!(first, second);
+        assert_eq!(from, 4000);
+    }
+
+    #[test]
+    fn an_unanswered_read_is_sent_again() {
+        let mut lease = Lease::new();
+        let first = read(&lease.start("n1"));
+
+        assert!(lease.tick("n1", Instant::now()).is_empty());
+        let second = read(&lease.tick("n1", Instant::now() + REQUEST_TIMEOUT));
+        assert_ne!(first, second);
+
+        // The late reply to the first read is not acted on twice
+        assert!(lease.receive_read_ok("n1", first, 0).is_empty());
+        cas(&lease.receive_read_ok("n1", second, 0));
+    }
+
+    #[test]
+    fn an_unanswered_cas_is_sent_again() {
+        let mut lease = Lease::new();
+        let msg_id = read(&lease.start("n1"));
+        let (first, _) = cas(&lease.receive_read_ok("n1", msg_id, 2000));
+
+        let (second, from) = cas(&lease.tick("n1", Instant::now() + REQUEST_TIMEOUT));
+
+        assert_ne!(first, second);
+        assert_eq!(from, 2000);
+        assert!(lease.receive_cas_ok("n1", first).is_empty());
+        lease.receive_cas_ok("n1", second);
+        assert_eq!(ids(&lease.generate("n1", "c1".to_string(), 1)), [2000]);
+    }
+
+    #[test]
+    fn an_idle_lease_sends_nothing_on_tick() {
+        let mut lease = leased(0);
+
+        assert!(lease
+            .tick("n1", Instant::now() + REQUEST_TIMEOUT * 2)
+            .is_empty());
+    }
+}
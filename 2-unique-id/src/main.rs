@@ -1,94 +1,174 @@
-use serde::{Deserialize, Serialize};
-use std::io::{self, BufRead, Write};
-use uuid7::Uuid;
-
-#[derive(Serialize, Deserialize, Debug)]
-struct Message {
-    src: String,
-    dest: String,
-    body: Body,
-}
+// Stdout carries the Maelstrom protocol, only the message writer may touch it.
+#![deny(clippy::print_stdout)]
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "type")]
-enum Body {
-    #[serde(rename = "init")]
-    Init {
-        msg_id: u64,
-        node_id: String,
-        node_ids: Vec<String>,
-    },
-    #[serde(rename = "init_ok")]
-    InitOk { in_reply_to: u64 },
-    #[serde(rename = "generate")]
-    Generate {
-        msg_id: u64,
-    },
-    #[serde(rename = "generate_ok")]
-    GenerateOk {
-        msg_id: u64,
-        in_reply_to: u64,
-        id: Uuid,
-    },
-    #[serde(rename = "error")]
-    Error {
-        in_reply_to: u64,
-        code: u64,
-        text: String,
-    },
-}
+mod config;
+mod generator;
+mod lease;
+mod message;
+mod snowflake;
+
+use crate::config::Config;
+use crate::generator::Source;
+use crate::message::{Body, Message};
+use clap::Parser;
+use std::io::{self, BufRead, Stdout, Write};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info_span, warn};
+
+/// Maelstrom's `malformed-request` error.
+const MALFORMED_REQUEST: u64 = 12;
+
+/// How often leased blocks check for `lin-kv` requests which went unanswered.
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
 
 fn main() {
-    let stdin = io::stdin();
+    let config = Config::parse();
+    logging::init(&config.log_level);
+
+    // Lines arrive on their own thread so the loop can wake up for ticks
+    let (lines_tx, lines_rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            if lines_tx.send(line.unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+
     let mut stdout = io::stdout();
     let mut node_id = String::new();
+    let mut source: Option<Source> = None;
+
+    let mut next_tick = Instant::now() + TICK_INTERVAL;
+
+    loop {
+        // Ticks resend lost requests, so a busy stdin must not hold them off
+        if Instant::now() >= next_tick {
+            if let Some(Source::Leased(lease)) = source.as_mut() {
+                send(&mut stdout, lease.tick(&node_id, Instant::now()));
+            }
+            next_tick = Instant::now() + TICK_INTERVAL;
+        }
+
+        let until_tick = next_tick.saturating_duration_since(Instant::now());
+        let line = match lines_rx.recv_timeout(until_tick) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
 
-    for line in stdin.lock().lines() {
-        let input: Message = serde_json::from_str(&line.unwrap()).unwrap();
-        match input.body {
-            Body::Init {
-                msg_id,
-                node_id: id,
-                ..
-            } => {
+        let input: Message = serde_json::from_str(&line).unwrap();
+        let (body_type, msg_id) = logging::header(&input.body);
+        let span = info_span!("message", node_id = %node_id, msg_id, body_type = %body_type);
+        let _enter = span.enter();
+
+        match (input.body, source.as_mut()) {
+            (
+                Body::Init {
+                    msg_id,
+                    node_id: id,
+                    ..
+                },
+                _,
+            ) => {
                 node_id = id;
-                let output = Message {
+
+                let mut new_source =
+                    match Source::new(config.id_strategy, &node_id, config.id_clock_regression) {
+                        Ok(new_source) => new_source,
+                        Err(e) => {
+                            error!("Cannot make IDs: {}", e);
+
+                            let output = Message {
+                                src: node_id.clone(),
+                                dest: input.src,
+                                body: Body::Error {
+                                    in_reply_to: msg_id,
+                                    code: MALFORMED_REQUEST,
+                                    text: e,
+                                },
+                            };
+                            send(&mut stdout, [output]);
+                            continue;
+                        }
+                    };
+
+                let mut outgoing = vec![Message {
                     src: node_id.clone(),
                     dest: input.src,
                     body: Body::InitOk {
                         in_reply_to: msg_id,
                     },
-                };
-                let output_json = serde_json::to_string(&output).unwrap();
-                writeln!(stdout, "{}", output_json).unwrap();
-                stdout.flush().unwrap();
+                }];
+
+                if let Source::Leased(lease) = &mut new_source {
+                    outgoing.extend(lease.start(&node_id));
+                }
+
+                source = Some(new_source);
+                send(&mut stdout, outgoing);
             }
-            Body::Generate { msg_id, .. } => {
-                let uuid = uuid7::uuid7();
+            (Body::Generate { msg_id, .. }, Some(Source::Leased(lease))) => {
+                send(&mut stdout, lease.generate(&node_id, input.src, msg_id));
+            }
+            (Body::Generate { msg_id, after }, Some(Source::Local(generator))) => {
+                if let Some(after) = after {
+                    generator.observe(&after);
+                }
+
                 let output = Message {
                     src: node_id.clone(),
                     dest: input.src,
                     body: Body::GenerateOk {
                         msg_id,
                         in_reply_to: msg_id,
-                        id: uuid,
+                        id: generator.generate(),
                     },
                 };
-                let output_json = serde_json::to_string(&output).unwrap();
-                writeln!(stdout, "{}", output_json).unwrap();
-                stdout.flush().unwrap();
+                send(&mut stdout, [output]);
             }
-            Body::Error {
-                in_reply_to,
-                code,
-                text,
-            } => {
-                eprintln!(
-                    "Error received (in_reply_to: {}, code: {}, text: {})",
-                    in_reply_to, code, text
+            (Body::ReadOk { in_reply_to, value }, Some(Source::Leased(lease))) => {
+                send(
+                    &mut stdout,
+                    lease.receive_read_ok(&node_id, in_reply_to, value),
                 );
             }
+            (Body::CasOk { in_reply_to }, Some(Source::Leased(lease))) => {
+                send(&mut stdout, lease.receive_cas_ok(&node_id, in_reply_to));
+            }
+            (
+                Body::Error {
+                    in_reply_to, code, ..
+                },
+                Some(Source::Leased(lease)),
+            ) => {
+                send(
+                    &mut stdout,
+                    lease.receive_error(&node_id, in_reply_to, code),
+                );
+            }
+            (
+                Body::Error {
+                    in_reply_to,
+                    code,
+                    text,
+                },
+                _,
+            ) => {
+                warn!(in_reply_to, code, "Error received: {}", text);
+            }
+            (Body::Generate { .. }, None) => warn!("Received generate before init"),
             _ => (),
         }
     }
 }
+
+fn send(stdout: &mut Stdout, outgoing: impl IntoIterator<Item = Message>) {
+    for output in outgoing {
+        let output_json = serde_json::to_string(&output).unwrap();
+        writeln!(stdout, "{}", output_json).unwrap();
+    }
+    stdout.flush().unwrap();
+}
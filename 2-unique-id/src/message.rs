@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+use uuid7::Uuid;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Message {
+    pub src: String,
+    pub dest: String,
+    pub body: Body,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+pub enum Body {
+    #[serde(rename = "init")]
+    Init {
+        msg_id: u64,
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    #[serde(rename = "init_ok")]
+    InitOk { in_reply_to: u64 },
+    #[serde(rename = "generate")]
+    Generate {
+        msg_id: u64,
+        /// An ID the client already got, the new one sorts after it where the
+        /// strategy can promise that.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        after: Option<Id>,
+    },
+    #[serde(rename = "generate_ok")]
+    GenerateOk {
+        msg_id: u64,
+        in_reply_to: u64,
+        id: Id,
+    },
+    #[serde(rename = "read")]
+    Read { msg_id: u64, key: String },
+    #[serde(rename = "read_ok")]
+    ReadOk { in_reply_to: u64, value: u64 },
+    #[serde(rename = "cas")]
+    Cas {
+        msg_id: u64,
+        key: String,
+        from: u64,
+        to: u64,
+        create_if_not_exists: bool,
+    },
+    #[serde(rename = "cas_ok")]
+    CasOk { in_reply_to: u64 },
+    #[serde(rename = "error")]
+    Error {
+        in_reply_to: u64,
+        code: u64,
+        text: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(untagged)]
+pub enum Id {
+    Uuid(Uuid),
+    Number(u64),
+    /// A value which is only unique together with the node which issued it.
+    Scoped {
+        node: String,
+        value: u64,
+    },
+}
@@ -0,0 +1,292 @@
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 2023-01-01T00:00:00Z, gives the 41 timestamp bits roughly 69 years of room.
+const EPOCH: u64 = 1_672_531_200_000;
+const NODE_BITS: u64 = 10;
+const SEQUENCE_BITS: u64 = 12;
+const MAX_NODE: u64 = (1 << NODE_BITS) - 1;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+
+/// What to do when the wall clock goes backwards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ClockRegression {
+    /// Block until the clock caught up with the last timestamp we used.
+    Wait,
+    /// Keep issuing IDs from the last timestamp, moving it forward once its sequence runs out.
+    Borrow,
+}
+
+impl FromStr for ClockRegression {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "wait" => Ok(ClockRegression::Wait),
+            "borrow" => Ok(ClockRegression::Borrow),
+            _ => Err(format!("Unknown clock regression policy: {}", policy)),
+        }
+    }
+}
+
+/// The wall clock the timestamps come from.
+pub(crate) trait Clock: Send {
+    /// Milliseconds since the UNIX epoch.
+    fn now(&self) -> u64;
+
+    fn sleep(&self, millis: u64);
+}
+
+#[derive(Debug)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System clock is before the UNIX epoch")
+            .as_millis() as u64
+    }
+
+    fn sleep(&self, millis: u64) {
+        thread::sleep(Duration::from_millis(millis));
+    }
+}
+
+/// 64-bit, k-sortable IDs made of a millisecond timestamp, the node number and
+/// a per-millisecond sequence, unique without any coordination between nodes.
+#[derive(Debug)]
+pub(crate) struct Snowflake<C = SystemClock> {
+    node: u64,
+    /// Milliseconds since `EPOCH`.
+    last_timestamp: u64,
+    sequence: u64,
+    on_regression: ClockRegression,
+    clock: C,
+}
+
+impl Snowflake {
+    /// Takes the node number from the numeric suffix of a Maelstrom node id,
+    /// e.g. `n3`. Fails for an id without one, or one too large for the node bits.
+    pub(crate) fn new(node_id: &str, on_regression: ClockRegression) -> Result<Snowflake, String> {
+        Snowflake::with_clock(node_id, on_regression, SystemClock)
+    }
+}
+
+impl<C: Clock> Snowflake<C> {
+    pub(crate) fn with_clock(
+        node_id: &str,
+        on_regression: ClockRegression,
+        clock: C,
+    ) -> Result<Snowflake<C>, String> {
+        let node = node_id
+            .trim_start_matches(|c: char| !c.is_ascii_digit())
+            .parse::<u64>()
+            .map_err(|_| {
+                format!(
+                    "Snowflake IDs need a node id with a numeric suffix, not {}",
+                    node_id
+                )
+            })?;
+
+        if node > MAX_NODE {
+            return Err(format!(
+                "Node number {} does not fit in {} bits",
+                node, NODE_BITS
+            ));
+        }
+
+        Ok(Snowflake {
+            node,
+            last_timestamp: 0,
+            sequence: 0,
+            on_regression,
+            clock,
+        })
+    }
+
+    pub(crate) fn generate(&mut self) -> u64 {
+        let mut timestamp = self.millis();
+
+        if timestamp < self.last_timestamp {
+            timestamp = match self.on_regression {
+                ClockRegression::Wait => self.wait_until(self.last_timestamp),
+                ClockRegression::Borrow => self.last_timestamp,
+            };
+        }
+
+        if timestamp == self.last_timestamp {
+            self.sequence = (self.sequence + 1) & MAX_SEQUENCE;
+
+            if self.sequence == 0 {
+                timestamp = match self.on_regression {
+                    ClockRegression::Wait => self.wait_until(self.last_timestamp + 1),
+                    ClockRegression::Borrow => self.last_timestamp + 1,
+                };
+            }
+        } else {
+            self.sequence = 0;
+        }
+
+        self.last_timestamp = timestamp;
+
+        (timestamp << (NODE_BITS + SEQUENCE_BITS)) | (self.node << SEQUENCE_BITS) | self.sequence
+    }
+
+    /// Milliseconds since `EPOCH`. A clock set before it reads as `EPOCH`
+    /// itself, and is then handled like a clock which went backwards.
+    fn millis(&self) -> u64 {
+        self.clock.now().saturating_sub(EPOCH)
+    }
+
+    fn wait_until(&self, timestamp: u64) -> u64 {
+        loop {
+            let now = self.millis();
+
+            if now >= timestamp {
+                return now;
+            }
+
+            self.clock.sleep(timestamp - now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    /// A clock the test sets by hand, sleeping moves it forward.
+    #[derive(Clone, Debug)]
+    struct ManualClock(Arc<AtomicU64>);
+
+    impl ManualClock {
+        fn at(millis: u64) -> ManualClock {
+            ManualClock(Arc::new(AtomicU64::new(millis)))
+        }
+
+        fn set(&self, millis: u64) {
+            self.0.store(millis, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+
+        fn sleep(&self, millis: u64) {
+            self.0.fetch_add(millis, Ordering::SeqCst);
+        }
+    }
+
+    fn timestamp(id: u64) -> u64 {
+        id >> (NODE_BITS + SEQUENCE_BITS)
+    }
+
+    fn sequence(id: u64) -> u64 {
+        id & MAX_SEQUENCE
+    }
+
+    fn snowflake(on_regression: ClockRegression, clock: &ManualClock) -> Snowflake<ManualClock> {
+        Snowflake::with_clock("n3", on_regression, clock.clone()).unwrap()
+    }
+
+    #[test]
+    fn ids_carry_the_timestamp_node_and_sequence() {
+        let clock = ManualClock::at(EPOCH + 100);
+        let mut snowflake = snowflake(ClockRegression::Wait, &clock);
+
+        let first = snowflake.generate();
+        let second = snowflake.generate();
+
+        assert_eq!(timestamp(first), 100);
+        assert_eq!((first >> SEQUENCE_BITS) & MAX_NODE, 3);
+        assert_eq!((sequence(first), sequence(second)), (0, 1));
+    }
+
+    #[test]
+    fn borrow_keeps_the_last_timestamp_when_the_clock_goes_back() {
+        let clock = ManualClock::at(EPOCH + 100);
+        let mut snowflake = snowflake(ClockRegression::Borrow, &clock);
+        let before = snowflake.generate();
+
+        clock.set(EPOCH + 50);
+        let after = snowflake.generate();
+
+        assert!(after > before);
+        assert_eq!(timestamp(after), 100);
+        assert_eq!(clock.now(), EPOCH + 50);
+    }
+
+    #[test]
+    fn borrow_moves_past_the_last_timestamp_once_its_sequence_runs_out() {
+        let clock = ManualClock::at(EPOCH + 100);
+        let mut snowflake = snowflake(ClockRegression::Borrow, &clock);
+        snowflake.generate();
+        clock.set(EPOCH + 50);
+
+        let ids: Vec<u64> = (0..=MAX_SEQUENCE).map(|_| snowflake.generate()).collect();
+
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(timestamp(*ids.last().unwrap()), 101);
+        assert_eq!(clock.now(), EPOCH + 50);
+    }
+
+    #[test]
+    fn wait_blocks_until_the_clock_catches_up() {
+        let clock = ManualClock::at(EPOCH + 100);
+        let mut snowflake = snowflake(ClockRegression::Wait, &clock);
+        let before = snowflake.generate();
+
+        clock.set(EPOCH + 50);
+        let after = snowflake.generate();
+
+        assert!(after > before);
+        assert_eq!(timestamp(after), 100);
+        assert_eq!(clock.now(), EPOCH + 100);
+    }
+
+    #[test]
+    fn wait_sleeps_into_the_next_millisecond_once_the_sequence_runs_out() {
+        let clock = ManualClock::at(EPOCH + 100);
+        let mut snowflake = snowflake(ClockRegression::Wait, &clock);
+
+        let ids: Vec<u64> = (0..=MAX_SEQUENCE + 1)
+            .map(|_| snowflake.generate())
+            .collect();
+
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(timestamp(*ids.last().unwrap()), 101);
+        assert_eq!(clock.now(), EPOCH + 101);
+    }
+
+    #[test]
+    fn a_clock_before_the_epoch_does_not_underflow() {
+        let clock = ManualClock::at(EPOCH - 1000);
+        let mut snowflake = snowflake(ClockRegression::Borrow, &clock);
+
+        let first = snowflake.generate();
+        let second = snowflake.generate();
+
+        assert_eq!(timestamp(first), 0);
+        assert!(second > first);
+    }
+
+    #[test]
+    fn node_ids_which_do_not_fit_are_refused() {
+        let clock = ManualClock::at(EPOCH);
+
+        for node_id in ["c1x", "node", "n1024"] {
+            assert!(
+                Snowflake::with_clock(node_id, ClockRegression::Wait, clock.clone()).is_err(),
+                "{} was accepted",
+                node_id
+            );
+        }
+        assert!(Snowflake::with_clock("n1023", ClockRegression::Wait, clock).is_ok());
+    }
+}
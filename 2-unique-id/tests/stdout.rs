@@ -0,0 +1,65 @@
+//! Feeds every message type through the node with each ID strategy and checks
+//! that everything it writes to stdout is a protocol message, diagnostics
+//! belong on stderr.
+
+#[allow(dead_code)]
+#[path = "../src/message.rs"]
+mod message;
+
+use message::Message;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const STRATEGIES: &[&str] = &["uuid7", "uuid4", "snowflake", "counter", "hlc", "lease"];
+
+const MESSAGES: &[&str] = &[
+    r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"init_ok","in_reply_to":1}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"generate","msg_id":2}}"#,
+    r#"{"src":"lin-kv","dest":"n1","body":{"type":"error","in_reply_to":1,"code":20,"text":"key does not exist"}}"#,
+    r#"{"src":"lin-kv","dest":"n1","body":{"type":"cas_ok","in_reply_to":2}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"generate","msg_id":3}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"generate_ok","msg_id":4,"in_reply_to":3,"id":7}}"#,
+    r#"{"src":"n2","dest":"n1","body":{"type":"read","msg_id":5,"key":"id-high-water-mark"}}"#,
+    r#"{"src":"lin-kv","dest":"n1","body":{"type":"read_ok","in_reply_to":5,"value":1000}}"#,
+    r#"{"src":"n2","dest":"n1","body":{"type":"cas","msg_id":6,"key":"id-high-water-mark","from":0,"to":1000,"create_if_not_exists":true}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"error","in_reply_to":4,"code":11,"text":"busy"}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"generate","msg_id":7}}"#,
+];
+
+fn run_node(strategy: &str) -> String {
+    let mut node = Command::new(env!("CARGO_BIN_EXE_ch02-unique-id"))
+        .env("ID_STRATEGY", strategy)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut stdin = node.stdin.take().unwrap();
+    for message in MESSAGES {
+        writeln!(stdin, "{}", message).unwrap();
+    }
+    drop(stdin);
+
+    let output = node.wait_with_output().unwrap();
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn stdout_only_carries_messages() {
+    for strategy in STRATEGIES {
+        let stdout = run_node(strategy);
+
+        assert!(
+            !stdout.is_empty(),
+            "Node with {} answered nothing",
+            strategy
+        );
+
+        for line in stdout.lines() {
+            serde_json::from_str::<Message>(line)
+                .unwrap_or_else(|e| panic!("{:?} from {} is not a message: {}", line, strategy, e));
+        }
+    }
+}
@@ -0,0 +1,9 @@
+use clap::Parser;
+
+/// A single node keeps every value itself, only its logging is configurable.
+#[derive(Parser, Debug, Clone)]
+pub(crate) struct Config {
+    /// Log filter, e.g. `debug` or `ch03a_single_node_broadcast=trace`.
+    #[arg(long, env = "RUST_LOG", default_value = "info", value_parser = logging::parse_filter)]
+    pub(crate) log_level: String,
+}
@@ -1,6 +1,6 @@
 use crate::message::Message;
-use log::info;
 use std::io::{BufRead, Write};
+use tracing::debug;
 
 #[derive(Debug)]
 pub struct Connection<'a> {
@@ -19,8 +19,8 @@ impl<'a> Connection<'a> {
     pub fn read_one(&mut self) -> Option<Message> {
         let mut buf = String::new();
         let _ = self.reader.read_line(&mut buf);
-        info!("read_from_stdin:: {buf:?}");
-        return Some(Message::parse_message(buf));
+        debug!("Read from stdin: {buf:?}");
+        Some(Message::parse_message(buf))
     }
 
     pub fn read(&mut self) -> Option<Message> {
@@ -39,7 +39,7 @@ impl<'a> Connection<'a> {
     }
 
     pub fn write(&mut self, message: Message) {
-        info!("write to stdout: {message:?}");
+        debug!("Write to stdout: {message:?}");
         let message = Message::format_message(message);
         writeln!(self.writer, "{}", message).unwrap();
         self.writer.flush().unwrap();
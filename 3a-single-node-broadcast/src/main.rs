@@ -1,20 +1,33 @@
+// Stdout carries the Maelstrom protocol, only the message writer may touch it.
+#![deny(clippy::print_stdout)]
+
+mod config;
 mod connection;
 mod message;
 mod node;
 mod storage;
 
+use crate::config::Config;
 use crate::connection::Connection;
 use crate::message::{Body, Message};
 use crate::node::Node;
+use clap::Parser;
+use tracing::{info_span, warn};
 
 fn main() {
-    log4rs::init_file("log4rs.yaml", Default::default()).unwrap();
+    let config = Config::parse();
+    logging::init(&config.log_level);
+
     let stdin = std::io::stdin();
     let mut connection = Connection::new(stdin);
 
     let mut node = init_node(&mut connection);
 
     while let Some(message) = connection.read() {
+        let (body_type, msg_id) = logging::header(&message.body);
+        let span = info_span!("message", node_id = %node.id, msg_id, body_type = %body_type);
+        let _enter = span.enter();
+
         handle_message(&mut node, &mut connection, message);
     }
 }
@@ -91,10 +104,7 @@ fn handle_message(node: &mut Node, connection: &mut Connection, input: Message)
             code,
             text,
         } => {
-            eprintln!(
-                "Error received (in_reply_to: {}, code: {}, text: {})",
-                in_reply_to, code, text
-            );
+            warn!(in_reply_to, code, "Error received: {}", text);
         }
         _ => (),
     }
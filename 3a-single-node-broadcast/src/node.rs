@@ -15,13 +15,11 @@ impl Node {
         match message.body {
             Body::Init {
                 node_id, node_ids, ..
-            } => {
-                return Node {
-                    id: node_id,
-                    availble_nodes: node_ids,
-                    storage: Storage::new(),
-                }
-            }
+            } => Node {
+                id: node_id,
+                availble_nodes: node_ids,
+                storage: Storage::new(),
+            },
             _ => panic!("Invalid message type"),
         }
     }
@@ -0,0 +1,48 @@
+//! Feeds every message type through the node and checks that everything it
+//! writes to stdout is a protocol message, diagnostics belong on stderr.
+
+#[allow(dead_code)]
+#[path = "../src/message.rs"]
+mod message;
+
+use message::Message;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const MESSAGES: &[&str] = &[
+    r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2","n3"]}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"init_ok","in_reply_to":1}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"topology","msg_id":2,"topology":{"n1":["n2","n3"],"n2":["n1"],"n3":["n1"]}}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"topology_ok","msg_id":3,"in_reply_to":2}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":4,"message":10}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"broadcast_ok","msg_id":5,"in_reply_to":4}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":6}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"read_ok","msg_id":7,"in_reply_to":6,"messages":[10]}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"error","in_reply_to":6,"code":11,"text":"busy"}}"#,
+];
+
+#[test]
+fn stdout_only_carries_messages() {
+    let mut node = Command::new(env!("CARGO_BIN_EXE_ch03a-single-node-broadcast"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut stdin = node.stdin.take().unwrap();
+    for message in MESSAGES {
+        writeln!(stdin, "{}", message).unwrap();
+    }
+    drop(stdin);
+
+    let output = node.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert!(!stdout.is_empty(), "Node did not answer anything");
+
+    for line in stdout.lines() {
+        serde_json::from_str::<Message>(line)
+            .unwrap_or_else(|e| panic!("{:?} is not a message: {}", line, e));
+    }
+}
@@ -0,0 +1,111 @@
+//! Broadcast throughput of a single node under a high message rate: client
+//! broadcasts interleaved with peer gossip, written as fast as the node takes
+//! them, timed until every `broadcast_ok` came back.
+//!
+//! `cargo bench`, `BENCH_BROADCASTS` sets the number of broadcasts per run.
+
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_BROADCASTS: usize = 20_000;
+/// Every how many broadcasts a peer gossips a batch of values.
+const GOSSIP_EVERY: usize = 10;
+
+const INIT: &str = r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2","n3","n4","n5"]}}"#;
+const TOPOLOGY: &str = r#"{"src":"c1","dest":"n1","body":{"type":"topology","msg_id":2,"topology":{"n1":["n2","n3","n4","n5"]}}}"#;
+
+/// Topologies with different numbers of peers per gossip round.
+const CONFIGS: &[&[(&str, &str)]] = &[
+    &[("TOPOLOGY", "maelstrom")],
+    &[("TOPOLOGY", "full")],
+    &[("TOPOLOGY", "tree"), ("FANOUT", "2")],
+];
+
+fn main() {
+    let broadcasts = std::env::var("BENCH_BROADCASTS")
+        .map(|n| n.parse().expect("BENCH_BROADCASTS is not a number"))
+        .unwrap_or(DEFAULT_BROADCASTS);
+
+    for env in CONFIGS {
+        let elapsed = run(env, broadcasts);
+
+        println!(
+            "{:<30} {} broadcasts in {:>8.1?}, {:>9.0} msg/s",
+            format!("{:?}", env),
+            broadcasts,
+            elapsed,
+            broadcasts as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
+
+fn run(env: &[(&str, &str)], broadcasts: usize) -> Duration {
+    let mut node = Command::new(env!("CARGO_BIN_EXE_ch03b-multi-node-broadcast"))
+        .envs(env.iter().cloned())
+        // Frequent gossip rounds compete with the handlers for the storage
+        .env("GOSSIP_INTERVAL", "50")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut stdin = node.stdin.take().unwrap();
+    let stdout = BufReader::new(node.stdout.take().unwrap());
+
+    writeln!(stdin, "{}", INIT).unwrap();
+    // Give the node time to answer `init` before the rest arrives, like Maelstrom does
+    thread::sleep(Duration::from_millis(300));
+    writeln!(stdin, "{}", TOPOLOGY).unwrap();
+
+    let start = Instant::now();
+
+    let writer = thread::spawn(move || {
+        for i in 0..broadcasts {
+            writeln!(
+                stdin,
+                r#"{{"src":"c1","dest":"n1","body":{{"type":"broadcast","msg_id":{},"message":{}}}}}"#,
+                i + 3,
+                i
+            )
+            .unwrap();
+
+            if i % GOSSIP_EVERY == 0 {
+                let values: Vec<String> = (0..GOSSIP_EVERY)
+                    .map(|v| (broadcasts + i + v).to_string())
+                    .collect();
+
+                writeln!(
+                    stdin,
+                    r#"{{"src":"n2","dest":"n1","body":{{"type":"gossip","messages":[{}]}}}}"#,
+                    values.join(",")
+                )
+                .unwrap();
+            }
+        }
+
+        stdin
+    });
+
+    let mut acknowledged = 0;
+
+    for line in stdout.lines() {
+        if line.unwrap().contains(r#""type":"broadcast_ok""#) {
+            acknowledged += 1;
+
+            if acknowledged == broadcasts {
+                break;
+            }
+        }
+    }
+
+    let elapsed = start.elapsed();
+
+    drop(writer.join().unwrap());
+    let _ = node.kill();
+    let _ = node.wait();
+
+    elapsed
+}
@@ -0,0 +1,78 @@
+use clap::{Parser, ValueEnum};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Gossip interval, topology and queueing of the multi-node broadcast. `--help`
+/// lists the environment variable standing in for each flag.
+#[derive(Parser, Debug, Clone)]
+pub(crate) struct Config {
+    /// Milliseconds between gossip rounds.
+    #[arg(long, env = "GOSSIP_INTERVAL", default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) gossip_interval: u64,
+
+    /// Which neighbours a node gossips with.
+    #[arg(long, env = "TOPOLOGY", value_enum, default_value_t = TopologyStrategy::Maelstrom)]
+    pub(crate) topology: TopologyStrategy,
+
+    /// Children per node in the `tree` topology.
+    #[arg(long, env = "FANOUT", default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) fanout: u64,
+
+    /// Most values in a single gossip message, unlimited if unset.
+    #[arg(long, env = "BATCH_SIZE", value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) batch_size: Option<u64>,
+
+    /// Messages each queue between reader, handler and writer holds.
+    #[arg(long, env = "QUEUE_CAPACITY", default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) queue_capacity: u64,
+
+    /// Milliseconds between queue depth reports in the log.
+    #[arg(long, env = "METRICS_INTERVAL", default_value_t = 5000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) metrics_interval: u64,
+
+    /// Log filter, e.g. `debug` or `ch03b_multi_node_broadcast=trace`.
+    #[arg(long, env = "RUST_LOG", default_value = "info", value_parser = logging::parse_filter)]
+    pub(crate) log_level: String,
+}
+
+impl Config {
+    pub(crate) fn gossip_interval(&self) -> Duration {
+        Duration::from_millis(self.gossip_interval)
+    }
+
+    pub(crate) fn metrics_interval(&self) -> Duration {
+        Duration::from_millis(self.metrics_interval)
+    }
+
+    /// Trims gossip to the configured batch size, the rest goes out in later rounds.
+    pub(crate) fn limit_batch(&self, mut messages: Vec<u64>) -> Vec<u64> {
+        if let Some(batch_size) = self.batch_size {
+            messages.truncate(batch_size as usize);
+        }
+
+        messages
+    }
+
+    /// Neighbours of every node under the configured strategy.
+    pub(crate) fn arrange(
+        &self,
+        given: HashMap<String, Vec<String>>,
+        nodes: &[String],
+    ) -> HashMap<String, Vec<String>> {
+        match self.topology {
+            TopologyStrategy::Maelstrom => given,
+            TopologyStrategy::Full => topology::full(nodes),
+            TopologyStrategy::Tree => topology::tree(nodes, self.fanout as usize),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TopologyStrategy {
+    /// The topology from Maelstrom's `topology` message.
+    Maelstrom,
+    /// Every node is a neighbour of every other node.
+    Full,
+    /// A spanning tree with `fanout` children per node, ordered by node number.
+    Tree,
+}
@@ -0,0 +1,89 @@
+//! Bounded queues between reader, handler and writer. A full queue blocks
+//! its sender, so stdin backs up until the handler catches up. Nothing is
+//! shed: peers send each value only once and never retransmit, so a dropped
+//! gossip message would be lost for good.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvError, SendError, SyncSender};
+use std::sync::Arc;
+use tracing::info;
+
+/// Number of messages waiting in a queue.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Depth(Arc<AtomicUsize>);
+
+impl Depth {
+    pub(crate) fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Sending half of a bounded queue which keeps track of its depth.
+#[derive(Debug)]
+pub(crate) struct QueueSender<T> {
+    inner: SyncSender<T>,
+    depth: Depth,
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        QueueSender {
+            inner: self.inner.clone(),
+            depth: self.depth.clone(),
+        }
+    }
+}
+
+/// Receiving half of a bounded queue which keeps track of its depth.
+#[derive(Debug)]
+pub(crate) struct QueueReceiver<T> {
+    inner: Receiver<T>,
+    depth: Depth,
+}
+
+/// A queue holding at most `capacity` messages, senders wait for room.
+pub(crate) fn bounded<T>(capacity: usize) -> (QueueSender<T>, QueueReceiver<T>) {
+    let (inner, receiver) = mpsc::sync_channel(capacity);
+    let depth = Depth::default();
+
+    (
+        QueueSender {
+            inner,
+            depth: depth.clone(),
+        },
+        QueueReceiver {
+            inner: receiver,
+            depth,
+        },
+    )
+}
+
+impl<T> QueueSender<T> {
+    /// Waits for room, fails once the receiver is gone.
+    pub(crate) fn send(&self, item: T) -> Result<(), SendError<T>> {
+        self.depth.0.fetch_add(1, Ordering::Relaxed);
+
+        self.inner.send(item).inspect_err(|_| {
+            self.depth.0.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+
+    pub(crate) fn depth(&self) -> Depth {
+        self.depth.clone()
+    }
+}
+
+impl<T> QueueReceiver<T> {
+    /// Waits for the next item, fails once every sender is gone.
+    pub(crate) fn recv(&self) -> Result<T, RecvError> {
+        let item = self.inner.recv()?;
+        self.depth.0.fetch_sub(1, Ordering::Relaxed);
+
+        Ok(item)
+    }
+}
+
+/// Logs how many messages wait on each queue.
+pub(crate) fn report(input: &Depth, output: &Depth) {
+    info!(input = input.get(), output = output.get(), "Queue depth");
+}
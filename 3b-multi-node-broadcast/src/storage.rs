@@ -19,17 +19,11 @@ impl Storage {
     pub(crate) fn add_message(&mut self, message: u64, node: String) {
         self.messages.0.insert(message);
 
-        if self.received_messages.contains_key(&node) {
-            self.received_messages
-                .get_mut(&node)
-                .unwrap()
-                .0
-                .insert(message);
-        } else {
-            let mut v = Messages::default();
-            v.0.insert(message);
-            self.received_messages.insert(node, v);
-        }
+        self.received_messages
+            .entry(node)
+            .or_default()
+            .0
+            .insert(message);
     }
 
     pub(crate) fn get_messages(&mut self) -> Vec<u64> {
@@ -37,21 +31,9 @@ impl Storage {
     }
 
     pub(crate) fn get_messages_for_node(&self, node: String) -> Vec<u64> {
-        let received: Vec<u64> = self
-            .received_messages
-            .iter()
-            .filter(|(key, _)| *key == &node)
-            .flat_map(|(_, Messages(value))| value)
-            .cloned()
-            .collect();
-
-        let sent: Vec<u64> = self
-            .sent_messages
-            .iter()
-            .filter(|(key, _)| *key == &node)
-            .flat_map(|(_, Messages(value))| value)
-            .cloned()
-            .collect();
+        let empty = HashSet::new();
+        let received = self.received_messages.get(&node).map_or(&empty, |m| &m.0);
+        let sent = self.sent_messages.get(&node).map_or(&empty, |m| &m.0);
 
         self.messages
             .0
@@ -62,15 +44,11 @@ impl Storage {
     }
 
     pub(crate) fn add_to_sent_messages(&mut self, messages: HashSet<u64>, node: String) {
-        if self.sent_messages.contains_key(&node) {
-            self.sent_messages
-                .get_mut(&node)
-                .unwrap()
-                .0
-                .extend(messages);
-        } else {
-            self.sent_messages.insert(node, Messages(messages));
-        }
+        self.sent_messages
+            .entry(node)
+            .or_default()
+            .0
+            .extend(messages);
     }
 
     pub(crate) fn init_topology(&mut self, topology: HashMap<String, Vec<String>>) {
@@ -0,0 +1,60 @@
+//! Feeds every message type through the node and checks that everything it
+//! writes to stdout, gossip included, is a protocol message, diagnostics
+//! belong on stderr.
+
+#[allow(dead_code)]
+#[path = "../src/message.rs"]
+mod message;
+
+use message::Message;
+
+const BIN: &str = env!("CARGO_BIN_EXE_ch03b-multi-node-broadcast");
+
+const MESSAGES: &[&str] = &[
+    r#"{"src":"c1","dest":"n1","body":{"type":"init_ok","in_reply_to":1}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"topology","msg_id":2,"topology":{"n1":["n2","n3"],"n2":["n1"],"n3":["n1"]}}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"topology_ok","msg_id":3,"in_reply_to":2}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":4,"message":10}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"broadcast_ok","msg_id":5,"in_reply_to":4}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":6}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"read_ok","msg_id":7,"in_reply_to":6,"messages":[10]}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"error","in_reply_to":6,"code":11,"text":"busy"}}"#,
+    r#"{"src":"n2","dest":"n1","body":{"type":"gossip","messages":[11,12]}}"#,
+];
+
+/// The requests among `MESSAGES`.
+const REQUESTS: [u64; 3] = [2, 4, 6];
+
+/// The defaults, and a tuned topology which gossips one value at a time.
+const CONFIGS: &[&[(&str, &str)]] = &[
+    &[],
+    &[
+        ("GOSSIP_INTERVAL", "100"),
+        ("TOPOLOGY", "tree"),
+        ("FANOUT", "2"),
+        ("BATCH_SIZE", "1"),
+    ],
+];
+
+#[test]
+fn stdout_only_carries_messages() {
+    for config in CONFIGS {
+        let stdout = harness::run_node(BIN, config, MESSAGES, &REQUESTS);
+
+        assert!(
+            !stdout.is_empty(),
+            "Node with {:?} answered nothing",
+            config
+        );
+
+        for line in stdout.lines() {
+            serde_json::from_str::<Message>(line)
+                .unwrap_or_else(|e| panic!("{:?} with {:?} is not a message: {}", line, config, e));
+        }
+    }
+}
+
+#[test]
+fn overloaded_node_answers_every_request_once() {
+    harness::assert_overload_answered_once(BIN, &[("QUEUE_CAPACITY", "1")]);
+}
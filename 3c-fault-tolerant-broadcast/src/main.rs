@@ -1,193 +1,278 @@
+// Stdout carries the Maelstrom protocol, only the message writer may touch it.
+#![deny(clippy::print_stdout)]
+
+mod config;
 mod message;
 mod node;
+mod queue;
 mod storage;
 
+use crate::config::Config;
 use crate::message::{Body, Message};
-use crate::node::Node;
+use crate::node::{Node, NodeActor};
+use crate::queue::{Overload, QueueReceiver, QueueSender, Shed};
 
+use clap::Parser;
 use std::io::prelude::*;
 use std::io::{BufReader, Write};
-use std::sync::{
-    mpsc,
-    mpsc::{Receiver, Sender},
-    Arc, Mutex,
-};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use tracing::{info_span, warn};
 
 fn main() {
-    let (reader_tx, mut reader_rx) = mpsc::channel();
-    let (writer_tx, mut writer_rx) = mpsc::channel();
+    let config = Arc::new(Config::parse());
+    logging::init(&config.log_level);
+
+    let capacity = config.queue_capacity as usize;
+    let (reader_tx, mut reader_rx) = queue::bounded(capacity);
+    let (writer_tx, mut writer_rx) = queue::bounded(capacity);
 
-    let node = Arc::new(Mutex::new(Node::default()));
+    let shed = Arc::new(Shed::default());
+    let (input_depth, output_depth) = (reader_tx.depth(), writer_tx.depth());
+
+    let node = NodeActor::spawn(Node::default());
 
     let n1 = node.clone();
     let n2 = node.clone();
+    let config1 = config.clone();
+    let config2 = config.clone();
+    let shed1 = shed.clone();
 
-    let reader_tx1: Sender<Message> = reader_tx.clone();
-    let writer_tx1: Sender<Message> = writer_tx.clone();
-    let writer_tx2: Sender<Message> = writer_tx.clone();
+    let reader_tx1: QueueSender<Message> = reader_tx.clone();
+    let writer_tx1: QueueSender<Message> = writer_tx.clone();
+    let writer_tx2: QueueSender<Message> = writer_tx.clone();
+    let writer_tx3: QueueSender<Message> = writer_tx.clone();
 
     let read = thread::spawn(move || {
-        read_from_stdin(reader_tx1);
+        read_from_stdin(reader_tx1, writer_tx3, config2.overload, &shed1);
     });
 
     let write = thread::spawn(move || {
         write_to_stdout(&mut writer_rx);
     });
 
+    let metrics_interval = config.metrics_interval();
+    let metrics = thread::spawn(move || loop {
+        thread::sleep(metrics_interval);
+        queue::report(&input_depth, &output_depth, &shed);
+    });
+
     let gossip = thread::spawn(move || loop {
-        thread::sleep(Duration::from_secs(1));
-        gossip_messages(n1.clone(), writer_tx2.clone());
+        thread::sleep(config.gossip_interval());
+        gossip_messages(&n1, &writer_tx2, &config);
     });
 
     let handle = thread::spawn(move || {
-        handle_messages(n2, &mut reader_rx, writer_tx1);
+        handle_messages(n2, &mut reader_rx, writer_tx1, &config1);
     });
     let _ = handle.join();
     let _ = write.join();
     let _ = gossip.join();
+    let _ = metrics.join();
     let _ = read.join();
 }
 
-fn read_from_stdin(reader_tx: Sender<Message>) {
+fn read_from_stdin(
+    reader_tx: QueueSender<Message>,
+    writer: QueueSender<Message>,
+    overload: Overload,
+    shed: &Shed,
+) {
     let stdin = std::io::stdin();
     let mut reader = BufReader::new(stdin.lock());
 
     loop {
         let mut buf = String::new();
-        reader.read_line(&mut buf).unwrap();
+        if reader.read_line(&mut buf).unwrap() == 0 {
+            return;
+        }
+
         let message = Message::parse_message(buf.clone());
-        reader_tx.send(message).unwrap();
+
+        if !queue::admit(message, &reader_tx, &writer, overload, shed) {
+            return;
+        }
     }
 }
 
-fn write_to_stdout(writer_rx: &mut Receiver<Message>) {
+fn write_to_stdout(writer_rx: &mut QueueReceiver<Message>) {
     let mut stdout = std::io::stdout();
 
-    loop {
-        let message = writer_rx.recv().unwrap();
+    while let Ok(message) = writer_rx.recv() {
         let message = Message::format_message(message);
         writeln!(stdout, "{}", message).unwrap();
         stdout.flush().unwrap();
     }
 }
 
-fn gossip_messages(node: Arc<Mutex<Node>>, writer: Sender<Message>) {
-    let node = node.lock().unwrap();
-    if let Some(neighbours) = node.storage.get_neighbours(&node.get_id()) {
-        for n in neighbours {
-            let messages = node.storage.get_messages_for_node(n.clone());
-            let message = Message {
-                src: node.id.clone(),
-                dest: n.clone(),
+/// Retransmits unacknowledged batches and sends each neighbour the values it
+/// has not seen yet. The writer queue is only waited on once the node handed
+/// them out.
+fn gossip_messages(node: &NodeActor, writer: &QueueSender<Message>, config: &Arc<Config>) {
+    let config = config.clone();
+
+    let gossip = node.call(move |node| {
+        let id = node.get_id();
+        let mut gossip = vec![];
+
+        node.storage.outbox.next_round();
+
+        for (peer, seq, messages) in node.storage.outbox.get_retransmissions() {
+            let base = node.storage.outbox.base(&peer);
+            gossip.push(Message {
+                src: id.clone(),
+                dest: peer,
                 body: Body::Gossip {
-                    messages: messages.clone(),
+                    seq,
+                    base,
+                    messages,
                 },
-            };
+            });
+        }
+
+        for n in node.storage.get_neighbours(&id).unwrap_or_default() {
+            let messages = config.limit_batch(node.storage.get_messages_for_node(n.clone()));
 
-            writer.send(message).unwrap();
+            if messages.is_empty() {
+                continue;
+            }
+
+            let seq = node.storage.outbox.send(&n, messages.clone());
+            let base = node.storage.outbox.base(&n);
+            gossip.push(Message {
+                src: id.clone(),
+                dest: n,
+                body: Body::Gossip {
+                    seq,
+                    base,
+                    messages,
+                },
+            });
+        }
+
+        gossip
+    });
+
+    for message in gossip {
+        if writer.send(message).is_err() {
+            return;
         }
     }
 }
 
-fn handle_messages(node: Arc<Mutex<Node>>, input: &mut Receiver<Message>, writer: Sender<Message>) {
+fn handle_messages(
+    node: NodeActor,
+    input: &mut QueueReceiver<Message>,
+    writer: QueueSender<Message>,
+    config: &Arc<Config>,
+) {
     while let Ok(input) = input.recv() {
-        match input.body {
-            Body::Init { msg_id, .. } => {
-                node.lock().unwrap().init(input.clone());
-                let id = node.lock().unwrap().get_id();
-                let response = Message {
-                    src: id,
-                    dest: input.src,
-                    body: Body::InitOk {
-                        in_reply_to: msg_id,
-                    },
-                };
-
-                writer.send(response).unwrap();
-            }
-            Body::Broadcast { msg_id, message } => {
-                let id = node.lock().unwrap().get_id();
-                node.lock()
-                    .unwrap()
-                    .storage
-                    .add_message(message, id.clone());
-
-                let response = Message {
-                    src: id,
-                    dest: input.src,
-                    body: Body::BroadcastOk {
-                        msg_id,
-                        in_reply_to: msg_id,
-                    },
-                };
-
-                writer.send(response).unwrap();
-            }
-            Body::Gossip { messages } => {
-                let id = node.lock().unwrap().get_id();
-                for m in messages.iter() {
-                    node.lock().unwrap().storage.add_message(*m, id.clone());
-                }
-
-                let response = Message {
-                    src: id,
-                    dest: input.src,
-                    body: Body::GossipOk { messages },
-                };
-
-                writer.send(response).unwrap();
-            }
-            Body::GossipOk { messages } => {
-                let id = node.lock().unwrap().get_id();
-                node.lock()
-                    .unwrap()
-                    .storage
-                    .add_to_sent_messages(messages, id.clone());
-            }
-            Body::Read { msg_id } => {
-                let id = node.lock().unwrap().get_id();
-
-                let response = Message {
-                    src: id,
-                    dest: input.src,
-                    body: Body::ReadOk {
-                        msg_id,
-                        in_reply_to: msg_id,
-                        messages: node.lock().unwrap().storage.get_messages(),
-                    },
-                };
-
-                writer.send(response).unwrap();
-            }
-            Body::Topology { msg_id, topology } => {
-                let id = node.lock().unwrap().get_id();
-                node.lock().unwrap().storage.init_topology(topology);
-
-                let response = Message {
-                    src: id,
-                    dest: input.src,
-                    body: Body::TopologyOk {
-                        msg_id,
-                        in_reply_to: msg_id,
-                    },
-                };
-
-                writer.send(response).unwrap();
-            }
-            Body::Error {
-                in_reply_to,
-                code,
-                text,
-            } => {
-                eprintln!(
-                    "Error received (in_reply_to: {}, code: {}, text: {})",
-                    in_reply_to, code, text
-                );
+        let (body_type, msg_id) = logging::header(&input.body);
+        let span = info_span!("message", node_id = %input.dest, msg_id, body_type = %body_type);
+        let _enter = span.enter();
+
+        if let Body::Error {
+            in_reply_to,
+            code,
+            text,
+        } = &input.body
+        {
+            warn!(in_reply_to, code, "Error received: {}", text);
+            continue;
+        }
+
+        let config = config.clone();
+
+        if let Some(response) = node.call(move |node| handle(node, input, &config)) {
+            let _ = writer.send(response);
+        }
+    }
+    warn!("Nothing left to read from the receiver");
+}
+
+/// Applies `input` to the node, returns the reply if it needs one.
+fn handle(node: &mut Node, input: Message, config: &Config) -> Option<Message> {
+    match input.body {
+        Body::Init { msg_id, .. } => {
+            node.init(input.clone());
+            let response = Message {
+                src: node.get_id(),
+                dest: input.src,
+                body: Body::InitOk {
+                    in_reply_to: msg_id,
+                },
+            };
+
+            Some(response)
+        }
+        Body::Broadcast { msg_id, message } => {
+            let id = node.get_id();
+            node.storage.add_message(message, id.clone());
+
+            let response = Message {
+                src: id,
+                dest: input.src,
+                body: Body::BroadcastOk {
+                    msg_id,
+                    in_reply_to: msg_id,
+                },
+            };
+
+            Some(response)
+        }
+        Body::Gossip {
+            seq,
+            base,
+            messages,
+        } => {
+            for m in messages.iter() {
+                node.storage.add_message(*m, input.src.clone());
             }
-            _ => (),
+
+            let (ack, sack) = node.storage.outbox.receive(&input.src, seq, base);
+
+            let response = Message {
+                src: node.get_id(),
+                dest: input.src,
+                body: Body::GossipOk { ack, sack },
+            };
+
+            Some(response)
+        }
+        Body::GossipOk { ack, sack } => {
+            let messages = node.storage.outbox.acknowledge(&input.src, ack, sack);
+            node.storage.add_to_sent_messages(messages, input.src);
+
+            None
+        }
+        Body::Read { msg_id } => {
+            let response = Message {
+                src: node.get_id(),
+                dest: input.src,
+                body: Body::ReadOk {
+                    msg_id,
+                    in_reply_to: msg_id,
+                    messages: node.storage.get_messages(),
+                },
+            };
+
+            Some(response)
+        }
+        Body::Topology { msg_id, topology } => {
+            let topology = config.arrange(topology, &node.availble_nodes);
+            node.storage.init_topology(topology);
+
+            let response = Message {
+                src: node.get_id(),
+                dest: input.src,
+                body: Body::TopologyOk {
+                    msg_id,
+                    in_reply_to: msg_id,
+                },
+            };
+
+            Some(response)
         }
+        _ => None,
     }
-    println!("Error, nothing to read from receiver");
 }
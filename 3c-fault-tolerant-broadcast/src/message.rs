@@ -50,13 +50,37 @@ pub enum Body {
         in_reply_to: u64,
     },
     Gossip {
+        seq: u64,
+        /// Every batch up to this one was acknowledged by the receiver.
+        #[serde(default)]
+        base: u64,
         messages: Vec<u64>,
     },
     GossipOk {
-        messages: Vec<u64>,
+        /// Every batch up to and including this sequence number arrived.
+        ack: u64,
+        /// Batches above `ack` which arrived out of order.
+        sack: Vec<u64>,
     },
 }
 
+impl Body {
+    /// Gossip from peers, the first to go under overload.
+    pub(crate) fn is_gossip(&self) -> bool {
+        matches!(self, Body::Gossip { .. })
+    }
+
+    /// The `msg_id` of a client request, which can be turned away under overload.
+    pub(crate) fn request_id(&self) -> Option<u64> {
+        match self {
+            Body::Broadcast { msg_id, .. }
+            | Body::Read { msg_id }
+            | Body::Topology { msg_id, .. } => Some(*msg_id),
+            _ => None,
+        }
+    }
+}
+
 impl Message {
     pub(crate) fn parse_message(message: String) -> Message {
         serde_json::from_str(&message).unwrap()
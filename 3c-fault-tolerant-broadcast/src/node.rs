@@ -1,4 +1,6 @@
 use serde::{Deserialize, Serialize};
+use std::sync::mpsc;
+use std::thread;
 
 use crate::message::{Body, Message};
 use crate::storage::Storage;
@@ -27,3 +29,47 @@ impl Node {
         self.id.clone()
     }
 }
+
+type Command = Box<dyn FnOnce(&mut Node) + Send>;
+
+/// Commands queued for the node before callers wait for room.
+const COMMAND_CAPACITY: usize = 1000;
+
+/// Owns the `Node` on a thread of its own. The handler and the gossip thread
+/// send it commands which run one after another, and write their messages
+/// once a command returned, so no lock is held while the writer queue is full.
+#[derive(Clone)]
+pub(crate) struct NodeActor {
+    commands: mpsc::SyncSender<Command>,
+}
+
+impl NodeActor {
+    pub(crate) fn spawn(mut node: Node) -> NodeActor {
+        let (commands, inbox) = mpsc::sync_channel::<Command>(COMMAND_CAPACITY);
+
+        thread::spawn(move || {
+            for command in inbox {
+                command(&mut node);
+            }
+        });
+
+        NodeActor { commands }
+    }
+
+    /// Runs `command` on the node's thread and returns its result.
+    pub(crate) fn call<R, F>(&self, command: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Node) -> R + Send + 'static,
+    {
+        let (reply, result) = mpsc::sync_channel(1);
+
+        self.commands
+            .send(Box::new(move |node| {
+                let _ = reply.send(command(node));
+            }))
+            .expect("Node actor stopped");
+
+        result.recv().expect("Node actor stopped")
+    }
+}
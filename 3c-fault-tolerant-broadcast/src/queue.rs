@@ -0,0 +1,166 @@
+use crate::message::{Body, Message};
+use clap::ValueEnum;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvError, SendError, SyncSender, TrySendError};
+use std::sync::Arc;
+use tracing::{debug, info};
+
+/// Maelstrom's `temporarily-unavailable` error, clients may retry the request.
+const TEMPORARILY_UNAVAILABLE: u64 = 11;
+
+/// What the reader does with a message when the handler's queue is full.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Overload {
+    /// Wait for room, stdin backs up until the handler catches up.
+    Block,
+    /// Drop gossip, peers send it again until it is acknowledged, and wait for
+    /// room for everything else.
+    DropGossip,
+    /// Drop gossip and answer client requests with `temporarily-unavailable`.
+    Reject,
+}
+
+/// Number of messages waiting in a queue.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Depth(Arc<AtomicUsize>);
+
+impl Depth {
+    pub(crate) fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Sending half of a bounded queue which keeps track of its depth.
+#[derive(Debug)]
+pub(crate) struct QueueSender<T> {
+    inner: SyncSender<T>,
+    depth: Depth,
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        QueueSender {
+            inner: self.inner.clone(),
+            depth: self.depth.clone(),
+        }
+    }
+}
+
+/// Receiving half of a bounded queue which keeps track of its depth.
+#[derive(Debug)]
+pub(crate) struct QueueReceiver<T> {
+    inner: Receiver<T>,
+    depth: Depth,
+}
+
+/// A queue holding at most `capacity` messages, senders wait for room.
+pub(crate) fn bounded<T>(capacity: usize) -> (QueueSender<T>, QueueReceiver<T>) {
+    let (inner, receiver) = mpsc::sync_channel(capacity);
+    let depth = Depth::default();
+
+    (
+        QueueSender {
+            inner,
+            depth: depth.clone(),
+        },
+        QueueReceiver {
+            inner: receiver,
+            depth,
+        },
+    )
+}
+
+impl<T> QueueSender<T> {
+    /// Waits for room, fails once the receiver is gone.
+    pub(crate) fn send(&self, item: T) -> Result<(), SendError<T>> {
+        self.depth.0.fetch_add(1, Ordering::Relaxed);
+
+        self.inner.send(item).inspect_err(|_| {
+            self.depth.0.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+
+    /// Fails right away if the queue is full or the receiver is gone.
+    pub(crate) fn try_send(&self, item: T) -> Result<(), TrySendError<T>> {
+        self.depth.0.fetch_add(1, Ordering::Relaxed);
+
+        self.inner.try_send(item).inspect_err(|_| {
+            self.depth.0.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+
+    pub(crate) fn depth(&self) -> Depth {
+        self.depth.clone()
+    }
+}
+
+impl<T> QueueReceiver<T> {
+    /// Waits for the next item, fails once every sender is gone.
+    pub(crate) fn recv(&self) -> Result<T, RecvError> {
+        let item = self.inner.recv()?;
+        self.depth.0.fetch_sub(1, Ordering::Relaxed);
+
+        Ok(item)
+    }
+}
+
+/// Messages the overload policy turned away.
+#[derive(Debug, Default)]
+pub(crate) struct Shed {
+    dropped: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// Queues `message` for the handler, applying `overload` when the queue is
+/// full. Returns `false` once the handler is gone.
+pub(crate) fn admit(
+    message: Message,
+    input: &QueueSender<Message>,
+    writer: &QueueSender<Message>,
+    overload: Overload,
+    shed: &Shed,
+) -> bool {
+    let message = match input.try_send(message) {
+        Ok(()) => return true,
+        Err(TrySendError::Disconnected(_)) => return false,
+        Err(TrySendError::Full(message)) => message,
+    };
+
+    match (overload, message.body.request_id()) {
+        (Overload::DropGossip | Overload::Reject, _) if message.body.is_gossip() => {
+            shed.dropped.fetch_add(1, Ordering::Relaxed);
+            debug!(src = %message.src, "Queue full, dropped gossip");
+            true
+        }
+        (Overload::Reject, Some(msg_id)) => {
+            shed.rejected.fetch_add(1, Ordering::Relaxed);
+            debug!(src = %message.src, msg_id, "Queue full, rejected request");
+
+            let response = Message {
+                src: message.dest,
+                dest: message.src,
+                body: Body::Error {
+                    in_reply_to: msg_id,
+                    code: TEMPORARILY_UNAVAILABLE,
+                    text: "node is overloaded".to_string(),
+                },
+            };
+
+            // Without a writer there is nobody to answer, but the handler may still run
+            let _ = writer.send(response);
+            true
+        }
+        _ => input.send(message).is_ok(),
+    }
+}
+
+/// Logs how many messages wait on each queue and how many were shed so far.
+pub(crate) fn report(input: &Depth, output: &Depth, shed: &Shed) {
+    info!(
+        input = input.get(),
+        output = output.get(),
+        dropped = shed.dropped.load(Ordering::Relaxed),
+        rejected = shed.rejected.load(Ordering::Relaxed),
+        "Queue depth"
+    );
+}
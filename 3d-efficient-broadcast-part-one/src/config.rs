@@ -0,0 +1,132 @@
+use crate::dispatch::OrderBy;
+use crate::queue::Overload;
+use crate::scheduler::MissedTick;
+use clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Timing and peer selection of the gossip rounds, and how many messages are
+/// handled at once. Each flag has an environment variable, e.g. `MIN_FANOUT`.
+#[derive(Parser, Debug, Clone)]
+pub(crate) struct Config {
+    /// Milliseconds between gossip rounds.
+    #[arg(long, env = "GOSSIP_INTERVAL", default_value_t = 150, value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) gossip_interval: u64,
+
+    /// Most milliseconds of random delay added to each gossip round.
+    #[arg(long, env = "GOSSIP_JITTER", default_value_t = 30)]
+    pub(crate) gossip_jitter: u64,
+
+    /// What happens to gossip rounds which could not start on time.
+    #[arg(long, env = "MISSED_TICK", value_enum, default_value_t = MissedTick::Skip)]
+    pub(crate) missed_tick: MissedTick,
+
+    /// Which peers a node gossips with.
+    #[arg(long, env = "TOPOLOGY", value_enum, default_value_t = TopologyStrategy::Random)]
+    pub(crate) topology: TopologyStrategy,
+
+    /// Fewest peers picked per round in the `random` topology.
+    #[arg(long, env = "MIN_FANOUT", default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) min_fanout: u64,
+
+    /// Most peers picked per round in the `random` topology, children per node in `tree`.
+    #[arg(long, env = "FANOUT", default_value_t = 25, value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) fanout: u64,
+
+    /// Most values in a single gossip message, unlimited if unset.
+    #[arg(long, env = "BATCH_SIZE", value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) batch_size: Option<u64>,
+
+    /// Most messages handled at the same time.
+    #[arg(long, env = "CONCURRENCY", default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) concurrency: u64,
+
+    /// Which messages are handled in the order they arrived: source, key or none.
+    #[arg(long, env = "ORDER_BY", value_enum, default_value_t = OrderBy::Source)]
+    pub(crate) order_by: OrderBy,
+
+    /// Messages each queue between reader, handlers and writer holds.
+    #[arg(long, env = "QUEUE_CAPACITY", default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) queue_capacity: u64,
+
+    /// What happens to messages arriving while the handlers' queue is full:
+    /// block, drop-gossip or reject.
+    #[arg(long, env = "OVERLOAD", value_enum, default_value_t = Overload::Block)]
+    pub(crate) overload: Overload,
+
+    /// Milliseconds between queue depth reports in the log.
+    #[arg(long, env = "METRICS_INTERVAL", default_value_t = 5000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) metrics_interval: u64,
+
+    /// Log filter, e.g. `debug` or `ch03d_efficient_broadcast_part_one=trace`.
+    #[arg(long, env = "RUST_LOG", default_value = "info", value_parser = logging::parse_filter)]
+    pub(crate) log_level: String,
+}
+
+impl Config {
+    /// Parses the flags and environment, exits with usage on invalid values.
+    pub(crate) fn load() -> Config {
+        let config = Config::parse();
+
+        if config.topology == TopologyStrategy::Random && config.min_fanout > config.fanout {
+            Config::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!(
+                        "--min-fanout {} is larger than --fanout {}",
+                        config.min_fanout, config.fanout
+                    ),
+                )
+                .exit();
+        }
+
+        config
+    }
+
+    pub(crate) fn gossip_interval(&self) -> Duration {
+        Duration::from_millis(self.gossip_interval)
+    }
+
+    pub(crate) fn gossip_jitter(&self) -> Duration {
+        Duration::from_millis(self.gossip_jitter)
+    }
+
+    pub(crate) fn metrics_interval(&self) -> Duration {
+        Duration::from_millis(self.metrics_interval)
+    }
+
+    /// Trims gossip to the configured batch size, the rest goes out in later rounds.
+    pub(crate) fn limit_batch(&self, mut messages: Vec<u64>) -> Vec<u64> {
+        if let Some(batch_size) = self.batch_size {
+            messages.truncate(batch_size as usize);
+        }
+
+        messages
+    }
+
+    /// Neighbours of every node under a fixed topology strategy, `random` keeps none.
+    pub(crate) fn arrange(
+        &self,
+        given: HashMap<String, Vec<String>>,
+        nodes: &[String],
+    ) -> HashMap<String, Vec<String>> {
+        match self.topology {
+            TopologyStrategy::Random => HashMap::new(),
+            TopologyStrategy::Maelstrom => given,
+            TopologyStrategy::Full => topology::full(nodes),
+            TopologyStrategy::Tree => topology::tree(nodes, self.fanout as usize),
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TopologyStrategy {
+    /// A fresh random set of peers from the whole network every round.
+    Random,
+    /// The topology from Maelstrom's `topology` message.
+    Maelstrom,
+    /// Every node is a neighbour of every other node.
+    Full,
+    /// A spanning tree with `fanout` children per node, ordered by node number.
+    Tree,
+}
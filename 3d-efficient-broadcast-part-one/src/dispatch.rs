@@ -0,0 +1,165 @@
+use crate::message::{Body, Message};
+use clap::ValueEnum;
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task::JoinSet;
+
+/// Messages a lane buffers before the dispatcher waits for it.
+const LANE_CAPACITY: usize = 100;
+
+/// Which messages have to be handled in the order they arrived.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OrderBy {
+    /// Messages from the same node.
+    Source,
+    /// Broadcasts of the same value, other messages by their source.
+    Key,
+    /// None, any message may overtake any other.
+    None,
+}
+
+/// Handles up to `concurrency` messages at once. Each runs in one of
+/// `concurrency` lanes, and messages with the same ordering key always share a
+/// lane, so they are handled one after another in the order they arrived.
+/// Returns once `input` is closed and every lane is drained.
+pub(crate) async fn run<F, Fut>(
+    input: &mut Receiver<Message>,
+    concurrency: usize,
+    order_by: OrderBy,
+    handler: F,
+) where
+    F: Fn(Message) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let mut tasks = JoinSet::new();
+
+    let lanes: Vec<Sender<Message>> = (0..concurrency)
+        .map(|_| {
+            let (lane, mut queue) = mpsc::channel(LANE_CAPACITY);
+            let handler = handler.clone();
+
+            tasks.spawn(async move {
+                while let Some(message) = queue.recv().await {
+                    handler(message).await;
+                }
+            });
+
+            lane
+        })
+        .collect();
+
+    while let Some(message) = input.recv().await {
+        let lane = match ordering_key(&message, order_by) {
+            Some(key) => &lanes[(key % lanes.len() as u64) as usize],
+            // Unordered messages go wherever the least work is queued
+            None => lanes.iter().max_by_key(|lane| lane.capacity()).unwrap(),
+        };
+
+        if lane.send(message).await.is_err() {
+            break;
+        }
+    }
+
+    drop(lanes);
+    while tasks.join_next().await.is_some() {}
+}
+
+fn ordering_key(message: &Message, order_by: OrderBy) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+
+    match (order_by, &message.body) {
+        (OrderBy::None, _) => return None,
+        (OrderBy::Key, Body::Broadcast { message, .. }) => message.hash(&mut hasher),
+        (OrderBy::Key | OrderBy::Source, _) => message.src.hash(&mut hasher),
+    }
+
+    Some(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::sync::Notify;
+    use tokio::time;
+
+    const LANES: usize = 4;
+
+    fn read(src: &str, msg_id: u64) -> Message {
+        Message {
+            src: src.to_string(),
+            dest: "n1".to_string(),
+            body: Body::Read { msg_id },
+        }
+    }
+
+    /// A source whose messages go to another lane than those of `src`.
+    fn other_lane(src: &str) -> String {
+        let lane = |src: &str| ordering_key(&read(src, 0), OrderBy::Source).unwrap() % LANES as u64;
+
+        (2..)
+            .map(|i| format!("c{}", i))
+            .find(|other| lane(other) != lane(src))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn messages_with_the_same_key_keep_their_order() {
+        let (input_tx, mut input) = mpsc::channel(100);
+        for msg_id in 0..50 {
+            input_tx.send(read("c1", msg_id)).await.unwrap();
+        }
+        drop(input_tx);
+
+        let handled = Arc::new(Mutex::new(vec![]));
+        let handler = {
+            let handled = handled.clone();
+            move |message: Message| {
+                let handled = handled.clone();
+                async move {
+                    // Later messages would overtake if the key was spread across lanes
+                    tokio::task::yield_now().await;
+                    if let Body::Read { msg_id } = message.body {
+                        handled.lock().unwrap().push(msg_id);
+                    }
+                }
+            }
+        };
+
+        run(&mut input, LANES, OrderBy::Source, handler).await;
+
+        assert_eq!(*handled.lock().unwrap(), (0..50).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn messages_with_different_keys_are_handled_at_the_same_time() {
+        let other = other_lane("c1");
+        let (input_tx, mut input) = mpsc::channel(100);
+        input_tx.send(read("c1", 1)).await.unwrap();
+        input_tx.send(read(&other, 2)).await.unwrap();
+        drop(input_tx);
+
+        // The first handler waits for the second, which never runs if lanes take turns
+        let released = Arc::new(Notify::new());
+        let handler = move |message: Message| {
+            let released = released.clone();
+            async move {
+                if message.src == "c1" {
+                    released.notified().await;
+                } else {
+                    released.notify_one();
+                }
+            }
+        };
+
+        time::timeout(
+            Duration::from_secs(5),
+            run(&mut input, LANES, OrderBy::Source, handler),
+        )
+        .await
+        .expect("Lanes did not run concurrently");
+    }
+}
@@ -1,48 +1,71 @@
+// Stdout carries the Maelstrom protocol, only the message writer may touch it.
+#![deny(clippy::print_stdout)]
+
+mod config;
+mod dispatch;
 mod message;
 mod node;
+mod queue;
+mod scheduler;
 mod storage;
 
+use crate::config::{Config, TopologyStrategy};
 use crate::message::{Body, Message};
 use crate::node::Node;
-use crate::storage::Storage;
+use crate::queue::{Overload, Shed};
+use crate::scheduler::MissedTick;
+use crate::storage::{Storage, StorageActor};
 
 use rand::prelude::*;
 use rand::rngs::StdRng;
 use std::io::Write;
+use std::mem;
+use std::process;
 use std::sync::Arc;
 use std::time::Duration;
-use std::{println, thread};
 use tokio::io::AsyncBufReadExt;
 use tokio::io::BufReader;
+use tokio::signal;
 use tokio::sync::{
     mpsc,
     mpsc::{Receiver, Sender},
-    Mutex,
+    watch,
 };
-
-const GOSSIP_DELAY: u64 = 150;
-const MIN_AMOUNT_NODES: usize = 4;
-const NETWORK_SIZE: usize = 25;
+use tracing::{info, info_span, warn};
 
 #[tokio::main]
 async fn main() {
-    let (reader_tx, mut reader_rx) = mpsc::channel(1000);
-    let (writer_tx, mut writer_rx) = mpsc::channel(1000);
+    let config = Arc::new(Config::load());
+    logging::init(&config.log_level);
+
+    let capacity = config.queue_capacity as usize;
+    let (reader_tx, mut reader_rx) = mpsc::channel(capacity);
+    let (writer_tx, mut writer_rx) = mpsc::channel(capacity);
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
 
-    let reader_tx1: Sender<Message> = reader_tx.clone();
     let writer_tx1: Sender<Message> = writer_tx.clone();
     let writer_tx2: Sender<Message> = writer_tx.clone();
+    let writer_tx3: Sender<Message> = writer_tx.clone();
+
+    let shed = Arc::new(Shed::default());
+    let (reader_weak, writer_weak) = (reader_tx.downgrade(), writer_tx.downgrade());
 
     let node = Node::default();
-    let store = Arc::new(Mutex::new(Storage::default()));
+    let store = StorageActor::spawn(Storage::default());
 
-    let node = init_node(node).await;
+    let node = Arc::new(init_node(node, &writer_tx).await);
+    drop(writer_tx);
 
     let n1 = node.clone();
     let s1 = store.clone();
+    let config1 = config.clone();
+    let config2 = config.clone();
+    let shed1 = shed.clone();
+    let shutdown_rx1 = shutdown_rx.clone();
+    let overload = config.overload;
 
     let read = tokio::spawn(async move {
-        read_from_stdin(reader_tx1).await;
+        read_from_stdin(reader_tx, writer_tx3, overload, &shed).await;
     });
 
     let write = tokio::spawn(async move {
@@ -50,22 +73,54 @@ async fn main() {
     });
 
     let gossip = tokio::spawn(async move {
-        loop {
-            thread::sleep(Duration::from_millis(GOSSIP_DELAY));
-            gossip_messages(n1.clone(), s1.clone(), writer_tx2.clone()).await;
-        }
+        scheduler::run_every(
+            config1.gossip_interval(),
+            config1.gossip_jitter(),
+            config1.missed_tick,
+            shutdown_rx1,
+            || gossip_messages(&n1, &s1, &writer_tx2, &config1),
+        )
+        .await;
     });
 
-    let handle = tokio::spawn(async move {
-        handle_messages(node.clone(), store.clone(), &mut reader_rx, writer_tx1).await;
+    let metrics = tokio::spawn(async move {
+        scheduler::run_every(
+            config2.metrics_interval(),
+            Duration::ZERO,
+            MissedTick::Skip,
+            shutdown_rx,
+            || async { queue::report(&reader_weak, &writer_weak, &shed1) },
+        )
+        .await;
     });
 
-    let _ = tokio::try_join!(read, handle, write, gossip);
+    let mut handle = tokio::spawn(async move {
+        handle_messages(node, store, &mut reader_rx, writer_tx1, config).await;
+    });
+
+    // Stdin closing ends the handler, after which gossip stops and the writer drains
+    tokio::select! {
+        _ = &mut handle => {}
+        _ = signal::ctrl_c() => {
+            warn!("Interrupted, shutting down");
+            handle.abort();
+        }
+    }
+
+    // The reader and handler hold senders to the writer, it only ends once they are gone
+    read.abort();
+    let _ = tokio::join!(read, handle);
+
+    let _ = shutdown_tx.send(());
+    let _ = tokio::join!(gossip, metrics, write);
+
+    // A pending stdin read sits on a blocking thread the runtime would wait for on drop
+    process::exit(0);
 }
 
-async fn init_node(node: Node) -> Node {
+/// Waits for `init` and queues the `init_ok` on the writer, which owns stdout.
+async fn init_node(node: Node, writer: &Sender<Message>) -> Node {
     let stdin = tokio::io::stdin();
-    let mut stdout = std::io::stdout();
 
     let mut reader = BufReader::new(stdin);
     let mut buf = String::new();
@@ -74,180 +129,247 @@ async fn init_node(node: Node) -> Node {
     let message = Message::parse_message(buf.clone());
     let node = node.init(message.clone());
 
-    match message.body {
-        Body::Init {
-            msg_id, node_id, ..
-        } => {
-            let response = Message {
-                src: node_id,
-                dest: message.src.clone(),
-                body: Body::InitOk {
-                    in_reply_to: msg_id,
-                },
-            };
-
-            let message = Message::format_message(response);
-            writeln!(stdout, "{}", message).unwrap();
-            stdout.flush().unwrap();
-        }
-        _ => (),
+    if let Body::Init {
+        msg_id, node_id, ..
+    } = message.body
+    {
+        let response = Message {
+            src: node_id,
+            dest: message.src.clone(),
+            body: Body::InitOk {
+                in_reply_to: msg_id,
+            },
+        };
+
+        // Nothing can be answered without the writer, the node still runs
+        let _ = writer.send(response).await;
     }
 
     node
 }
 
-async fn read_from_stdin(reader_tx: Sender<Message>) {
+async fn read_from_stdin(
+    reader_tx: Sender<Message>,
+    writer: Sender<Message>,
+    overload: Overload,
+    shed: &Shed,
+) {
     let stdin = tokio::io::stdin();
     let mut reader = BufReader::new(stdin);
 
     loop {
         let mut buf = String::new();
-        reader.read_line(&mut buf).await.unwrap();
+
+        if reader.read_line(&mut buf).await.unwrap() == 0 {
+            return;
+        }
+
         let message = Message::parse_message(buf.clone());
-        reader_tx.send(message).await.unwrap();
+
+        if !queue::admit(message, &reader_tx, &writer, overload, shed).await {
+            return;
+        }
     }
 }
 
 async fn write_to_stdout(writer_rx: &mut Receiver<Message>) {
     let mut stdout = std::io::stdout();
 
-    loop {
-        let message = writer_rx.recv().await.unwrap();
+    while let Some(message) = writer_rx.recv().await {
         let message = Message::format_message(message);
         writeln!(stdout, "{}", message).unwrap();
         stdout.flush().unwrap();
     }
 }
 
-async fn gossip_messages(node: Node, storage: Arc<Mutex<Storage>>, writer: Sender<Message>) {
-    let mut rng = StdRng::from_entropy();
+/// One gossip round on the storage task, the messages are sent once it is done.
+async fn gossip_messages(
+    node: &Arc<Node>,
+    storage: &StorageActor,
+    writer: &Sender<Message>,
+    config: &Arc<Config>,
+) {
+    let node = node.clone();
+    let config = config.clone();
 
-    let num_to_select = rng.gen_range(MIN_AMOUNT_NODES..=NETWORK_SIZE);
+    let outgoing = storage
+        .call(move |storage| gossip_round(storage, &node, &config))
+        .await;
 
-    let selected_neighbours: Vec<String> = node
-        .get_network()
-        .choose_multiple(&mut rng, num_to_select)
-        .cloned()
-        .collect();
+    for message in outgoing {
+        if writer.send(message).await.is_err() {
+            return;
+        }
+    }
+}
 
-    let mut tasks = vec![];
+/// Retransmits unacknowledged batches and sends new values to the selected peers.
+fn gossip_round(storage: &mut Storage, node: &Node, config: &Config) -> Vec<Message> {
+    let selected_neighbours: Vec<String> = match config.topology {
+        TopologyStrategy::Random => {
+            let mut rng = StdRng::from_entropy();
+            let num_to_select = rng.gen_range(config.min_fanout..=config.fanout) as usize;
+
+            node.get_network()
+                .choose_multiple(&mut rng, num_to_select)
+                .cloned()
+                .collect()
+        }
+        _ => storage.get_neighbours(&node.id),
+    };
+
+    storage.outbox.next_round();
+    let mut batches = storage.outbox.get_retransmissions();
 
     for n in selected_neighbours {
-        let storage_clone = storage.clone();
-        let writer_clone = writer.clone();
-        let node_clone = node.clone();
+        let messages = config.limit_batch(storage.get_new_messages_for_neighbour(n.clone()));
 
-        let task = tokio::spawn(async move {
-            let messages = storage_clone
-                .lock()
-                .await
-                .get_new_messages_for_neighbour(n.clone());
+        if messages.is_empty() {
+            continue;
+        }
 
-            if messages.is_empty() {
-                return;
-            }
+        let seq = storage.outbox.send(&n, messages.clone());
+        batches.push((n, seq, messages));
+    }
+
+    batches
+        .into_iter()
+        .map(|(peer, seq, messages)| {
+            let base = storage.outbox.base(&peer);
 
-            let message = Message {
-                src: node_clone.id.clone(),
-                dest: n.clone(),
+            Message {
+                src: node.id.clone(),
+                dest: peer,
                 body: Body::Gossip {
-                    messages: messages.clone(),
+                    seq,
+                    base,
+                    messages,
                 },
-            };
-
-            writer_clone.send(message).await.unwrap();
-        });
-
-        tasks.push(task);
-    }
-
-    // Wait for all the gossip tasks to complete
-    for task in tasks {
-        task.await.unwrap();
-    }
+            }
+        })
+        .collect()
 }
 
 async fn handle_messages(
-    node: Node,
-    storage: Arc<Mutex<Storage>>,
+    node: Arc<Node>,
+    storage: StorageActor,
     input: &mut Receiver<Message>,
     writer: Sender<Message>,
+    config: Arc<Config>,
 ) {
-    while let Some(input) = input.recv().await {
-        match input.body {
-            Body::Broadcast { msg_id, message } => {
-                let id = node.id.clone();
-                storage.lock().await.add_message(message);
-
-                let response = Message {
-                    src: id,
-                    dest: input.src,
-                    body: Body::BroadcastOk {
-                        msg_id,
-                        in_reply_to: msg_id,
-                    },
-                };
-
-                writer.send(response).await.unwrap();
-            }
-            Body::Gossip { messages } => {
-                let id = node.id.clone();
-                storage
-                    .lock()
-                    .await
-                    .add_messages(messages.clone(), input.src.clone());
-
-                let response = Message {
-                    src: id,
-                    dest: input.src,
-                    body: Body::GossipOk { messages },
-                };
-
-                writer.send(response).await.unwrap();
-            }
-            Body::GossipOk { messages } => {
-                storage
-                    .lock()
-                    .await
-                    .add_to_sent_messages(messages, input.src);
-            }
-            Body::Read { msg_id } => {
-                let response = Message {
-                    src: node.id.clone(),
-                    dest: input.src,
-                    body: Body::ReadOk {
-                        msg_id,
-                        in_reply_to: msg_id,
-                        messages: storage.lock().await.get_messages(),
-                    },
-                };
-
-                writer.send(response).await.unwrap();
-            }
-            Body::Topology { msg_id, .. } => {
-                let response = Message {
-                    src: node.id.clone(),
-                    dest: input.src,
-                    body: Body::TopologyOk {
-                        msg_id,
-                        in_reply_to: msg_id,
-                    },
-                };
-
-                writer.send(response).await.unwrap();
-            }
-            Body::Error {
-                in_reply_to,
-                code,
-                text,
-            } => {
-                eprintln!(
-                    "Error received (in_reply_to: {}, code: {}, text: {})",
-                    in_reply_to, code, text
-                );
+    let concurrency = config.concurrency as usize;
+    let order_by = config.order_by;
+
+    let handler = move |input: Message| {
+        let (body_type, msg_id) = logging::header(&input.body);
+        let span = info_span!("message", node_id = %node.id, msg_id, body_type = %body_type);
+        let node = node.clone();
+        let storage = storage.clone();
+        let writer = writer.clone();
+        let config = config.clone();
+
+        async move {
+            let Some(input) = span.in_scope(|| prepare(input, &node, &config)) else {
+                return;
+            };
+
+            let outgoing = storage
+                .call(move |storage| {
+                    let _enter = span.enter();
+                    handle_message(storage, &node, input)
+                })
+                .await;
+
+            for message in outgoing {
+                if writer.send(message).await.is_err() {
+                    return;
+                }
             }
-            _ => (),
         }
+    };
+
+    dispatch::run(input, concurrency, order_by, handler).await;
+    info!("Input closed, shutting down");
+}
+
+/// Does the work on a message which needs no storage in its lane, so lanes
+/// only queue up behind each other for the storage itself. Returns what is
+/// left for the storage, if anything.
+fn prepare(mut input: Message, node: &Node, config: &Config) -> Option<Message> {
+    match &mut input.body {
+        Body::Topology { topology, .. } => {
+            let given = mem::take(topology);
+            *topology = config.arrange(given, &node.availble_nodes);
+            Some(input)
+        }
+        Body::Error {
+            in_reply_to,
+            code,
+            text,
+        } => {
+            warn!(in_reply_to, code, "Error received: {}", text);
+            None
+        }
+        _ => Some(input),
+    }
+}
+
+/// Applies one message to the storage and returns the messages to send in
+/// response. The message went through `prepare` first.
+fn handle_message(storage: &mut Storage, node: &Node, input: Message) -> Vec<Message> {
+    match input.body {
+        Body::Broadcast { msg_id, message } => {
+            storage.add_message(message);
+
+            vec![Message {
+                src: node.id.clone(),
+                dest: input.src,
+                body: Body::BroadcastOk {
+                    msg_id,
+                    in_reply_to: msg_id,
+                },
+            }]
+        }
+        Body::Gossip {
+            seq,
+            base,
+            messages,
+        } => {
+            storage.add_messages(messages, input.src.clone());
+            let (ack, sack) = storage.outbox.receive(&input.src, seq, base);
+
+            vec![Message {
+                src: node.id.clone(),
+                dest: input.src,
+                body: Body::GossipOk { ack, sack },
+            }]
+        }
+        Body::GossipOk { ack, sack } => {
+            let messages = storage.outbox.acknowledge(&input.src, ack, sack);
+            storage.add_to_sent_messages(messages, input.src);
+            vec![]
+        }
+        Body::Read { msg_id } => vec![Message {
+            src: node.id.clone(),
+            dest: input.src,
+            body: Body::ReadOk {
+                msg_id,
+                in_reply_to: msg_id,
+                messages: storage.get_messages(),
+            },
+        }],
+        Body::Topology { msg_id, topology } => {
+            storage.init_topology(topology);
+
+            vec![Message {
+                src: node.id.clone(),
+                dest: input.src,
+                body: Body::TopologyOk {
+                    msg_id,
+                    in_reply_to: msg_id,
+                },
+            }]
+        }
+        _ => vec![],
     }
-    println!("Error, nothing to read from receiver");
 }
@@ -17,13 +17,11 @@ impl Node {
         match message.body {
             Body::Init {
                 node_id, node_ids, ..
-            } => {
-                return Node {
-                    id: node_id.clone(),
-                    availble_nodes: node_ids.clone(),
-                    network: self.init_network(node_ids),
-                }
-            }
+            } => Node {
+                id: node_id.clone(),
+                availble_nodes: node_ids.clone(),
+                network: self.init_network(node_ids),
+            },
             _ => panic!("Invalid message type"),
         }
     }
@@ -1,5 +1,7 @@
+use outbox::Outbox;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use tokio::sync::{mpsc, oneshot};
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub(crate) struct Messages(pub(crate) HashSet<u64>);
@@ -8,8 +10,11 @@ pub(crate) struct Messages(pub(crate) HashSet<u64>);
 pub(crate) struct Storage {
     pub(crate) messages: Messages,
     pub(crate) received_gossip_messages: HashMap<String, Messages>,
+    /// Values each peer acknowledged.
     pub(crate) sent_messages: HashMap<String, Messages>,
-    pub(crate) retry: HashMap<String, u8>,
+    pub(crate) outbox: Outbox,
+    /// Fixed gossip neighbours, empty while peers are picked at random.
+    pub(crate) topology: HashMap<String, Vec<String>>,
 }
 
 impl Storage {
@@ -20,17 +25,11 @@ impl Storage {
     }
 
     pub(crate) fn add_messages(&mut self, messages: Vec<u64>, node: String) {
-        if self.received_gossip_messages.contains_key(&node) {
-            self.received_gossip_messages
-                .get_mut(&node)
-                .unwrap()
-                .0
-                .extend(messages.iter());
-        } else {
-            let mut v = Messages::default();
-            v.0.extend(messages.iter());
-            self.received_gossip_messages.insert(node, v);
-        }
+        self.received_gossip_messages
+            .entry(node)
+            .or_default()
+            .0
+            .extend(messages.iter());
 
         for m in messages {
             if !self.messages.0.contains(&m) {
@@ -44,42 +43,81 @@ impl Storage {
     }
 
     pub(crate) fn get_new_messages_for_neighbour(&self, node: String) -> Vec<u64> {
-        let received_messages = self.messages.0.clone().into_iter().collect::<Vec<_>>();
+        let empty = HashSet::new();
+        let sent_to_node = self.sent_messages.get(&node).map_or(&empty, |m| &m.0);
+        let received_from_node = self
+            .received_gossip_messages
+            .get(&node)
+            .map_or(&empty, |m| &m.0);
+        let in_flight = self.outbox.get_in_flight(&node);
 
-        let sent_to_node: Vec<u64> = self
-            .sent_messages
+        self.messages
+            .0
             .iter()
-            .filter(|(key, _)| *key == &node)
-            .flat_map(|(_, Messages(value))| value)
+            .filter(|x| {
+                !sent_to_node.contains(x)
+                    && !received_from_node.contains(x)
+                    && !in_flight.contains(x)
+            })
             .cloned()
-            .collect();
+            .collect()
+    }
 
-        let received_from_node: Vec<u64> = self
-            .received_gossip_messages
-            .iter()
-            .filter(|(key, _)| *key == &node)
-            .flat_map(|(_, Messages(value))| value)
-            .cloned()
-            .collect();
+    pub(crate) fn add_to_sent_messages(&mut self, messages: Vec<u64>, node: String) {
+        self.sent_messages
+            .entry(node)
+            .or_default()
+            .0
+            .extend(messages);
+    }
 
-        let filtered_messages: Vec<u64> = received_messages
-            .into_iter()
-            .filter(|x| !sent_to_node.contains(x) && !received_from_node.contains(x))
-            .collect();
+    pub(crate) fn init_topology(&mut self, topology: HashMap<String, Vec<String>>) {
+        self.topology = topology;
+    }
 
-        filtered_messages
+    pub(crate) fn get_neighbours(&self, node_id: &str) -> Vec<String> {
+        self.topology.get(node_id).cloned().unwrap_or_default()
     }
+}
 
-    pub(crate) fn add_to_sent_messages(&mut self, messages: Vec<u64>, node: String) {
-        if self.sent_messages.contains_key(&node) {
-            self.sent_messages
-                .get_mut(&node)
-                .unwrap()
-                .0
-                .extend(messages);
-        } else {
-            self.sent_messages
-                .insert(node, Messages(messages.iter().cloned().collect()));
-        }
+type Command = Box<dyn FnOnce(&mut Storage) + Send>;
+
+/// Owns the `Storage` on a task of its own. Handlers and gossip rounds send it
+/// commands which run one after another, so nothing contends on a lock and no
+/// state is held across an `await`.
+#[derive(Clone)]
+pub(crate) struct StorageActor {
+    commands: mpsc::Sender<Command>,
+}
+
+impl StorageActor {
+    pub(crate) fn spawn(mut storage: Storage) -> StorageActor {
+        let (commands, mut inbox) = mpsc::channel::<Command>(1000);
+
+        tokio::spawn(async move {
+            while let Some(command) = inbox.recv().await {
+                command(&mut storage);
+            }
+        });
+
+        StorageActor { commands }
+    }
+
+    /// Runs `command` on the storage task and returns its result.
+    pub(crate) async fn call<R, F>(&self, command: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Storage) -> R + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+
+        self.commands
+            .send(Box::new(move |storage| {
+                let _ = reply.send(command(storage));
+            }))
+            .await
+            .expect("Storage actor stopped");
+
+        result.await.expect("Storage actor stopped")
     }
 }
@@ -0,0 +1,116 @@
+//! Feeds every message type through the node and checks that everything it
+//! writes to stdout, gossip included, is a protocol message, diagnostics
+//! belong on stderr.
+
+#[allow(dead_code)]
+#[path = "../src/message.rs"]
+mod message;
+
+use harness::{answered, run_node_until, Node};
+use message::Message;
+
+const BIN: &str = env!("CARGO_BIN_EXE_ch03d-efficient-broadcast-part-one");
+
+const MESSAGES: &[&str] = &[
+    r#"{"src":"c1","dest":"n1","body":{"type":"init_ok","in_reply_to":1}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"topology","msg_id":2,"topology":{"n1":["n2","n3"],"n2":["n1"],"n3":["n1"]}}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"topology_ok","msg_id":3,"in_reply_to":2}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":4,"message":10}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"broadcast_ok","msg_id":5,"in_reply_to":4}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":6}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"read_ok","msg_id":7,"in_reply_to":6,"messages":[10]}}"#,
+    r#"{"src":"c1","dest":"n1","body":{"type":"error","in_reply_to":6,"code":11,"text":"busy"}}"#,
+    r#"{"src":"n2","dest":"n1","body":{"type":"gossip","seq":1,"messages":[11,12]}}"#,
+    r#"{"src":"n2","dest":"n1","body":{"type":"gossip_ok","ack":1,"sack":[]}}"#,
+];
+
+/// The requests among `MESSAGES`.
+const REQUESTS: [u64; 3] = [2, 4, 6];
+
+/// The defaults, and a tuned topology which gossips one value at a time.
+const CONFIGS: &[&[(&str, &str)]] = &[
+    &[],
+    &[
+        ("GOSSIP_INTERVAL", "100"),
+        ("TOPOLOGY", "tree"),
+        ("FANOUT", "2"),
+        ("MIN_FANOUT", "1"),
+        ("BATCH_SIZE", "1"),
+    ],
+];
+
+#[test]
+fn stdout_only_carries_messages() {
+    for config in CONFIGS {
+        let stdout = harness::run_node(BIN, config, MESSAGES, &REQUESTS);
+
+        assert!(
+            !stdout.is_empty(),
+            "Node with {:?} answered nothing",
+            config
+        );
+
+        for line in stdout.lines() {
+            serde_json::from_str::<Message>(line)
+                .unwrap_or_else(|e| panic!("{:?} with {:?} is not a message: {}", line, config, e));
+        }
+    }
+}
+
+#[test]
+fn every_request_is_answered_under_each_ordering() {
+    let messages: Vec<String> = MESSAGES.iter().map(|m| m.to_string()).collect();
+
+    for order_by in ["source", "key", "none"] {
+        let env = [("ORDER_BY", order_by), ("CONCURRENCY", "8")];
+
+        // Times out unless each request, gossip included, is answered
+        run_node_until(BIN, &env, &messages, |output| {
+            answered(output, REQUESTS)
+                && output
+                    .iter()
+                    .any(|m| m["dest"] == "n2" && m["body"]["type"] == "gossip_ok")
+        });
+    }
+}
+
+#[test]
+fn reads_see_earlier_broadcasts_from_the_same_source() {
+    let env = [("ORDER_BY", "source"), ("CONCURRENCY", "8")];
+    let stdout = harness::run_node(BIN, &env, MESSAGES, &REQUESTS);
+
+    let read_ok = stdout
+        .lines()
+        .find(|line| line.contains(r#""type":"read_ok""#))
+        .expect("Node did not answer the read");
+    let read_ok: serde_json::Value = serde_json::from_str(read_ok).unwrap();
+
+    assert!(read_ok["body"]["messages"]
+        .as_array()
+        .unwrap()
+        .contains(&serde_json::json!(10)));
+}
+
+#[test]
+fn overloaded_node_answers_every_request_once() {
+    for overload in ["block", "drop-gossip", "reject"] {
+        let env = [
+            ("OVERLOAD", overload),
+            ("QUEUE_CAPACITY", "1"),
+            ("CONCURRENCY", "1"),
+        ];
+        harness::assert_overload_answered_once(BIN, &env);
+    }
+}
+
+#[test]
+fn node_exits_on_interrupt() {
+    let mut node = Node::start(BIN, &[]);
+    node.send(&[MESSAGES[1].to_string()]);
+    node.run_until(|output| answered(output, [2]));
+
+    // Stdin stays open, only the signal may end the node
+    node.interrupt();
+
+    assert!(node.wait().success());
+}
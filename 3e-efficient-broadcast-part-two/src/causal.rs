@@ -0,0 +1,298 @@
+use std::collections::{BTreeMap, HashMap};
+
+use rand::{prelude::*, rngs::StdRng};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+	message::{Body, Message, Stamped},
+	node::Node,
+	storage::Storage,
+};
+
+/// Broadcasts delivered by each origin, keyed by node id.
+pub(crate) type VectorClock = BTreeMap<String, u64>;
+
+/// Causal broadcast state: values are delivered only after every value their
+/// origin had delivered when broadcasting them.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub(crate) struct Causal {
+	/// Broadcasts delivered so far, from each origin.
+	clock: VectorClock,
+	/// Delivered broadcasts, in delivery order.
+	log: Vec<Stamped>,
+	/// Broadcasts waiting for one of their dependencies, by origin and sequence.
+	#[serde(skip)]
+	pending: HashMap<String, BTreeMap<u64, Stamped>>,
+}
+
+impl Causal {
+	/// Values in delivery order, which respects happens-before.
+	pub(crate) fn read(&self) -> Vec<u64> {
+		self.log.iter().map(|s| s.message).collect()
+	}
+
+	fn is_delivered(&self, stamped: &Stamped) -> bool {
+		sequence(stamped) <= self.seen(&stamped.origin)
+	}
+
+	/// The next broadcast of its origin, and everything it depends on is delivered.
+	fn is_deliverable(&self, stamped: &Stamped) -> bool {
+		stamped.clock.iter().all(|(node, &count)| {
+			if *node == stamped.origin {
+				count == self.seen(node) + 1
+			} else {
+				count <= self.seen(node)
+			}
+		})
+	}
+
+	fn seen(&self, node: &str) -> u64 {
+		self.clock.get(node).cloned().unwrap_or_default()
+	}
+
+	/// Number of broadcasts waiting for one of their dependencies.
+	pub(crate) fn pending(&self) -> usize {
+		self.pending.values().map(BTreeMap::len).sum()
+	}
+
+	/// Buffers the broadcasts and delivers every one whose dependencies are
+	/// met, returns the ones delivered.
+	pub(crate) fn receive(&mut self, stamped: Vec<Stamped>) -> Vec<Stamped> {
+		for s in stamped {
+			if !self.is_delivered(&s) {
+				self.pending
+					.entry(s.origin.clone())
+					.or_default()
+					.insert(sequence(&s), s);
+			}
+		}
+
+		let mut delivered = vec![];
+
+		// Only the next broadcast of each origin can be deliverable, so every
+		// pass looks at one broadcast per origin until none makes progress.
+		loop {
+			let origins: Vec<String> = self.pending.keys().cloned().collect();
+			let before = delivered.len();
+
+			for origin in origins {
+				while let Some(stamped) = self.next_deliverable(&origin) {
+					self.deliver(stamped.clone());
+					delivered.push(stamped);
+				}
+			}
+
+			if delivered.len() == before {
+				break;
+			}
+		}
+
+		self.pending.retain(|_, buffered| !buffered.is_empty());
+		delivered
+	}
+
+	/// Takes the next broadcast of `origin` out of the buffer if its
+	/// dependencies are delivered.
+	fn next_deliverable(&mut self, origin: &str) -> Option<Stamped> {
+		let buffered = self.pending.get(origin)?;
+		let (&sequence, stamped) = buffered.iter().next()?;
+
+		if !self.is_deliverable(stamped) {
+			return None;
+		}
+
+		self.pending.get_mut(origin)?.remove(&sequence)
+	}
+
+	/// Appends a broadcast whose dependencies are met to the log.
+	pub(crate) fn deliver(&mut self, stamped: Stamped) {
+		if self.is_delivered(&stamped) {
+			return;
+		}
+
+		self.clock
+			.insert(stamped.origin.clone(), sequence(&stamped));
+		self.log.push(stamped);
+	}
+
+	/// Delivered broadcasts the given clock has not seen.
+	fn missing(&self, clock: &VectorClock) -> Vec<Stamped> {
+		self.log
+			.iter()
+			.filter(|s| sequence(s) > clock.get(&s.origin).cloned().unwrap_or_default())
+			.cloned()
+			.collect()
+	}
+}
+
+/// Position of a broadcast among those of its origin, starting at 1.
+fn sequence(stamped: &Stamped) -> u64 {
+	stamped
+		.clock
+		.get(&stamped.origin)
+		.cloned()
+		.unwrap_or_default()
+}
+
+/// Stamps a value broadcast by a client with the next tick of our clock,
+/// delivers it and sends it to our neighbours. A value already delivered keeps
+/// its stamp, so it is read once.
+pub(crate) fn broadcast(storage: &mut Storage, id: &str, message: u64) -> Vec<Message> {
+	if storage.messages.0.contains(&message) {
+		return vec![];
+	}
+
+	let mut clock = storage.causal.clock.clone();
+	*clock.entry(id.to_string()).or_default() += 1;
+
+	let stamped = Stamped {
+		message,
+		origin: id.to_string(),
+		clock,
+	};
+
+	storage.deliver(stamped.clone());
+	push(storage, id, vec![stamped], id)
+}
+
+/// Delivers the broadcasts whose dependencies are met, buffers the rest, and
+/// forwards whatever was delivered.
+pub(crate) fn receive_push(
+	storage: &mut Storage,
+	id: &str,
+	from: &str,
+	messages: Vec<Stamped>,
+) -> Vec<Message> {
+	let delivered = storage.receive_causal(messages);
+
+	if storage.causal.pending() > 0 {
+		debug!(
+			pending = storage.causal.pending(),
+			"Waiting for causal dependencies"
+		);
+	}
+
+	push(storage, id, delivered, from)
+}
+
+/// Sends a peer every delivered broadcast its clock is missing.
+pub(crate) fn receive_sync(
+	storage: &mut Storage,
+	id: &str,
+	from: &str,
+	clock: VectorClock,
+) -> Vec<Message> {
+	let messages = storage.causal.missing(&clock);
+
+	if messages.is_empty() {
+		return vec![];
+	}
+
+	vec![Message {
+		src: id.to_string(),
+		dest: from.to_string(),
+		body: Body::CausalPush { messages },
+	}]
+}
+
+/// Anti-entropy: sends our clock to `fanout` random peers, which answer with
+/// whatever broadcasts we missed.
+pub(crate) fn tick(storage: &Storage, node: &Node, fanout: usize) -> Vec<Message> {
+	let mut rng = StdRng::from_entropy();
+
+	storage
+		.get_network(node)
+		.into_iter()
+		.filter(|n| *n != node.id)
+		.choose_multiple(&mut rng, fanout)
+		.into_iter()
+		.map(|peer| Message {
+			src: node.id.clone(),
+			dest: peer,
+			body: Body::CausalSync {
+				clock: storage.causal.clock.clone(),
+			},
+		})
+		.collect()
+}
+
+fn push(storage: &Storage, id: &str, messages: Vec<Stamped>, from: &str) -> Vec<Message> {
+	if messages.is_empty() {
+		return vec![];
+	}
+
+	storage
+		.get_neighbours(id)
+		.into_iter()
+		.filter(|n| n != from)
+		.map(|peer| Message {
+			src: id.to_string(),
+			dest: peer,
+			body: Body::CausalPush {
+				messages: messages.clone(),
+			},
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn stamped(message: u64, origin: &str, clock: &[(&str, u64)]) -> Stamped {
+		Stamped {
+			message,
+			origin: origin.to_string(),
+			clock: clock.iter().map(|(n, c)| (n.to_string(), *c)).collect(),
+		}
+	}
+
+	#[test]
+	fn a_value_broadcast_twice_is_stamped_once() {
+		let mut storage = Storage::default();
+
+		assert!(broadcast(&mut storage, "n1", 7).is_empty());
+		assert!(broadcast(&mut storage, "n1", 7).is_empty());
+
+		assert_eq!(storage.causal.read(), vec![7]);
+		assert_eq!(storage.causal.seen("n1"), 1);
+	}
+
+	#[test]
+	fn broadcasts_wait_for_their_dependencies() {
+		let mut causal = Causal::default();
+
+		// n2's second broadcast depends on n1's first one
+		let delivered = causal.receive(vec![
+			stamped(3, "n2", &[("n1", 1), ("n2", 2)]),
+			stamped(2, "n2", &[("n2", 1)]),
+		]);
+		assert_eq!(delivered, vec![stamped(2, "n2", &[("n2", 1)])]);
+		assert_eq!(causal.pending(), 1);
+
+		let delivered = causal.receive(vec![stamped(1, "n1", &[("n1", 1)])]);
+		assert_eq!(
+			delivered,
+			vec![
+				stamped(1, "n1", &[("n1", 1)]),
+				stamped(3, "n2", &[("n1", 1), ("n2", 2)]),
+			]
+		);
+		assert_eq!(causal.pending(), 0);
+		assert_eq!(causal.read(), vec![2, 1, 3]);
+	}
+
+	#[test]
+	fn a_broadcast_received_twice_is_buffered_once() {
+		let mut causal = Causal::default();
+		let late = stamped(2, "n1", &[("n1", 2)]);
+
+		causal.receive(vec![late.clone(), late.clone()]);
+		assert_eq!(causal.pending(), 1);
+
+		causal.receive(vec![stamped(1, "n1", &[("n1", 1)]), late]);
+		assert_eq!(causal.pending(), 0);
+		assert_eq!(causal.read(), vec![1, 2]);
+	}
+}
@@ -0,0 +1,113 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum};
+
+use crate::{
+	dispatch::OrderBy,
+	gossip::{self, Mode},
+	queue::Overload,
+};
+
+/// Gossip mode, delivery order, persistence and concurrency of the broadcast
+/// node. `--help` lists the environment variable of each flag.
+#[derive(Parser, Debug, Clone)]
+pub(crate) struct Config {
+	#[command(flatten)]
+	pub(crate) gossip: gossip::Config,
+
+	/// Most messages handled at the same time.
+	#[arg(long, env = "CONCURRENCY", default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+	pub(crate) concurrency: u64,
+
+	/// Which messages are handled in the order they arrived: source, key or none.
+	#[arg(long, env = "ORDER_BY", value_enum, default_value_t = OrderBy::Source)]
+	pub(crate) order_by: OrderBy,
+
+	/// Messages each queue between reader, handlers and writer holds.
+	#[arg(long, env = "QUEUE_CAPACITY", default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+	pub(crate) queue_capacity: u64,
+
+	/// What happens to messages arriving while the handlers' queue is full:
+	/// block, drop-gossip or reject.
+	#[arg(long, env = "OVERLOAD", value_enum, default_value_t = Overload::Block)]
+	pub(crate) overload: Overload,
+
+	/// Milliseconds between queue depth reports in the log.
+	#[arg(long, env = "METRICS_INTERVAL", default_value_t = 5000, value_parser = clap::value_parser!(u64).range(1..))]
+	pub(crate) metrics_interval: u64,
+
+	/// Directory for the write-ahead log and snapshots, one subdirectory per
+	/// node. Nothing is persisted if unset.
+	#[arg(long, env = "DATA_DIR")]
+	pub(crate) data_dir: Option<PathBuf>,
+
+	/// Log entries after which the storage is written to a snapshot.
+	#[arg(long, env = "SNAPSHOT_EVERY", default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+	pub(crate) snapshot_every: u64,
+
+	/// Log filter, e.g. `debug` or `ch03e_efficient_broadcast_part_two=trace`.
+	#[arg(long, env = "RUST_LOG", default_value = "info", value_parser = logging::parse_filter)]
+	pub(crate) log_level: String,
+}
+
+impl Config {
+	/// Parses the flags and environment, exits with usage on invalid values.
+	pub(crate) fn load() -> Config {
+		let config = Config::parse();
+		let gossip = config.gossip;
+
+		if gossip.mode == Mode::Random && gossip.min_fanout > gossip.max_fanout {
+			Config::command()
+				.error(
+					ErrorKind::ArgumentConflict,
+					format!(
+						"--min-fanout {} is larger than --max-fanout {}",
+						gossip.min_fanout, gossip.max_fanout
+					),
+				)
+				.exit();
+		}
+
+		config
+	}
+}
+
+/// In which order nodes deliver broadcast values, and `read` returns them.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Delivery {
+	/// Any order, values spread through the configured gossip mode.
+	Unordered,
+	/// Each value after every value its origin had delivered when it was
+	/// broadcast. Values carry vector clocks and spread by eager push to our
+	/// neighbours, with anti-entropy rounds instead of the gossip mode.
+	Causal,
+	/// The same order on every node, numbered by a sequencer which fails
+	/// over to the next node when it crashes.
+	Total,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TopologyStrategy {
+	/// The topology from Maelstrom's `topology` message.
+	Maelstrom,
+	/// Every node is a neighbour of every other node.
+	Full,
+	/// A spanning tree with `fanout` children per node, ordered by node number.
+	Tree,
+}
+
+impl TopologyStrategy {
+	/// Neighbours of every node, `fanout` is the number of children in a tree.
+	pub(crate) fn arrange(
+		&self,
+		given: HashMap<String, Vec<String>>,
+		nodes: &[String],
+		fanout: usize,
+	) -> HashMap<String, Vec<String>> {
+		match self {
+			TopologyStrategy::Maelstrom => given,
+			TopologyStrategy::Full => topology::full(nodes),
+			TopologyStrategy::Tree => topology::tree(nodes, fanout),
+		}
+	}
+}
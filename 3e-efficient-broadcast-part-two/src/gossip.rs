@@ -0,0 +1,374 @@
+use std::{str::FromStr, time::Duration};
+
+use clap::Args;
+use rand::{prelude::*, rngs::StdRng};
+use tracing::debug;
+
+use crate::{
+	config::{Delivery, TopologyStrategy},
+	membership::Membership,
+	message::{Body, Message, Rumor},
+	node::Node,
+	scheduler::MissedTick,
+	storage::Storage,
+};
+
+/// How values travel between nodes in an epidemic gossip round.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Mode {
+	/// Push unacknowledged values to a random number of peers each round.
+	Random,
+	/// Push hot rumors to `fanout` peers.
+	Push,
+	/// Ask `fanout` peers for everything we are missing.
+	Pull,
+	/// Push hot rumors and pull missing values in the same round.
+	PushPull,
+	/// Forward new values to our topology neighbours as soon as they arrive,
+	/// and only gossip what they have not acknowledged yet.
+	Eager,
+	/// Eager push along a self-healing spanning tree, lazy `ihave` announcements to the rest.
+	Plumtree,
+}
+
+impl FromStr for Mode {
+	type Err = String;
+
+	fn from_str(mode: &str) -> Result<Self, Self::Err> {
+		match mode {
+			"random" => Ok(Mode::Random),
+			"push" => Ok(Mode::Push),
+			"pull" => Ok(Mode::Pull),
+			"push-pull" => Ok(Mode::PushPull),
+			"eager" => Ok(Mode::Eager),
+			"plumtree" => Ok(Mode::Plumtree),
+			_ => Err(format!("Unknown gossip mode: {}", mode)),
+		}
+	}
+}
+
+#[derive(Args, Clone, Copy, Debug)]
+#[group(id = "gossip")]
+pub(crate) struct Config {
+	/// How values travel: random, push, pull, push-pull, eager or plumtree.
+	#[arg(long = "gossip-mode", env = "GOSSIP_MODE", default_value = "random")]
+	pub(crate) mode: Mode,
+	/// Milliseconds between gossip rounds.
+	#[arg(long, env = "GOSSIP_INTERVAL", default_value_t = 500, value_parser = clap::value_parser!(u64).range(1..))]
+	pub(crate) gossip_interval: u64,
+	/// Most milliseconds of random delay added to each gossip round.
+	#[arg(long, env = "GOSSIP_JITTER", default_value_t = 100)]
+	pub(crate) gossip_jitter: u64,
+	/// What happens to gossip rounds which could not start on time.
+	#[arg(long, env = "MISSED_TICK", value_enum, default_value_t = MissedTick::Skip)]
+	pub(crate) missed_tick: MissedTick,
+	/// Number of peers contacted per round, children per node in the `tree` topology.
+	#[arg(long, env = "FANOUT", default_value_t = 3, value_parser = clap::value_parser!(u64).range(1..))]
+	pub(crate) fanout: u64,
+	/// Fewest peers picked per round in `random` mode.
+	#[arg(long, env = "MIN_FANOUT", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+	pub(crate) min_fanout: u64,
+	/// Most peers picked per round in `random` mode.
+	#[arg(long, env = "MAX_FANOUT", default_value_t = 25, value_parser = clap::value_parser!(u64).range(1..))]
+	pub(crate) max_fanout: u64,
+	/// Redundant receipts after which a rumor stops being spread.
+	#[arg(long, env = "REDUNDANCY", default_value_t = 2, value_parser = clap::value_parser!(u8).range(1..))]
+	pub(crate) redundancy: u8,
+	/// Where gossip targets come from: full or hyparview.
+	#[arg(long, env = "MEMBERSHIP", default_value = "full")]
+	pub(crate) membership: Membership,
+	/// Which neighbours a node forwards to under full membership.
+	#[arg(long, env = "TOPOLOGY", value_enum, default_value_t = TopologyStrategy::Maelstrom)]
+	pub(crate) topology: TopologyStrategy,
+	/// Most values in a single `gossip` message, unlimited if unset.
+	#[arg(long, env = "BATCH_SIZE", value_parser = clap::value_parser!(u64).range(1..))]
+	pub(crate) batch_size: Option<u64>,
+	/// Order in which values are delivered: unordered, causal or total.
+	#[arg(long, env = "DELIVERY", value_enum, default_value_t = Delivery::Unordered)]
+	pub(crate) delivery: Delivery,
+	/// Values a peer may be missing before `random` and `eager` gossip offer it
+	/// a snapshot instead of incremental batches.
+	#[arg(long, env = "CATCH_UP_THRESHOLD", default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+	pub(crate) catch_up_threshold: u64,
+	/// Most values in a single snapshot chunk.
+	#[arg(long, env = "CHUNK_SIZE", default_value_t = 500, value_parser = clap::value_parser!(u64).range(1..))]
+	pub(crate) chunk_size: u64,
+}
+
+impl Config {
+	pub(crate) fn gossip_interval(&self) -> Duration {
+		Duration::from_millis(self.gossip_interval)
+	}
+
+	pub(crate) fn gossip_jitter(&self) -> Duration {
+		Duration::from_millis(self.gossip_jitter)
+	}
+
+	/// The defaults, with `args` applied as if they were given on the command line.
+	#[cfg(test)]
+	pub(crate) fn for_test(args: &[&str]) -> Config {
+		use clap::Parser;
+
+		#[derive(Parser)]
+		struct Cli {
+			#[command(flatten)]
+			gossip: Config,
+		}
+
+		let args = std::iter::once("test").chain(args.iter().cloned());
+		Cli::parse_from(args).gossip
+	}
+
+	/// Trims gossip to the configured batch size, the rest goes out in later rounds.
+	pub(crate) fn limit_batch(&self, mut messages: Vec<u64>) -> Vec<u64> {
+		if let Some(batch_size) = self.batch_size {
+			messages.truncate(batch_size as usize);
+		}
+
+		messages
+	}
+}
+
+/// Push and/or pull with `fanout` random peers.
+pub(crate) fn epidemic_round(storage: &mut Storage, node: &Node, config: &Config) -> Vec<Message> {
+	let mut rng = StdRng::from_entropy();
+
+	let peers: Vec<String> = storage
+		.get_network(node)
+		.into_iter()
+		.filter(|n| *n != node.id)
+		.choose_multiple(&mut rng, config.fanout as usize);
+
+	let rumors = storage.get_hot_rumors();
+	let messages = storage.get_messages();
+	let mut outgoing = vec![];
+
+	for peer in peers {
+		if matches!(config.mode, Mode::Push | Mode::PushPull) && !rumors.is_empty() {
+			outgoing.push(Message {
+				src: node.id.clone(),
+				dest: peer.clone(),
+				body: Body::Push {
+					rumors: rumors.clone(),
+				},
+			});
+		}
+
+		if matches!(config.mode, Mode::Pull | Mode::PushPull) {
+			outgoing.push(Message {
+				src: node.id.clone(),
+				dest: peer,
+				body: Body::Pull {
+					messages: messages.clone(),
+				},
+			});
+		}
+	}
+
+	outgoing
+}
+
+/// Sends freshly learned values to our neighbours, except the node we got them from.
+pub(crate) fn forward(
+	storage: &mut Storage,
+	node: &Node,
+	messages: Vec<u64>,
+	from: &str,
+) -> Vec<Message> {
+	if messages.is_empty() {
+		return vec![];
+	}
+
+	let neighbours = storage.get_neighbours(&node.id);
+	let mut outgoing = vec![];
+
+	for n in neighbours.into_iter().filter(|n| n != from) {
+		let seq = storage.outbox.send(&n, messages.clone());
+		let base = storage.outbox.base(&n);
+
+		outgoing.push(Message {
+			src: node.id.clone(),
+			dest: n,
+			body: Body::Gossip {
+				seq,
+				base,
+				messages: messages.clone(),
+			},
+		});
+	}
+
+	outgoing
+}
+
+/// Stores the received rumors and returns the values we already knew about.
+pub(crate) fn receive_rumors(storage: &mut Storage, rumors: Vec<Rumor>, from: &str) -> Vec<u64> {
+	let mut known = vec![];
+
+	for Rumor { message, hops } in rumors {
+		if storage.add_rumor(message, hops) {
+			debug!("Received {} from {} after {} hops", message, from, hops);
+		} else {
+			known.push(message);
+		}
+	}
+
+	known
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashSet;
+
+	fn cluster() -> Node {
+		Node::for_test("n1", &["n1", "n2", "n3", "n4", "n5"])
+	}
+
+	#[test]
+	fn push_round_sends_hot_rumors_to_fanout_peers() {
+		let node = cluster();
+		let config = Config::for_test(&["--gossip-mode", "push", "--fanout", "2"]);
+		let mut storage = Storage::default();
+		storage.add_message(1);
+		storage.add_rumor(2, 3);
+
+		let outgoing = epidemic_round(&mut storage, &node, &config);
+
+		let peers: HashSet<&str> = outgoing.iter().map(|m| m.dest.as_str()).collect();
+		assert_eq!(outgoing.len(), 2);
+		assert_eq!(peers.len(), 2);
+		assert!(!peers.contains("n1"));
+
+		for message in outgoing {
+			let Body::Push { mut rumors } = message.body else {
+				panic!("Expected a push, got {:?}", message.body);
+			};
+			rumors.sort_by_key(|r| r.message);
+
+			// Each hop so far, plus the one to the peer
+			let hops: Vec<(u64, u32)> = rumors.iter().map(|r| (r.message, r.hops)).collect();
+			assert_eq!(hops, [(1, 1), (2, 4)]);
+		}
+	}
+
+	#[test]
+	fn push_round_without_hot_rumors_sends_nothing() {
+		let node = cluster();
+		let config = Config::for_test(&["--gossip-mode", "push"]);
+		let mut storage = Storage::default();
+		storage.add_message(1);
+		storage.mark_redundant(vec![1], 1);
+
+		assert!(epidemic_round(&mut storage, &node, &config).is_empty());
+	}
+
+	#[test]
+	fn pull_round_sends_our_digest() {
+		let node = cluster();
+		let config = Config::for_test(&["--gossip-mode", "pull", "--fanout", "3"]);
+		let mut storage = Storage::default();
+		storage.add_message(7);
+
+		let outgoing = epidemic_round(&mut storage, &node, &config);
+
+		assert_eq!(outgoing.len(), 3);
+		for message in outgoing {
+			assert!(matches!(message.body, Body::Pull { ref messages } if messages == &[7]));
+		}
+	}
+
+	#[test]
+	fn push_pull_round_does_both_with_each_peer() {
+		let node = cluster();
+		let config = Config::for_test(&["--gossip-mode", "push-pull", "--fanout", "1"]);
+		let mut storage = Storage::default();
+		storage.add_message(7);
+
+		let outgoing = epidemic_round(&mut storage, &node, &config);
+
+		assert_eq!(outgoing.len(), 2);
+		assert_eq!(outgoing[0].dest, outgoing[1].dest);
+		assert!(matches!(outgoing[0].body, Body::Push { .. }));
+		assert!(matches!(outgoing[1].body, Body::Pull { .. }));
+	}
+
+	#[test]
+	fn received_rumors_keep_their_hops_and_report_known_values() {
+		let mut storage = Storage::default();
+		storage.add_message(1);
+
+		let rumors = vec![
+			Rumor {
+				message: 1,
+				hops: 5,
+			},
+			Rumor {
+				message: 2,
+				hops: 3,
+			},
+		];
+
+		assert_eq!(receive_rumors(&mut storage, rumors, "n2"), [1]);
+		assert_eq!(storage.hops[&1], 0);
+		assert_eq!(storage.hops[&2], 3);
+		assert!(storage.hot_rumors.contains_key(&2));
+	}
+
+	#[test]
+	fn rumors_stop_spreading_after_redundant_receipts() {
+		let mut storage = Storage::default();
+		storage.add_message(1);
+		storage.add_message(2);
+
+		storage.mark_redundant(vec![1, 3], 2);
+		assert!(storage.hot_rumors.contains_key(&1));
+
+		storage.mark_redundant(vec![1], 2);
+		assert!(!storage.hot_rumors.contains_key(&1));
+		assert!(storage.hot_rumors.contains_key(&2));
+
+		// Still known, only no longer pushed
+		assert_eq!(storage.get_missing_rumors(vec![2]).len(), 1);
+	}
+
+	fn neighbours(storage: &mut Storage) {
+		let topology = [("n1", vec!["n2", "n3", "n4"])]
+			.into_iter()
+			.map(|(n, ns)| (n.to_string(), ns.into_iter().map(String::from).collect()))
+			.collect();
+		storage.init_topology(topology);
+	}
+
+	#[test]
+	fn forward_sends_to_every_neighbour_but_the_sender() {
+		let node = cluster();
+		let mut storage = Storage::default();
+		neighbours(&mut storage);
+
+		let mut outgoing = forward(&mut storage, &node, vec![5, 6], "n3");
+		outgoing.sort_by(|a, b| a.dest.cmp(&b.dest));
+
+		let dests: Vec<&str> = outgoing.iter().map(|m| m.dest.as_str()).collect();
+		assert_eq!(dests, ["n2", "n4"]);
+
+		for message in &outgoing {
+			assert!(matches!(
+				message.body,
+				Body::Gossip { seq: 1, base: 0, ref messages } if messages == &[5, 6]
+			));
+		}
+
+		// Held until acknowledged, so repair rounds do not send it again
+		assert_eq!(storage.outbox.get_in_flight("n2"), HashSet::from([5, 6]));
+		assert!(storage.outbox.get_in_flight("n3").is_empty());
+	}
+
+	#[test]
+	fn forward_of_nothing_new_sends_nothing() {
+		let node = cluster();
+		let mut storage = Storage::default();
+		neighbours(&mut storage);
+
+		assert!(forward(&mut storage, &node, vec![], "c1").is_empty());
+	}
+}
@@ -1,219 +1,826 @@
+// Stdout carries the Maelstrom protocol, only the message writer may touch it.
+#![deny(clippy::print_stdout)]
+
+mod causal;
+mod config;
+mod dispatch;
+mod gossip;
+mod membership;
 mod message;
 mod node;
+mod persistence;
+mod plumtree;
+mod queue;
+mod scheduler;
 mod storage;
+mod total;
+mod transfer;
 
-use std::{io::Write, println, sync::Arc, thread, time::Duration};
+use std::{io::Write, mem, process, sync::Arc, time::Duration};
 
 use rand::{prelude::*, rngs::StdRng};
 use tokio::{
 	io::{AsyncBufReadExt, BufReader},
+	signal,
 	sync::{
 		mpsc,
 		mpsc::{Receiver, Sender},
-		Mutex,
+		watch,
 	},
 };
+use tracing::{debug, error, info, info_span, warn};
 
 use crate::{
+	config::{Config, Delivery},
+	dispatch::OrderBy,
+	gossip::Mode,
+	membership::{HyParView, Membership},
 	message::{Body, Message},
 	node::Node,
-	storage::Storage,
+	queue::{Overload, Shed},
+	scheduler::MissedTick,
+	storage::{Storage, StorageActor},
+	transfer::Chunk,
 };
 
-const GOSSIP_DELAY: u64 = 500;
-const MIN_AMOUNT_NODES: usize = 1;
-const NETWORK_SIZE: usize = 25;
-
 #[tokio::main]
 async fn main() {
-	let (reader_tx, mut reader_rx) = mpsc::channel(1000);
-	let (writer_tx, mut writer_rx) = mpsc::channel(1000);
+	let Config {
+		gossip: config,
+		concurrency,
+		order_by,
+		queue_capacity,
+		overload,
+		metrics_interval,
+		data_dir,
+		snapshot_every,
+		log_level,
+	} = Config::load();
+	logging::init(&log_level);
+
+	let (reader_tx, mut reader_rx) = mpsc::channel(queue_capacity as usize);
+	let (writer_tx, mut writer_rx) = mpsc::channel(queue_capacity as usize);
+	let (shutdown_tx, shutdown_rx) = watch::channel(());
 
-	let reader_tx1: Sender<Message> = reader_tx.clone();
 	let writer_tx1: Sender<Message> = writer_tx.clone();
 	let writer_tx2: Sender<Message> = writer_tx.clone();
+	let writer_tx3: Sender<Message> = writer_tx.clone();
+
+	let shed = Arc::new(Shed::default());
+	let (reader_weak, writer_weak) = (reader_tx.downgrade(), writer_tx.downgrade());
+
+	// The writer runs before anything is queued for it, `init_ok` and the
+	// HyParView joins alone may fill a small queue
+	let write = tokio::spawn(async move {
+		write_to_stdout(&mut writer_rx).await;
+	});
+
+	let (node, init_ok) = Node::bootstrap().await;
+	let node = Arc::new(node);
+
+	// A restarted node recovers everything it knew before it answers `init`
+	let (mut storage, wal) = match data_dir {
+		Some(dir) => match persistence::recover(&dir.join(&node.id), snapshot_every) {
+			Ok((storage, wal)) => (storage, Some(wal)),
+			Err(e) => {
+				error!("Could not recover from {}: {}", dir.display(), e);
+				process::exit(1);
+			}
+		},
+		None => (Storage::default(), None),
+	};
+
+	// Maelstrom does not send the topology again, rebuild the tree from the recovered one
+	if config.mode == Mode::Plumtree
+		&& config.membership == Membership::Full
+		&& !storage.topology.is_empty()
+	{
+		let neighbours = storage.get_neighbours(&node.id);
+		storage.plumtree.init(neighbours);
+	}
 
-	let store = Arc::new(Mutex::new(Storage::default()));
+	let _ = writer_tx.send(init_ok).await;
 
-	let node = Node::bootstrap().await;
+	if config.membership == Membership::HyParView {
+		storage.membership = Some(HyParView::new(node.availble_nodes.len()));
+
+		for message in membership::join(&mut storage, &node.id, &node.availble_nodes) {
+			let _ = writer_tx.send(message).await;
+		}
+	}
+
+	drop(writer_tx);
+
+	let store = StorageActor::spawn(storage, wal);
 
 	let n1 = node.clone();
 	let s1 = store.clone();
+	let shed1 = shed.clone();
+	let shutdown_rx1 = shutdown_rx.clone();
 
 	let read = tokio::spawn(async move {
-		read_from_stdin(reader_tx1).await;
+		read_from_stdin(reader_tx, writer_tx3, overload, &shed).await;
 	});
 
-	let write = tokio::spawn(async move {
-		write_to_stdout(&mut writer_rx).await;
+	let gossip = tokio::spawn(async move {
+		scheduler::run_every(
+			config.gossip_interval(),
+			config.gossip_jitter(),
+			config.missed_tick,
+			shutdown_rx1,
+			|| gossip_messages(&n1, &s1, &writer_tx2, &config),
+		)
+		.await;
 	});
 
-	let gossip = tokio::spawn(async move {
-		loop {
-			thread::sleep(Duration::from_millis(GOSSIP_DELAY));
-			gossip_messages(n1.clone(), s1.clone(), writer_tx2.clone()).await;
-		}
+	let metrics = tokio::spawn(async move {
+		scheduler::run_every(
+			Duration::from_millis(metrics_interval),
+			Duration::ZERO,
+			MissedTick::Skip,
+			shutdown_rx,
+			|| async { queue::report(&reader_weak, &writer_weak, &shed1) },
+		)
+		.await;
 	});
 
-	let handle = tokio::spawn(async move {
-		handle_messages(node.clone(), store.clone(), &mut reader_rx, writer_tx1).await;
+	let mut handle = tokio::spawn(async move {
+		handle_messages(
+			node,
+			store,
+			&mut reader_rx,
+			writer_tx1,
+			config,
+			concurrency as usize,
+			order_by,
+		)
+		.await;
 	});
 
-	let _ = tokio::try_join!(read, handle, write, gossip);
+	// Stdin closing ends the handler, after which gossip stops and the writer drains
+	tokio::select! {
+		_ = &mut handle => {}
+		_ = signal::ctrl_c() => {
+			warn!("Interrupted, shutting down");
+			handle.abort();
+		}
+	}
+
+	// The reader and handler hold senders to the writer, it only ends once they are gone
+	read.abort();
+	let _ = tokio::join!(read, handle);
+
+	let _ = shutdown_tx.send(());
+	let _ = tokio::join!(gossip, metrics, write);
+
+	// A pending stdin read sits on a blocking thread the runtime would wait for on drop
+	process::exit(0);
 }
 
-async fn read_from_stdin(reader_tx: Sender<Message>) {
+async fn read_from_stdin(
+	reader_tx: Sender<Message>,
+	writer: Sender<Message>,
+	overload: Overload,
+	shed: &Shed,
+) {
 	let stdin = tokio::io::stdin();
 	let mut reader = BufReader::new(stdin);
 
 	loop {
 		let mut buf = String::new();
-		reader.read_line(&mut buf).await.unwrap();
+
+		if reader.read_line(&mut buf).await.unwrap() == 0 {
+			return;
+		}
+
 		let message = Message::parse_message(buf.clone());
-		reader_tx.send(message).await.unwrap();
+
+		if !queue::admit(message, &reader_tx, &writer, overload, shed).await {
+			return;
+		}
 	}
 }
 
 async fn write_to_stdout(writer_rx: &mut Receiver<Message>) {
 	let mut stdout = std::io::stdout();
 
-	loop {
-		let message = writer_rx.recv().await.unwrap();
+	while let Some(message) = writer_rx.recv().await {
 		let message = Message::format_message(message);
 		writeln!(stdout, "{}", message).unwrap();
 		stdout.flush().unwrap();
 	}
 }
 
-async fn gossip_messages(node: Node, storage: Arc<Mutex<Storage>>, writer: Sender<Message>) {
-	let mut rng = StdRng::from_entropy();
+/// One gossip round on the storage thread, the messages are sent once it is done.
+async fn gossip_messages(
+	node: &Arc<Node>,
+	storage: &StorageActor,
+	writer: &Sender<Message>,
+	config: &gossip::Config,
+) {
+	let node = node.clone();
+	let config = *config;
 
-	let num_to_select = rng.gen_range(MIN_AMOUNT_NODES..=NETWORK_SIZE);
+	let outgoing = storage
+		.call(move |storage| gossip_round(storage, &node, &config))
+		.await;
 
-	let selected_neighbours: Vec<String> = node
-		.get_network()
-		.choose_multiple(&mut rng, num_to_select)
-		.cloned()
-		.collect();
+	for message in outgoing {
+		if writer.send(message).await.is_err() {
+			return;
+		}
+	}
+}
 
-	let mut tasks = vec![];
+fn gossip_round(storage: &mut Storage, node: &Node, config: &gossip::Config) -> Vec<Message> {
+	let mut outgoing = vec![];
+
+	if config.membership == Membership::HyParView {
+		outgoing.extend(membership::tick(storage, &node.id));
+	}
+
+	match config.delivery {
+		Delivery::Causal => {
+			outgoing.extend(causal::tick(storage, node, config.fanout as usize));
+			return outgoing;
+		}
+		Delivery::Total => {
+			outgoing.extend(total::tick(storage, node));
+			return outgoing;
+		}
+		Delivery::Unordered => {}
+	}
+
+	let selected_neighbours: Vec<String> = match config.mode {
+		Mode::Push | Mode::Pull | Mode::PushPull => {
+			outgoing.extend(gossip::epidemic_round(storage, node, config));
+			return outgoing;
+		}
+		Mode::Plumtree => {
+			outgoing.extend(plumtree::tick(storage, &node.id));
+			return outgoing;
+		}
+		// New values were already forwarded, only repair what our neighbours did not acknowledge
+		Mode::Eager => storage.get_neighbours(&node.id),
+		Mode::Random => {
+			let mut rng = StdRng::from_entropy();
+
+			let num_to_select = rng.gen_range(config.min_fanout..=config.max_fanout) as usize;
+
+			storage
+				.get_network(node)
+				.choose_multiple(&mut rng, num_to_select)
+				.cloned()
+				.collect()
+		}
+	};
+
+	outgoing.extend(transfer::tick(storage, &node.id));
+	storage.outbox.next_round();
+	let mut batches = storage.outbox.get_retransmissions();
 
 	for n in selected_neighbours {
-		let storage_clone = storage.clone();
-		let writer_clone = writer.clone();
-		let node_clone = node.clone();
+		// Incremental gossip resumes once the peer confirmed its snapshot
+		if storage.transfers.is_sending_to(&n) {
+			continue;
+		}
 
-		let task = tokio::spawn(async move {
-			let messages = storage_clone
-				.lock()
-				.await
-				.get_new_messages_for_neighbour(n.clone());
+		let backlog = storage.get_new_messages_for_neighbour(n.clone());
 
-			if messages.is_empty() {
-				return;
-			}
+		if backlog.len() > config.catch_up_threshold as usize {
+			outgoing.push(transfer::offer(&node.id, &n, backlog.len()));
+			continue;
+		}
 
-			let message = Message {
-				src: node_clone.id.clone(),
-				dest: n.clone(),
-				body: Body::Gossip {
-					messages: messages.clone(),
-				},
-			};
+		let messages = config.limit_batch(backlog);
 
-			writer_clone.send(message).await.unwrap();
-		});
+		if messages.is_empty() {
+			continue;
+		}
 
-		tasks.push(task);
+		let seq = storage.outbox.send(&n, messages.clone());
+		batches.push((n, seq, messages));
 	}
 
-	// Wait for all the gossip tasks to complete
-	for task in tasks {
-		task.await.unwrap();
+	for (peer, seq, messages) in batches {
+		let base = storage.outbox.base(&peer);
+
+		outgoing.push(Message {
+			src: node.id.clone(),
+			dest: peer,
+			body: Body::Gossip {
+				seq,
+				base,
+				messages,
+			},
+		});
 	}
+
+	outgoing
 }
 
 async fn handle_messages(
-	node: Node,
-	storage: Arc<Mutex<Storage>>,
+	node: Arc<Node>,
+	storage: StorageActor,
 	input: &mut Receiver<Message>,
 	writer: Sender<Message>,
+	config: gossip::Config,
+	concurrency: usize,
+	order_by: OrderBy,
 ) {
-	while let Some(input) = input.recv().await {
-		match input.body {
-			Body::Broadcast { msg_id, message } => {
-				let id = node.id.clone();
-				storage.lock().await.add_message(message);
-
-				let response = Message {
-					src: id,
-					dest: input.src,
-					body: Body::BroadcastOk {
-						msg_id,
-						in_reply_to: msg_id,
-					},
-				};
-
-				writer.send(response).await.unwrap();
-			}
-			Body::Gossip { messages } => {
-				let id = node.id.clone();
-				storage
-					.lock()
-					.await
-					.add_messages(messages.clone(), input.src.clone());
-
-				let response = Message {
-					src: id,
-					dest: input.src,
-					body: Body::GossipOk { messages },
-				};
-
-				writer.send(response).await.unwrap();
-			}
-			Body::GossipOk { messages } => {
-				storage
-					.lock()
-					.await
-					.add_to_sent_messages(messages, input.src);
-			}
-			Body::Read { msg_id } => {
-				let response = Message {
-					src: node.id.clone(),
-					dest: input.src,
-					body: Body::ReadOk {
-						msg_id,
-						in_reply_to: msg_id,
-						messages: storage.lock().await.get_messages(),
-					},
-				};
-
-				writer.send(response).await.unwrap();
+	let handler = move |input: Message| {
+		let (body_type, msg_id) = logging::header(&input.body);
+		let span = info_span!("message", node_id = %node.id, msg_id, body_type = %body_type);
+		let node = node.clone();
+		let storage = storage.clone();
+		let writer = writer.clone();
+
+		async move {
+			let Some(input) = span.in_scope(|| prepare(input, &node, &config)) else {
+				return;
+			};
+
+			let outgoing = storage
+				.call(move |storage| {
+					let _enter = span.enter();
+					handle_message(storage, &node, input, &config)
+				})
+				.await;
+
+			for message in outgoing {
+				if writer.send(message).await.is_err() {
+					return;
+				}
 			}
-			Body::Topology { msg_id, .. } => {
-				let response = Message {
-					src: node.id.clone(),
-					dest: input.src,
-					body: Body::TopologyOk {
-						msg_id,
-						in_reply_to: msg_id,
-					},
-				};
-
-				writer.send(response).await.unwrap();
+		}
+	};
+
+	dispatch::run(input, concurrency, order_by, handler).await;
+	info!("Input closed, shutting down");
+}
+
+/// Does the work on a message which needs no storage in its lane, so lanes
+/// only queue up behind each other for the storage itself. Returns what is
+/// left for the storage, if anything.
+fn prepare(mut input: Message, node: &Node, config: &gossip::Config) -> Option<Message> {
+	match &mut input.body {
+		Body::SnapshotChunk {
+			version,
+			index,
+			checksum,
+			messages,
+			..
+		} if transfer::checksum(messages) != *checksum => {
+			warn!(
+				version,
+				index, "Dropping corrupted snapshot chunk from {}", input.src
+			);
+			None
+		}
+		Body::Topology { topology, .. } => {
+			let given = mem::take(topology);
+			*topology =
+				config
+					.topology
+					.arrange(given, &node.availble_nodes, config.fanout as usize);
+			Some(input)
+		}
+		Body::Error {
+			in_reply_to,
+			code,
+			text,
+		} => {
+			warn!(in_reply_to, code, "Error received: {}", text);
+			None
+		}
+		_ => Some(input),
+	}
+}
+
+/// Applies one message to the storage and returns the messages to send in
+/// response. The message went through `prepare` first.
+fn handle_message(
+	storage: &mut Storage,
+	node: &Node,
+	input: Message,
+	config: &gossip::Config,
+) -> Vec<Message> {
+	if let Some(view) = storage.membership.as_mut() {
+		view.seen(&input.src);
+	}
+
+	match input.body {
+		Body::Broadcast { msg_id, message } => {
+			let mut outgoing = match config.delivery {
+				Delivery::Causal => causal::broadcast(storage, &node.id, message),
+				Delivery::Total => total::broadcast(storage, node, message),
+				Delivery::Unordered => {
+					let new = storage.add_message(message);
+
+					match config.mode {
+						Mode::Eager if new => {
+							gossip::forward(storage, node, vec![message], &input.src)
+						}
+						Mode::Plumtree if new => plumtree::broadcast(storage, &node.id, message),
+						_ => vec![],
+					}
+				}
+			};
+
+			outgoing.push(Message {
+				src: node.id.clone(),
+				dest: input.src,
+				body: Body::BroadcastOk {
+					msg_id,
+					in_reply_to: msg_id,
+				},
+			});
+
+			outgoing
+		}
+		Body::Gossip {
+			seq,
+			base,
+			messages,
+		} => {
+			let new = storage.add_messages(messages, input.src.clone());
+			let (ack, sack) = storage.outbox.receive(&input.src, seq, base);
+
+			let mut outgoing = if config.mode == Mode::Eager {
+				gossip::forward(storage, node, new, &input.src)
+			} else {
+				vec![]
+			};
+
+			outgoing.push(Message {
+				src: node.id.clone(),
+				dest: input.src,
+				body: Body::GossipOk { ack, sack },
+			});
+
+			outgoing
+		}
+		Body::GossipOk { ack, sack } => {
+			let messages = storage.outbox.acknowledge(&input.src, ack, sack);
+			storage.add_to_sent_messages(messages, input.src);
+			vec![]
+		}
+		Body::Push { rumors } => {
+			let known = gossip::receive_rumors(storage, rumors, &input.src);
+
+			vec![Message {
+				src: node.id.clone(),
+				dest: input.src,
+				body: Body::PushOk { known },
+			}]
+		}
+		Body::PushOk { known } => {
+			storage.mark_redundant(known, config.redundancy);
+			vec![]
+		}
+		Body::Pull { messages } => {
+			let rumors = storage.get_missing_rumors(messages);
+
+			if rumors.is_empty() {
+				return vec![];
 			}
-			Body::Error {
-				in_reply_to,
-				code,
-				text,
-			} => {
-				eprintln!(
-					"Error received (in_reply_to: {}, code: {}, text: {})",
-					in_reply_to, code, text
-				);
+
+			vec![Message {
+				src: node.id.clone(),
+				dest: input.src,
+				body: Body::PullOk { rumors },
+			}]
+		}
+		Body::PullOk { rumors } => {
+			gossip::receive_rumors(storage, rumors, &input.src);
+			vec![]
+		}
+		Body::EagerPush { message, round } => {
+			plumtree::receive_eager_push(storage, &node.id, &input.src, message, round)
+		}
+		Body::IHave { rumors } => {
+			plumtree::receive_ihave(storage, &input.src, rumors);
+			vec![]
+		}
+		Body::Graft { messages } => {
+			plumtree::receive_graft(storage, &node.id, &input.src, messages)
+		}
+		Body::Prune {} => {
+			plumtree::receive_prune(storage, &input.src);
+			vec![]
+		}
+		Body::Join {} => membership::receive_join(storage, &node.id, &input.src),
+		Body::ForwardJoin { node: joining, ttl } => {
+			membership::receive_forward_join(storage, &node.id, &input.src, joining, ttl)
+		}
+		Body::Neighbor { high_priority } => {
+			membership::receive_neighbor(storage, &node.id, &input.src, high_priority)
+		}
+		Body::NeighborOk { accepted } => {
+			membership::receive_neighbor_ok(storage, &node.id, &input.src, accepted)
+		}
+		Body::Disconnect {} => {
+			membership::receive_disconnect(storage, &input.src);
+			vec![]
+		}
+		Body::Shuffle { origin, nodes, ttl } => {
+			membership::receive_shuffle(storage, &node.id, &input.src, origin, nodes, ttl)
+		}
+		Body::ShuffleOk { nodes } => {
+			membership::receive_shuffle_ok(storage, &node.id, nodes);
+			vec![]
+		}
+		Body::SnapshotOffer { values } => {
+			debug!(values, "Offered a snapshot by {}", input.src);
+			transfer::receive_offer(storage, &node.id, &input.src)
+		}
+		Body::SnapshotRequest { version, chunks } => transfer::receive_request(
+			storage,
+			&node.id,
+			&input.src,
+			version,
+			chunks,
+			config.chunk_size as usize,
+		),
+		Body::SnapshotChunk {
+			version,
+			index,
+			total,
+			messages,
+			..
+		} => {
+			let chunk = Chunk {
+				version,
+				index,
+				total,
+				messages,
+			};
+
+			transfer::receive_chunk(storage, &node.id, &input.src, chunk)
+		}
+		Body::SnapshotOk { version } => {
+			transfer::receive_ok(storage, &input.src, version);
+			vec![]
+		}
+		Body::CausalPush { messages } => {
+			causal::receive_push(storage, &node.id, &input.src, messages)
+		}
+		Body::CausalSync { clock } => causal::receive_sync(storage, &node.id, &input.src, clock),
+		Body::Sequence { message } => total::receive_sequence(storage, node, message),
+		Body::Sequenced {
+			epoch,
+			start,
+			messages,
+			delivered,
+		} => total::receive_sequenced(storage, node, &input.src, epoch, start, messages, delivered),
+		Body::SequencedOk { epoch, length } => {
+			total::receive_sequenced_ok(storage, node, &input.src, epoch, length)
+		}
+		Body::SequenceSync { from } => total::receive_sync(storage, node, &input.src, from),
+		Body::SequenceHeartbeat {
+			epoch,
+			length,
+			delivered,
+		} => total::receive_heartbeat(storage, node, &input.src, epoch, length, delivered),
+		Body::Takeover { epoch } => total::receive_takeover(storage, node, &input.src, epoch),
+		Body::TakeoverOk {
+			epoch,
+			stored_epoch,
+			delivered,
+			messages,
+		} => total::receive_takeover_ok(
+			storage,
+			node,
+			&input.src,
+			epoch,
+			stored_epoch,
+			delivered,
+			messages,
+		),
+		Body::Read { msg_id } => {
+			let messages = match config.delivery {
+				Delivery::Unordered => storage.get_messages(),
+				Delivery::Causal => storage.causal.read(),
+				Delivery::Total => storage.total.read(),
+			};
+
+			vec![Message {
+				src: node.id.clone(),
+				dest: input.src,
+				body: Body::ReadOk {
+					msg_id,
+					in_reply_to: msg_id,
+					messages,
+				},
+			}]
+		}
+		Body::Topology { msg_id, topology } => {
+			storage.init_topology(topology);
+
+			if config.mode == Mode::Plumtree && config.membership == Membership::Full {
+				let neighbours = storage.get_neighbours(&node.id);
+				storage.plumtree.init(neighbours);
 			}
-			_ => (),
+
+			vec![Message {
+				src: node.id.clone(),
+				dest: input.src,
+				body: Body::TopologyOk {
+					msg_id,
+					in_reply_to: msg_id,
+				},
+			}]
+		}
+		_ => vec![],
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::message::Rumor;
+
+	fn message(src: &str, body: Body) -> Message {
+		Message {
+			src: src.to_string(),
+			dest: "n1".to_string(),
+			body,
+		}
+	}
+
+	fn rumor(message: u64, hops: u32) -> Rumor {
+		Rumor { message, hops }
+	}
+
+	#[test]
+	fn push_is_answered_with_the_values_we_knew() {
+		let node = Node::for_test("n1", &["n1", "n2"]);
+		let config = gossip::Config::for_test(&["--gossip-mode", "push"]);
+		let mut storage = Storage::default();
+		storage.add_message(1);
+
+		let push = Body::Push {
+			rumors: vec![rumor(1, 2), rumor(2, 2)],
+		};
+		let outgoing = handle_message(&mut storage, &node, message("n2", push), &config);
+
+		assert_eq!(outgoing.len(), 1);
+		assert_eq!(outgoing[0].dest, "n2");
+		assert!(matches!(outgoing[0].body, Body::PushOk { ref known } if known == &[1]));
+		assert_eq!(storage.hops[&2], 2);
+	}
+
+	#[test]
+	fn push_ok_counts_redundant_receipts() {
+		let node = Node::for_test("n1", &["n1", "n2", "n3"]);
+		let config = gossip::Config::for_test(&["--gossip-mode", "push", "--redundancy", "2"]);
+		let mut storage = Storage::default();
+		storage.add_message(1);
+
+		for peer in ["n2", "n3"] {
+			assert!(storage.hot_rumors.contains_key(&1));
+			let push_ok = Body::PushOk { known: vec![1] };
+			assert!(
+				handle_message(&mut storage, &node, message(peer, push_ok), &config).is_empty()
+			);
+		}
+
+		assert!(storage.hot_rumors.is_empty());
+	}
+
+	#[test]
+	fn pull_is_answered_with_what_the_peer_misses() {
+		let node = Node::for_test("n1", &["n1", "n2"]);
+		let config = gossip::Config::for_test(&["--gossip-mode", "pull"]);
+		let mut storage = Storage::default();
+		storage.add_message(1);
+		storage.add_rumor(2, 4);
+
+		let pull = Body::Pull { messages: vec![1] };
+		let outgoing = handle_message(&mut storage, &node, message("n2", pull), &config);
+
+		assert_eq!(outgoing.len(), 1);
+		let Body::PullOk { ref rumors } = outgoing[0].body else {
+			panic!("Expected pull_ok, got {:?}", outgoing[0].body);
+		};
+		assert_eq!(rumors.len(), 1);
+		assert_eq!((rumors[0].message, rumors[0].hops), (2, 5));
+
+		// A peer missing nothing gets no answer
+		let pull = Body::Pull {
+			messages: vec![1, 2],
+		};
+		assert!(handle_message(&mut storage, &node, message("n2", pull), &config).is_empty());
+	}
+
+	#[test]
+	fn pull_ok_stores_the_missing_values() {
+		let node = Node::for_test("n1", &["n1", "n2"]);
+		let config = gossip::Config::for_test(&["--gossip-mode", "pull"]);
+		let mut storage = Storage::default();
+
+		let pull_ok = Body::PullOk {
+			rumors: vec![rumor(3, 1), rumor(4, 2)],
+		};
+		assert!(handle_message(&mut storage, &node, message("n2", pull_ok), &config).is_empty());
+
+		let mut messages = storage.get_messages();
+		messages.sort();
+		assert_eq!(messages, [3, 4]);
+		assert_eq!(storage.hops[&4], 2);
+	}
+
+	fn eager() -> (Node, gossip::Config, Storage) {
+		let node = Node::for_test("n1", &["n1", "n2", "n3"]);
+		let config = gossip::Config::for_test(&["--gossip-mode", "eager", "--topology", "full"]);
+		let mut storage = Storage::default();
+
+		let topology = config.topology.arrange(
+			Default::default(),
+			&node.availble_nodes,
+			config.fanout as usize,
+		);
+		storage.init_topology(topology);
+
+		(node, config, storage)
+	}
+
+	#[test]
+	fn broadcast_is_forwarded_at_once_in_eager_mode() {
+		let (node, config, mut storage) = eager();
+
+		let broadcast = Body::Broadcast {
+			msg_id: 1,
+			message: 9,
+		};
+		let outgoing = handle_message(&mut storage, &node, message("c1", broadcast), &config);
+
+		let mut gossip: Vec<&str> = outgoing
+			.iter()
+			.filter(|m| matches!(m.body, Body::Gossip { .. }))
+			.map(|m| m.dest.as_str())
+			.collect();
+		gossip.sort();
+		assert_eq!(gossip, ["n2", "n3"]);
+		assert!(matches!(
+			outgoing.last().unwrap().body,
+			Body::BroadcastOk { in_reply_to: 1, .. }
+		));
+
+		// The same value again is not forwarded a second time
+		let broadcast = Body::Broadcast {
+			msg_id: 2,
+			message: 9,
+		};
+		let outgoing = handle_message(&mut storage, &node, message("c1", broadcast), &config);
+		assert_eq!(outgoing.len(), 1);
+	}
+
+	#[test]
+	fn gossip_forwards_only_new_values_and_not_back() {
+		let (node, config, mut storage) = eager();
+		storage.add_message(1);
+
+		let gossip = Body::Gossip {
+			seq: 1,
+			base: 0,
+			messages: vec![1, 2],
+		};
+		let outgoing = handle_message(&mut storage, &node, message("n2", gossip), &config);
+
+		assert_eq!(outgoing.len(), 2);
+		assert_eq!(outgoing[0].dest, "n3");
+		assert!(matches!(outgoing[0].body, Body::Gossip { ref messages, .. } if messages == &[2]));
+		assert_eq!(outgoing[1].dest, "n2");
+		assert!(matches!(outgoing[1].body, Body::GossipOk { ack: 1, .. }));
+	}
+
+	#[test]
+	fn eager_rounds_only_repair_what_was_not_acknowledged() {
+		let (node, config, mut storage) = eager();
+
+		let broadcast = Body::Broadcast {
+			msg_id: 1,
+			message: 9,
+		};
+		handle_message(&mut storage, &node, message("c1", broadcast), &config);
+
+		// n2 acknowledges the forward, n3 never does
+		let ack = Body::GossipOk {
+			ack: 1,
+			sack: vec![],
+		};
+		handle_message(&mut storage, &node, message("n2", ack), &config);
+
+		let mut repaired = vec![];
+		for _ in 0..3 {
+			repaired.extend(gossip_round(&mut storage, &node, &config));
 		}
+
+		assert_eq!(repaired.len(), 1);
+		assert_eq!(repaired[0].dest, "n3");
+		assert!(
+			matches!(repaired[0].body, Body::Gossip { seq: 1, base: 0, ref messages } if messages == &[9])
+		);
 	}
-	println!("Error, nothing to read from receiver");
 }
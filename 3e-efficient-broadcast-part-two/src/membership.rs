@@ -0,0 +1,565 @@
+use std::{
+	collections::{HashMap, HashSet},
+	str::FromStr,
+	time::{Duration, Instant},
+};
+
+use rand::{prelude::*, rngs::StdRng};
+
+use crate::{
+	message::{Body, Message},
+	storage::Storage,
+};
+
+/// Random walk length of a `forward_join` before the joining node is added to an active view.
+const ACTIVE_RANDOM_WALK_LENGTH: u32 = 6;
+/// Remaining walk length at which the joining node is added to a passive view.
+const PASSIVE_RANDOM_WALK_LENGTH: u32 = 3;
+/// Active and passive entries sent along with a shuffle.
+const SHUFFLE_ACTIVE: usize = 3;
+const SHUFFLE_PASSIVE: usize = 4;
+/// Active peers we did not hear from for this long are considered failed.
+const FAILURE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Every how many gossip rounds we run the membership maintenance.
+const MEMBERSHIP_ROUNDS: u64 = 4;
+
+/// Where gossip targets come from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Membership {
+	/// Every node from `init`, and the neighbours from the `topology` message.
+	Full,
+	/// A bounded, self-healing HyParView overlay.
+	HyParView,
+}
+
+impl FromStr for Membership {
+	type Err = String;
+
+	fn from_str(membership: &str) -> Result<Self, Self::Err> {
+		match membership {
+			"full" => Ok(Membership::Full),
+			"hyparview" => Ok(Membership::HyParView),
+			_ => Err(format!("Unknown membership: {}", membership)),
+		}
+	}
+}
+
+/// HyParView partial membership: a small symmetric active view used for gossip,
+/// and a larger passive view of backup nodes refreshed through shuffles.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct HyParView {
+	active: HashSet<String>,
+	passive: HashSet<String>,
+	active_size: usize,
+	passive_size: usize,
+	last_seen: HashMap<String, Instant>,
+	/// Passive nodes we asked to become neighbours, and when.
+	pending: HashMap<String, Instant>,
+	rounds: u64,
+}
+
+impl HyParView {
+	/// Sizes the views logarithmically to the cluster size.
+	pub(crate) fn new(network_size: usize) -> HyParView {
+		let active_size = (network_size.max(2) as f64).log2().ceil() as usize + 1;
+
+		HyParView {
+			active_size,
+			passive_size: active_size * 6,
+			..HyParView::default()
+		}
+	}
+
+	pub(crate) fn get_active_view(&self) -> Vec<String> {
+		self.active.iter().cloned().collect()
+	}
+
+	/// Records that we heard from a peer, which keeps it in the active view.
+	pub(crate) fn seen(&mut self, peer: &str) {
+		if let Some(last_seen) = self.last_seen.get_mut(peer) {
+			*last_seen = Instant::now();
+		}
+	}
+
+	fn add_active(&mut self, id: &str, peer: &str) -> Vec<Message> {
+		if peer == id || self.active.contains(peer) {
+			return vec![];
+		}
+
+		let mut outgoing = vec![];
+
+		if self.active.len() >= self.active_size {
+			if let Some(dropped) = self.random_active(&[]) {
+				self.remove_active(&dropped);
+
+				outgoing.push(Message {
+					src: id.to_string(),
+					dest: dropped,
+					body: Body::Disconnect {},
+				});
+			}
+		}
+
+		self.passive.remove(peer);
+		self.pending.remove(peer);
+		self.active.insert(peer.to_string());
+		self.last_seen.insert(peer.to_string(), Instant::now());
+
+		outgoing
+	}
+
+	fn remove_active(&mut self, peer: &str) {
+		if self.active.remove(peer) {
+			self.last_seen.remove(peer);
+			self.add_passive(peer);
+		}
+	}
+
+	fn add_passive(&mut self, peer: &str) {
+		if self.active.contains(peer) || self.passive.contains(peer) {
+			return;
+		}
+
+		if self.passive.len() >= self.passive_size {
+			let mut rng = StdRng::from_entropy();
+
+			if let Some(dropped) = self.passive.iter().choose(&mut rng).cloned() {
+				self.passive.remove(&dropped);
+			}
+		}
+
+		self.passive.insert(peer.to_string());
+	}
+
+	fn random_active(&self, except: &[&str]) -> Option<String> {
+		let mut rng = StdRng::from_entropy();
+
+		self.active
+			.iter()
+			.filter(|p| !except.contains(&p.as_str()))
+			.choose(&mut rng)
+			.cloned()
+	}
+}
+
+/// Joins the overlay through the node with the lowest id.
+pub(crate) fn join(storage: &mut Storage, id: &str, nodes: &[String]) -> Vec<Message> {
+	let Some(view) = storage.membership.as_mut() else {
+		return vec![];
+	};
+
+	let contact = match nodes.iter().min() {
+		Some(contact) if contact != id => contact.clone(),
+		_ => return vec![],
+	};
+
+	let mut outgoing = view.add_active(id, &contact);
+
+	outgoing.push(Message {
+		src: id.to_string(),
+		dest: contact,
+		body: Body::Join {},
+	});
+
+	storage.sync_plumtree();
+	outgoing
+}
+
+pub(crate) fn receive_join(storage: &mut Storage, id: &str, from: &str) -> Vec<Message> {
+	let Some(view) = storage.membership.as_mut() else {
+		return vec![];
+	};
+
+	let mut outgoing = view.add_active(id, from);
+
+	for peer in view.active.iter().filter(|p| *p != from) {
+		outgoing.push(Message {
+			src: id.to_string(),
+			dest: peer.clone(),
+			body: Body::ForwardJoin {
+				node: from.to_string(),
+				ttl: ACTIVE_RANDOM_WALK_LENGTH,
+			},
+		});
+	}
+
+	storage.sync_plumtree();
+	outgoing
+}
+
+pub(crate) fn receive_forward_join(
+	storage: &mut Storage,
+	id: &str,
+	from: &str,
+	node: String,
+	ttl: u32,
+) -> Vec<Message> {
+	let Some(view) = storage.membership.as_mut() else {
+		return vec![];
+	};
+
+	if node == id {
+		return vec![];
+	}
+
+	let next = view.random_active(&[from, &node]);
+
+	let outgoing = match next {
+		Some(next) if ttl > 0 && view.active.len() > 1 => {
+			if ttl == PASSIVE_RANDOM_WALK_LENGTH {
+				view.add_passive(&node);
+			}
+
+			vec![Message {
+				src: id.to_string(),
+				dest: next,
+				body: Body::ForwardJoin { node, ttl: ttl - 1 },
+			}]
+		}
+		_ => {
+			let mut outgoing = view.add_active(id, &node);
+
+			outgoing.push(Message {
+				src: id.to_string(),
+				dest: node,
+				body: Body::Neighbor {
+					high_priority: true,
+				},
+			});
+
+			outgoing
+		}
+	};
+
+	storage.sync_plumtree();
+	outgoing
+}
+
+pub(crate) fn receive_neighbor(
+	storage: &mut Storage,
+	id: &str,
+	from: &str,
+	high_priority: bool,
+) -> Vec<Message> {
+	let Some(view) = storage.membership.as_mut() else {
+		return vec![];
+	};
+
+	let accepted = high_priority || view.active.len() < view.active_size;
+
+	let mut outgoing = if accepted {
+		view.add_active(id, from)
+	} else {
+		vec![]
+	};
+
+	outgoing.push(Message {
+		src: id.to_string(),
+		dest: from.to_string(),
+		body: Body::NeighborOk { accepted },
+	});
+
+	storage.sync_plumtree();
+	outgoing
+}
+
+pub(crate) fn receive_neighbor_ok(
+	storage: &mut Storage,
+	id: &str,
+	from: &str,
+	accepted: bool,
+) -> Vec<Message> {
+	let Some(view) = storage.membership.as_mut() else {
+		return vec![];
+	};
+
+	if view.pending.remove(from).is_none() || !accepted {
+		return vec![];
+	}
+
+	let outgoing = view.add_active(id, from);
+
+	storage.sync_plumtree();
+	outgoing
+}
+
+pub(crate) fn receive_disconnect(storage: &mut Storage, from: &str) {
+	if let Some(view) = storage.membership.as_mut() {
+		view.remove_active(from);
+		storage.sync_plumtree();
+	}
+}
+
+pub(crate) fn receive_shuffle(
+	storage: &mut Storage,
+	id: &str,
+	from: &str,
+	origin: String,
+	nodes: Vec<String>,
+	ttl: u32,
+) -> Vec<Message> {
+	let Some(view) = storage.membership.as_mut() else {
+		return vec![];
+	};
+
+	if origin == id {
+		return vec![];
+	}
+
+	if ttl > 1 && view.active.len() > 1 {
+		if let Some(next) = view.random_active(&[from, &origin]) {
+			return vec![Message {
+				src: id.to_string(),
+				dest: next,
+				body: Body::Shuffle {
+					origin,
+					nodes,
+					ttl: ttl - 1,
+				},
+			}];
+		}
+	}
+
+	let mut rng = StdRng::from_entropy();
+	let reply: Vec<String> = view
+		.passive
+		.iter()
+		.cloned()
+		.choose_multiple(&mut rng, nodes.len());
+
+	for node in nodes.iter().filter(|n| *n != id) {
+		view.add_passive(node);
+	}
+
+	vec![Message {
+		src: id.to_string(),
+		dest: origin,
+		body: Body::ShuffleOk { nodes: reply },
+	}]
+}
+
+pub(crate) fn receive_shuffle_ok(storage: &mut Storage, id: &str, nodes: Vec<String>) {
+	if let Some(view) = storage.membership.as_mut() {
+		for node in nodes.iter().filter(|n| *n != id) {
+			view.add_passive(node);
+		}
+	}
+}
+
+/// Drops active peers we did not hear from, refills the active view from the
+/// passive one, keeps our links alive and shuffles with a random neighbour.
+pub(crate) fn tick(storage: &mut Storage, id: &str) -> Vec<Message> {
+	tick_at(storage, id, Instant::now())
+}
+
+fn tick_at(storage: &mut Storage, id: &str, now: Instant) -> Vec<Message> {
+	let Some(view) = storage.membership.as_mut() else {
+		return vec![];
+	};
+
+	view.rounds += 1;
+
+	if !view.rounds.is_multiple_of(MEMBERSHIP_ROUNDS) {
+		return vec![];
+	}
+
+	let mut rng = StdRng::from_entropy();
+	let mut outgoing = vec![];
+
+	let failed: Vec<String> = view
+		.last_seen
+		.iter()
+		.filter(|(_, seen)| now.duration_since(**seen) > FAILURE_TIMEOUT)
+		.map(|(peer, _)| peer.clone())
+		.collect();
+
+	for peer in failed {
+		view.remove_active(&peer);
+	}
+
+	view.pending
+		.retain(|_, asked| now.duration_since(*asked) <= FAILURE_TIMEOUT);
+
+	if view.active.len() + view.pending.len() < view.active_size {
+		let candidate = view
+			.passive
+			.iter()
+			.filter(|p| !view.pending.contains_key(*p))
+			.choose(&mut rng)
+			.cloned();
+
+		if let Some(candidate) = candidate {
+			view.pending.insert(candidate.clone(), now);
+
+			outgoing.push(Message {
+				src: id.to_string(),
+				dest: candidate,
+				body: Body::Neighbor {
+					high_priority: view.active.is_empty(),
+				},
+			});
+		}
+	}
+
+	for peer in view.active.iter() {
+		outgoing.push(Message {
+			src: id.to_string(),
+			dest: peer.clone(),
+			body: Body::Ping {},
+		});
+	}
+
+	if let Some(peer) = view.random_active(&[]) {
+		let mut nodes = vec![id.to_string()];
+		nodes.extend(
+			view.active
+				.iter()
+				.filter(|p| **p != peer)
+				.cloned()
+				.choose_multiple(&mut rng, SHUFFLE_ACTIVE),
+		);
+		nodes.extend(
+			view.passive
+				.iter()
+				.cloned()
+				.choose_multiple(&mut rng, SHUFFLE_PASSIVE),
+		);
+
+		outgoing.push(Message {
+			src: id.to_string(),
+			dest: peer,
+			body: Body::Shuffle {
+				origin: id.to_string(),
+				nodes,
+				ttl: PASSIVE_RANDOM_WALK_LENGTH,
+			},
+		});
+	}
+
+	storage.sync_plumtree();
+	outgoing
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn overlay(network_size: usize) -> Storage {
+		let mut storage = Storage::default();
+		storage.membership = Some(HyParView::new(network_size));
+		storage
+	}
+
+	fn view(storage: &Storage) -> &HyParView {
+		storage.membership.as_ref().unwrap()
+	}
+
+	/// Runs the rounds up to the next maintenance, at `now`.
+	fn maintain(storage: &mut Storage, now: Instant) -> Vec<Message> {
+		(0..MEMBERSHIP_ROUNDS)
+			.map(|_| tick_at(storage, "n1", now))
+			.last()
+			.unwrap()
+	}
+
+	#[test]
+	fn views_grow_logarithmically_with_the_cluster() {
+		assert_eq!(HyParView::new(1).active_size, 2);
+		assert_eq!(HyParView::new(5).active_size, 4);
+		assert_eq!(HyParView::new(25).active_size, 6);
+		assert_eq!(HyParView::new(25).passive_size, 36);
+	}
+
+	#[test]
+	fn a_full_active_view_drops_a_peer_into_the_passive_view() {
+		let mut storage = overlay(2);
+
+		receive_join(&mut storage, "n1", "n2");
+		receive_join(&mut storage, "n1", "n3");
+		let outgoing = receive_join(&mut storage, "n1", "n4");
+
+		let disconnected: Vec<&Message> = outgoing
+			.iter()
+			.filter(|m| matches!(m.body, Body::Disconnect {}))
+			.collect();
+		assert_eq!(disconnected.len(), 1);
+		assert_eq!(view(&storage).active.len(), 2);
+		assert!(view(&storage).active.contains("n4"));
+		assert!(view(&storage).passive.contains(&disconnected[0].dest));
+	}
+
+	#[test]
+	fn the_passive_view_is_bounded() {
+		let mut storage = overlay(2);
+		let nodes = (2..100).map(|n| format!("n{}", n)).collect();
+
+		receive_shuffle_ok(&mut storage, "n1", nodes);
+
+		assert_eq!(view(&storage).passive.len(), view(&storage).passive_size);
+	}
+
+	#[test]
+	fn low_priority_neighbor_requests_are_refused_by_a_full_view() {
+		let mut storage = overlay(2);
+		receive_join(&mut storage, "n1", "n2");
+		receive_join(&mut storage, "n1", "n3");
+
+		let outgoing = receive_neighbor(&mut storage, "n1", "n4", false);
+
+		assert!(matches!(
+			outgoing[0].body,
+			Body::NeighborOk { accepted: false }
+		));
+		assert!(!view(&storage).active.contains("n4"));
+	}
+
+	#[test]
+	fn silent_peers_are_evicted_after_the_failure_timeout() {
+		let mut storage = overlay(5);
+		receive_join(&mut storage, "n1", "n2");
+		let now = Instant::now();
+
+		maintain(&mut storage, now);
+		assert!(view(&storage).active.contains("n2"));
+
+		maintain(&mut storage, now + FAILURE_TIMEOUT + Duration::from_secs(1));
+		assert!(view(&storage).active.is_empty());
+		assert!(view(&storage).passive.contains("n2"));
+		assert!(crate::plumtree::broadcast(&mut storage, "n1", 1).is_empty());
+	}
+
+	#[test]
+	fn passive_peers_are_promoted_to_refill_the_active_view() {
+		let mut storage = overlay(5);
+		receive_shuffle_ok(&mut storage, "n1", vec!["n2".to_string()]);
+
+		let outgoing = maintain(&mut storage, Instant::now());
+		let asked: Vec<&Message> = outgoing
+			.iter()
+			.filter(|m| matches!(m.body, Body::Neighbor { .. }))
+			.collect();
+
+		assert_eq!(asked.len(), 1);
+		assert_eq!(asked[0].dest, "n2");
+		// With nobody else to talk to the request cannot be refused
+		assert!(matches!(
+			asked[0].body,
+			Body::Neighbor {
+				high_priority: true
+			}
+		));
+
+		receive_neighbor_ok(&mut storage, "n1", "n2", true);
+		assert!(view(&storage).active.contains("n2"));
+		assert!(!view(&storage).passive.contains("n2"));
+	}
+
+	#[test]
+	fn neighbor_ok_we_did_not_ask_for_is_ignored() {
+		let mut storage = overlay(5);
+
+		receive_neighbor_ok(&mut storage, "n1", "n2", true);
+
+		assert!(view(&storage).active.is_empty());
+	}
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use serde::{Deserialize, Serialize};
 
@@ -51,11 +51,169 @@ pub enum Body {
 		in_reply_to: u64,
 	},
 	Gossip {
+		seq: u64,
+		/// Every batch up to this one was acknowledged by the receiver.
+		#[serde(default)]
+		base: u64,
 		messages: Vec<u64>,
 	},
 	GossipOk {
+		/// Every batch up to and including this sequence number arrived.
+		ack: u64,
+		/// Batches above `ack` which arrived out of order.
+		sack: Vec<u64>,
+	},
+	Push {
+		rumors: Vec<Rumor>,
+	},
+	PushOk {
+		known: Vec<u64>,
+	},
+	Pull {
 		messages: Vec<u64>,
 	},
+	PullOk {
+		rumors: Vec<Rumor>,
+	},
+	EagerPush {
+		message: u64,
+		round: u32,
+	},
+	#[serde(rename = "ihave")]
+	IHave {
+		rumors: Vec<Rumor>,
+	},
+	Graft {
+		messages: Vec<u64>,
+	},
+	Prune {},
+	Join {},
+	ForwardJoin {
+		node: String,
+		ttl: u32,
+	},
+	Neighbor {
+		high_priority: bool,
+	},
+	NeighborOk {
+		accepted: bool,
+	},
+	Disconnect {},
+	Shuffle {
+		origin: String,
+		nodes: Vec<String>,
+		ttl: u32,
+	},
+	ShuffleOk {
+		nodes: Vec<String>,
+	},
+	Ping {},
+	SnapshotOffer {
+		/// Values the peer is missing, as far as the sender knows.
+		values: u64,
+	},
+	SnapshotRequest {
+		/// The snapshot the `chunks` belong to, `None` asks for a new one.
+		version: Option<u64>,
+		chunks: Vec<u64>,
+	},
+	SnapshotChunk {
+		version: u64,
+		index: u64,
+		total: u64,
+		/// FNV-1a hash of `messages`.
+		checksum: u64,
+		messages: Vec<u64>,
+	},
+	SnapshotOk {
+		version: u64,
+	},
+	CausalPush {
+		messages: Vec<Stamped>,
+	},
+	CausalSync {
+		clock: BTreeMap<String, u64>,
+	},
+	Sequence {
+		message: u64,
+	},
+	Sequenced {
+		epoch: u64,
+		/// Position of the first value in the log.
+		start: u64,
+		messages: Vec<u64>,
+		/// Length of the prefix a majority stored.
+		delivered: u64,
+	},
+	SequencedOk {
+		epoch: u64,
+		/// Number of values the follower stored.
+		length: u64,
+	},
+	SequenceSync {
+		/// Position of the first value the follower is missing.
+		from: u64,
+	},
+	SequenceHeartbeat {
+		epoch: u64,
+		length: u64,
+		delivered: u64,
+	},
+	Takeover {
+		epoch: u64,
+	},
+	TakeoverOk {
+		epoch: u64,
+		/// Epoch whose sequencer numbered `messages`.
+		stored_epoch: u64,
+		delivered: u64,
+		messages: Vec<u64>,
+	},
+}
+
+/// A broadcast value with the vector clock of its origin when it was
+/// broadcast, for causal delivery.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Stamped {
+	pub message: u64,
+	pub origin: String,
+	pub clock: BTreeMap<String, u64>,
+}
+
+/// A broadcast value together with the number of hops it travelled so far.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Rumor {
+	pub message: u64,
+	pub hops: u32,
+}
+
+impl Body {
+	/// Gossip is repeated in later rounds or repaired by other peers, and
+	/// stalled snapshot transfers are retried, so it goes first under overload.
+	pub(crate) fn is_gossip(&self) -> bool {
+		matches!(
+			self,
+			Body::Gossip { .. }
+				| Body::Push { .. }
+				| Body::Pull { .. }
+				| Body::PullOk { .. }
+				| Body::IHave { .. }
+				| Body::SnapshotOffer { .. }
+				| Body::SnapshotChunk { .. }
+				| Body::CausalSync { .. }
+				| Body::SequenceHeartbeat { .. }
+		)
+	}
+
+	/// The `msg_id` of a client request, which can be turned away under overload.
+	pub(crate) fn request_id(&self) -> Option<u64> {
+		match self {
+			Body::Broadcast { msg_id, .. }
+			| Body::Read { msg_id }
+			| Body::Topology { msg_id, .. } => Some(*msg_id),
+			_ => None,
+		}
+	}
 }
 
 impl Message {
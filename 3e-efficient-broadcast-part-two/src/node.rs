@@ -1,4 +1,4 @@
-use std::{collections::HashSet, io::Write};
+use std::collections::HashSet;
 
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncBufReadExt, BufReader};
@@ -16,9 +16,10 @@ pub(crate) struct Node {
 }
 
 impl Node {
-	pub(crate) async fn bootstrap() -> Node {
+	/// Waits for `init`, returns the node and the `init_ok` to answer with once
+	/// it is ready to serve requests.
+	pub(crate) async fn bootstrap() -> (Node, Message) {
 		let stdin = tokio::io::stdin();
-		let mut stdout = std::io::stdout();
 
 		let mut reader = BufReader::new(stdin);
 		let mut buf = String::new();
@@ -27,39 +28,30 @@ impl Node {
 		let message = Message::parse_message(buf.clone());
 		let node = Node::init(message.clone());
 
-		match message.body {
-			Body::Init {
-				msg_id, node_id, ..
-			} => {
-				let response = Message {
-					src: node_id,
-					dest: message.src.clone(),
-					body: Body::InitOk {
-						in_reply_to: msg_id,
-					},
-				};
+		let Body::Init { msg_id, .. } = message.body else {
+			unreachable!("Node::init only accepts init");
+		};
 
-				let message = Message::format_message(response);
-				writeln!(stdout, "{}", message).unwrap();
-				stdout.flush().unwrap();
-			}
-			_ => (),
-		}
+		let init_ok = Message {
+			src: node.id.clone(),
+			dest: message.src,
+			body: Body::InitOk {
+				in_reply_to: msg_id,
+			},
+		};
 
-		node
+		(node, init_ok)
 	}
 
 	pub(crate) fn init(message: Message) -> Node {
 		match message.body {
 			Body::Init {
 				node_id, node_ids, ..
-			} => {
-				return Node {
-					id: node_id.clone(),
-					availble_nodes: node_ids.clone(),
-					network: Node::init_network(node_ids),
-				}
-			}
+			} => Node {
+				id: node_id.clone(),
+				availble_nodes: node_ids.clone(),
+				network: Node::init_network(node_ids),
+			},
 			_ => panic!("Invalid message type"),
 		}
 	}
@@ -74,3 +66,17 @@ impl Node {
 		self.network.0.clone().into_iter().collect::<Vec<_>>()
 	}
 }
+
+#[cfg(test)]
+impl Node {
+	/// Node `id` in a cluster of `nodes`, as `init` would set it up.
+	pub(crate) fn for_test(id: &str, nodes: &[&str]) -> Node {
+		let nodes: Vec<String> = nodes.iter().map(|n| n.to_string()).collect();
+
+		Node {
+			id: id.to_string(),
+			availble_nodes: nodes.clone(),
+			network: Node::init_network(nodes),
+		}
+	}
+}
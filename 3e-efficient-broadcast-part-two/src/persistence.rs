@@ -0,0 +1,171 @@
+use std::{
+	collections::HashMap,
+	fs::{self, File, OpenOptions},
+	io::{self, BufRead, BufReader, BufWriter, Write},
+	path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{message::Stamped, storage::Storage};
+
+const SNAPSHOT: &str = "snapshot.json";
+const WAL: &str = "wal.jsonl";
+
+/// One change to the broadcast state. Replaying an entry twice has the same
+/// effect as replaying it once, so a crash between writing a snapshot and
+/// truncating the log loses nothing.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum Entry {
+	/// A value we learned, and the hops it travelled before reaching us.
+	Learned { message: u64, hops: u32 },
+	/// Values a peer gossiped to us.
+	Received { peer: String, messages: Vec<u64> },
+	/// Values a peer acknowledged.
+	Acknowledged { peer: String, messages: Vec<u64> },
+	Topology {
+		topology: HashMap<String, Vec<String>>,
+	},
+	/// A broadcast delivered in causal order.
+	Delivered { message: Stamped },
+	/// Values numbered by the sequencer of `epoch`, from position `start`.
+	Numbered {
+		epoch: u64,
+		start: u64,
+		messages: Vec<u64>,
+	},
+	/// Length of the delivered prefix of the totally ordered log.
+	Ordered { length: u64 },
+	/// The total order epoch we moved to.
+	Epoch { epoch: u64 },
+}
+
+/// Write-ahead log of a node's `Storage`, compacted into a snapshot every
+/// `snapshot_every` entries. The log is flushed to the OS before any reply
+/// leaves the node, which survives the node being killed; snapshots are
+/// synced to disk.
+#[derive(Debug)]
+pub(crate) struct Wal {
+	dir: PathBuf,
+	log: BufWriter<File>,
+	entries: u64,
+	snapshot_every: u64,
+}
+
+/// Restores the storage from the snapshot and log in `dir`, creating it on the
+/// first start, and opens the log for appending.
+pub(crate) fn recover(dir: &Path, snapshot_every: u64) -> io::Result<(Storage, Wal)> {
+	fs::create_dir_all(dir)?;
+
+	let mut storage = match File::open(dir.join(SNAPSHOT)) {
+		Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+		Err(e) if e.kind() == io::ErrorKind::NotFound => Storage::default(),
+		Err(e) => return Err(e),
+	};
+
+	let mut entries = 0;
+	let mut valid = 0;
+
+	if let Ok(file) = File::open(dir.join(WAL)) {
+		for line in BufReader::new(file).lines() {
+			let line = line?;
+
+			// Only the last entry can be torn, by a crash in the middle of a write
+			let Ok(entry) = serde_json::from_str::<Entry>(&line) else {
+				warn!("Dropping torn log entry {:?}", line);
+				break;
+			};
+
+			replay(&mut storage, entry);
+			entries += 1;
+			valid += line.len() as u64 + 1;
+		}
+	}
+
+	let log = OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(dir.join(WAL))?;
+	log.set_len(valid)?;
+
+	info!(
+		messages = storage.messages.0.len(),
+		entries,
+		"Recovered from {}",
+		dir.display()
+	);
+
+	let wal = Wal {
+		dir: dir.to_path_buf(),
+		log: BufWriter::new(log),
+		entries,
+		snapshot_every,
+	};
+
+	Ok((storage, wal))
+}
+
+fn replay(storage: &mut Storage, entry: Entry) {
+	match entry {
+		Entry::Learned { message, hops } => {
+			storage.add_rumor(message, hops);
+		}
+		Entry::Received { peer, messages } => {
+			storage.add_messages(messages, peer);
+		}
+		Entry::Acknowledged { peer, messages } => storage.add_to_sent_messages(messages, peer),
+		Entry::Topology { topology } => storage.init_topology(topology),
+		Entry::Delivered { message } => storage.deliver(message),
+		Entry::Numbered {
+			epoch,
+			start,
+			messages,
+		} => storage.store(epoch, start as usize, messages),
+		Entry::Ordered { length } => {
+			storage.deliver_numbered(length as usize);
+		}
+		Entry::Epoch { epoch } => storage.enter_epoch(epoch),
+	}
+}
+
+impl Wal {
+	/// Appends the changes the storage journaled since the last commit, and
+	/// replaces the log with a snapshot once it grew long enough.
+	pub(crate) fn commit(&mut self, storage: &mut Storage) -> io::Result<()> {
+		let journal = storage.take_journal();
+
+		if journal.is_empty() {
+			return Ok(());
+		}
+
+		for entry in &journal {
+			serde_json::to_writer(&mut self.log, entry)?;
+			self.log.write_all(b"\n")?;
+		}
+
+		self.log.flush()?;
+		self.entries += journal.len() as u64;
+
+		if self.entries >= self.snapshot_every {
+			self.snapshot(storage)?;
+		}
+
+		Ok(())
+	}
+
+	fn snapshot(&mut self, storage: &Storage) -> io::Result<()> {
+		let partial = self.dir.join(format!("{}.tmp", SNAPSHOT));
+
+		let mut file = BufWriter::new(File::create(&partial)?);
+		serde_json::to_writer(&mut file, storage)?;
+		file.into_inner()?.sync_all()?;
+
+		fs::rename(&partial, self.dir.join(SNAPSHOT))?;
+		self.log.get_ref().set_len(0)?;
+		self.entries = 0;
+
+		Ok(())
+	}
+}
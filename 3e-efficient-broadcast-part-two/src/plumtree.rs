@@ -0,0 +1,384 @@
+use std::{
+	collections::{HashMap, HashSet, VecDeque},
+	time::{Duration, Instant},
+};
+
+use rand::{prelude::*, rngs::StdRng};
+
+use crate::{
+	message::{Body, Message, Rumor},
+	storage::Storage,
+};
+
+/// How long we wait for a value announced through `ihave` before grafting the announcer.
+const GRAFT_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Every how many rounds we pull from a random peer to recover values lost during partitions.
+const ANTI_ENTROPY_ROUNDS: u64 = 10;
+
+/// Plumtree state: eager push along a spanning tree, lazy `ihave` announcements to everyone else.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Plumtree {
+	eager_push_peers: HashSet<String>,
+	lazy_push_peers: HashSet<String>,
+	/// Announcements to send to each lazy peer with the next round.
+	lazy_queue: HashMap<String, Vec<Rumor>>,
+	/// Values announced to us which did not arrive yet.
+	missing: HashMap<u64, Missing>,
+	rounds: u64,
+}
+
+#[derive(Clone, Debug)]
+struct Missing {
+	announcements: VecDeque<(String, u32)>,
+	deadline: Instant,
+}
+
+impl Plumtree {
+	/// Starts with every neighbour in the eager set, duplicates prune it down to a tree.
+	pub(crate) fn init(&mut self, neighbours: Vec<String>) {
+		self.eager_push_peers = neighbours.into_iter().collect();
+		self.lazy_push_peers.clear();
+	}
+
+	/// Follows neighbours going up and down in the membership layer.
+	pub(crate) fn sync(&mut self, neighbours: Vec<String>) {
+		let neighbours: HashSet<String> = neighbours.into_iter().collect();
+
+		self.eager_push_peers.retain(|p| neighbours.contains(p));
+		self.lazy_push_peers.retain(|p| neighbours.contains(p));
+
+		for peer in neighbours {
+			if !self.lazy_push_peers.contains(&peer) {
+				self.eager_push_peers.insert(peer);
+			}
+		}
+	}
+
+	/// Stops waiting for a value, however it reached us.
+	pub(crate) fn received(&mut self, message: u64) {
+		self.missing.remove(&message);
+	}
+
+	fn add_eager(&mut self, peer: &str) {
+		self.lazy_push_peers.remove(peer);
+		self.eager_push_peers.insert(peer.to_string());
+	}
+
+	fn add_lazy(&mut self, peer: &str) {
+		self.eager_push_peers.remove(peer);
+		self.lazy_push_peers.insert(peer.to_string());
+	}
+
+	fn push(&mut self, id: &str, message: u64, round: u32, from: &str) -> Vec<Message> {
+		for peer in self.lazy_push_peers.iter().filter(|p| *p != from) {
+			self.lazy_queue
+				.entry(peer.clone())
+				.or_default()
+				.push(Rumor {
+					message,
+					hops: round + 1,
+				});
+		}
+
+		self.eager_push_peers
+			.iter()
+			.filter(|p| *p != from)
+			.map(|peer| Message {
+				src: id.to_string(),
+				dest: peer.clone(),
+				body: Body::EagerPush {
+					message,
+					round: round + 1,
+				},
+			})
+			.collect()
+	}
+}
+
+/// Starts spreading a value we received from a client.
+pub(crate) fn broadcast(storage: &mut Storage, id: &str, message: u64) -> Vec<Message> {
+	storage.plumtree.push(id, message, 0, id)
+}
+
+pub(crate) fn receive_eager_push(
+	storage: &mut Storage,
+	id: &str,
+	from: &str,
+	message: u64,
+	round: u32,
+) -> Vec<Message> {
+	if !storage.add_rumor(message, round) {
+		// Someone else is already pushing this value to us, leave the tree edge to them
+		storage.plumtree.add_lazy(from);
+
+		return vec![Message {
+			src: id.to_string(),
+			dest: from.to_string(),
+			body: Body::Prune {},
+		}];
+	}
+
+	storage.plumtree.add_eager(from);
+	storage.plumtree.push(id, message, round, from)
+}
+
+pub(crate) fn receive_ihave(storage: &mut Storage, from: &str, rumors: Vec<Rumor>) {
+	receive_ihave_at(storage, from, rumors, Instant::now())
+}
+
+fn receive_ihave_at(storage: &mut Storage, from: &str, rumors: Vec<Rumor>, now: Instant) {
+	for Rumor { message, hops } in rumors {
+		if storage.messages.0.contains(&message) {
+			continue;
+		}
+
+		storage
+			.plumtree
+			.missing
+			.entry(message)
+			.or_insert_with(|| Missing {
+				announcements: VecDeque::new(),
+				deadline: now + GRAFT_TIMEOUT,
+			})
+			.announcements
+			.push_back((from.to_string(), hops));
+	}
+}
+
+pub(crate) fn receive_graft(
+	storage: &mut Storage,
+	id: &str,
+	from: &str,
+	messages: Vec<u64>,
+) -> Vec<Message> {
+	storage.plumtree.add_eager(from);
+
+	messages
+		.into_iter()
+		.filter(|m| storage.messages.0.contains(m))
+		.map(|message| Message {
+			src: id.to_string(),
+			dest: from.to_string(),
+			body: Body::EagerPush {
+				message,
+				round: storage.hops.get(&message).cloned().unwrap_or_default() + 1,
+			},
+		})
+		.collect()
+}
+
+pub(crate) fn receive_prune(storage: &mut Storage, from: &str) {
+	storage.plumtree.add_lazy(from);
+}
+
+/// Flushes the queued announcements, grafts peers whose announced values did
+/// not arrive in time and regularly pulls from a random peer.
+pub(crate) fn tick(storage: &mut Storage, id: &str) -> Vec<Message> {
+	tick_at(storage, id, Instant::now())
+}
+
+fn tick_at(storage: &mut Storage, id: &str, now: Instant) -> Vec<Message> {
+	let plumtree = &mut storage.plumtree;
+
+	let mut outgoing: Vec<Message> = plumtree
+		.lazy_queue
+		.drain()
+		.map(|(peer, rumors)| Message {
+			src: id.to_string(),
+			dest: peer,
+			body: Body::IHave { rumors },
+		})
+		.collect();
+
+	let mut grafts: HashMap<String, Vec<u64>> = HashMap::new();
+
+	for (message, missing) in plumtree.missing.iter_mut() {
+		if missing.deadline > now {
+			continue;
+		}
+
+		if let Some((peer, _)) = missing.announcements.pop_front() {
+			grafts.entry(peer).or_default().push(*message);
+			missing.deadline = now + GRAFT_TIMEOUT;
+		}
+	}
+
+	plumtree
+		.missing
+		.retain(|_, m| !m.announcements.is_empty() || m.deadline > now);
+
+	for (peer, messages) in grafts {
+		plumtree.add_eager(&peer);
+
+		outgoing.push(Message {
+			src: id.to_string(),
+			dest: peer,
+			body: Body::Graft { messages },
+		});
+	}
+
+	plumtree.rounds += 1;
+
+	if plumtree.rounds.is_multiple_of(ANTI_ENTROPY_ROUNDS) {
+		let mut rng = StdRng::from_entropy();
+
+		let peer = plumtree
+			.eager_push_peers
+			.iter()
+			.chain(plumtree.lazy_push_peers.iter())
+			.choose(&mut rng)
+			.cloned();
+
+		if let Some(peer) = peer {
+			outgoing.push(Message {
+				src: id.to_string(),
+				dest: peer,
+				body: Body::Pull {
+					messages: storage.get_messages(),
+				},
+			});
+		}
+	}
+
+	outgoing
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::gossip;
+
+	fn tree(eager: &[&str]) -> Storage {
+		let mut storage = Storage::default();
+		storage
+			.plumtree
+			.init(eager.iter().map(|p| p.to_string()).collect());
+		storage
+	}
+
+	fn dests(outgoing: &[Message]) -> Vec<&str> {
+		let mut dests: Vec<&str> = outgoing.iter().map(|m| m.dest.as_str()).collect();
+		dests.sort();
+		dests
+	}
+
+	fn ihave(message: u64) -> Vec<Rumor> {
+		vec![Rumor { message, hops: 1 }]
+	}
+
+	#[test]
+	fn eager_push_goes_to_every_eager_peer_but_the_sender() {
+		let mut storage = tree(&["n2", "n3", "n4"]);
+
+		let outgoing = receive_eager_push(&mut storage, "n1", "n2", 1, 1);
+
+		assert_eq!(dests(&outgoing), ["n3", "n4"]);
+		assert!(outgoing.iter().all(|m| matches!(
+			m.body,
+			Body::EagerPush {
+				message: 1,
+				round: 2
+			}
+		)));
+	}
+
+	#[test]
+	fn duplicate_eager_push_prunes_the_edge_to_lazy() {
+		let mut storage = tree(&["n2", "n3"]);
+		receive_eager_push(&mut storage, "n1", "n2", 1, 1);
+
+		let outgoing = receive_eager_push(&mut storage, "n1", "n3", 1, 1);
+		assert_eq!(dests(&outgoing), ["n3"]);
+		assert!(matches!(outgoing[0].body, Body::Prune {}));
+
+		// From now on n3 only hears about values through ihave
+		let outgoing = broadcast(&mut storage, "n1", 2);
+		assert_eq!(dests(&outgoing), ["n2"]);
+
+		let outgoing = tick(&mut storage, "n1");
+		assert_eq!(dests(&outgoing), ["n3"]);
+		assert!(matches!(outgoing[0].body, Body::IHave { ref rumors } if rumors[0].message == 2));
+	}
+
+	#[test]
+	fn prune_moves_the_peer_to_lazy() {
+		let mut storage = tree(&["n2", "n3"]);
+
+		receive_prune(&mut storage, "n2");
+
+		assert_eq!(dests(&broadcast(&mut storage, "n1", 1)), ["n3"]);
+		assert_eq!(dests(&tick(&mut storage, "n1")), ["n2"]);
+	}
+
+	#[test]
+	fn missing_value_grafts_the_announcer_after_the_timeout() {
+		let mut storage = tree(&["n2"]);
+		receive_prune(&mut storage, "n3");
+		let now = Instant::now();
+
+		receive_ihave_at(&mut storage, "n3", ihave(5), now);
+		assert!(tick_at(&mut storage, "n1", now).is_empty());
+
+		let outgoing = tick_at(&mut storage, "n1", now + GRAFT_TIMEOUT);
+		assert_eq!(dests(&outgoing), ["n3"]);
+		assert!(matches!(outgoing[0].body, Body::Graft { ref messages } if messages == &[5]));
+
+		// The grafted peer is back on the tree
+		assert_eq!(dests(&broadcast(&mut storage, "n1", 6)), ["n2", "n3"]);
+	}
+
+	#[test]
+	fn graft_tries_the_next_announcer_when_the_first_does_not_deliver() {
+		let mut storage = tree(&[]);
+		let now = Instant::now();
+
+		receive_ihave_at(&mut storage, "n2", ihave(5), now);
+		receive_ihave_at(&mut storage, "n3", ihave(5), now);
+
+		let first = tick_at(&mut storage, "n1", now + GRAFT_TIMEOUT);
+		let second = tick_at(&mut storage, "n1", now + GRAFT_TIMEOUT * 2);
+
+		assert_eq!(dests(&first), ["n2"]);
+		assert_eq!(dests(&second), ["n3"]);
+	}
+
+	#[test]
+	fn value_arriving_by_eager_push_cancels_the_graft() {
+		let mut storage = tree(&["n2"]);
+		let now = Instant::now();
+
+		receive_ihave_at(&mut storage, "n3", ihave(5), now);
+		receive_eager_push(&mut storage, "n1", "n2", 5, 1);
+
+		assert!(tick_at(&mut storage, "n1", now + GRAFT_TIMEOUT).is_empty());
+	}
+
+	#[test]
+	fn value_arriving_by_anti_entropy_cancels_the_graft() {
+		let mut storage = tree(&["n2"]);
+		let now = Instant::now();
+
+		receive_ihave_at(&mut storage, "n3", ihave(5), now);
+		gossip::receive_rumors(&mut storage, ihave(5), "n2");
+
+		assert!(tick_at(&mut storage, "n1", now + GRAFT_TIMEOUT).is_empty());
+	}
+
+	#[test]
+	fn graft_is_answered_with_the_values_we_have() {
+		let mut storage = tree(&[]);
+		storage.add_message(5);
+
+		let outgoing = receive_graft(&mut storage, "n1", "n2", vec![5, 6]);
+
+		assert_eq!(outgoing.len(), 1);
+		assert!(matches!(
+			outgoing[0].body,
+			Body::EagerPush {
+				message: 5,
+				round: 1
+			}
+		));
+		assert_eq!(dests(&broadcast(&mut storage, "n1", 7)), ["n2"]);
+	}
+}
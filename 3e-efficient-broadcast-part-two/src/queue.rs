@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use clap::ValueEnum;
+use tokio::sync::mpsc::{error::TrySendError, Sender, WeakSender};
+use tracing::{debug, info};
+
+use crate::message::{Body, Message};
+
+/// Maelstrom's `temporarily-unavailable` error, clients may retry the request.
+const TEMPORARILY_UNAVAILABLE: u64 = 11;
+
+/// What the reader does with a message when the handlers' queue is full.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Overload {
+	/// Wait for room, stdin backs up until the handlers catch up.
+	Block,
+	/// Drop gossip, it is repeated in later rounds, and wait for room for
+	/// everything else.
+	DropGossip,
+	/// Drop gossip and answer client requests with `temporarily-unavailable`.
+	Reject,
+}
+
+/// Messages the overload policy turned away.
+#[derive(Debug, Default)]
+pub(crate) struct Shed {
+	dropped: AtomicU64,
+	rejected: AtomicU64,
+}
+
+/// Queues `message` for the handlers, applying `overload` when the queue is
+/// full. Returns `false` once the handlers are gone.
+pub(crate) async fn admit(
+	message: Message,
+	input: &Sender<Message>,
+	writer: &Sender<Message>,
+	overload: Overload,
+	shed: &Shed,
+) -> bool {
+	let message = match input.try_send(message) {
+		Ok(()) => return true,
+		Err(TrySendError::Closed(_)) => return false,
+		Err(TrySendError::Full(message)) => message,
+	};
+
+	match (overload, message.body.request_id()) {
+		(Overload::DropGossip | Overload::Reject, _) if message.body.is_gossip() => {
+			shed.dropped.fetch_add(1, Ordering::Relaxed);
+			debug!(src = %message.src, "Queue full, dropped gossip");
+			true
+		}
+		(Overload::Reject, Some(msg_id)) => {
+			shed.rejected.fetch_add(1, Ordering::Relaxed);
+			debug!(src = %message.src, msg_id, "Queue full, rejected request");
+
+			let response = Message {
+				src: message.dest,
+				dest: message.src,
+				body: Body::Error {
+					in_reply_to: msg_id,
+					code: TEMPORARILY_UNAVAILABLE,
+					text: "node is overloaded".to_string(),
+				},
+			};
+
+			// Without a writer there is nobody to answer, but handlers may still run
+			let _ = writer.send(response).await;
+			true
+		}
+		_ => input.send(message).await.is_ok(),
+	}
+}
+
+/// Logs how many messages wait on each queue and how many were shed so far.
+/// Holds the queues weakly, so they still close once their last user is done.
+pub(crate) fn report(input: &WeakSender<Message>, writer: &WeakSender<Message>, shed: &Shed) {
+	info!(
+		input = depth(input),
+		output = depth(writer),
+		dropped = shed.dropped.load(Ordering::Relaxed),
+		rejected = shed.rejected.load(Ordering::Relaxed),
+		"Queue depth"
+	);
+}
+
+fn depth<T>(queue: &WeakSender<T>) -> usize {
+	queue
+		.upgrade()
+		.map_or(0, |queue| queue.max_capacity() - queue.capacity())
+}
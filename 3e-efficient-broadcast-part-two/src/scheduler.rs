@@ -0,0 +1,200 @@
+use std::{future::Future, time::Duration};
+
+use clap::ValueEnum;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+	sync::watch,
+	time::{self, Instant, MissedTickBehavior},
+};
+
+/// What the scheduler does with rounds it could not start on time.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MissedTick {
+	/// Run the missed rounds back to back until caught up.
+	Burst,
+	/// Start the next round a full period after the late one.
+	Delay,
+	/// Drop the missed rounds and stay on the original schedule.
+	Skip,
+}
+
+impl From<MissedTick> for MissedTickBehavior {
+	fn from(missed: MissedTick) -> MissedTickBehavior {
+		match missed {
+			MissedTick::Burst => MissedTickBehavior::Burst,
+			MissedTick::Delay => MissedTickBehavior::Delay,
+			MissedTick::Skip => MissedTickBehavior::Skip,
+		}
+	}
+}
+
+/// Runs `round` every `period`, each one delayed by a random share of `jitter`
+/// so nodes started together do not gossip in lockstep, until `shutdown` fires
+/// or its sender goes away.
+pub(crate) async fn run_every<F, Fut>(
+	period: Duration,
+	jitter: Duration,
+	missed: MissedTick,
+	mut shutdown: watch::Receiver<()>,
+	mut round: F,
+) where
+	F: FnMut() -> Fut,
+	Fut: Future<Output = ()>,
+{
+	let mut ticker = time::interval_at(Instant::now() + period, period);
+	ticker.set_missed_tick_behavior(missed.into());
+
+	let mut rng = StdRng::from_entropy();
+
+	loop {
+		tokio::select! {
+			_ = ticker.tick() => {}
+			_ = shutdown.changed() => return,
+		}
+
+		if !jitter.is_zero() {
+			let delay = rng.gen_range(Duration::ZERO..=jitter);
+
+			tokio::select! {
+				_ = time::sleep(delay) => {}
+				_ = shutdown.changed() => return,
+			}
+		}
+
+		round().await;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{cell::RefCell, rc::Rc};
+
+	use super::*;
+
+	const PERIOD: Duration = Duration::from_millis(100);
+
+	/// Milliseconds after the start at which each round began, within `run_for`.
+	/// The first round takes `first_round` to finish, the others no time.
+	async fn starts(
+		jitter: Duration,
+		missed: MissedTick,
+		first_round: Duration,
+		run_for: Duration,
+	) -> Vec<u128> {
+		let start = Instant::now();
+		let starts = Rc::new(RefCell::new(vec![]));
+		let (_shutdown_tx, shutdown) = watch::channel(());
+
+		let rounds = run_every(PERIOD, jitter, missed, shutdown, || {
+			let starts = starts.clone();
+			async move {
+				starts.borrow_mut().push(start.elapsed().as_millis());
+				if starts.borrow().len() == 1 {
+					time::sleep(first_round).await;
+				}
+			}
+		});
+		let _ = time::timeout(run_for, rounds).await;
+
+		starts.take()
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn rounds_start_every_period() {
+		let starts = starts(
+			Duration::ZERO,
+			MissedTick::Skip,
+			Duration::ZERO,
+			Duration::from_millis(450),
+		)
+		.await;
+
+		assert_eq!(starts, [100, 200, 300, 400]);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn jitter_delays_each_round_by_at_most_its_bound() {
+		let jitter = Duration::from_millis(30);
+		let starts = starts(
+			jitter,
+			MissedTick::Skip,
+			Duration::ZERO,
+			Duration::from_millis(10_050),
+		)
+		.await;
+
+		assert_eq!(starts.len(), 100);
+		for (i, start) in starts.iter().enumerate() {
+			let tick = (i as u128 + 1) * PERIOD.as_millis();
+			assert!(
+				(tick..=tick + jitter.as_millis()).contains(start),
+				"Round {} started at {}ms",
+				i,
+				start
+			);
+		}
+		assert!(
+			starts.iter().any(|start| start % PERIOD.as_millis() != 0),
+			"No round was delayed"
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn missed_ticks_follow_the_configured_behaviour() {
+		let slow = Duration::from_millis(250);
+		let run_for = Duration::from_millis(520);
+
+		assert_eq!(
+			starts(Duration::ZERO, MissedTick::Burst, slow, run_for).await,
+			[100, 350, 350, 400, 500]
+		);
+		assert_eq!(
+			starts(Duration::ZERO, MissedTick::Delay, slow, run_for).await,
+			[100, 350, 450]
+		);
+		assert_eq!(
+			starts(Duration::ZERO, MissedTick::Skip, slow, run_for).await,
+			[100, 350, 400, 500]
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn shutdown_stops_the_rounds() {
+		let rounds = Rc::new(RefCell::new(0));
+		let (shutdown_tx, shutdown) = watch::channel(());
+
+		let run = run_every(PERIOD, PERIOD, MissedTick::Skip, shutdown, || {
+			*rounds.borrow_mut() += 1;
+			async {}
+		});
+		let stop = async {
+			time::sleep(Duration::from_millis(250)).await;
+			shutdown_tx.send(()).unwrap();
+		};
+
+		time::timeout(Duration::from_secs(1), async { tokio::join!(run, stop) })
+			.await
+			.expect("Rounds went on after shutdown");
+		assert!((1..=2).contains(&*rounds.borrow()));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn dropping_the_sender_stops_the_rounds() {
+		let (shutdown_tx, shutdown) = watch::channel(());
+		drop(shutdown_tx);
+
+		let run = run_every(
+			PERIOD,
+			Duration::ZERO,
+			MissedTick::Skip,
+			shutdown,
+			|| async {
+				panic!("A round ran without a shutdown sender");
+			},
+		);
+
+		time::timeout(Duration::from_secs(1), run)
+			.await
+			.expect("Rounds went on without a shutdown sender");
+	}
+}
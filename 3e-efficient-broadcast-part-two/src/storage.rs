@@ -1,6 +1,23 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+	collections::{HashMap, HashSet},
+	process, thread,
+};
 
+use outbox::Outbox;
 use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+
+use crate::{
+	causal::Causal,
+	membership::HyParView,
+	message::{Rumor, Stamped},
+	node::Node,
+	persistence::{Entry, Wal},
+	plumtree::Plumtree,
+	total::Total,
+	transfer::Transfers,
+};
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub(crate) struct Messages(pub(crate) HashSet<u64>);
@@ -9,78 +26,331 @@ pub(crate) struct Messages(pub(crate) HashSet<u64>);
 pub(crate) struct Storage {
 	pub(crate) messages: Messages,
 	pub(crate) received_gossip_messages: HashMap<String, Messages>,
+	/// Values each peer acknowledged.
 	pub(crate) sent_messages: HashMap<String, Messages>,
-	pub(crate) retry: HashMap<String, u8>,
+	/// Batches in flight are not persisted, after a restart the gossip rounds
+	/// offer every value a peer did not acknowledge again.
+	#[serde(skip)]
+	pub(crate) outbox: Outbox,
+	/// Hops each value travelled before we first received it.
+	pub(crate) hops: HashMap<u64, u32>,
+	/// Rumors still being spread, with the number of redundant receipts seen so far.
+	pub(crate) hot_rumors: HashMap<u64, u8>,
+	pub(crate) topology: HashMap<String, Vec<String>>,
+	/// Values in causal order, when causal delivery is enabled.
+	#[serde(default)]
+	pub(crate) causal: Causal,
+	/// Values in the agreed order, when total order delivery is enabled.
+	#[serde(default)]
+	pub(crate) total: Total,
+	#[serde(skip)]
+	pub(crate) plumtree: Plumtree,
+	/// Partial view of the cluster, when HyParView membership is enabled.
+	#[serde(skip)]
+	pub(crate) membership: Option<HyParView>,
+	/// Snapshot transfers in progress, a restarted node is offered a new one.
+	#[serde(skip)]
+	pub(crate) transfers: Transfers,
+	/// Changes not written to the log yet, `None` unless persistence is enabled.
+	#[serde(skip)]
+	journal: Option<Vec<Entry>>,
 }
 
 impl Storage {
-	pub(crate) fn add_message(&mut self, message: u64) {
-		if !self.messages.0.contains(&message) {
-			self.messages.0.insert(message);
+	/// Stores a value broadcast by a client, returns `false` if it was already known.
+	pub(crate) fn add_message(&mut self, message: u64) -> bool {
+		if !self.messages.0.insert(message) {
+			return false;
 		}
+
+		self.hops.insert(message, 0);
+		self.hot_rumors.insert(message, 0);
+		self.plumtree.received(message);
+		self.record(|| Entry::Learned { message, hops: 0 });
+		true
 	}
 
-	pub(crate) fn add_messages(&mut self, messages: Vec<u64>, node: String) {
-		if self.received_gossip_messages.contains_key(&node) {
-			self.received_gossip_messages
-				.get_mut(&node)
-				.unwrap()
-				.0
-				.extend(messages.iter());
-		} else {
-			let mut v = Messages::default();
-			v.0.extend(messages.iter());
-			self.received_gossip_messages.insert(node, v);
+	/// Stores gossiped values and returns the ones we did not know about yet.
+	pub(crate) fn add_messages(&mut self, messages: Vec<u64>, node: String) -> Vec<u64> {
+		self.record(|| Entry::Received {
+			peer: node.clone(),
+			messages: messages.clone(),
+		});
+
+		self.received_gossip_messages
+			.entry(node)
+			.or_default()
+			.0
+			.extend(messages.iter());
+
+		let new: Vec<u64> = messages
+			.into_iter()
+			.filter(|m| self.messages.0.insert(*m))
+			.collect();
+
+		for m in &new {
+			self.plumtree.received(*m);
 		}
 
-		for m in messages {
-			if !self.messages.0.contains(&m) {
-				self.messages.0.insert(m);
-			}
+		new
+	}
+
+	/// Stores a value received through epidemic gossip, returns `false` if it was already known.
+	pub(crate) fn add_rumor(&mut self, message: u64, hops: u32) -> bool {
+		if !self.messages.0.insert(message) {
+			return false;
 		}
+
+		self.hops.insert(message, hops);
+		self.hot_rumors.insert(message, 0);
+		self.plumtree.received(message);
+		self.record(|| Entry::Learned { message, hops });
+		true
 	}
 
 	pub(crate) fn get_messages(&mut self) -> Vec<u64> {
 		self.messages.0.iter().cloned().collect()
 	}
 
-	pub(crate) fn get_new_messages_for_neighbour(&self, node: String) -> Vec<u64> {
-		let received_messages = self.messages.0.clone().into_iter().collect::<Vec<_>>();
+	pub(crate) fn get_hot_rumors(&self) -> Vec<Rumor> {
+		self.hot_rumors.keys().map(|m| self.as_rumor(*m)).collect()
+	}
 
-		let sent_to_node: Vec<u64> = self
-			.sent_messages
+	/// Every value we know about which is missing from the given digest.
+	pub(crate) fn get_missing_rumors(&self, digest: Vec<u64>) -> Vec<Rumor> {
+		let digest: HashSet<u64> = digest.into_iter().collect();
+
+		self.messages
+			.0
 			.iter()
-			.filter(|(key, _)| *key == &node)
-			.flat_map(|(_, Messages(value))| value)
-			.cloned()
-			.collect();
+			.filter(|m| !digest.contains(m))
+			.map(|m| self.as_rumor(*m))
+			.collect()
+	}
 
-		let received_from_node: Vec<u64> = self
+	/// Counts a redundant receipt for each value and stops spreading the ones
+	/// which reached the `redundancy` limit.
+	pub(crate) fn mark_redundant(&mut self, messages: Vec<u64>, redundancy: u8) {
+		for m in messages {
+			if let Some(count) = self.hot_rumors.get_mut(&m) {
+				*count += 1;
+
+				if *count >= redundancy {
+					self.hot_rumors.remove(&m);
+				}
+			}
+		}
+	}
+
+	fn as_rumor(&self, message: u64) -> Rumor {
+		Rumor {
+			message,
+			hops: self.hops.get(&message).cloned().unwrap_or_default() + 1,
+		}
+	}
+
+	pub(crate) fn get_new_messages_for_neighbour(&self, node: String) -> Vec<u64> {
+		let empty = HashSet::new();
+		let sent_to_node = self.sent_messages.get(&node).map_or(&empty, |m| &m.0);
+		let received_from_node = self
 			.received_gossip_messages
+			.get(&node)
+			.map_or(&empty, |m| &m.0);
+		let in_flight = self.outbox.get_in_flight(&node);
+
+		self.messages
+			.0
 			.iter()
-			.filter(|(key, _)| *key == &node)
-			.flat_map(|(_, Messages(value))| value)
+			.filter(|x| {
+				!sent_to_node.contains(x)
+					&& !received_from_node.contains(x)
+					&& !in_flight.contains(x)
+			})
 			.cloned()
-			.collect();
+			.collect()
+	}
 
-		let filtered_messages: Vec<u64> = received_messages
-			.into_iter()
-			.filter(|x| !sent_to_node.contains(x) && !received_from_node.contains(x))
-			.collect();
+	pub(crate) fn init_topology(&mut self, topology: HashMap<String, Vec<String>>) {
+		self.record(|| Entry::Topology {
+			topology: topology.clone(),
+		});
+		self.topology = topology;
+	}
+
+	pub(crate) fn get_neighbours(&self, node_id: &str) -> Vec<String> {
+		match &self.membership {
+			Some(view) => view.get_active_view(),
+			None => self.topology.get(node_id).cloned().unwrap_or_default(),
+		}
+	}
 
-		filtered_messages
+	/// The nodes we can pick gossip targets from.
+	pub(crate) fn get_network(&self, node: &Node) -> Vec<String> {
+		match &self.membership {
+			Some(view) => view.get_active_view(),
+			None => node.get_network(),
+		}
+	}
+
+	pub(crate) fn sync_plumtree(&mut self) {
+		if let Some(view) = &self.membership {
+			self.plumtree.sync(view.get_active_view());
+		}
 	}
 
 	pub(crate) fn add_to_sent_messages(&mut self, messages: Vec<u64>, node: String) {
-		if self.sent_messages.contains_key(&node) {
-			self.sent_messages
-				.get_mut(&node)
-				.unwrap()
-				.0
-				.extend(messages);
-		} else {
-			self.sent_messages
-				.insert(node, Messages(messages.iter().cloned().collect()));
+		self.record(|| Entry::Acknowledged {
+			peer: node.clone(),
+			messages: messages.clone(),
+		});
+
+		self.sent_messages
+			.entry(node)
+			.or_default()
+			.0
+			.extend(messages);
+	}
+
+	/// Delivers a broadcast in causal order, its dependencies must be delivered.
+	pub(crate) fn deliver(&mut self, stamped: Stamped) {
+		self.record(|| Entry::Delivered {
+			message: stamped.clone(),
+		});
+		self.messages.0.insert(stamped.message);
+		self.causal.deliver(stamped);
+	}
+
+	/// Buffers broadcasts until their dependencies are delivered, returns the
+	/// ones delivered.
+	pub(crate) fn receive_causal(&mut self, messages: Vec<Stamped>) -> Vec<Stamped> {
+		let delivered = self.causal.receive(messages);
+
+		for stamped in &delivered {
+			self.messages.0.insert(stamped.message);
+			self.record(|| Entry::Delivered {
+				message: stamped.clone(),
+			});
+		}
+
+		delivered
+	}
+
+	/// Stores values numbered by the sequencer of `epoch` for total order.
+	pub(crate) fn store(&mut self, epoch: u64, start: usize, messages: Vec<u64>) {
+		self.record(|| Entry::Numbered {
+			epoch,
+			start: start as u64,
+			messages: messages.clone(),
+		});
+		self.total.store(epoch, start, messages);
+	}
+
+	/// Delivers the first `length` numbered values, returns `false` if they
+	/// already were.
+	pub(crate) fn deliver_numbered(&mut self, length: usize) -> bool {
+		if !self.total.deliver(length) {
+			return false;
+		}
+
+		self.record(|| Entry::Ordered {
+			length: length as u64,
+		});
+		true
+	}
+
+	/// Moves total order broadcast to a later epoch, whose sequencer we follow
+	/// from now on.
+	pub(crate) fn enter_epoch(&mut self, epoch: u64) {
+		if self.total.enter(epoch) {
+			self.record(|| Entry::Epoch { epoch });
+		}
+	}
+
+	/// Starts journaling changes for the write-ahead log.
+	pub(crate) fn enable_journal(&mut self) {
+		self.journal = Some(vec![]);
+	}
+
+	/// Changes journaled since the last call.
+	pub(crate) fn take_journal(&mut self) -> Vec<Entry> {
+		self.journal
+			.as_mut()
+			.map(std::mem::take)
+			.unwrap_or_default()
+	}
+
+	fn record(&mut self, entry: impl FnOnce() -> Entry) {
+		if let Some(journal) = self.journal.as_mut() {
+			journal.push(entry());
+		}
+	}
+}
+
+type Command = Box<dyn FnOnce(&mut Storage) -> Reply + Send>;
+/// Hands a command's result back, once its changes are logged.
+type Reply = Box<dyn FnOnce() + Send>;
+
+/// Owns the `Storage` on a thread of its own. Handlers and gossip rounds send it
+/// commands which run one after another, so nothing contends on a lock and no
+/// state is held across an `await`.
+#[derive(Clone)]
+pub(crate) struct StorageActor {
+	commands: mpsc::Sender<Command>,
+}
+
+impl StorageActor {
+	/// Moves the storage onto a thread of its own, so writing and syncing the
+	/// log never holds up a runtime worker. With a `wal`, the changes of each
+	/// command are logged before its result is handed back. A node which
+	/// cannot write its log any more stops, rather than acknowledge changes
+	/// a restart would lose.
+	pub(crate) fn spawn(mut storage: Storage, mut wal: Option<Wal>) -> StorageActor {
+		let (commands, mut inbox) = mpsc::channel::<Command>(1000);
+
+		if wal.is_some() {
+			storage.enable_journal();
 		}
+
+		thread::Builder::new()
+			.name("storage".to_string())
+			.spawn(move || {
+				while let Some(command) = inbox.blocking_recv() {
+					let reply = command(&mut storage);
+
+					if let Some(log) = wal.as_mut() {
+						if let Err(e) = log.commit(&mut storage) {
+							error!("Could not write the log, stopping: {}", e);
+							process::exit(1);
+						}
+					}
+
+					reply();
+				}
+			})
+			.expect("Could not start the storage thread");
+
+		StorageActor { commands }
+	}
+
+	/// Runs `command` on the storage thread and returns its result.
+	pub(crate) async fn call<R, F>(&self, command: F) -> R
+	where
+		R: Send + 'static,
+		F: FnOnce(&mut Storage) -> R + Send + 'static,
+	{
+		let (reply, result) = oneshot::channel();
+
+		self.commands
+			.send(Box::new(move |storage| {
+				let result = command(storage);
+
+				Box::new(move || {
+					let _ = reply.send(result);
+				})
+			}))
+			.await
+			.expect("Storage actor stopped");
+
+		result.await.expect("Storage actor stopped")
 	}
 }
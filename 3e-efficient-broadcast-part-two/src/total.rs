@@ -0,0 +1,723 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::{
+	message::{Body, Message},
+	node::Node,
+	storage::Storage,
+};
+
+/// Rounds without a heartbeat after which followers move on to the next sequencer.
+const FAILOVER_ROUNDS: u64 = 5;
+/// Rounds a value waits to show up in the log before we ask the sequencer again.
+const RETRY_ROUNDS: u64 = 3;
+
+/// Total order broadcast through a sequencer: one node numbers every value,
+/// and every node delivers them by number once a majority of nodes stored
+/// them. The sequencer of epoch `e` is the `e`-th node in id order, wrapping
+/// around. Followers which stop hearing from it move to the next epoch and
+/// ignore the old sequencer from then on, so it cannot reach a majority any
+/// more. The new sequencer collects the logs of a majority and adopts the one
+/// numbered in the latest epoch, which holds every delivered value, before it
+/// numbers anything new.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub(crate) struct Total {
+	/// Numbered values we stored, in the order of the sequencer of `stored_epoch`.
+	numbered: Vec<u64>,
+	/// Length of the prefix of `numbered` a majority stored, which is delivered.
+	delivered: usize,
+	/// Values in the delivered prefix.
+	values: HashSet<u64>,
+	/// Epoch whose sequencer numbered the values we stored.
+	stored_epoch: u64,
+	epoch: u64,
+	/// Numbered values which arrived before the ones in front of them.
+	#[serde(skip)]
+	pending: BTreeMap<usize, u64>,
+	/// Values broadcast through us which are not delivered yet, with the
+	/// rounds since we last asked for them to be numbered.
+	#[serde(skip)]
+	unsequenced: HashMap<u64, u64>,
+	#[serde(skip)]
+	rounds_since_heartbeat: u64,
+	/// As sequencer, how many numbered values each follower stored.
+	#[serde(skip)]
+	stored_by: HashMap<String, usize>,
+	/// The logs collected while we take over as sequencer.
+	#[serde(skip)]
+	takeover: Option<Takeover>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Takeover {
+	/// How much of the log each node which answered delivered.
+	delivered: HashMap<String, usize>,
+	/// The log numbered in the latest epoch, the longest among those.
+	log: (u64, Vec<u64>),
+}
+
+impl Total {
+	/// Values in the order every node delivers them.
+	pub(crate) fn read(&self) -> Vec<u64> {
+		self.numbered[..self.delivered].to_vec()
+	}
+
+	/// Whether the value is numbered already, delivered or not.
+	fn is_numbered(&self, message: u64) -> bool {
+		self.values.contains(&message) || self.numbered[self.delivered..].contains(&message)
+	}
+
+	/// Stores values numbered by the sequencer of `epoch`, from position
+	/// `start`. Values of an earlier epoch past the delivered prefix are
+	/// replaced, which needs `start` to be inside the delivered prefix.
+	pub(crate) fn store(&mut self, epoch: u64, start: usize, messages: Vec<u64>) {
+		if epoch != self.stored_epoch {
+			self.numbered.truncate(self.delivered);
+			self.stored_epoch = epoch;
+		}
+
+		for (i, message) in messages.into_iter().enumerate() {
+			if start + i == self.numbered.len() {
+				self.numbered.push(message);
+			}
+		}
+	}
+
+	/// Delivers the first `length` numbered values, returns `false` if they
+	/// already were.
+	pub(crate) fn deliver(&mut self, length: usize) -> bool {
+		let length = length.min(self.numbered.len());
+
+		if length <= self.delivered {
+			return false;
+		}
+
+		for message in &self.numbered[self.delivered..length] {
+			self.values.insert(*message);
+			self.unsequenced.remove(message);
+		}
+
+		self.delivered = length;
+		true
+	}
+
+	/// Moves to a later epoch, the sequencer of an earlier one is ignored from
+	/// now on. Returns `false` if we already were in that epoch.
+	pub(crate) fn enter(&mut self, epoch: u64) -> bool {
+		self.rounds_since_heartbeat = 0;
+
+		if epoch <= self.epoch {
+			return false;
+		}
+
+		self.epoch = epoch;
+		self.takeover = None;
+		self.stored_by.clear();
+		self.pending.clear();
+		true
+	}
+}
+
+/// The sequencer of an epoch.
+fn sequencer(node: &Node, epoch: u64) -> String {
+	let mut nodes = node.availble_nodes.clone();
+	nodes.sort();
+
+	nodes[(epoch % nodes.len() as u64) as usize].clone()
+}
+
+fn is_sequencer(storage: &Storage, node: &Node) -> bool {
+	sequencer(node, storage.total.epoch) == node.id && storage.total.takeover.is_none()
+}
+
+fn majority(node: &Node) -> usize {
+	node.availble_nodes.len() / 2 + 1
+}
+
+/// Numbers a value broadcast by a client, or asks the sequencer to. Either way
+/// we ask again until it is delivered, in case the sequencer loses it.
+pub(crate) fn broadcast(storage: &mut Storage, node: &Node, message: u64) -> Vec<Message> {
+	if storage.total.values.contains(&message) {
+		return vec![];
+	}
+
+	storage.total.unsequenced.insert(message, 0);
+
+	if is_sequencer(storage, node) {
+		return sequence(storage, node, message);
+	}
+
+	vec![Message {
+		src: node.id.clone(),
+		dest: sequencer(node, storage.total.epoch),
+		body: Body::Sequence { message },
+	}]
+}
+
+/// Appends a value to the log and sends its number to every other node.
+fn sequence(storage: &mut Storage, node: &Node, message: u64) -> Vec<Message> {
+	if storage.total.is_numbered(message) {
+		return vec![];
+	}
+
+	let start = storage.total.numbered.len();
+	storage.store(storage.total.epoch, start, vec![message]);
+
+	let mut outgoing = announce(storage, node, start, vec![message]);
+	outgoing.extend(deliver_stored(storage, node));
+	outgoing
+}
+
+fn announce(storage: &Storage, node: &Node, start: usize, messages: Vec<u64>) -> Vec<Message> {
+	node.availble_nodes
+		.iter()
+		.filter(|n| **n != node.id)
+		.map(|peer| numbered(storage, node, peer, start, messages.clone()))
+		.collect()
+}
+
+fn numbered(
+	storage: &Storage,
+	node: &Node,
+	peer: &str,
+	start: usize,
+	messages: Vec<u64>,
+) -> Message {
+	Message {
+		src: node.id.clone(),
+		dest: peer.to_string(),
+		body: Body::Sequenced {
+			epoch: storage.total.epoch,
+			start: start as u64,
+			messages,
+			delivered: storage.total.delivered as u64,
+		},
+	}
+}
+
+/// As sequencer, delivers whatever a majority stored and tells the followers.
+fn deliver_stored(storage: &mut Storage, node: &Node) -> Vec<Message> {
+	let mut lengths: Vec<usize> = storage.total.stored_by.values().copied().collect();
+	lengths.push(storage.total.numbered.len());
+	lengths.sort_unstable_by(|a, b| b.cmp(a));
+
+	let Some(&length) = lengths.get(majority(node) - 1) else {
+		return vec![];
+	};
+
+	if !storage.deliver_numbered(length) {
+		return vec![];
+	}
+
+	heartbeat(storage, node)
+}
+
+pub(crate) fn receive_sequence(storage: &mut Storage, node: &Node, message: u64) -> Vec<Message> {
+	if is_sequencer(storage, node) {
+		return sequence(storage, node, message);
+	}
+
+	// Not our turn (yet), the broadcasting node asks again
+	vec![]
+}
+
+/// Stores numbered values in order, buffering those which overtook others,
+/// and tells the sequencer how many we stored.
+pub(crate) fn receive_sequenced(
+	storage: &mut Storage,
+	node: &Node,
+	from: &str,
+	epoch: u64,
+	start: u64,
+	messages: Vec<u64>,
+	delivered: u64,
+) -> Vec<Message> {
+	if epoch < storage.total.epoch || from != sequencer(node, epoch) {
+		debug!(
+			epoch,
+			"Ignoring values numbered by stale sequencer {}", from
+		);
+		return vec![];
+	}
+
+	storage.enter_epoch(epoch);
+	let total = &mut storage.total;
+	let start = start as usize;
+
+	// The first values of a new sequencer have to replace whatever we stored
+	// past the delivered prefix, later ones follow on from what we have
+	let end = if total.stored_epoch == epoch {
+		total.numbered.len()
+	} else {
+		total.delivered
+	};
+
+	if start > end {
+		for (i, message) in messages.into_iter().enumerate() {
+			total.pending.insert(start + i, message);
+		}
+	} else {
+		storage.store(epoch, start, messages);
+
+		let total = &mut storage.total;
+		let mut next = total.numbered.len();
+		let mut following = vec![];
+
+		while let Some(message) = total.pending.remove(&next) {
+			following.push(message);
+			next += 1;
+		}
+
+		total.pending.retain(|seq, _| *seq >= next);
+
+		if !following.is_empty() {
+			storage.store(epoch, next - following.len(), following);
+		}
+	}
+
+	if storage.total.stored_epoch != epoch {
+		return vec![];
+	}
+
+	storage.deliver_numbered(delivered as usize);
+
+	vec![Message {
+		src: node.id.clone(),
+		dest: from.to_string(),
+		body: Body::SequencedOk {
+			epoch,
+			length: storage.total.numbered.len() as u64,
+		},
+	}]
+}
+
+/// As sequencer, delivers the values a majority of nodes stored.
+pub(crate) fn receive_sequenced_ok(
+	storage: &mut Storage,
+	node: &Node,
+	from: &str,
+	epoch: u64,
+	length: u64,
+) -> Vec<Message> {
+	if epoch != storage.total.epoch || !is_sequencer(storage, node) {
+		return vec![];
+	}
+
+	let stored = storage.total.stored_by.entry(from.to_string()).or_default();
+	*stored = (*stored).max(length as usize);
+
+	deliver_stored(storage, node)
+}
+
+/// Heartbeats tell followers the sequencer is alive, how long its log is and
+/// how much of it is delivered, so they notice values they missed at the end.
+pub(crate) fn receive_heartbeat(
+	storage: &mut Storage,
+	node: &Node,
+	from: &str,
+	epoch: u64,
+	length: u64,
+	delivered: u64,
+) -> Vec<Message> {
+	if epoch < storage.total.epoch || from != sequencer(node, epoch) {
+		return vec![];
+	}
+
+	storage.enter_epoch(epoch);
+
+	let missing = if storage.total.stored_epoch == epoch {
+		storage.deliver_numbered(delivered as usize);
+
+		if (storage.total.numbered.len() as u64) >= length {
+			return vec![];
+		}
+
+		storage.total.numbered.len()
+	} else {
+		storage.total.delivered
+	};
+
+	vec![Message {
+		src: node.id.clone(),
+		dest: from.to_string(),
+		body: Body::SequenceSync {
+			from: missing as u64,
+		},
+	}]
+}
+
+pub(crate) fn receive_sync(storage: &Storage, node: &Node, from: &str, start: u64) -> Vec<Message> {
+	let log = &storage.total.numbered;
+
+	if !is_sequencer(storage, node) || start as usize > log.len() {
+		return vec![];
+	}
+
+	vec![numbered(
+		storage,
+		node,
+		from,
+		start as usize,
+		log[start as usize..].to_vec(),
+	)]
+}
+
+/// Hands our log to the node taking over as sequencer, we ignore the earlier
+/// sequencers from now on.
+pub(crate) fn receive_takeover(
+	storage: &mut Storage,
+	node: &Node,
+	from: &str,
+	epoch: u64,
+) -> Vec<Message> {
+	if epoch < storage.total.epoch || from != sequencer(node, epoch) {
+		return vec![];
+	}
+
+	storage.enter_epoch(epoch);
+
+	vec![Message {
+		src: node.id.clone(),
+		dest: from.to_string(),
+		body: Body::TakeoverOk {
+			epoch,
+			stored_epoch: storage.total.stored_epoch,
+			delivered: storage.total.delivered as u64,
+			messages: storage.total.numbered.clone(),
+		},
+	}]
+}
+
+/// Keeps the log numbered in the latest epoch, and starts numbering values
+/// once a majority answered.
+pub(crate) fn receive_takeover_ok(
+	storage: &mut Storage,
+	node: &Node,
+	from: &str,
+	epoch: u64,
+	stored_epoch: u64,
+	delivered: u64,
+	messages: Vec<u64>,
+) -> Vec<Message> {
+	if epoch != storage.total.epoch {
+		return vec![];
+	}
+
+	let Some(takeover) = storage.total.takeover.as_mut() else {
+		return vec![];
+	};
+	takeover
+		.delivered
+		.insert(from.to_string(), delivered as usize);
+
+	// Every delivered value is in the log of a node which answered, and the
+	// sequencer of the latest epoch had them all when it started numbering
+	if (stored_epoch, messages.len()) > (takeover.log.0, takeover.log.1.len()) {
+		takeover.log = (stored_epoch, messages);
+	}
+
+	if takeover.delivered.len() + 1 < majority(node) {
+		return vec![];
+	}
+
+	let Some(takeover) = storage.total.takeover.take() else {
+		return vec![];
+	};
+	let start = storage.total.delivered;
+	let log = takeover.log.1.get(start..).unwrap_or_default().to_vec();
+	storage.store(epoch, start, log);
+
+	info!(
+		epoch,
+		length = storage.total.numbered.len(),
+		"Took over as sequencer"
+	);
+
+	let waiting: Vec<u64> = storage
+		.total
+		.unsequenced
+		.keys()
+		.copied()
+		.filter(|m| !storage.total.is_numbered(*m))
+		.collect();
+
+	if !waiting.is_empty() {
+		let end = storage.total.numbered.len();
+		storage.store(epoch, end, waiting);
+	}
+
+	let mut outgoing = heartbeat(storage, node);
+	outgoing.extend(takeover.delivered.into_iter().map(|(peer, delivered)| {
+		let start = delivered.min(storage.total.numbered.len());
+
+		numbered(
+			storage,
+			node,
+			&peer,
+			start,
+			storage.total.numbered[start..].to_vec(),
+		)
+	}));
+	outgoing.extend(deliver_stored(storage, node));
+	outgoing
+}
+
+fn heartbeat(storage: &Storage, node: &Node) -> Vec<Message> {
+	node.availble_nodes
+		.iter()
+		.filter(|n| **n != node.id)
+		.map(|peer| Message {
+			src: node.id.clone(),
+			dest: peer.clone(),
+			body: Body::SequenceHeartbeat {
+				epoch: storage.total.epoch,
+				length: storage.total.numbered.len() as u64,
+				delivered: storage.total.delivered as u64,
+			},
+		})
+		.collect()
+}
+
+/// Sends heartbeats as sequencer, and as follower asks again for values
+/// which were not delivered yet and fails over once the sequencer went quiet.
+pub(crate) fn tick(storage: &mut Storage, node: &Node) -> Vec<Message> {
+	if is_sequencer(storage, node) {
+		return heartbeat(storage, node);
+	}
+
+	let mut outgoing = vec![];
+
+	if storage.total.takeover.is_none() {
+		storage.total.rounds_since_heartbeat += 1;
+	}
+
+	if storage.total.rounds_since_heartbeat >= FAILOVER_ROUNDS {
+		let epoch = storage.total.epoch + 1;
+		storage.enter_epoch(epoch);
+
+		info!(
+			epoch,
+			"Sequencer went quiet, failing over to {}",
+			sequencer(node, epoch)
+		);
+
+		if sequencer(node, epoch) == node.id {
+			let total = &mut storage.total;
+			total.takeover = Some(Takeover {
+				delivered: HashMap::new(),
+				log: (total.stored_epoch, total.numbered.clone()),
+			});
+		}
+	}
+
+	let total = &mut storage.total;
+
+	if total.takeover.is_some() {
+		let epoch = total.epoch;
+
+		outgoing.extend(
+			node.availble_nodes
+				.iter()
+				.filter(|n| **n != node.id)
+				.map(|peer| Message {
+					src: node.id.clone(),
+					dest: peer.clone(),
+					body: Body::Takeover { epoch },
+				}),
+		);
+
+		return outgoing;
+	}
+
+	let dest = sequencer(node, total.epoch);
+
+	for (message, rounds) in total.unsequenced.iter_mut() {
+		*rounds += 1;
+
+		if *rounds >= RETRY_ROUNDS {
+			*rounds = 0;
+
+			outgoing.push(Message {
+				src: node.id.clone(),
+				dest: dest.clone(),
+				body: Body::Sequence { message: *message },
+			});
+		}
+	}
+
+	outgoing
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const NODES: [&str; 3] = ["n1", "n2", "n3"];
+
+	/// Nodes exchanging total order messages, with some links cut.
+	struct Cluster {
+		nodes: BTreeMap<String, (Node, Storage)>,
+		cut: HashSet<(String, String)>,
+	}
+
+	impl Cluster {
+		fn new() -> Cluster {
+			let nodes = NODES
+				.iter()
+				.map(|id| {
+					(
+						id.to_string(),
+						(Node::for_test(id, &NODES), Storage::default()),
+					)
+				})
+				.collect();
+
+			Cluster {
+				nodes,
+				cut: HashSet::new(),
+			}
+		}
+
+		fn partition(&mut self, alone: &str) {
+			for other in NODES.iter().filter(|n| **n != alone) {
+				self.cut.insert((alone.to_string(), other.to_string()));
+				self.cut.insert((other.to_string(), alone.to_string()));
+			}
+		}
+
+		fn heal(&mut self) {
+			self.cut.clear();
+		}
+
+		fn broadcast(&mut self, id: &str, message: u64) {
+			let (node, storage) = self.nodes.get_mut(id).unwrap();
+			let outgoing = broadcast(storage, node, message);
+			self.deliver(outgoing);
+		}
+
+		fn tick(&mut self, rounds: usize) {
+			for _ in 0..rounds {
+				let mut outgoing = vec![];
+				for (node, storage) in self.nodes.values_mut() {
+					outgoing.extend(tick(storage, node));
+				}
+				self.deliver(outgoing);
+			}
+		}
+
+		fn deliver(&mut self, mut queue: Vec<Message>) {
+			while let Some(message) = queue.pop() {
+				if self
+					.cut
+					.contains(&(message.src.clone(), message.dest.clone()))
+				{
+					continue;
+				}
+
+				let (node, storage) = self.nodes.get_mut(&message.dest).unwrap();
+				let from = message.src.as_str();
+
+				queue.extend(match message.body {
+					Body::Sequence { message } => receive_sequence(storage, node, message),
+					Body::Sequenced {
+						epoch,
+						start,
+						messages,
+						delivered,
+					} => receive_sequenced(storage, node, from, epoch, start, messages, delivered),
+					Body::SequencedOk { epoch, length } => {
+						receive_sequenced_ok(storage, node, from, epoch, length)
+					}
+					Body::SequenceSync { from: start } => receive_sync(storage, node, from, start),
+					Body::SequenceHeartbeat {
+						epoch,
+						length,
+						delivered,
+					} => receive_heartbeat(storage, node, from, epoch, length, delivered),
+					Body::Takeover { epoch } => receive_takeover(storage, node, from, epoch),
+					Body::TakeoverOk {
+						epoch,
+						stored_epoch,
+						delivered,
+						messages,
+					} => receive_takeover_ok(
+						storage,
+						node,
+						from,
+						epoch,
+						stored_epoch,
+						delivered,
+						messages,
+					),
+					body => panic!("Unexpected {:?}", body),
+				});
+			}
+		}
+
+		fn read(&self, id: &str) -> Vec<u64> {
+			self.nodes[id].1.total.read()
+		}
+	}
+
+	#[test]
+	fn values_are_delivered_once_a_majority_stored_them() {
+		let mut cluster = Cluster::new();
+
+		cluster.broadcast("n1", 1);
+		cluster.broadcast("n3", 2);
+		cluster.tick(1);
+
+		for id in NODES {
+			assert_eq!(cluster.read(id), vec![1, 2], "{} disagrees", id);
+		}
+	}
+
+	#[test]
+	fn a_partitioned_sequencer_delivers_nothing_and_rejoins_the_new_order() {
+		let mut cluster = Cluster::new();
+		cluster.broadcast("n2", 1);
+		cluster.tick(1);
+
+		// n1 numbers epoch 0, but nobody stores what it numbers from now on
+		cluster.partition("n1");
+		cluster.broadcast("n1", 2);
+		assert_eq!(cluster.read("n1"), vec![1]);
+
+		// n2 takes over for epoch 1 with n3, and numbers a value of its own
+		cluster.tick(FAILOVER_ROUNDS as usize + 1);
+		cluster.broadcast("n2", 3);
+		assert_eq!(cluster.read("n2"), vec![1, 3]);
+		assert_eq!(cluster.read("n3"), vec![1, 3]);
+		assert_eq!(cluster.read("n1"), vec![1]);
+
+		// Once healed n1 follows n2, and asks it to number its value again
+		cluster.heal();
+		cluster.tick(RETRY_ROUNDS as usize + 1);
+
+		for id in NODES {
+			assert_eq!(cluster.read(id), vec![1, 3, 2], "{} disagrees", id);
+		}
+	}
+
+	#[test]
+	fn takeover_keeps_values_a_majority_stored() {
+		let mut cluster = Cluster::new();
+
+		// n1 numbers a value only n2 stores, which is still a majority with n1
+		cluster.partition("n3");
+		cluster.broadcast("n1", 1);
+		assert_eq!(cluster.read("n1"), vec![1]);
+		assert_eq!(cluster.read("n3"), Vec::<u64>::new());
+
+		// n1 crashes, n2 takes over with n3 which never saw the value
+		cluster.heal();
+		cluster.partition("n1");
+		cluster.tick(FAILOVER_ROUNDS as usize + 1);
+		cluster.broadcast("n3", 2);
+
+		assert_eq!(cluster.read("n2"), vec![1, 2]);
+		assert_eq!(cluster.read("n3"), vec![1, 2]);
+	}
+}
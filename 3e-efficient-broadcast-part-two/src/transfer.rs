@@ -0,0 +1,446 @@
+use std::collections::{BTreeMap, HashMap};
+
+use tracing::{debug, info};
+
+use crate::{
+	message::{Body, Message},
+	storage::Storage,
+};
+
+/// Gossip rounds without progress after which a stalled transfer is retried:
+/// the receiver asks again for the chunks it misses, the sender offers again
+/// in case the confirmation was lost.
+const RETRY_ROUNDS: u64 = 3;
+
+/// Snapshot transfers to peers which fell too far behind for incremental
+/// gossip, and from peers which offered us one.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Transfers {
+	outgoing: HashMap<String, Snapshot>,
+	incoming: HashMap<String, Incoming>,
+	next_version: u64,
+}
+
+/// Every value we knew when a peer asked for a snapshot, split into chunks.
+#[derive(Clone, Debug)]
+struct Snapshot {
+	version: u64,
+	chunks: Vec<Vec<u64>>,
+	idle_rounds: u64,
+}
+
+/// Chunks of a snapshot received so far.
+#[derive(Clone, Debug)]
+struct Incoming {
+	/// `None` until the first chunk tells us which snapshot we are receiving.
+	version: Option<u64>,
+	total: u64,
+	received: BTreeMap<u64, usize>,
+	idle_rounds: u64,
+}
+
+impl Incoming {
+	fn new() -> Incoming {
+		Incoming {
+			version: None,
+			total: 0,
+			received: BTreeMap::new(),
+			idle_rounds: 0,
+		}
+	}
+
+	fn missing(&self) -> Vec<u64> {
+		(0..self.total)
+			.filter(|index| !self.received.contains_key(index))
+			.collect()
+	}
+}
+
+impl Transfers {
+	/// Whether incremental gossip to the peer waits for a snapshot transfer.
+	pub(crate) fn is_sending_to(&self, peer: &str) -> bool {
+		self.outgoing.contains_key(peer)
+	}
+}
+
+/// A `snapshot_chunk` whose checksum matched.
+#[derive(Clone, Debug)]
+pub(crate) struct Chunk {
+	pub(crate) version: u64,
+	pub(crate) index: u64,
+	pub(crate) total: u64,
+	pub(crate) messages: Vec<u64>,
+}
+
+/// FNV-1a over the values of a chunk, so a corrupted chunk is not applied.
+pub(crate) fn checksum(messages: &[u64]) -> u64 {
+	messages
+		.iter()
+		.flat_map(|m| m.to_le_bytes())
+		.fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+			(hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+		})
+}
+
+/// Tells a peer with more than the threshold of values missing to ask for a snapshot.
+pub(crate) fn offer(node_id: &str, peer: &str, backlog: usize) -> Message {
+	debug!("{} misses {} values, offering a snapshot", peer, backlog);
+
+	Message {
+		src: node_id.to_string(),
+		dest: peer.to_string(),
+		body: Body::SnapshotOffer {
+			values: backlog as u64,
+		},
+	}
+}
+
+pub(crate) fn receive_offer(storage: &mut Storage, node_id: &str, from: &str) -> Vec<Message> {
+	if storage.transfers.incoming.contains_key(from) {
+		return vec![];
+	}
+
+	storage
+		.transfers
+		.incoming
+		.insert(from.to_string(), Incoming::new());
+
+	vec![Message {
+		src: node_id.to_string(),
+		dest: from.to_string(),
+		body: Body::SnapshotRequest {
+			version: None,
+			chunks: vec![],
+		},
+	}]
+}
+
+/// Sends the requested chunks of the snapshot, or all of a new one if the
+/// peer asks for a version we do not have.
+pub(crate) fn receive_request(
+	storage: &mut Storage,
+	node_id: &str,
+	from: &str,
+	version: Option<u64>,
+	chunks: Vec<u64>,
+	chunk_size: usize,
+) -> Vec<Message> {
+	let current = storage.transfers.outgoing.get(from).map(|s| s.version);
+
+	let chunks = if version.is_some() && version == current {
+		storage
+			.transfers
+			.outgoing
+			.get_mut(from)
+			.unwrap()
+			.idle_rounds = 0;
+		chunks
+	} else {
+		let mut messages = storage.get_messages();
+		messages.sort_unstable();
+
+		let snapshot = Snapshot {
+			version: storage.transfers.next_version,
+			chunks: messages.chunks(chunk_size).map(|c| c.to_vec()).collect(),
+			idle_rounds: 0,
+		};
+		storage.transfers.next_version += 1;
+
+		info!(
+			version = snapshot.version,
+			chunks = snapshot.chunks.len(),
+			"Sending a snapshot of {} values to {}",
+			messages.len(),
+			from
+		);
+
+		let all = (0..snapshot.chunks.len() as u64).collect();
+		storage
+			.transfers
+			.outgoing
+			.insert(from.to_string(), snapshot);
+		all
+	};
+
+	let snapshot = &storage.transfers.outgoing[from];
+	let total = snapshot.chunks.len() as u64;
+
+	chunks
+		.into_iter()
+		.filter_map(|index| snapshot.chunks.get(index as usize).map(|c| (index, c)))
+		.map(|(index, messages)| Message {
+			src: node_id.to_string(),
+			dest: from.to_string(),
+			body: Body::SnapshotChunk {
+				version: snapshot.version,
+				index,
+				total,
+				checksum: checksum(messages),
+				messages: messages.clone(),
+			},
+		})
+		.collect()
+}
+
+/// Stores the values of a chunk whose checksum was checked, and confirms the
+/// snapshot once every chunk arrived.
+pub(crate) fn receive_chunk(
+	storage: &mut Storage,
+	node_id: &str,
+	from: &str,
+	chunk: Chunk,
+) -> Vec<Message> {
+	let Chunk {
+		version,
+		index,
+		total,
+		messages,
+		..
+	} = chunk;
+
+	let incoming = storage
+		.transfers
+		.incoming
+		.entry(from.to_string())
+		.or_insert_with(Incoming::new);
+
+	// A newer snapshot replaces the one we were receiving, a chunk of an older one is stale
+	match incoming.version {
+		Some(current) if current > version => return vec![],
+		Some(current) if current == version => {}
+		_ => {
+			*incoming = Incoming::new();
+			incoming.version = Some(version);
+			incoming.total = total;
+		}
+	}
+
+	incoming.idle_rounds = 0;
+	incoming.received.insert(index, messages.len());
+
+	if !incoming.missing().is_empty() {
+		storage.add_messages(messages, from.to_string());
+		return vec![];
+	}
+
+	let values: usize = incoming.received.values().sum();
+	storage.transfers.incoming.remove(from);
+	storage.add_messages(messages, from.to_string());
+
+	info!(
+		version,
+		"Received a snapshot of {} values from {}", values, from
+	);
+
+	vec![Message {
+		src: node_id.to_string(),
+		dest: from.to_string(),
+		body: Body::SnapshotOk { version },
+	}]
+}
+
+/// The peer has every value of the snapshot, incremental gossip resumes from there.
+pub(crate) fn receive_ok(storage: &mut Storage, from: &str, version: u64) {
+	match storage.transfers.outgoing.get(from) {
+		Some(snapshot) if snapshot.version == version => {
+			let snapshot = storage.transfers.outgoing.remove(from).unwrap();
+			let messages = snapshot.chunks.into_iter().flatten().collect();
+			storage.add_to_sent_messages(messages, from.to_string());
+		}
+		_ => debug!(
+			version,
+			"Ignoring confirmation of an old snapshot from {}", from
+		),
+	}
+}
+
+/// Retries the transfers which stalled.
+pub(crate) fn tick(storage: &mut Storage, node_id: &str) -> Vec<Message> {
+	let mut outgoing = vec![];
+
+	for (peer, snapshot) in storage.transfers.outgoing.iter_mut() {
+		snapshot.idle_rounds += 1;
+
+		if snapshot.idle_rounds < RETRY_ROUNDS {
+			continue;
+		}
+
+		snapshot.idle_rounds = 0;
+		let values = snapshot.chunks.iter().map(Vec::len).sum();
+		outgoing.push(offer(node_id, peer, values));
+	}
+
+	for (peer, incoming) in storage.transfers.incoming.iter_mut() {
+		incoming.idle_rounds += 1;
+
+		if incoming.idle_rounds < RETRY_ROUNDS {
+			continue;
+		}
+
+		incoming.idle_rounds = 0;
+
+		outgoing.push(Message {
+			src: node_id.to_string(),
+			dest: peer.clone(),
+			body: Body::SnapshotRequest {
+				version: incoming.version,
+				chunks: incoming.missing(),
+			},
+		});
+	}
+
+	outgoing
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn chunk(version: u64, index: u64, total: u64, messages: &[u64]) -> Chunk {
+		Chunk {
+			version,
+			index,
+			total,
+			messages: messages.to_vec(),
+		}
+	}
+
+	fn known(storage: &Storage) -> Vec<u64> {
+		let mut messages: Vec<u64> = storage.messages.0.iter().copied().collect();
+		messages.sort_unstable();
+		messages
+	}
+
+	#[test]
+	fn snapshot_is_sent_in_chunks_and_confirmed_once_complete() {
+		let mut sender = Storage::default();
+		for message in 1..=5 {
+			sender.add_message(message);
+		}
+
+		let chunks = receive_request(&mut sender, "n1", "n2", None, vec![], 2);
+		assert_eq!(chunks.len(), 3);
+		assert!(sender.transfers.is_sending_to("n2"));
+
+		let mut receiver = Storage::default();
+		receive_offer(&mut receiver, "n2", "n1");
+
+		let mut outgoing = vec![];
+		for message in chunks {
+			let Body::SnapshotChunk {
+				version,
+				index,
+				total,
+				checksum: expected,
+				messages,
+			} = message.body
+			else {
+				panic!("Expected a chunk, got {:?}", message.body);
+			};
+			assert_eq!(checksum(&messages), expected);
+
+			let chunk = chunk(version, index, total, &messages);
+			outgoing = receive_chunk(&mut receiver, "n2", "n1", chunk);
+		}
+
+		assert_eq!(known(&receiver), [1, 2, 3, 4, 5]);
+		assert!(matches!(
+			outgoing[..],
+			[Message {
+				body: Body::SnapshotOk { version: 0 },
+				..
+			}]
+		));
+
+		receive_ok(&mut sender, "n2", 0);
+		assert!(!sender.transfers.is_sending_to("n2"));
+	}
+
+	#[test]
+	fn chunks_of_an_older_snapshot_are_stale() {
+		let mut storage = Storage::default();
+
+		assert!(receive_chunk(&mut storage, "n1", "n2", chunk(2, 0, 2, &[1])).is_empty());
+		assert!(receive_chunk(&mut storage, "n1", "n2", chunk(1, 1, 2, &[2])).is_empty());
+
+		assert_eq!(known(&storage), [1]);
+		assert_eq!(storage.transfers.incoming["n2"].version, Some(2));
+		assert_eq!(storage.transfers.incoming["n2"].missing(), [1]);
+	}
+
+	#[test]
+	fn a_newer_snapshot_replaces_the_one_in_progress() {
+		let mut storage = Storage::default();
+		receive_chunk(&mut storage, "n1", "n2", chunk(1, 0, 3, &[1]));
+
+		// Version 2 has a single chunk, nothing of version 1 is waited for any more
+		let outgoing = receive_chunk(&mut storage, "n1", "n2", chunk(2, 0, 1, &[1, 2]));
+
+		assert!(matches!(
+			outgoing[..],
+			[Message {
+				body: Body::SnapshotOk { version: 2 },
+				..
+			}]
+		));
+		assert!(!storage.transfers.incoming.contains_key("n2"));
+	}
+
+	#[test]
+	fn requests_for_the_current_version_resend_only_the_missing_chunks() {
+		let mut storage = Storage::default();
+		for message in 1..=4 {
+			storage.add_message(message);
+		}
+
+		receive_request(&mut storage, "n1", "n2", None, vec![], 1);
+		let resent = receive_request(&mut storage, "n1", "n2", Some(0), vec![2], 1);
+		assert!(matches!(
+			resent[..],
+			[Message {
+				body: Body::SnapshotChunk {
+					version: 0,
+					index: 2,
+					..
+				},
+				..
+			}]
+		));
+
+		// A version we no longer have starts a new snapshot
+		let fresh = receive_request(&mut storage, "n1", "n2", Some(7), vec![2], 1);
+		assert_eq!(fresh.len(), 4);
+		assert!(fresh
+			.iter()
+			.all(|m| matches!(m.body, Body::SnapshotChunk { version: 1, .. })));
+	}
+
+	#[test]
+	fn stalled_transfers_are_retried_by_the_tick() {
+		let mut storage = Storage::default();
+		storage.add_message(1);
+		receive_request(&mut storage, "n1", "n2", None, vec![], 1);
+		receive_chunk(&mut storage, "n1", "n3", chunk(4, 1, 3, &[5]));
+
+		for _ in 1..RETRY_ROUNDS {
+			assert!(tick(&mut storage, "n1").is_empty());
+		}
+
+		let mut retries = tick(&mut storage, "n1");
+		retries.sort_by(|a, b| a.dest.cmp(&b.dest));
+
+		assert!(matches!(
+			retries[..],
+			[
+				Message { body: Body::SnapshotOffer { values: 1 }, .. },
+				Message { body: Body::SnapshotRequest { version: Some(4), ref chunks }, .. },
+			] if chunks == &[0, 2]
+		));
+
+		// Progress puts the retry off again
+		receive_chunk(&mut storage, "n1", "n3", chunk(4, 0, 3, &[6]));
+		for _ in 1..RETRY_ROUNDS {
+			assert!(tick(&mut storage, "n1").iter().all(|m| m.dest != "n3"));
+		}
+	}
+}
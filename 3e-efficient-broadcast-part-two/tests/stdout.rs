@@ -0,0 +1,473 @@
+//! Feeds every message type through the node and checks that everything it
+//! writes to stdout, gossip included, is a protocol message, diagnostics
+//! belong on stderr.
+
+#[allow(dead_code)]
+#[path = "../src/message.rs"]
+mod message;
+
+use harness::{answered, run_node_until, Node, INIT};
+use message::Message;
+use serde_json::Value;
+
+const BIN: &str = env!("CARGO_BIN_EXE_ch03e-efficient-broadcast-part-two");
+
+const MESSAGES: &[&str] = &[
+	r#"{"src":"c1","dest":"n1","body":{"type":"init_ok","in_reply_to":1}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"topology","msg_id":2,"topology":{"n1":["n2","n3"],"n2":["n1"],"n3":["n1"]}}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"topology_ok","msg_id":3,"in_reply_to":2}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":4,"message":10}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"broadcast_ok","msg_id":5,"in_reply_to":4}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":6}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"read_ok","msg_id":7,"in_reply_to":6,"messages":[10]}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"error","in_reply_to":6,"code":11,"text":"busy"}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"gossip","seq":1,"messages":[11,12]}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"gossip_ok","ack":1,"sack":[3]}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"push","rumors":[{"message":13,"hops":1}]}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"push_ok","known":[10]}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"pull","messages":[]}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"pull_ok","rumors":[{"message":14,"hops":2}]}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"eager_push","message":15,"round":1}}"#,
+	r#"{"src":"n3","dest":"n1","body":{"type":"eager_push","message":15,"round":1}}"#,
+	r#"{"src":"n3","dest":"n1","body":{"type":"ihave","rumors":[{"message":16,"hops":1}]}}"#,
+	r#"{"src":"n3","dest":"n1","body":{"type":"graft","messages":[10,15]}}"#,
+	r#"{"src":"n3","dest":"n1","body":{"type":"prune"}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"join"}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"forward_join","node":"n3","ttl":2}}"#,
+	r#"{"src":"n3","dest":"n1","body":{"type":"neighbor","high_priority":false}}"#,
+	r#"{"src":"n3","dest":"n1","body":{"type":"neighbor_ok","accepted":true}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"shuffle","origin":"n2","nodes":["n2","n3"],"ttl":1}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"shuffle_ok","nodes":["n3"]}}"#,
+	r#"{"src":"n3","dest":"n1","body":{"type":"disconnect"}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"ping"}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"snapshot_offer","values":2}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"snapshot_chunk","version":0,"index":0,"total":2,"checksum":1,"messages":[17]}}"#,
+	r#"{"src":"n3","dest":"n1","body":{"type":"snapshot_request","version":null,"chunks":[]}}"#,
+	r#"{"src":"n3","dest":"n1","body":{"type":"snapshot_ok","version":0}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"causal_push","messages":[{"message":18,"origin":"n2","clock":{"n2":1}}]}}"#,
+	r#"{"src":"n3","dest":"n1","body":{"type":"causal_sync","clock":{"n3":2}}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"sequence","message":19}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"sequenced","epoch":1,"start":0,"messages":[19]}}"#,
+	r#"{"src":"n3","dest":"n1","body":{"type":"sequence_sync","from":0}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"sequence_heartbeat","epoch":1,"length":2}}"#,
+	r#"{"src":"n3","dest":"n1","body":{"type":"takeover","epoch":2}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"takeover_ok","epoch":3,"messages":[19]}}"#,
+];
+
+/// The requests among `MESSAGES`.
+const REQUESTS: [u64; 3] = [2, 4, 6];
+
+/// Every gossip mode, HyParView membership, a tuned topology, each ordering
+/// of concurrently handled messages, causal and total order delivery.
+const CONFIGS: &[&[(&str, &str)]] = &[
+	&[("GOSSIP_MODE", "random")],
+	&[("GOSSIP_MODE", "push")],
+	&[("GOSSIP_MODE", "pull")],
+	&[("GOSSIP_MODE", "push-pull")],
+	&[("GOSSIP_MODE", "eager")],
+	&[("GOSSIP_MODE", "plumtree")],
+	&[("GOSSIP_MODE", "plumtree"), ("MEMBERSHIP", "hyparview")],
+	&[
+		("GOSSIP_MODE", "eager"),
+		("GOSSIP_INTERVAL", "100"),
+		("TOPOLOGY", "tree"),
+		("FANOUT", "2"),
+		("BATCH_SIZE", "1"),
+	],
+	&[("ORDER_BY", "key"), ("CONCURRENCY", "8")],
+	&[("ORDER_BY", "none"), ("CONCURRENCY", "1")],
+	&[("DELIVERY", "causal")],
+	&[("DELIVERY", "total")],
+];
+
+/// Whether the node sent a message to `dest` with `body`.
+fn sent(output: &[Value], dest: &str, body: Value) -> bool {
+	output
+		.iter()
+		.any(|m| m["dest"] == dest && m["body"] == body)
+}
+
+#[test]
+fn stdout_only_carries_messages() {
+	for config in CONFIGS {
+		let stdout = harness::run_node(BIN, config, MESSAGES, &REQUESTS);
+
+		assert!(
+			!stdout.is_empty(),
+			"Node with {:?} answered nothing",
+			config
+		);
+
+		for line in stdout.lines() {
+			serde_json::from_str::<Message>(line)
+				.unwrap_or_else(|e| panic!("{:?} with {:?} is not a message: {}", line, config, e));
+		}
+	}
+}
+
+#[test]
+fn joining_node_starts_with_a_single_slot_writer_queue() {
+	// n3 queues `init_ok` and its HyParView join before it reads anything else
+	let init = INIT.replace(r#""node_id":"n1""#, r#""node_id":"n3""#);
+	let env = [("MEMBERSHIP", "hyparview"), ("QUEUE_CAPACITY", "1")];
+
+	let mut node = Node::start_with(BIN, &env, &init);
+	node.run_until(|output| sent(output, "n1", serde_json::json!({"type": "join"})));
+}
+
+#[test]
+fn reads_see_earlier_broadcasts_from_the_same_source() {
+	let messages: Vec<String> = MESSAGES.iter().map(|m| m.to_string()).collect();
+	let stdout = run_node_until(
+		BIN,
+		&[("ORDER_BY", "source"), ("CONCURRENCY", "8")],
+		&messages,
+		|output| answered(output, [6]),
+	);
+
+	let read_ok = stdout
+		.lines()
+		.find(|line| line.contains(r#""type":"read_ok""#))
+		.expect("Node did not answer the read");
+	let read_ok: serde_json::Value = serde_json::from_str(read_ok).unwrap();
+
+	assert!(read_ok["body"]["messages"]
+		.as_array()
+		.unwrap()
+		.contains(&serde_json::json!(10)));
+}
+
+#[test]
+fn overloaded_node_answers_every_request_once() {
+	for overload in ["block", "drop-gossip", "reject"] {
+		let env = [
+			("OVERLOAD", overload),
+			("QUEUE_CAPACITY", "1"),
+			("CONCURRENCY", "1"),
+		];
+		harness::assert_overload_answered_once(BIN, &env);
+	}
+}
+
+#[test]
+fn restarted_node_recovers_what_it_knew() {
+	let learned: Vec<String> = [
+		r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":2,"message":1}}"#,
+		r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":3,"message":2}}"#,
+		r#"{"src":"n2","dest":"n1","body":{"type":"gossip","seq":1,"messages":[3,4]}}"#,
+		r#"{"src":"n2","dest":"n1","body":{"type":"push","rumors":[{"message":5,"hops":1}]}}"#,
+	]
+	.iter()
+	.map(|m| m.to_string())
+	.collect();
+	let read = vec![r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":4}}"#.to_string()];
+
+	// Both straight from the log and from a snapshot plus the rest of the log
+	for snapshot_every in ["1000", "3"] {
+		let dir = std::env::temp_dir().join(format!(
+			"ch03e-recovery-{}-{}",
+			std::process::id(),
+			snapshot_every
+		));
+		let _ = std::fs::remove_dir_all(&dir);
+
+		let env = [
+			("DATA_DIR", dir.to_str().unwrap()),
+			("SNAPSHOT_EVERY", snapshot_every),
+		];
+		// Replies only go out once what they changed is in the log
+		run_node_until(BIN, &env, &learned, |output| {
+			answered(output, [2, 3])
+				&& sent(
+					output,
+					"n2",
+					serde_json::json!({"type": "gossip_ok", "ack": 1, "sack": []}),
+				) && output
+				.iter()
+				.any(|m| m["dest"] == "n2" && m["body"]["type"] == "push_ok")
+		});
+		let stdout = run_node_until(BIN, &env, &read, |output| answered(output, [4]));
+		let _ = std::fs::remove_dir_all(&dir);
+
+		let read_ok = stdout
+			.lines()
+			.find(|line| line.contains(r#""type":"read_ok""#))
+			.expect("Node did not answer the read");
+		let read_ok: serde_json::Value = serde_json::from_str(read_ok).unwrap();
+
+		let mut messages: Vec<u64> = read_ok["body"]["messages"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.map(|m| m.as_u64().unwrap())
+			.collect();
+		messages.sort();
+
+		assert_eq!(
+			messages,
+			[1, 2, 3, 4, 5],
+			"Snapshot every {}",
+			snapshot_every
+		);
+	}
+}
+
+/// FNV-1a over the little-endian bytes of each value, like the node computes it.
+fn checksum(messages: &[u64]) -> u64 {
+	messages
+		.iter()
+		.flat_map(|m| m.to_le_bytes())
+		.fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+			(hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+		})
+}
+
+#[test]
+fn node_exits_on_interrupt() {
+	let mut node = Node::start(BIN, &[]);
+	node.send(&[MESSAGES[1].to_string()]);
+	node.run_until(|output| answered(output, [2]));
+
+	// Stdin stays open, only the signal may end the node
+	node.interrupt();
+
+	assert!(node.wait().success());
+}
+
+#[test]
+fn node_stops_once_it_cannot_write_its_log() {
+	let dir = std::env::temp_dir().join(format!("ch03e-failing-log-{}", std::process::id()));
+	let _ = std::fs::remove_dir_all(&dir);
+	let env = [("DATA_DIR", dir.to_str().unwrap()), ("SNAPSHOT_EVERY", "1")];
+
+	let mut node = Node::start(BIN, &env);
+	// The snapshot after the next change has nowhere to go
+	std::fs::remove_dir_all(&dir).unwrap();
+	node.send(&[
+		r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":2,"message":1}}"#
+			.to_string(),
+	]);
+
+	assert!(!node.wait().success());
+	assert!(
+		node.stdout
+			.iter()
+			.all(|line| !line.contains("broadcast_ok")),
+		"Node acknowledged a change it could not log"
+	);
+}
+
+#[test]
+fn lagging_peer_catches_up_from_a_snapshot() {
+	let mut messages: Vec<String> = (1..=5)
+		.map(|i| {
+			format!(
+				r#"{{"src":"c1","dest":"n1","body":{{"type":"broadcast","msg_id":{},"message":{}}}}}"#,
+				i + 1,
+				i
+			)
+		})
+		.collect();
+	messages.extend([
+		r#"{"src":"n2","dest":"n1","body":{"type":"snapshot_request","version":null,"chunks":[]}}"#
+			.to_string(),
+		r#"{"src":"n2","dest":"n1","body":{"type":"snapshot_ok","version":0}}"#.to_string(),
+		format!(
+			r#"{{"src":"n3","dest":"n1","body":{{"type":"snapshot_chunk","version":0,"index":0,"total":2,"checksum":{},"messages":[41]}}}}"#,
+			checksum(&[41]) + 1
+		),
+		format!(
+			r#"{{"src":"n3","dest":"n1","body":{{"type":"snapshot_chunk","version":0,"index":0,"total":2,"checksum":{},"messages":[42]}}}}"#,
+			checksum(&[42])
+		),
+		format!(
+			r#"{{"src":"n3","dest":"n1","body":{{"type":"snapshot_chunk","version":0,"index":1,"total":2,"checksum":{},"messages":[43]}}}}"#,
+			checksum(&[43])
+		),
+		r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":20}}"#.to_string(),
+	]);
+
+	let env = [
+		("GOSSIP_MODE", "random"),
+		("GOSSIP_INTERVAL", "100"),
+		("CATCH_UP_THRESHOLD", "2"),
+		("CHUNK_SIZE", "2"),
+		("CONCURRENCY", "1"),
+	];
+	let stdout = run_node_until(BIN, &env, &messages, |output| {
+		answered(output, [20])
+			&& output
+				.iter()
+				.any(|m| m["dest"] == "n3" && m["body"]["type"] == "snapshot_ok")
+			&& output
+				.iter()
+				.filter(|m| m["dest"] == "n2" && m["body"]["type"] == "snapshot_chunk")
+				.count() == 3
+	});
+	let output: Vec<serde_json::Value> = stdout
+		.lines()
+		.map(|line| serde_json::from_str(line).unwrap())
+		.collect();
+
+	// The snapshot arrives in checksummed chunks of at most `CHUNK_SIZE` values
+	let mut sent: Vec<u64> = vec![];
+	for chunk in output
+		.iter()
+		.filter(|m| m["dest"] == "n2" && m["body"]["type"] == "snapshot_chunk")
+	{
+		let values: Vec<u64> = chunk["body"]["messages"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.map(|m| m.as_u64().unwrap())
+			.collect();
+
+		assert!(values.len() <= 2);
+		assert_eq!(chunk["body"]["checksum"], checksum(&values));
+		sent.extend(values);
+	}
+	sent.sort();
+	assert_eq!(sent, [1, 2, 3, 4, 5]);
+
+	// After confirming the snapshot the peer only gets gossip about later values
+	for gossip in output
+		.iter()
+		.filter(|m| m["dest"] == "n2" && m["body"]["type"] == "gossip")
+	{
+		for value in gossip["body"]["messages"].as_array().unwrap() {
+			assert!(!sent.contains(&value.as_u64().unwrap()));
+		}
+	}
+
+	// A corrupted chunk is dropped, the snapshot completes with a good copy
+	assert!(output
+		.iter()
+		.any(|m| m["dest"] == "n3" && m["body"]["type"] == "snapshot_ok"));
+
+	let read_ok = output
+		.iter()
+		.find(|m| m["body"]["type"] == "read_ok")
+		.expect("Node did not answer the read");
+	let read: Vec<u64> = read_ok["body"]["messages"]
+		.as_array()
+		.unwrap()
+		.iter()
+		.map(|m| m.as_u64().unwrap())
+		.collect();
+
+	assert!(read.contains(&42) && read.contains(&43) && !read.contains(&41));
+}
+
+#[test]
+fn causal_delivery_waits_for_dependencies() {
+	let messages: Vec<String> = [
+		r#"{"src":"c1","dest":"n1","body":{"type":"topology","msg_id":2,"topology":{"n1":["n2","n3"],"n2":["n1"],"n3":["n1"]}}}"#,
+		// Broadcast by n2 after it delivered n3's first value
+		r#"{"src":"n2","dest":"n1","body":{"type":"causal_push","messages":[{"message":21,"origin":"n2","clock":{"n2":1,"n3":1}}]}}"#,
+		r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":3}}"#,
+		r#"{"src":"n3","dest":"n1","body":{"type":"causal_push","messages":[{"message":20,"origin":"n3","clock":{"n3":1}}]}}"#,
+		r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":4,"message":22}}"#,
+		r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":5}}"#,
+	]
+	.iter()
+	.map(|m| m.to_string())
+	.collect();
+
+	let env = [("DELIVERY", "causal"), ("CONCURRENCY", "1")];
+	let stdout = run_node_until(BIN, &env, &messages, |output| {
+		answered(output, [3, 5])
+			&& output.iter().any(|m| {
+				m["dest"] == "n2"
+					&& m["body"]["type"] == "causal_push"
+					&& m["body"]["messages"][0]["message"] == 22
+			})
+	});
+	let output: Vec<serde_json::Value> = stdout
+		.lines()
+		.map(|line| serde_json::from_str(line).unwrap())
+		.collect();
+
+	let reads: Vec<&serde_json::Value> = output
+		.iter()
+		.filter(|m| m["body"]["type"] == "read_ok")
+		.map(|m| &m["body"]["messages"])
+		.collect();
+
+	assert_eq!(reads.len(), 2, "Node did not answer both reads");
+	assert_eq!(reads[0], &serde_json::json!([]));
+	assert_eq!(reads[1], &serde_json::json!([20, 21, 22]));
+
+	// Our own broadcast depends on everything delivered before it
+	let pushed: Vec<&serde_json::Value> = output
+		.iter()
+		.filter(|m| m["body"]["type"] == "causal_push" && m["dest"] == "n2")
+		.flat_map(|m| m["body"]["messages"].as_array().unwrap())
+		.filter(|m| m["message"] == 22)
+		.collect();
+
+	assert_eq!(pushed.len(), 1, "Node did not push its broadcast once");
+	assert_eq!(
+		pushed[0]["clock"],
+		serde_json::json!({"n1": 1, "n2": 1, "n3": 1})
+	);
+}
+
+#[test]
+fn total_order_follows_the_sequencer() {
+	let messages: Vec<String> = [
+		// n2 numbers values in epoch 1, the second one overtakes the first
+		r#"{"src":"n2","dest":"n1","body":{"type":"sequenced","epoch":1,"start":1,"messages":[41],"delivered":0}}"#,
+		r#"{"src":"n2","dest":"n1","body":{"type":"sequenced","epoch":1,"start":0,"messages":[40],"delivered":0}}"#,
+		// n3 is not the sequencer of epoch 0 and epoch 0 is over anyway
+		r#"{"src":"n3","dest":"n1","body":{"type":"sequenced","epoch":0,"start":2,"messages":[99],"delivered":3}}"#,
+		// Once a majority stored both, n2 tells us they are delivered
+		r#"{"src":"n2","dest":"n1","body":{"type":"sequence_heartbeat","epoch":1,"length":2,"delivered":2}}"#,
+		r#"{"src":"c1","dest":"n1","body":{"type":"broadcast","msg_id":2,"message":42}}"#,
+		r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":3}}"#,
+	]
+	.iter()
+	.map(|m| m.to_string())
+	.collect();
+
+	let env = [
+		("DELIVERY", "total"),
+		("CONCURRENCY", "1"),
+		("GOSSIP_INTERVAL", "50"),
+		("GOSSIP_JITTER", "0"),
+	];
+	let takeover = serde_json::json!({"type": "takeover", "epoch": 3});
+	let stdout = run_node_until(BIN, &env, &messages, |output| {
+		answered(output, [3])
+			&& sent(output, "n2", takeover.clone())
+			&& sent(output, "n3", takeover.clone())
+	});
+	let output: Vec<serde_json::Value> = stdout
+		.lines()
+		.map(|line| serde_json::from_str(line).unwrap())
+		.collect();
+
+	let read_ok = output
+		.iter()
+		.find(|m| m["body"]["type"] == "read_ok")
+		.expect("Node did not answer the read");
+	assert_eq!(read_ok["body"]["messages"], serde_json::json!([40, 41]));
+
+	// The sequencer learns how many values we stored
+	assert!(output.iter().any(|m| m["dest"] == "n2"
+		&& m["body"] == serde_json::json!({"type": "sequenced_ok", "epoch": 1, "length": 2})));
+
+	// A broadcast goes to the sequencer to be numbered
+	assert!(output.iter().any(|m| m["dest"] == "n2"
+		&& m["body"] == serde_json::json!({"type": "sequence", "message": 42})));
+
+	// Without heartbeats the node fails over through n3 to itself, and asks
+	// the others for their logs before numbering anything
+	for peer in ["n2", "n3"] {
+		assert!(
+			output.iter().any(|m| m["dest"] == peer
+				&& m["body"] == serde_json::json!({"type": "takeover", "epoch": 3})),
+			"No takeover sent to {}",
+			peer
+		);
+	}
+}
@@ -1,189 +1,106 @@
-use std::io::{self, BufRead, Write};
+// Stdout carries the Maelstrom protocol, only the message writer may touch it.
+#![deny(clippy::print_stdout)]
 
-use serde::{Deserialize, Serialize};
+mod message;
+mod node;
 
-const SEQ_KV: &str = "seq-kv";
-const KEY: &str = "counter";
+use std::{
+	io::{self, BufRead},
+	sync::mpsc::{self, RecvTimeoutError},
+	thread,
+	time::{Duration, Instant},
+};
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Message {
-	src: String,
-	dest: String,
-	body: Body,
+use clap::{Parser, ValueEnum};
+use tracing::{info_span, warn};
+
+use crate::{message::Message, node::Node};
+
+/// How reads treat the counter kept in `seq-kv`, also settable as e.g.
+/// `READ_MODE=converge`.
+#[derive(Parser, Debug)]
+struct Config {
+	/// How reads see adds made through other nodes: fresh or converge.
+	#[arg(long, env = "READ_MODE", value_enum, default_value_t = ReadMode::Fresh)]
+	read_mode: ReadMode,
+	/// Milliseconds between re-reads of the counter in the convergence mode.
+	#[arg(long, env = "CONVERGE_INTERVAL", default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..))]
+	converge_interval: u64,
+	/// Re-reads in a row which must agree before the convergence mode answers reads.
+	#[arg(long, env = "STABLE_READS", default_value_t = 3, value_parser = clap::value_parser!(u64).range(1..))]
+	stable_reads: u64,
+	/// Milliseconds a request to `seq-kv` waits for its answer before it is resent.
+	#[arg(long, env = "REQUEST_TIMEOUT", default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+	request_timeout: u64,
+	/// Milliseconds a read in the convergence mode waits for the counter to
+	/// be stable before it reads the counter fresh instead.
+	#[arg(long, env = "HOLD_TIMEOUT", default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+	hold_timeout: u64,
+	/// Log filter, e.g. `debug` or `ch4_grow_only_counter=trace`.
+	#[arg(long, env = "RUST_LOG", default_value = "info", value_parser = logging::parse_filter)]
+	log_level: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-enum Body {
-	Error {
-		in_reply_to: u64,
-		code: u64,
-		text: String,
-	},
-	Init {
-		msg_id: u64,
-		node_id: String,
-		node_ids: Vec<String>,
-	},
-	InitOk {
-		in_reply_to: u64,
-	},
-	Read {
-		msg_id: Option<u64>,
-		key: Option<String>,
-	},
-	ReadOk {
-		in_reply_to: Option<u64>,
-		value: u64,
-	},
-	Add {
-		msg_id: u64,
-		delta: u64,
-	},
-	AddOk {
-		in_reply_to: u64,
-	},
-	Write {
-		msg_id: u64,
-		key: String,
-		value: u64,
-	},
-	WriteOk {
-		in_reply_to: u64,
-	},
-	Cas {
-		msg_id: u64,
-		key: String,
-		from: u64,
-		to: u64,
-	},
-	CasOk {
-		msg_id: u64,
-		in_reply_to: u64,
-	},
+/// `seq-kv` may serve a node a stale counter for as long as it likes, unless
+/// the node wrote something since.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ReadMode {
+	/// Each read writes a dummy key first, then reads the counter.
+	Fresh,
+	/// The node re-reads the counter all along, and answers reads from the
+	/// last value once enough re-reads in a row agreed on it.
+	Converge,
 }
 
 fn main() {
-	let stdin = io::stdin();
-	let mut stdout = io::stdout();
-	let mut stderr = io::stderr();
+	let config = Config::parse();
+	logging::init(&config.log_level);
 
-	let mut id = String::new();
-	let mut counter = 0;
-	let mut tmp_counter = 0;
+	// Lines arrive on their own thread so the loop can wake up for ticks
+	let (lines_tx, lines_rx) = mpsc::channel();
+	thread::spawn(move || {
+		for line in io::stdin().lock().lines() {
+			if lines_tx.send(line.unwrap()).is_err() {
+				break;
+			}
+		}
+	});
 
-	for line in stdin.lock().lines() {
-		let input = serde_json::from_str::<Message>(&line.as_ref().unwrap());
+	let interval = Duration::from_millis(config.converge_interval);
+	let mut next_tick = Instant::now() + interval;
+	let mut node = Node::new(
+		config.read_mode,
+		config.stable_reads,
+		Duration::from_millis(config.request_timeout),
+		Duration::from_millis(config.hold_timeout),
+	);
 
-		if let Err(e) = input {
-			writeln!(stderr, "Error: {:?}", e).unwrap();
-			stderr.flush().unwrap();
-			continue;
+	loop {
+		// Ticks resend lost requests, so a busy stdin must not hold them off
+		if Instant::now() >= next_tick {
+			node.tick();
+			next_tick = Instant::now() + interval;
 		}
-		let input: Message = serde_json::from_str(&line.unwrap()).unwrap();
-
-		match input.body {
-			Body::Error { code, .. } => match code {
-				22 => {
-					let output = Message {
-						src: id.clone(),
-						dest: SEQ_KV.to_string(),
-						body: Body::Read {
-							msg_id: Some(2),
-							key: Some(KEY.to_string()),
-						},
-					};
-					let output_json = serde_json::to_string(&output).unwrap();
-					writeln!(stdout, "{}", output_json).unwrap();
-					stdout.flush().unwrap();
-				}
-				_ => {
-					writeln!(stderr, "Error: {:?}", input).unwrap();
-					stderr.flush().unwrap();
-				}
-			},
-			Body::Init {
-				msg_id, node_id, ..
-			} => {
-				id = node_id.clone();
-				let output = Message {
-					src: id.clone(),
-					dest: input.src,
-					body: Body::InitOk {
-						in_reply_to: msg_id,
-					},
-				};
-				let output_json = serde_json::to_string(&output).unwrap();
-				writeln!(stdout, "{}", output_json).unwrap();
 
-				let output = Message {
-					src: id.clone(),
-					dest: SEQ_KV.to_string(),
-					body: Body::Write {
-						msg_id,
-						key: KEY.to_string(),
-						value: counter,
-					},
-				};
-				let output_json = serde_json::to_string(&output).unwrap();
-				writeln!(stdout, "{}", output_json).unwrap();
-				stdout.flush().unwrap();
-			}
-			Body::Read { msg_id, .. } => {
-				let output = Message {
-					src: id.clone(),
-					dest: input.src,
-					body: Body::ReadOk {
-						in_reply_to: msg_id,
-						value: counter,
-					},
-				};
-				let output_json = serde_json::to_string(&output).unwrap();
-				writeln!(stdout, "{}", output_json).unwrap();
+		let line = match lines_rx.recv_timeout(next_tick.saturating_duration_since(Instant::now()))
+		{
+			Ok(line) => line,
+			Err(RecvTimeoutError::Timeout) => continue,
+			Err(RecvTimeoutError::Disconnected) => break,
+		};
 
-				stdout.flush().unwrap();
+		let input = match serde_json::from_str::<Message>(&line) {
+			Ok(input) => input,
+			Err(e) => {
+				warn!("Malformed message: {:?}", e);
+				continue;
 			}
-			Body::Add { msg_id, delta } => {
-				tmp_counter += delta;
+		};
 
-				let output = Message {
-					src: id.clone(),
-					dest: SEQ_KV.to_string(),
-					body: Body::Cas {
-						msg_id,
-						key: KEY.to_string(),
-						from: counter,
-						to: tmp_counter,
-					},
-				};
-				let output_json = serde_json::to_string(&output).unwrap();
-				writeln!(stdout, "{}", output_json).unwrap();
+		let (body_type, msg_id) = logging::header(&input.body);
+		let span = info_span!("message", node_id = %node.id, msg_id, body_type = %body_type);
+		let _enter = span.enter();
 
-				let output = Message {
-					src: id.clone(),
-					dest: input.src,
-					body: Body::AddOk {
-						in_reply_to: msg_id,
-					},
-				};
-				let output_json = serde_json::to_string(&output).unwrap();
-				writeln!(stdout, "{}", output_json).unwrap();
-				stdout.flush().unwrap();
-			}
-			Body::WriteOk { .. } => {
-				//
-			}
-			Body::AddOk { .. } => {
-				//
-			}
-			Body::CasOk { .. } => {
-				counter = tmp_counter;
-			}
-			Body::ReadOk { value, .. } => {
-				counter = value;
-			}
-			_ => println!("Unhandled message: {:?}", input),
-		}
+		node.handle(input);
 	}
 }
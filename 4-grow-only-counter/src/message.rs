@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Message {
+	pub src: String,
+	pub dest: String,
+	pub body: Body,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Body {
+	Error {
+		in_reply_to: u64,
+		code: u64,
+		text: String,
+	},
+	Init {
+		msg_id: u64,
+		node_id: String,
+		node_ids: Vec<String>,
+	},
+	InitOk {
+		in_reply_to: u64,
+	},
+	Read {
+		msg_id: Option<u64>,
+		key: Option<String>,
+	},
+	ReadOk {
+		in_reply_to: Option<u64>,
+		value: u64,
+	},
+	Add {
+		msg_id: u64,
+		delta: u64,
+	},
+	AddOk {
+		in_reply_to: u64,
+	},
+	Write {
+		msg_id: u64,
+		key: String,
+		value: u64,
+	},
+	WriteOk {
+		in_reply_to: u64,
+	},
+	Cas {
+		msg_id: u64,
+		key: String,
+		from: u64,
+		to: u64,
+		/// Compare a missing key as if it held `from`.
+		#[serde(default)]
+		create_if_not_exists: bool,
+	},
+	CasOk {
+		msg_id: u64,
+		in_reply_to: u64,
+	},
+	/// Asks a peer for the highest counter it saw, sent by a (re)started node.
+	SnapshotRequest {},
+	/// The whole state of a counter node is one number, so its snapshot fits
+	/// in a single message.
+	Snapshot {
+		value: u64,
+	},
+}
@@ -0,0 +1,390 @@
+use std::{
+	collections::HashMap,
+	io::{self, Write},
+	time::{Duration, Instant},
+};
+
+use tracing::{debug, warn};
+
+use crate::{
+	message::{Body, Message},
+	ReadMode,
+};
+
+const SEQ_KV: &str = "seq-kv";
+const KEY: &str = "counter";
+
+/// Maelstrom's `timeout` error, the request may or may not have taken effect.
+const TIMEOUT: u64 = 0;
+/// Maelstrom's `key-does-not-exist` error.
+const KEY_DOES_NOT_EXIST: u64 = 20;
+/// Maelstrom's `precondition-failed` error.
+const PRECONDITION_FAILED: u64 = 22;
+
+/// A client request, by who sent it and its `msg_id`.
+type Client = (String, u64);
+
+/// Why we read the counter from `seq-kv`.
+#[derive(Debug)]
+enum Purpose {
+	/// A client asked for the value.
+	Client(Client),
+	/// The periodic re-read of the convergence mode.
+	Refresh,
+	/// A `cas` failed, the adds it carried wait for the current value.
+	Retry,
+}
+
+/// A request of ours to `seq-kv`, by its `msg_id`.
+#[derive(Debug)]
+enum Request {
+	/// The dummy write which makes the read after it fresh: `seq-kv` never
+	/// shows a node a state older than its own last write.
+	Fence(Purpose),
+	Read(Purpose),
+	/// Adds the deltas of `adds`, which are acknowledged once it succeeds.
+	Cas {
+		delta: u64,
+		to: u64,
+		adds: Vec<Client>,
+	},
+}
+
+pub(crate) struct Node {
+	pub(crate) id: String,
+	read_mode: ReadMode,
+	stable_reads: u64,
+	next_msg_id: u64,
+	/// The highest value of the counter we saw.
+	counter: u64,
+	/// Sum of the deltas of `adds`, which are not in `seq-kv` yet.
+	pending: u64,
+	adds: Vec<Client>,
+	/// A `cas`, or the read after a failed one, is in flight. There is never
+	/// more than one, so each compares against what the last one left.
+	flushing: bool,
+	/// Requests waiting for an answer, and when they were sent.
+	requests: HashMap<u64, (Request, Instant)>,
+	/// How long a request waits for its answer before we give up on it.
+	request_timeout: Duration,
+	/// Refreshes in a row which read the same value, and the value.
+	stable: u64,
+	last_refresh: Option<u64>,
+	refreshing: bool,
+	/// Client reads waiting for the value to be stable, and since when.
+	held: Vec<(Client, Instant)>,
+	/// How long a read waits for the value to be stable before it reads fresh.
+	hold_timeout: Duration,
+}
+
+impl Node {
+	pub(crate) fn new(
+		read_mode: ReadMode,
+		stable_reads: u64,
+		request_timeout: Duration,
+		hold_timeout: Duration,
+	) -> Node {
+		Node {
+			id: String::new(),
+			read_mode,
+			stable_reads,
+			next_msg_id: 0,
+			counter: 0,
+			pending: 0,
+			adds: vec![],
+			flushing: false,
+			requests: HashMap::new(),
+			request_timeout,
+			stable: 0,
+			last_refresh: None,
+			refreshing: false,
+			held: vec![],
+			hold_timeout,
+		}
+	}
+
+	fn send(&self, dest: &str, body: Body) {
+		let output = Message {
+			src: self.id.clone(),
+			dest: dest.to_string(),
+			body,
+		};
+		let output_json = serde_json::to_string(&output).unwrap();
+
+		let mut stdout = io::stdout().lock();
+		writeln!(stdout, "{}", output_json).unwrap();
+		stdout.flush().unwrap();
+	}
+
+	fn request(&mut self, body: impl FnOnce(u64) -> Body, request: Request) {
+		self.next_msg_id += 1;
+		self.send(SEQ_KV, body(self.next_msg_id));
+		self.requests
+			.insert(self.next_msg_id, (request, Instant::now()));
+	}
+
+	fn take_request(&mut self, msg_id: u64) -> Option<Request> {
+		self.requests.remove(&msg_id).map(|(request, _)| request)
+	}
+
+	/// Reads the counter after a dummy write with a value no other write
+	/// had, so the read sees at least every add acknowledged before it.
+	fn fenced_read(&mut self, purpose: Purpose) {
+		let key = format!("fence-{}", self.id);
+		self.request(
+			|msg_id| Body::Write {
+				msg_id,
+				key,
+				value: msg_id,
+			},
+			Request::Fence(purpose),
+		);
+	}
+
+	fn read(&mut self, purpose: Purpose) {
+		self.request(
+			|msg_id| Body::Read {
+				msg_id: Some(msg_id),
+				key: Some(KEY.to_string()),
+			},
+			Request::Read(purpose),
+		);
+	}
+
+	/// Moves the waiting adds into `seq-kv` with a single `cas`.
+	fn flush(&mut self) {
+		if self.flushing || self.adds.is_empty() {
+			return;
+		}
+
+		let from = self.counter;
+		let to = self.counter + self.pending;
+		let request = Request::Cas {
+			delta: self.pending,
+			to,
+			adds: std::mem::take(&mut self.adds),
+		};
+
+		self.flushing = true;
+		self.pending = 0;
+		self.request(
+			|msg_id| Body::Cas {
+				msg_id,
+				key: KEY.to_string(),
+				from,
+				to,
+				// The first add of the cluster creates the counter
+				create_if_not_exists: from == 0,
+			},
+			request,
+		);
+	}
+
+	/// Once enough refreshes in a row agree, answers the reads held for it.
+	fn answer_held(&mut self) {
+		if self.stable < self.stable_reads {
+			return;
+		}
+
+		for ((src, msg_id), _) in std::mem::take(&mut self.held) {
+			self.reply_read(src, msg_id);
+		}
+	}
+
+	/// A `cas` which failed without a definite answer may still have added
+	/// its deltas, so adding them again could count them twice. Their clients
+	/// learn that we do not know, and we read where the counter is now.
+	fn abandon(&mut self, delta: u64, adds: Vec<Client>) {
+		warn!(delta, "A cas may or may not have taken effect");
+
+		for (src, msg_id) in adds {
+			self.send(
+				&src,
+				Body::Error {
+					in_reply_to: msg_id,
+					code: TIMEOUT,
+					text: "the add may or may not have taken effect".to_string(),
+				},
+			);
+		}
+
+		self.read(Purpose::Retry);
+	}
+
+	fn reply_read(&self, src: String, msg_id: u64) {
+		self.send(
+			&src,
+			Body::ReadOk {
+				in_reply_to: Some(msg_id),
+				value: self.counter,
+			},
+		);
+	}
+
+	/// Re-reads the counter in the convergence mode, resends requests `seq-kv`
+	/// did not answer and reads fresh for reads held too long.
+	pub(crate) fn tick(&mut self) {
+		self.tick_at(Instant::now());
+	}
+
+	fn tick_at(&mut self, now: Instant) {
+		let expired: Vec<u64> = self
+			.requests
+			.iter()
+			.filter(|(_, (_, sent))| now.duration_since(*sent) >= self.request_timeout)
+			.map(|(msg_id, _)| *msg_id)
+			.collect();
+
+		for msg_id in expired {
+			let Some(request) = self.take_request(msg_id) else {
+				continue;
+			};
+			debug!(msg_id, "No answer from seq-kv for {:?}", request);
+
+			match request {
+				Request::Fence(purpose) => self.fenced_read(purpose),
+				Request::Read(purpose) => self.read(purpose),
+				Request::Cas { delta, adds, .. } => self.abandon(delta, adds),
+			}
+		}
+
+		// Adds keep the value from being stable, so a read only waits so long
+		let (late, held) = std::mem::take(&mut self.held)
+			.into_iter()
+			.partition(|(_, since)| now.duration_since(*since) >= self.hold_timeout);
+		self.held = held;
+
+		for (client, _) in late {
+			self.fenced_read(Purpose::Client(client));
+		}
+
+		if self.read_mode == ReadMode::Converge && !self.id.is_empty() && !self.refreshing {
+			self.refreshing = true;
+			self.fenced_read(Purpose::Refresh);
+		}
+	}
+
+	pub(crate) fn handle(&mut self, input: Message) {
+		match input.body {
+			Body::Init {
+				msg_id,
+				node_id,
+				node_ids,
+			} => {
+				self.id = node_id;
+				self.send(
+					&input.src,
+					Body::InitOk {
+						in_reply_to: msg_id,
+					},
+				);
+
+				// A (re)started node catches up from its peers instead of starting at zero
+				for peer in node_ids.iter().filter(|n| **n != self.id) {
+					self.send(peer, Body::SnapshotRequest {});
+				}
+			}
+			Body::SnapshotRequest {} => {
+				self.send(
+					&input.src,
+					Body::Snapshot {
+						value: self.counter,
+					},
+				);
+			}
+			// The counter only grows, a snapshot can never take it back. There
+			// is no gossip to resume afterwards, `seq-kv` holds the counter.
+			Body::Snapshot { value } => self.counter = self.counter.max(value),
+			Body::Read {
+				msg_id: Some(msg_id),
+				..
+			} => match self.read_mode {
+				ReadMode::Fresh => self.fenced_read(Purpose::Client((input.src, msg_id))),
+				ReadMode::Converge => {
+					self.held.push(((input.src, msg_id), Instant::now()));
+					self.answer_held();
+				}
+			},
+			Body::Add { msg_id, delta } => {
+				self.pending += delta;
+				self.adds.push((input.src, msg_id));
+				self.stable = 0;
+				self.flush();
+			}
+			Body::WriteOk { in_reply_to } => match self.take_request(in_reply_to) {
+				Some(Request::Fence(purpose)) => self.read(purpose),
+				other => debug!(in_reply_to, "Unexpected write_ok for {:?}", other),
+			},
+			Body::ReadOk {
+				in_reply_to: Some(in_reply_to),
+				value,
+			} => match self.take_request(in_reply_to) {
+				Some(Request::Read(purpose)) => self.receive_read(purpose, value),
+				other => debug!(in_reply_to, "Unexpected read_ok for {:?}", other),
+			},
+			Body::CasOk { in_reply_to, .. } => match self.take_request(in_reply_to) {
+				Some(Request::Cas { to, adds, .. }) => {
+					self.counter = self.counter.max(to);
+					self.flushing = false;
+
+					for (src, msg_id) in adds {
+						self.send(
+							&src,
+							Body::AddOk {
+								in_reply_to: msg_id,
+							},
+						);
+					}
+
+					self.flush();
+				}
+				other => debug!(in_reply_to, "Unexpected cas_ok for {:?}", other),
+			},
+			Body::Error {
+				in_reply_to, code, ..
+			} => match self.take_request(in_reply_to) {
+				// Nobody added anything yet
+				Some(Request::Read(purpose)) if code == KEY_DOES_NOT_EXIST => {
+					self.receive_read(purpose, 0)
+				}
+				// Someone else added since we last read, the adds wait for
+				// the current value and go out with the next cas
+				Some(Request::Cas {
+					delta, mut adds, ..
+				}) if code == PRECONDITION_FAILED || code == KEY_DOES_NOT_EXIST => {
+					debug!(code, "Retrying cas of {}", delta);
+					self.pending += delta;
+					adds.append(&mut self.adds);
+					self.adds = adds;
+					self.read(Purpose::Retry);
+				}
+				Some(Request::Cas { delta, adds, .. }) => self.abandon(delta, adds),
+				// The read goes on without its fence, it is only less fresh
+				Some(Request::Fence(purpose)) => self.read(purpose),
+				other => warn!(code, "Error received for {:?}", other),
+			},
+			_ => warn!("Unhandled message: {:?}", input),
+		}
+	}
+
+	fn receive_read(&mut self, purpose: Purpose, value: u64) {
+		self.counter = self.counter.max(value);
+
+		match purpose {
+			Purpose::Client((src, msg_id)) => self.reply_read(src, msg_id),
+			Purpose::Refresh => {
+				self.refreshing = false;
+				self.stable = match self.last_refresh == Some(value) {
+					true => self.stable + 1,
+					false => 1,
+				};
+				self.last_refresh = Some(value);
+				self.answer_held();
+			}
+			Purpose::Retry => {
+				self.flushing = false;
+				self.flush();
+			}
+		}
+	}
+}
@@ -0,0 +1,184 @@
+//! Runs a cluster of nodes against an in-process `seq-kv` which serves stale
+//! reads and fails some `cas`, and checks that once the adds are
+//! acknowledged every node reads their sum.
+
+use std::{
+	collections::BTreeMap,
+	io::{BufRead, BufReader, Write},
+	process::{Child, ChildStdin, Command, Stdio},
+	sync::mpsc::{self, Receiver},
+	thread,
+	time::{Duration, Instant},
+};
+
+use kv_services::{Config, Services, PRECONDITION_FAILED};
+use serde_json::{json, Value};
+
+const CLIENT: &str = "c1";
+const NODES: usize = 3;
+const ADDS: u64 = 60;
+const TIMEOUT: Duration = Duration::from_secs(20);
+
+struct Cluster {
+	children: Vec<Child>,
+	stdins: BTreeMap<String, ChildStdin>,
+	lines: Receiver<String>,
+	services: Services,
+	/// Replies to `CLIENT`, by `in_reply_to`.
+	replies: BTreeMap<u64, Value>,
+}
+
+impl Cluster {
+	fn start(read_mode: &str, services: Services) -> Cluster {
+		let ids: Vec<String> = (1..=NODES).map(|i| format!("n{}", i)).collect();
+		let (lines_tx, lines) = mpsc::channel();
+		let mut children = vec![];
+		let mut stdins = BTreeMap::new();
+
+		for id in &ids {
+			let mut child = Command::new(env!("CARGO_BIN_EXE_ch4-grow-only-counter"))
+				.env("READ_MODE", read_mode)
+				.env("CONVERGE_INTERVAL", "10")
+				.stdin(Stdio::piped())
+				.stdout(Stdio::piped())
+				.stderr(Stdio::null())
+				.spawn()
+				.unwrap();
+
+			let stdout = BufReader::new(child.stdout.take().unwrap());
+			let lines_tx = lines_tx.clone();
+			thread::spawn(move || {
+				for line in stdout.lines() {
+					if lines_tx.send(line.unwrap()).is_err() {
+						break;
+					}
+				}
+			});
+
+			stdins.insert(id.clone(), child.stdin.take().unwrap());
+			children.push(child);
+		}
+
+		let mut cluster = Cluster {
+			children,
+			stdins,
+			lines,
+			services,
+			replies: BTreeMap::new(),
+		};
+
+		for (i, id) in ids.iter().enumerate() {
+			cluster.send(
+				"c0",
+				id,
+				json!({"type": "init", "msg_id": i, "node_id": id, "node_ids": ids}),
+			);
+		}
+
+		cluster
+	}
+
+	fn send(&mut self, src: &str, dest: &str, body: Value) {
+		let message = json!({"src": src, "dest": dest, "body": body});
+		self.deliver(dest, &message.to_string());
+	}
+
+	fn deliver(&mut self, dest: &str, line: &str) {
+		writeln!(self.stdins.get_mut(dest).unwrap(), "{}", line).unwrap();
+	}
+
+	/// Delivers what the nodes write until `done` holds for the replies.
+	fn run_until(&mut self, done: impl Fn(&BTreeMap<u64, Value>) -> bool) {
+		let deadline = Instant::now() + TIMEOUT;
+
+		while !done(&self.replies) {
+			let line = self
+				.lines
+				.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+				.unwrap_or_else(|_| panic!("Timed out with replies {:?}", self.replies));
+			let message: Value = serde_json::from_str(&line).unwrap();
+			let src = message["src"].as_str().unwrap().to_string();
+			let dest = message["dest"].as_str().unwrap().to_string();
+
+			if let Some(reply) = self.services.route_line(&line) {
+				self.deliver(&src, &reply);
+			} else if self.stdins.contains_key(&dest) {
+				self.deliver(&dest, &line);
+			} else if dest == CLIENT {
+				let in_reply_to = message["body"]["in_reply_to"].as_u64().unwrap();
+				self.replies.insert(in_reply_to, message["body"].clone());
+			}
+		}
+	}
+}
+
+impl Drop for Cluster {
+	fn drop(&mut self) {
+		for child in &mut self.children {
+			let _ = child.kill();
+			let _ = child.wait();
+		}
+	}
+}
+
+/// Adds 1 to `ADDS` through every node in turn, then reads from every node.
+fn final_reads(read_mode: &str) {
+	let services = Services::new(Config {
+		staleness: 20,
+		cas_fault_rate: 0.1,
+		cas_fault: PRECONDITION_FAILED,
+		seed: 7,
+	});
+	let mut cluster = Cluster::start(read_mode, services);
+
+	for msg_id in 1..=ADDS {
+		let node = format!("n{}", msg_id as usize % NODES + 1);
+		cluster.send(
+			"c1",
+			&node,
+			json!({"type": "add", "msg_id": msg_id, "delta": msg_id}),
+		);
+
+		// Let a few adds race each other
+		if msg_id % 5 == 0 {
+			cluster.run_until(|replies| replies.len() as u64 == msg_id);
+		}
+	}
+
+	cluster.run_until(|replies| replies.len() as u64 == ADDS);
+
+	let acknowledged: u64 = cluster
+		.replies
+		.iter()
+		.filter(|(_, body)| body["type"] == "add_ok")
+		.map(|(msg_id, _)| msg_id)
+		.sum();
+	assert_eq!(
+		acknowledged,
+		ADDS * (ADDS + 1) / 2,
+		"Not every add was acknowledged"
+	);
+
+	for (i, node) in (1..=NODES).map(|i| format!("n{}", i)).enumerate() {
+		let msg_id = 1000 + i as u64;
+		cluster.send(CLIENT, &node, json!({"type": "read", "msg_id": msg_id}));
+		cluster.run_until(|replies| replies.contains_key(&msg_id));
+
+		assert_eq!(
+			cluster.replies[&msg_id],
+			json!({"type": "read_ok", "in_reply_to": msg_id, "value": acknowledged}),
+			"{} read a stale counter",
+			node
+		);
+	}
+}
+
+#[test]
+fn fresh_reads_see_every_acknowledged_add() {
+	final_reads("fresh");
+}
+
+#[test]
+fn converged_reads_see_every_acknowledged_add() {
+	final_reads("converge");
+}
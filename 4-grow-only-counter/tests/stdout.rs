@@ -0,0 +1,217 @@
+//! Feeds every message type through the node and checks that everything it
+//! writes to stdout is a protocol message, diagnostics belong on stderr.
+
+#[allow(dead_code)]
+#[path = "../src/message.rs"]
+mod message;
+
+use std::{
+	io::{BufRead, BufReader, Write},
+	process::{Child, ChildStdin, Command, Stdio},
+	sync::mpsc::{self, Receiver},
+	thread,
+	time::{Duration, Instant},
+};
+
+use message::Message;
+use serde_json::{json, Value};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const MESSAGES: &[&str] = &[
+	r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"init_ok","in_reply_to":1}}"#,
+	r#"{"src":"seq-kv","dest":"n1","body":{"type":"write_ok","in_reply_to":1}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":2,"delta":3}}"#,
+	r#"{"src":"seq-kv","dest":"n1","body":{"type":"cas_ok","msg_id":3,"in_reply_to":2}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"add_ok","in_reply_to":2}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":4,"delta":5}}"#,
+	r#"{"src":"seq-kv","dest":"n1","body":{"type":"error","in_reply_to":4,"code":22,"text":"precondition failed"}}"#,
+	r#"{"src":"seq-kv","dest":"n1","body":{"type":"read_ok","in_reply_to":2,"value":8}}"#,
+	r#"{"src":"seq-kv","dest":"n1","body":{"type":"error","in_reply_to":5,"code":20,"text":"key does not exist"}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":6}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"write","msg_id":7,"key":"counter","value":1}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"cas","msg_id":8,"key":"counter","from":1,"to":2}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"snapshot_request"}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"snapshot","value":9}}"#,
+	r#"not json at all"#,
+];
+
+#[test]
+fn stdout_only_carries_messages() {
+	let mut node = Command::new(env!("CARGO_BIN_EXE_ch4-grow-only-counter"))
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::null())
+		.spawn()
+		.unwrap();
+
+	let mut stdin = node.stdin.take().unwrap();
+	for message in MESSAGES {
+		writeln!(stdin, "{}", message).unwrap();
+	}
+	drop(stdin);
+
+	let output = node.wait_with_output().unwrap();
+	let stdout = String::from_utf8(output.stdout).unwrap();
+
+	assert!(!stdout.is_empty(), "Node did not answer anything");
+
+	for line in stdout.lines() {
+		serde_json::from_str::<Message>(line)
+			.unwrap_or_else(|e| panic!("{:?} is not a message: {}", line, e));
+	}
+}
+
+/// A node whose output is read as it is written.
+struct Node {
+	child: Child,
+	stdin: ChildStdin,
+	output: Receiver<Value>,
+}
+
+impl Node {
+	fn start(env: &[(&str, &str)]) -> Node {
+		let mut child = Command::new(env!("CARGO_BIN_EXE_ch4-grow-only-counter"))
+			.envs(env.iter().copied())
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::null())
+			.spawn()
+			.unwrap();
+
+		let stdout = BufReader::new(child.stdout.take().unwrap());
+		let (output_tx, output) = mpsc::channel();
+		thread::spawn(move || {
+			for line in stdout.lines() {
+				let message = serde_json::from_str(&line.unwrap()).unwrap();
+				if output_tx.send(message).is_err() {
+					break;
+				}
+			}
+		});
+
+		let mut node = Node {
+			stdin: child.stdin.take().unwrap(),
+			child,
+			output,
+		};
+		node.send(
+			"c0",
+			json!({"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}),
+		);
+		node.until(|m| m["body"]["type"] == "init_ok");
+
+		node
+	}
+
+	fn send(&mut self, src: &str, body: Value) {
+		let message = json!({"src": src, "dest": "n1", "body": body});
+		writeln!(self.stdin, "{}", message).unwrap();
+	}
+
+	/// The next message the node writes for which `found` holds.
+	fn until(&self, found: impl Fn(&Value) -> bool) -> Value {
+		let deadline = Instant::now() + TIMEOUT;
+
+		loop {
+			let message = self
+				.output
+				.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+				.expect("Timed out waiting for the node");
+
+			if found(&message) {
+				return message;
+			}
+		}
+	}
+}
+
+impl Drop for Node {
+	fn drop(&mut self) {
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+	}
+}
+
+fn is(message: &Value, dest: &str, body_type: &str) -> bool {
+	message["dest"] == dest && message["body"]["type"] == body_type
+}
+
+#[test]
+fn an_indefinite_cas_failure_is_not_retried() {
+	let mut node = Node::start(&[]);
+
+	node.send("c1", json!({"type": "add", "msg_id": 2, "delta": 5}));
+	let cas = node.until(|m| is(m, "seq-kv", "cas"));
+
+	node.send(
+		"seq-kv",
+		json!({"type": "error", "in_reply_to": cas["body"]["msg_id"], "code": 0, "text": "timeout"}),
+	);
+	let error = node.until(|m| m["dest"] == "c1");
+	assert_eq!(error["body"]["type"], "error");
+	assert_eq!(error["body"]["in_reply_to"], 2);
+	assert_eq!(error["body"]["code"], 0);
+
+	// The counter did take the delta, which must not be added again
+	let read = node.until(|m| is(m, "seq-kv", "read"));
+	node.send(
+		"seq-kv",
+		json!({"type": "read_ok", "in_reply_to": read["body"]["msg_id"], "value": 5}),
+	);
+	node.send("c1", json!({"type": "add", "msg_id": 3, "delta": 2}));
+
+	let cas = node.until(|m| is(m, "seq-kv", "cas"));
+	assert_eq!(cas["body"]["from"], 5);
+	assert_eq!(cas["body"]["to"], 7);
+}
+
+#[test]
+fn an_unanswered_cas_is_given_up_on() {
+	let mut node = Node::start(&[("REQUEST_TIMEOUT", "50"), ("CONVERGE_INTERVAL", "10")]);
+
+	node.send("c1", json!({"type": "add", "msg_id": 2, "delta": 5}));
+	node.until(|m| is(m, "seq-kv", "cas"));
+
+	let error = node.until(|m| m["dest"] == "c1");
+	assert_eq!(error["body"]["type"], "error");
+	assert_eq!(error["body"]["code"], 0);
+
+	// The read which finds out where the counter is now is resent as well
+	let read = node.until(|m| is(m, "seq-kv", "read"));
+	let resent = node.until(|m| is(m, "seq-kv", "read"));
+	assert_ne!(read["body"]["msg_id"], resent["body"]["msg_id"]);
+}
+
+#[test]
+fn a_read_is_not_held_forever_while_adds_keep_coming() {
+	let mut node = Node::start(&[
+		("READ_MODE", "converge"),
+		("STABLE_READS", "100000"),
+		("CONVERGE_INTERVAL", "10"),
+		("HOLD_TIMEOUT", "100"),
+	]);
+	node.send("c1", json!({"type": "read", "msg_id": 2}));
+
+	let deadline = Instant::now() + TIMEOUT;
+	loop {
+		let message = node
+			.output
+			.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+			.expect("The read was never answered");
+		let msg_id = &message["body"]["msg_id"];
+
+		if is(&message, "c1", "read_ok") {
+			assert_eq!(message["body"]["in_reply_to"], 2);
+			break;
+		} else if is(&message, "seq-kv", "write") {
+			node.send("seq-kv", json!({"type": "write_ok", "in_reply_to": msg_id}));
+		} else if is(&message, "seq-kv", "read") {
+			node.send(
+				"seq-kv",
+				json!({"type": "read_ok", "in_reply_to": msg_id, "value": 0}),
+			);
+		}
+	}
+}
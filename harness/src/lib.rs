@@ -0,0 +1,197 @@
+//! Drives a broadcast node binary over stdin and stdout the way Maelstrom
+//! does, for the end-to-end tests of each node.
+
+use std::{
+	io::{BufRead, BufReader, Write},
+	process::{Child, ChildStdin, Command, ExitStatus, Stdio},
+	sync::mpsc::{self, Receiver, RecvTimeoutError},
+	thread,
+	time::{Duration, Instant},
+};
+
+use serde_json::Value;
+
+/// How long the node may take to write what a test waits for.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const INIT: &str = r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2","n3"]}}"#;
+
+/// A node under test, killed when dropped.
+pub struct Node {
+	child: Child,
+	stdin: ChildStdin,
+	lines: Receiver<String>,
+	/// Everything the node wrote so far.
+	pub stdout: Vec<String>,
+}
+
+impl Node {
+	/// Starts the node binary at `bin` and waits for it to answer `init`, like
+	/// Maelstrom does.
+	pub fn start(bin: &str, env: &[(&str, &str)]) -> Node {
+		Node::start_with(bin, env, INIT)
+	}
+
+	/// Starts the node with its own `init` message.
+	pub fn start_with(bin: &str, env: &[(&str, &str)], init: &str) -> Node {
+		let mut child = Command::new(bin)
+			.envs(env.iter().cloned())
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::null())
+			.spawn()
+			.unwrap();
+
+		let stdout = BufReader::new(child.stdout.take().unwrap());
+		let (lines_tx, lines) = mpsc::channel();
+		thread::spawn(move || {
+			for line in stdout.lines() {
+				if lines_tx.send(line.unwrap()).is_err() {
+					break;
+				}
+			}
+		});
+
+		let mut node = Node {
+			stdin: child.stdin.take().unwrap(),
+			child,
+			lines,
+			stdout: vec![],
+		};
+		node.send(&[init.to_string()]);
+		node.run_until(|output| output.iter().any(|m| m["body"]["type"] == "init_ok"));
+		node
+	}
+
+	pub fn send(&mut self, messages: &[String]) {
+		for message in messages {
+			writeln!(self.stdin, "{}", message).unwrap();
+		}
+	}
+
+	/// Sends the node SIGINT, as Ctrl-C in a terminal does.
+	pub fn interrupt(&mut self) {
+		let status = Command::new("kill")
+			.args(["-INT", &self.child.id().to_string()])
+			.status()
+			.unwrap();
+
+		assert!(status.success(), "Could not interrupt the node");
+	}
+
+	/// Collects what the node writes until `done` holds for it, and returns all of it.
+	pub fn run_until(&mut self, done: impl Fn(&[Value]) -> bool) -> String {
+		let deadline = Instant::now() + TIMEOUT;
+		let mut output: Vec<Value> = self
+			.stdout
+			.iter()
+			.map(|line| serde_json::from_str(line).unwrap_or_default())
+			.collect();
+
+		while !done(&output) {
+			let line = self
+				.lines
+				.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+				.unwrap_or_else(|_| panic!("Timed out with output {:#?}", self.stdout));
+
+			output.push(serde_json::from_str(&line).unwrap_or_default());
+			self.stdout.push(line);
+		}
+
+		self.stdout.join("\n")
+	}
+
+	/// Collects what the node writes until it exits, and returns how it exited.
+	pub fn wait(&mut self) -> ExitStatus {
+		let deadline = Instant::now() + TIMEOUT;
+
+		loop {
+			match self
+				.lines
+				.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+			{
+				Ok(line) => self.stdout.push(line),
+				Err(RecvTimeoutError::Disconnected) => return self.child.wait().unwrap(),
+				Err(RecvTimeoutError::Timeout) => {
+					panic!("Node did not stop, output {:#?}", self.stdout)
+				}
+			}
+		}
+	}
+}
+
+impl Drop for Node {
+	fn drop(&mut self) {
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+	}
+}
+
+/// Runs a node on `messages` until it answered each of `requests` and reached
+/// out to a peer on its own.
+pub fn run_node(bin: &str, env: &[(&str, &str)], messages: &[&str], requests: &[u64]) -> String {
+	let messages: Vec<String> = messages.iter().map(|m| m.to_string()).collect();
+
+	run_node_until(bin, env, &messages, |output| {
+		answered(output, requests.iter().copied())
+			&& output.iter().any(|m| {
+				m["dest"] != "c1" && !m["body"]["type"].as_str().unwrap_or("_ok").ends_with("_ok")
+			})
+	})
+}
+
+pub fn run_node_until(
+	bin: &str,
+	env: &[(&str, &str)],
+	messages: &[String],
+	done: impl Fn(&[Value]) -> bool,
+) -> String {
+	let mut node = Node::start(bin, env);
+	node.send(messages);
+	node.run_until(done)
+}
+
+/// Whether the client got an answer to each of `requests`.
+pub fn answered(output: &[Value], requests: impl IntoIterator<Item = u64>) -> bool {
+	requests.into_iter().all(|msg_id| {
+		output
+			.iter()
+			.any(|m| m["dest"] == "c1" && m["body"]["in_reply_to"] == msg_id)
+	})
+}
+
+/// Floods a node with a tiny queue with broadcasts and checks that each is
+/// answered exactly once, with `broadcast_ok` or, if `env` makes the node
+/// reject what it cannot queue, with a `temporarily-unavailable` error.
+pub fn assert_overload_answered_once(bin: &str, env: &[(&str, &str)]) {
+	let broadcasts: Vec<String> = (0..300)
+		.map(|i| {
+			format!(
+				r#"{{"src":"c1","dest":"n1","body":{{"type":"broadcast","msg_id":{},"message":{}}}}}"#,
+				i + 2,
+				i
+			)
+		})
+		.collect();
+	let rejects = env.contains(&("OVERLOAD", "reject"));
+
+	let stdout = run_node_until(bin, env, &broadcasts, |output| answered(output, 2..302));
+
+	let answers: Vec<Value> = stdout
+		.lines()
+		.map(|line| serde_json::from_str(line).unwrap())
+		.filter(|message: &Value| message["dest"] == "c1")
+		.collect();
+
+	assert_eq!(answers.len(), 1 + broadcasts.len(), "{:?}", env);
+
+	for answer in &answers[1..] {
+		let body = &answer["body"];
+
+		match (rejects, body["type"].as_str().unwrap()) {
+			(_, "broadcast_ok") => {}
+			(true, "error") => assert_eq!(body["code"], 11),
+			(_, other) => panic!("Unexpected {} with {:?}", other, env),
+		}
+	}
+}
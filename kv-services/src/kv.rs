@@ -0,0 +1,253 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_json::Value;
+
+use crate::message::{Body, Message};
+
+/// Maelstrom's `key-does-not-exist` error.
+pub const KEY_DOES_NOT_EXIST: u64 = 20;
+/// Maelstrom's `precondition-failed` error.
+pub const PRECONDITION_FAILED: u64 = 22;
+
+/// The guarantees a service gives, after the Maelstrom service of each name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Consistency {
+	/// `seq-kv`: reads may be stale, but never older than what the same client
+	/// read or wrote before. Writes and `cas` act on the latest state.
+	Sequential,
+	/// `lin-kv`: every operation acts on the latest state.
+	Linearizable,
+	/// `lww-kv`: reads may be stale, and may go back in time. A `cas` compares
+	/// against a stale state too, and the last write wins.
+	LastWriteWins,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+	/// Most states behind the latest a read may see, ignored by `lin-kv`.
+	pub staleness: usize,
+	/// Chance of a `cas` failing with `cas_fault` without taking effect.
+	pub cas_fault_rate: f64,
+	pub cas_fault: u64,
+	pub seed: u64,
+}
+
+impl Config {
+	/// Panics on a setting the service cannot honour, rather than on the
+	/// first request which uses it.
+	fn validate(&self) {
+		assert!(
+			(0.0..=1.0).contains(&self.cas_fault_rate),
+			"cas_fault_rate must be within 0 and 1, not {}",
+			self.cas_fault_rate
+		);
+	}
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Config {
+			staleness: 0,
+			cas_fault_rate: 0.0,
+			cas_fault: PRECONDITION_FAILED,
+			seed: 0,
+		}
+	}
+}
+
+type State = BTreeMap<String, Value>;
+
+/// One key/value service. Each write makes a new state, the last
+/// `staleness` of which stay around for stale reads.
+pub struct Kv {
+	name: String,
+	consistency: Consistency,
+	config: Config,
+	rng: StdRng,
+	/// States from oldest to latest, the latest is number `version`.
+	states: VecDeque<State>,
+	version: usize,
+	/// Oldest state each client may still see.
+	floors: HashMap<String, usize>,
+	next_msg_id: u64,
+}
+
+impl Kv {
+	/// # Panics
+	///
+	/// If `cas_fault_rate` is not within 0 and 1.
+	pub fn new(name: &str, consistency: Consistency, config: Config) -> Kv {
+		config.validate();
+
+		Kv {
+			name: name.to_string(),
+			consistency,
+			config,
+			rng: StdRng::seed_from_u64(config.seed),
+			states: VecDeque::from([State::new()]),
+			version: 0,
+			floors: HashMap::new(),
+			next_msg_id: 0,
+		}
+	}
+
+	pub fn config(&self) -> &Config {
+		&self.config
+	}
+
+	/// # Panics
+	///
+	/// If `cas_fault_rate` is not within 0 and 1.
+	pub fn set_config(&mut self, config: Config) {
+		config.validate();
+		self.config = config;
+	}
+
+	/// The latest value of `key`, as a linearizable read would see it.
+	pub fn get(&self, key: &Value) -> Option<&Value> {
+		self.states.back().unwrap().get(&key.to_string())
+	}
+
+	/// Answers a request from a client, `None` for anything else.
+	pub fn handle(&mut self, message: &Message) -> Option<Message> {
+		let client = message.src.as_str();
+		self.next_msg_id += 1;
+		let msg_id = self.next_msg_id;
+
+		let body = match &message.body {
+			Body::Read { msg_id: id, key } => match self.view(client).get(&key.to_string()) {
+				Some(value) => Body::ReadOk {
+					msg_id,
+					in_reply_to: *id,
+					value: value.clone(),
+				},
+				None => missing(msg_id, *id, key),
+			},
+			Body::Write {
+				msg_id: id,
+				key,
+				value,
+			} => {
+				self.write(client, key, value);
+				Body::WriteOk {
+					msg_id,
+					in_reply_to: *id,
+				}
+			}
+			Body::Cas {
+				msg_id: id,
+				key,
+				from,
+				to,
+				create_if_not_exists,
+			} => {
+				if self.rng.gen_bool(self.config.cas_fault_rate) {
+					return Some(self.reply(
+						message,
+						error(msg_id, *id, self.config.cas_fault, "injected fault"),
+					));
+				}
+
+				match self.cas_view(client).get(&key.to_string()) {
+					None if !create_if_not_exists => missing(msg_id, *id, key),
+					Some(value) if value != from => error(
+						msg_id,
+						*id,
+						PRECONDITION_FAILED,
+						&format!("current value {} is not {}", value, from),
+					),
+					_ => {
+						self.write(client, key, to);
+						Body::CasOk {
+							msg_id,
+							in_reply_to: *id,
+						}
+					}
+				}
+			}
+			// Services do not answer replies
+			_ => return None,
+		};
+
+		Some(self.reply(message, body))
+	}
+
+	fn reply(&self, request: &Message, body: Body) -> Message {
+		Message {
+			src: self.name.clone(),
+			dest: request.src.clone(),
+			body,
+		}
+	}
+
+	/// The state a read by `client` sees.
+	fn view(&mut self, client: &str) -> &State {
+		let oldest = self.version + 1 - self.states.len();
+		let stalest = match self.consistency {
+			Consistency::Linearizable => self.version,
+			Consistency::Sequential => self
+				.version
+				.saturating_sub(self.config.staleness)
+				.max(oldest)
+				.max(self.floors.get(client).copied().unwrap_or(0)),
+			Consistency::LastWriteWins => self
+				.version
+				.saturating_sub(self.config.staleness)
+				.max(oldest),
+		};
+
+		let version = self.rng.gen_range(stalest..=self.version);
+		if self.consistency == Consistency::Sequential {
+			self.floors.insert(client.to_string(), version);
+		}
+
+		&self.states[version - oldest]
+	}
+
+	/// The state a `cas` by `client` compares against.
+	fn cas_view(&mut self, client: &str) -> &State {
+		match self.consistency {
+			Consistency::LastWriteWins => self.view(client),
+			_ => {
+				self.floors.insert(client.to_string(), self.version);
+				self.states.back().unwrap()
+			}
+		}
+	}
+
+	/// Makes a new latest state, which `client` sees from now on.
+	fn write(&mut self, client: &str, key: &Value, value: &Value) {
+		let mut state = self.states.back().unwrap().clone();
+		state.insert(key.to_string(), value.clone());
+
+		self.states.push_back(state);
+		self.version += 1;
+
+		while self.states.len() > self.config.staleness + 1 {
+			self.states.pop_front();
+		}
+
+		if self.consistency == Consistency::Sequential {
+			self.floors.insert(client.to_string(), self.version);
+		}
+	}
+}
+
+fn error(msg_id: u64, in_reply_to: u64, code: u64, text: &str) -> Body {
+	Body::Error {
+		msg_id,
+		in_reply_to,
+		code,
+		text: text.to_string(),
+	}
+}
+
+fn missing(msg_id: u64, in_reply_to: u64, key: &Value) -> Body {
+	error(
+		msg_id,
+		in_reply_to,
+		KEY_DOES_NOT_EXIST,
+		&format!("key {} does not exist", key),
+	)
+}
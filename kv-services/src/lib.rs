@@ -0,0 +1,73 @@
+//! In-process stand-ins for Maelstrom's key/value services, so nodes which
+//! keep their state in `seq-kv`, `lin-kv` or `lww-kv` run without Maelstrom.
+//!
+//! A test harness hands every message a node addresses to a service to
+//! [`Services::route`] and delivers the reply back to the node. How stale
+//! reads get and how often `cas` fails is set per service through [`Config`],
+//! and runs are reproducible from its seed.
+
+mod kv;
+mod message;
+
+use std::collections::BTreeMap;
+
+pub use crate::{
+	kv::{Config, Consistency, Kv, KEY_DOES_NOT_EXIST, PRECONDITION_FAILED},
+	message::{Body, Message},
+};
+
+pub const SEQ_KV: &str = "seq-kv";
+pub const LIN_KV: &str = "lin-kv";
+pub const LWW_KV: &str = "lww-kv";
+
+/// The three services, by the name nodes address them with.
+pub struct Services {
+	services: BTreeMap<&'static str, Kv>,
+}
+
+impl Services {
+	/// Every service with the same settings.
+	pub fn new(config: Config) -> Services {
+		Services::with(config, config, config)
+	}
+
+	pub fn with(seq: Config, lin: Config, lww: Config) -> Services {
+		Services {
+			services: BTreeMap::from([
+				(SEQ_KV, Kv::new(SEQ_KV, Consistency::Sequential, seq)),
+				(LIN_KV, Kv::new(LIN_KV, Consistency::Linearizable, lin)),
+				(LWW_KV, Kv::new(LWW_KV, Consistency::LastWriteWins, lww)),
+			]),
+		}
+	}
+
+	pub fn get(&self, name: &str) -> Option<&Kv> {
+		self.services.get(name)
+	}
+
+	pub fn get_mut(&mut self, name: &str) -> Option<&mut Kv> {
+		self.services.get_mut(name)
+	}
+
+	/// Whether messages to `dest` are for a service.
+	pub fn serves(&self, dest: &str) -> bool {
+		self.services.contains_key(dest)
+	}
+
+	/// Handles a message to a service and returns the reply, `None` if no
+	/// service goes by its `dest` or the message is not a request.
+	pub fn route(&mut self, message: &Message) -> Option<Message> {
+		self.services
+			.get_mut(message.dest.as_str())?
+			.handle(message)
+	}
+
+	/// Same as [`Services::route`] for a line of JSON, as a node writes it to
+	/// stdout. Lines which are not messages to a service give `None`.
+	pub fn route_line(&mut self, line: &str) -> Option<String> {
+		let message: Message = serde_json::from_str(line).ok()?;
+		let reply = self.route(&message)?;
+
+		Some(serde_json::to_string(&reply).unwrap())
+	}
+}
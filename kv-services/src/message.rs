@@ -0,0 +1,52 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Message {
+	pub src: String,
+	pub dest: String,
+	pub body: Body,
+}
+
+/// The key/value protocol of Maelstrom's services, requests and replies.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Body {
+	Error {
+		msg_id: u64,
+		in_reply_to: u64,
+		code: u64,
+		text: String,
+	},
+	Read {
+		msg_id: u64,
+		key: Value,
+	},
+	ReadOk {
+		msg_id: u64,
+		in_reply_to: u64,
+		value: Value,
+	},
+	Write {
+		msg_id: u64,
+		key: Value,
+		value: Value,
+	},
+	WriteOk {
+		msg_id: u64,
+		in_reply_to: u64,
+	},
+	Cas {
+		msg_id: u64,
+		key: Value,
+		from: Value,
+		to: Value,
+		#[serde(default)]
+		create_if_not_exists: bool,
+	},
+	CasOk {
+		msg_id: u64,
+		in_reply_to: u64,
+	},
+}
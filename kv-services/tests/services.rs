@@ -0,0 +1,234 @@
+//! Drives each service through requests from several clients and checks the
+//! guarantees it gives, and the ones it does not.
+
+use kv_services::{
+	Body, Config, Message, Services, KEY_DOES_NOT_EXIST, LIN_KV, LWW_KV, PRECONDITION_FAILED,
+	SEQ_KV,
+};
+use serde_json::{json, Value};
+
+struct Client {
+	name: &'static str,
+	next_msg_id: u64,
+}
+
+impl Client {
+	fn new(name: &'static str) -> Client {
+		Client {
+			name,
+			next_msg_id: 0,
+		}
+	}
+
+	fn send(&mut self, services: &mut Services, dest: &str, body: Value) -> Body {
+		self.next_msg_id += 1;
+
+		let mut body = body;
+		body["msg_id"] = json!(self.next_msg_id);
+		let message = Message {
+			src: self.name.to_string(),
+			dest: dest.to_string(),
+			body: serde_json::from_value(body).unwrap(),
+		};
+
+		let reply = services.route(&message).unwrap();
+		assert_eq!(reply.src, dest);
+		assert_eq!(reply.dest, self.name);
+		reply.body
+	}
+
+	fn read(&mut self, services: &mut Services, dest: &str) -> Option<u64> {
+		match self.send(services, dest, json!({"type": "read", "key": "x"})) {
+			Body::ReadOk { value, .. } => Some(value.as_u64().unwrap()),
+			Body::Error { code, .. } if code == KEY_DOES_NOT_EXIST => None,
+			reply => panic!("Unexpected reply {:?}", reply),
+		}
+	}
+
+	fn write(&mut self, services: &mut Services, dest: &str, value: u64) {
+		let reply = self.send(
+			services,
+			dest,
+			json!({"type": "write", "key": "x", "value": value}),
+		);
+		assert!(matches!(reply, Body::WriteOk { .. }), "{:?}", reply);
+	}
+
+	fn cas(&mut self, services: &mut Services, dest: &str, from: u64, to: u64) -> Body {
+		self.send(
+			services,
+			dest,
+			json!({"type": "cas", "key": "x", "from": from, "to": to}),
+		)
+	}
+}
+
+fn stale(staleness: usize) -> Config {
+	Config {
+		staleness,
+		..Config::default()
+	}
+}
+
+#[test]
+fn lin_kv_always_reads_the_latest_value() {
+	let mut services = Services::new(stale(10));
+	let mut a = Client::new("a");
+	let mut b = Client::new("b");
+
+	assert_eq!(a.read(&mut services, LIN_KV), None);
+	assert!(matches!(
+		a.cas(&mut services, LIN_KV, 0, 1),
+		Body::Error { code, .. } if code == KEY_DOES_NOT_EXIST
+	));
+
+	for value in 1..=20 {
+		a.write(&mut services, LIN_KV, value);
+		assert_eq!(b.read(&mut services, LIN_KV), Some(value));
+	}
+
+	assert!(matches!(
+		b.cas(&mut services, LIN_KV, 19, 21),
+		Body::Error { code, .. } if code == PRECONDITION_FAILED
+	));
+	assert!(matches!(
+		b.cas(&mut services, LIN_KV, 20, 21),
+		Body::CasOk { .. }
+	));
+	assert_eq!(a.read(&mut services, LIN_KV), Some(21));
+}
+
+#[test]
+fn seq_kv_reads_are_stale_but_never_go_back() {
+	let mut services = Services::new(stale(5));
+	let mut writer = Client::new("writer");
+	let mut reader = Client::new("reader");
+
+	let mut stale_reads = 0;
+	let mut last = None;
+
+	for value in 1..=100 {
+		writer.write(&mut services, SEQ_KV, value);
+
+		// Clients always see their own writes
+		assert_eq!(writer.read(&mut services, SEQ_KV), Some(value));
+
+		let read = reader.read(&mut services, SEQ_KV);
+		assert!(read >= last, "Read {:?} after {:?}", read, last);
+		assert!(
+			read.unwrap_or(0) + 5 >= value,
+			"Read {:?} of {}",
+			read,
+			value
+		);
+
+		if read != Some(value) {
+			stale_reads += 1;
+		}
+		last = read;
+	}
+
+	assert!(stale_reads > 0, "No read was stale");
+
+	// A cas acts on the latest value however stale the client's reads
+	assert!(matches!(
+		reader.cas(&mut services, SEQ_KV, 100, 101),
+		Body::CasOk { .. }
+	));
+}
+
+#[test]
+fn lww_kv_reads_may_go_back_and_lose_updates() {
+	let mut services = Services::new(stale(5));
+	let mut writer = Client::new("writer");
+	let mut reader = Client::new("reader");
+
+	for value in 1..=5 {
+		writer.write(&mut services, LWW_KV, value);
+	}
+
+	let reads: Vec<u64> = (0..50)
+		.map(|_| reader.read(&mut services, LWW_KV).unwrap_or(0))
+		.collect();
+
+	assert!(
+		reads.windows(2).any(|w| w[1] < w[0]),
+		"Reads never went back: {:?}",
+		reads
+	);
+
+	// A cas against a stale value may still succeed, and overwrite newer ones
+	let swapped =
+		(0..50).any(|_| matches!(writer.cas(&mut services, LWW_KV, 1, 1), Body::CasOk { .. }));
+	assert!(swapped, "No cas against a stale value succeeded");
+}
+
+#[test]
+fn injected_cas_faults_do_not_take_effect() {
+	let mut services = Services::new(Config {
+		cas_fault_rate: 1.0,
+		cas_fault: 11,
+		..Config::default()
+	});
+	let mut a = Client::new("a");
+
+	a.write(&mut services, SEQ_KV, 1);
+
+	for _ in 0..10 {
+		assert!(matches!(
+			a.cas(&mut services, SEQ_KV, 1, 2),
+			Body::Error { code: 11, .. }
+		));
+	}
+
+	assert_eq!(a.read(&mut services, SEQ_KV), Some(1));
+	assert_eq!(
+		services.get(SEQ_KV).unwrap().get(&json!("x")),
+		Some(&json!(1))
+	);
+}
+
+#[test]
+fn lines_for_nodes_are_not_routed() {
+	let mut services = Services::new(Config::default());
+
+	let line = r#"{"src":"n1","dest":"lin-kv","body":{"type":"cas","msg_id":3,"key":"x","from":null,"to":1,"create_if_not_exists":true}}"#;
+	let reply: Value = serde_json::from_str(&services.route_line(line).unwrap()).unwrap();
+	assert_eq!(reply["dest"], "n1");
+	assert_eq!(reply["body"]["type"], "cas_ok");
+	assert_eq!(reply["body"]["in_reply_to"], 3);
+
+	assert!(services.serves(SEQ_KV));
+	assert!(!services.serves("n2"));
+	assert_eq!(
+		services
+			.route_line(r#"{"src":"n1","dest":"n2","body":{"type":"read","msg_id":4,"key":"x"}}"#),
+		None
+	);
+	assert_eq!(
+		services.route_line(
+			r#"{"src":"n1","dest":"seq-kv","body":{"type":"read_ok","msg_id":5,"in_reply_to":1,"value":1}}"#
+		),
+		None
+	);
+}
+
+#[test]
+#[should_panic(expected = "cas_fault_rate")]
+fn a_cas_fault_rate_above_one_is_refused_up_front() {
+	Services::new(Config {
+		cas_fault_rate: 1.5,
+		..Config::default()
+	});
+}
+
+#[test]
+#[should_panic(expected = "cas_fault_rate")]
+fn a_negative_cas_fault_rate_is_refused_when_reconfiguring() {
+	let mut services = Services::new(Config::default());
+
+	services.get_mut(SEQ_KV).unwrap().set_config(Config {
+		cas_fault_rate: -0.1,
+		..Config::default()
+	});
+}
@@ -0,0 +1,69 @@
+use std::collections::BTreeMap;
+
+use raft::StateMachine;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Maelstrom's `key-does-not-exist` error.
+pub(crate) const KEY_DOES_NOT_EXIST: u64 = 20;
+/// Maelstrom's `precondition-failed` error.
+pub(crate) const PRECONDITION_FAILED: u64 = 22;
+
+/// A request as it is replicated through the log. Reads go through the log
+/// too, so they see every write committed before them.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "op")]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Command {
+	Read { key: Value },
+	Write { key: Value, value: Value },
+	Cas { key: Value, from: Value, to: Value },
+}
+
+#[derive(Debug)]
+pub(crate) enum Outcome {
+	Read(Value),
+	Written,
+	Swapped,
+}
+
+/// Outcome of a command, or a Maelstrom error code and text.
+pub(crate) type Output = Result<Outcome, (u64, String)>;
+
+/// The replicated store. Keys are kept as their JSON text, so any JSON value
+/// can be a key.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub(crate) struct Store(BTreeMap<String, Value>);
+
+impl StateMachine for Store {
+	type Command = Command;
+	type Output = Output;
+
+	fn apply(&mut self, command: &Command) -> Output {
+		match command {
+			Command::Read { key } => match self.0.get(&key.to_string()) {
+				Some(value) => Ok(Outcome::Read(value.clone())),
+				None => Err(missing(key)),
+			},
+			Command::Write { key, value } => {
+				self.0.insert(key.to_string(), value.clone());
+				Ok(Outcome::Written)
+			}
+			Command::Cas { key, from, to } => match self.0.get_mut(&key.to_string()) {
+				None => Err(missing(key)),
+				Some(value) if value != from => Err((
+					PRECONDITION_FAILED,
+					format!("expected {}, but had {}", from, value),
+				)),
+				Some(value) => {
+					*value = to.clone();
+					Ok(Outcome::Swapped)
+				}
+			},
+		}
+	}
+}
+
+fn missing(key: &Value) -> (u64, String) {
+	(KEY_DOES_NOT_EXIST, format!("key {} does not exist", key))
+}
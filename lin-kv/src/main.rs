@@ -0,0 +1,319 @@
+// Stdout carries the Maelstrom protocol, only the transport may touch it.
+#![deny(clippy::print_stdout)]
+
+mod kv;
+mod message;
+
+use std::{
+	collections::{hash_map::RandomState, HashMap},
+	hash::BuildHasher,
+	time::Duration,
+};
+
+use clap::Parser;
+use raft::{
+	transport::{Event, Transport},
+	Message, NotLeader, Raft,
+};
+use tracing::{debug, info_span, warn};
+
+use crate::{
+	kv::{Command, Outcome, Store},
+	message::Body,
+};
+
+/// Maelstrom's indefinite `timeout` error, the request may or may not have taken effect.
+const TIMEOUT: u64 = 0;
+/// Maelstrom's definite `temporarily-unavailable` error, the request did not take effect.
+const TEMPORARILY_UNAVAILABLE: u64 = 11;
+
+/// Raft timing and log compaction, also settable as e.g. `ELECTION_TIMEOUT=800`.
+#[derive(Parser, Debug)]
+struct Config {
+	/// Milliseconds between Raft ticks.
+	#[arg(long, env = "TICK_INTERVAL", default_value_t = 20, value_parser = clap::value_parser!(u64).range(1..))]
+	tick_interval: u64,
+	/// Fewest milliseconds without a leader before a node stands for election,
+	/// each election waits up to twice as long.
+	#[arg(long, env = "ELECTION_TIMEOUT", default_value_t = 400, value_parser = clap::value_parser!(u64).range(1..))]
+	election_timeout: u64,
+	/// Milliseconds between heartbeats of an idle leader.
+	#[arg(long, env = "HEARTBEAT_INTERVAL", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+	heartbeat_interval: u64,
+	/// Milliseconds a request may wait for the log before the client is told it timed out.
+	#[arg(long, env = "REQUEST_TIMEOUT", default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+	request_timeout: u64,
+	/// Applied log entries after which the store is compacted into a snapshot.
+	#[arg(long, env = "SNAPSHOT_THRESHOLD", default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+	snapshot_threshold: u64,
+	/// Log filter, e.g. `debug` or `lin_kv=trace,raft=debug`.
+	#[arg(long, env = "RUST_LOG", default_value = "info", value_parser = logging::parse_filter)]
+	log_level: String,
+}
+
+impl Config {
+	/// Converts the durations into ticks, none shorter than one.
+	fn raft(&self) -> raft::Config {
+		let ticks = |ms: u64| (ms / self.tick_interval).max(1);
+
+		raft::Config {
+			election_timeout_min: ticks(self.election_timeout),
+			election_timeout_max: ticks(self.election_timeout) * 2,
+			heartbeat_interval: ticks(self.heartbeat_interval),
+			snapshot_threshold: self.snapshot_threshold as usize,
+			..raft::Config::default()
+		}
+	}
+}
+
+/// A request waiting for an answer, and the tick after which it times out.
+#[derive(Debug)]
+struct Pending {
+	client: String,
+	msg_id: u64,
+	deadline: u64,
+}
+
+struct Node {
+	id: String,
+	nodes: Vec<String>,
+	raft: Raft<Store>,
+	transport: Transport<Command, Body>,
+	/// Requests we proposed, by log index, with the term they were proposed in.
+	proposed: HashMap<u64, (u64, Pending)>,
+	/// Requests we forwarded to the leader, by the `msg_id` we sent them with.
+	forwarded: HashMap<u64, Pending>,
+	next_msg_id: u64,
+	ticks: u64,
+	request_ticks: u64,
+}
+
+fn main() {
+	let config = Config::parse();
+	logging::init(&config.log_level);
+
+	let (transport, cluster) = Transport::connect(Duration::from_millis(config.tick_interval));
+	let seed = RandomState::new().hash_one(&cluster.id);
+
+	let mut node = Node {
+		raft: Raft::new(cluster.id.clone(), &cluster.node_ids, config.raft(), seed),
+		id: cluster.id,
+		nodes: cluster.node_ids,
+		transport,
+		proposed: HashMap::new(),
+		forwarded: HashMap::new(),
+		next_msg_id: 0,
+		ticks: 0,
+		request_ticks: (config.request_timeout / config.tick_interval).max(1),
+	};
+
+	while let Some(event) = node.transport.recv() {
+		match event {
+			Event::Raft(message) => node.raft.step(&message.src, message.body),
+			Event::Client(message) => {
+				let (body_type, msg_id) = logging::header(&message.body);
+				let span =
+					info_span!("message", node_id = %node.id, msg_id, body_type = %body_type);
+				let _enter = span.enter();
+
+				node.handle(message);
+			}
+			Event::Tick => {
+				node.ticks += 1;
+				node.raft.tick();
+				node.expire();
+			}
+		}
+
+		node.flush();
+	}
+}
+
+impl Node {
+	fn send(&mut self, dest: &str, body: Body) {
+		self.transport.send(&Message {
+			src: self.id.clone(),
+			dest: dest.to_string(),
+			body,
+		});
+	}
+
+	fn handle(&mut self, message: Message<Body>) {
+		if let Some(in_reply_to) = message.body.in_reply_to() {
+			// The leader answered a request we forwarded
+			match self.forwarded.remove(&in_reply_to) {
+				Some(pending) => {
+					let body = message.body.reply_to(pending.msg_id);
+					self.send(&pending.client, body);
+				}
+				None => debug!(in_reply_to, "Reply to a forgotten request"),
+			}
+			return;
+		}
+
+		let (msg_id, command) = match message.body.clone() {
+			Body::Read { msg_id, key } => (msg_id, Command::Read { key }),
+			Body::Write { msg_id, key, value } => (msg_id, Command::Write { key, value }),
+			Body::Cas {
+				msg_id,
+				key,
+				from,
+				to,
+			} => (msg_id, Command::Cas { key, from, to }),
+			_ => {
+				warn!("Unhandled message: {:?}", message);
+				return;
+			}
+		};
+
+		let pending = Pending {
+			client: message.src,
+			msg_id,
+			deadline: self.ticks + self.request_ticks,
+		};
+
+		match self.raft.propose(command) {
+			Ok((index, term)) => {
+				self.proposed.insert(index, (term, pending));
+			}
+			// Only requests straight from clients are forwarded, so they never loop
+			Err(NotLeader {
+				leader: Some(leader),
+			}) if !self.nodes.contains(&pending.client) => {
+				self.next_msg_id += 1;
+				let forwarded = forward(message.body, self.next_msg_id);
+
+				self.send(&leader, forwarded);
+				self.forwarded.insert(self.next_msg_id, pending);
+			}
+			Err(_) => self.fail(
+				pending,
+				TEMPORARILY_UNAVAILABLE,
+				"no leader to serve the request",
+			),
+		}
+	}
+
+	/// Sends what Raft produced, and answers the requests whose commands were applied.
+	fn flush(&mut self) {
+		for message in self.raft.take_messages() {
+			self.transport.send(&message);
+		}
+
+		for applied in self.raft.take_applied() {
+			let Some((term, pending)) = self.proposed.remove(&applied.index) else {
+				continue;
+			};
+
+			if term != applied.term {
+				self.fail(
+					pending,
+					TEMPORARILY_UNAVAILABLE,
+					"superseded by another leader",
+				);
+				continue;
+			}
+
+			let body = match applied.output {
+				Ok(Outcome::Read(value)) => Body::ReadOk {
+					in_reply_to: pending.msg_id,
+					value,
+				},
+				Ok(Outcome::Written) => Body::WriteOk {
+					in_reply_to: pending.msg_id,
+				},
+				Ok(Outcome::Swapped) => Body::CasOk {
+					in_reply_to: pending.msg_id,
+				},
+				Err((code, text)) => Body::Error {
+					in_reply_to: pending.msg_id,
+					code,
+					text,
+				},
+			};
+
+			self.send(&pending.client, body);
+		}
+
+		// Whatever is committed is applied, a proposal still waiting either lost
+		// its slot to an entry without a command or was applied inside a
+		// snapshot from the leader, whose outputs we never see
+		let commit_index = self.raft.commit_index();
+		let unreported: Vec<u64> = self
+			.proposed
+			.keys()
+			.filter(|index| **index <= commit_index)
+			.cloned()
+			.collect();
+
+		for index in unreported {
+			let (term, pending) = self.proposed.remove(&index).unwrap();
+
+			match self.raft.term_at(index) {
+				Some(applied) if applied != term => self.fail(
+					pending,
+					TEMPORARILY_UNAVAILABLE,
+					"superseded by another leader",
+				),
+				_ => self.fail(
+					pending,
+					TIMEOUT,
+					"applied inside a snapshot, outcome unknown",
+				),
+			}
+		}
+	}
+
+	/// Tells clients whose requests waited too long that we do not know their outcome.
+	fn expire(&mut self) {
+		let now = self.ticks;
+
+		let proposed: Vec<u64> = self
+			.proposed
+			.iter()
+			.filter(|(_, (_, p))| p.deadline < now)
+			.map(|(index, _)| *index)
+			.collect();
+		let forwarded: Vec<u64> = self
+			.forwarded
+			.iter()
+			.filter(|(_, p)| p.deadline < now)
+			.map(|(id, _)| *id)
+			.collect();
+
+		for index in proposed {
+			let (_, pending) = self.proposed.remove(&index).unwrap();
+			self.fail(pending, TIMEOUT, "timed out waiting for the log");
+		}
+
+		for id in forwarded {
+			let pending = self.forwarded.remove(&id).unwrap();
+			self.fail(pending, TIMEOUT, "timed out waiting for the leader");
+		}
+	}
+
+	fn fail(&mut self, pending: Pending, code: u64, text: &str) {
+		let body = Body::Error {
+			in_reply_to: pending.msg_id,
+			code,
+			text: text.to_string(),
+		};
+
+		self.send(&pending.client, body);
+	}
+}
+
+/// The request with the `msg_id` we forward it under.
+fn forward(body: Body, msg_id: u64) -> Body {
+	match body {
+		Body::Read { key, .. } => Body::Read { msg_id, key },
+		Body::Write { key, value, .. } => Body::Write { msg_id, key, value },
+		Body::Cas { key, from, to, .. } => Body::Cas {
+			msg_id,
+			key,
+			from,
+			to,
+		},
+		reply => reply,
+	}
+}
@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Maelstrom's key/value workload, requests and replies alike, since a node
+/// forwards requests to the leader and relays its replies.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Body {
+	Error {
+		in_reply_to: u64,
+		code: u64,
+		text: String,
+	},
+	Read {
+		msg_id: u64,
+		key: Value,
+	},
+	ReadOk {
+		in_reply_to: u64,
+		value: Value,
+	},
+	Write {
+		msg_id: u64,
+		key: Value,
+		value: Value,
+	},
+	WriteOk {
+		in_reply_to: u64,
+	},
+	Cas {
+		msg_id: u64,
+		key: Value,
+		from: Value,
+		to: Value,
+	},
+	CasOk {
+		in_reply_to: u64,
+	},
+}
+
+impl Body {
+	/// The `in_reply_to` of a reply.
+	pub(crate) fn in_reply_to(&self) -> Option<u64> {
+		match self {
+			Body::Error { in_reply_to, .. }
+			| Body::ReadOk { in_reply_to, .. }
+			| Body::WriteOk { in_reply_to }
+			| Body::CasOk { in_reply_to } => Some(*in_reply_to),
+			_ => None,
+		}
+	}
+
+	/// The same reply, to the request `msg_id`.
+	pub(crate) fn reply_to(self, msg_id: u64) -> Body {
+		match self {
+			Body::Error { code, text, .. } => Body::Error {
+				in_reply_to: msg_id,
+				code,
+				text,
+			},
+			Body::ReadOk { value, .. } => Body::ReadOk {
+				in_reply_to: msg_id,
+				value,
+			},
+			Body::WriteOk { .. } => Body::WriteOk {
+				in_reply_to: msg_id,
+			},
+			Body::CasOk { .. } => Body::CasOk {
+				in_reply_to: msg_id,
+			},
+			request => request,
+		}
+	}
+}
@@ -0,0 +1,144 @@
+//! Feeds requests through the node and checks that everything it writes to
+//! stdout is a protocol message, diagnostics belong on stderr.
+
+use std::{
+	io::{BufRead, BufReader, Write},
+	process::{Command, Stdio},
+	sync::mpsc,
+	thread,
+	time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+/// How long the node may take to become leader and answer every request.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// `msg_id`s from here on probe whether the node is leader yet.
+const PROBES: u64 = 1000;
+
+const INIT: &str = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}"#;
+
+const MESSAGES: &[&str] = &[
+	r#"{"src":"c1","dest":"n1","body":{"type":"write","msg_id":1,"key":1,"value":3}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":2,"key":1}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"cas","msg_id":3,"key":1,"from":4,"to":5}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"cas","msg_id":4,"key":1,"from":3,"to":5}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":5,"key":1}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":6,"key":2}}"#,
+	r#"{"src":"c1","dest":"n1","body":{"type":"cas","msg_id":7,"key":2,"from":1,"to":2}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"read_ok","in_reply_to":9,"value":1}}"#,
+	r#"{"src":"n2","dest":"n1","body":{"type":"append_entries_ok","term":9,"success":false,"match_index":0}}"#,
+	r#"not json at all"#,
+];
+
+/// Runs a single node, waits for it to elect itself, feeds it `messages` and
+/// returns what it wrote once it answered each of them.
+fn run(messages: &[&str]) -> Vec<Value> {
+	let mut node = Command::new(env!("CARGO_BIN_EXE_lin-kv"))
+		.env("TICK_INTERVAL", "5")
+		.env("ELECTION_TIMEOUT", "20")
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::null())
+		.spawn()
+		.unwrap();
+
+	let stdout = BufReader::new(node.stdout.take().unwrap());
+	let (lines_tx, lines) = mpsc::channel();
+	thread::spawn(move || {
+		for line in stdout.lines() {
+			if lines_tx.send(line.unwrap()).is_err() {
+				break;
+			}
+		}
+	});
+
+	let deadline = Instant::now() + TIMEOUT;
+	let next = || -> Value {
+		let line = lines
+			.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+			.expect("Timed out waiting for the node");
+
+		serde_json::from_str(&line).unwrap_or_else(|e| panic!("{:?} is not JSON: {}", line, e))
+	};
+
+	let mut stdin = node.stdin.take().unwrap();
+	writeln!(stdin, "{}", INIT).unwrap();
+	let mut output = vec![next()];
+
+	// Reads fail as temporarily unavailable until the node elected itself
+	for msg_id in PROBES.. {
+		let probe = json!({"src": "c1", "dest": "n1", "body": {"type": "read", "msg_id": msg_id, "key": 0}});
+		writeln!(stdin, "{}", probe).unwrap();
+
+		let reply = next();
+		if reply["body"]["code"] != 11 {
+			break;
+		}
+		thread::sleep(Duration::from_millis(5));
+	}
+
+	for message in messages {
+		writeln!(stdin, "{}", message).unwrap();
+	}
+
+	let requests = messages
+		.iter()
+		.filter(|m| m.contains(r#""src":"c1""#) && m.contains(r#""msg_id""#))
+		.count();
+	let answered = |output: &[Value]| {
+		output
+			.iter()
+			.filter(|m| m["dest"] == "c1" && m["body"]["in_reply_to"].as_u64() < Some(PROBES))
+			.count()
+	};
+
+	while answered(&output) < requests {
+		output.push(next());
+	}
+
+	let _ = node.kill();
+	let _ = node.wait();
+	output
+}
+
+#[test]
+fn stdout_only_carries_messages() {
+	let output = run(MESSAGES);
+
+	assert!(!output.is_empty(), "Node did not answer anything");
+
+	for message in output {
+		assert!(
+			message["src"].is_string()
+				&& message["dest"].is_string()
+				&& message["body"]["type"].is_string(),
+			"{} is not a message",
+			message
+		);
+	}
+}
+
+#[test]
+fn single_node_serves_the_key_value_workload() {
+	let output = run(MESSAGES);
+
+	let replies: Vec<&Value> = output
+		.iter()
+		.filter(|m| m["dest"] == "c1" && m["body"]["in_reply_to"].as_u64() < Some(PROBES))
+		.map(|m| &m["body"])
+		.collect();
+
+	assert_eq!(
+		replies,
+		[
+			&json!({"type": "write_ok", "in_reply_to": 1}),
+			&json!({"type": "read_ok", "in_reply_to": 2, "value": 3}),
+			&json!({"type": "error", "in_reply_to": 3, "code": 22, "text": "expected 4, but had 3"}),
+			&json!({"type": "cas_ok", "in_reply_to": 4}),
+			&json!({"type": "read_ok", "in_reply_to": 5, "value": 5}),
+			&json!({"type": "error", "in_reply_to": 6, "code": 20, "text": "key 2 does not exist"}),
+			&json!({"type": "error", "in_reply_to": 7, "code": 20, "text": "key 2 does not exist"}),
+		]
+	);
+}
@@ -0,0 +1,210 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// How an event relates to its operation.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+	/// The client sent the request.
+	Invoke,
+	/// The operation took effect.
+	Ok,
+	/// The operation did not take effect.
+	Fail,
+	/// The client gave up on the operation, it may or may not take effect.
+	Info,
+}
+
+/// A key/value operation. A read is invoked without a value and completes
+/// with the one it read, `null` for a key which was never written.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "f")]
+#[serde(rename_all = "snake_case")]
+pub enum Op {
+	Read {
+		key: Value,
+		#[serde(default)]
+		value: Value,
+	},
+	Write {
+		key: Value,
+		value: Value,
+	},
+	Cas {
+		key: Value,
+		from: Value,
+		to: Value,
+	},
+}
+
+impl Op {
+	pub fn key(&self) -> &Value {
+		match self {
+			Op::Read { key, .. } | Op::Write { key, .. } | Op::Cas { key, .. } => key,
+		}
+	}
+}
+
+/// One line of a history, e.g.
+/// `{"process":1,"type":"ok","f":"read","key":"x","value":3}`.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Event {
+	pub process: u64,
+	#[serde(rename = "type")]
+	pub kind: Kind,
+	#[serde(flatten)]
+	pub op: Op,
+}
+
+impl Event {
+	pub fn invoke(process: u64, op: Op) -> Event {
+		Event {
+			process,
+			kind: Kind::Invoke,
+			op,
+		}
+	}
+
+	pub fn ok(process: u64, op: Op) -> Event {
+		Event {
+			process,
+			kind: Kind::Ok,
+			op,
+		}
+	}
+
+	pub fn fail(process: u64, op: Op) -> Event {
+		Event {
+			process,
+			kind: Kind::Fail,
+			op,
+		}
+	}
+
+	pub fn info(process: u64, op: Op) -> Event {
+		Event {
+			process,
+			kind: Kind::Info,
+			op,
+		}
+	}
+}
+
+/// An operation which may have taken effect, between the positions in the
+/// history of its invocation and its return. Without a return it may take
+/// effect at any point after its invocation, or never.
+#[derive(Clone, Debug)]
+pub(crate) struct Operation {
+	pub(crate) call: usize,
+	pub(crate) ret: Option<usize>,
+	/// The operation as it completed, reads carry the value they read.
+	pub(crate) op: Op,
+	invoke: Event,
+	/// The completion and its position, `info` ones included.
+	completion: Option<(usize, Event)>,
+}
+
+/// Pairs invocations with their completions and groups the operations by
+/// key, in the order keys first appear. Failed operations never took effect
+/// and reads which did not complete observed nothing, so both are left out.
+pub(crate) fn by_key(history: &[Event]) -> Vec<(Value, Vec<Operation>)> {
+	let mut operations: Vec<Option<Operation>> = vec![];
+	let mut running: HashMap<u64, usize> = HashMap::new();
+
+	for (time, event) in history.iter().enumerate() {
+		if event.kind == Kind::Invoke {
+			// A process invoking again gave up on its last operation
+			running.insert(event.process, operations.len());
+			operations.push(Some(Operation {
+				call: time,
+				ret: None,
+				op: event.op.clone(),
+				invoke: event.clone(),
+				completion: None,
+			}));
+			continue;
+		}
+
+		let Some(i) = running.remove(&event.process) else {
+			continue;
+		};
+
+		match event.kind {
+			Kind::Ok => {
+				let operation = operations[i].as_mut().unwrap();
+				operation.ret = Some(time);
+				operation.completion = Some((time, event.clone()));
+
+				if let Op::Read { value, .. } = &event.op {
+					operation.op = Op::Read {
+						key: operation.op.key().clone(),
+						value: value.clone(),
+					};
+				}
+			}
+			Kind::Fail => operations[i] = None,
+			_ => operations[i].as_mut().unwrap().completion = Some((time, event.clone())),
+		}
+	}
+
+	let mut keys: Vec<(Value, Vec<Operation>)> = vec![];
+	let mut index: HashMap<String, usize> = HashMap::new();
+
+	for operation in operations.into_iter().flatten() {
+		if matches!(operation.op, Op::Read { .. }) && operation.ret.is_none() {
+			continue;
+		}
+
+		let key = operation.op.key();
+		let i = *index.entry(key.to_string()).or_insert_with(|| {
+			keys.push((key.clone(), vec![]));
+			keys.len() - 1
+		});
+
+		keys[i].1.push(operation);
+	}
+
+	keys
+}
+
+/// The events of the operations, in the order they happened.
+pub(crate) fn events(operations: &[Operation]) -> Vec<Event> {
+	let mut events: Vec<(usize, Event)> = vec![];
+
+	for operation in operations {
+		events.push((operation.call, operation.invoke.clone()));
+
+		if let Some(completion) = &operation.completion {
+			events.push(completion.clone());
+		}
+	}
+
+	events.sort_by_key(|(time, _)| *time);
+	events.into_iter().map(|(_, event)| event).collect()
+}
+
+/// Values the operations read or compared against, bar `null`.
+pub(crate) fn observed(operations: &[Operation]) -> HashSet<String> {
+	operations
+		.iter()
+		.filter_map(|o| match &o.op {
+			Op::Read { value, .. } | Op::Cas { from: value, .. } => Some(value),
+			Op::Write { .. } => None,
+		})
+		.filter(|value| !value.is_null())
+		.map(|value| value.to_string())
+		.collect()
+}
+
+/// Values the operations wrote.
+pub(crate) fn written(operations: &[Operation]) -> HashSet<String> {
+	operations
+		.iter()
+		.filter_map(|o| match &o.op {
+			Op::Write { value, .. } | Op::Cas { to: value, .. } => Some(value.to_string()),
+			Op::Read { .. } => None,
+		})
+		.collect()
+}
@@ -0,0 +1,89 @@
+//! A linearizability checker for histories of key/value operations, such as
+//! Maelstrom's `lin-kv` workload records.
+//!
+//! A history is the sequence of [`Event`]s clients observed: each operation is
+//! invoked, then completes `ok`, `fail`s without taking effect, or ends as
+//! `info` when the client never learned whether it took effect. Keys are
+//! independent registers, so each key is checked on its own, with the search
+//! of Wing & Gong as refined by Lowe: operations are linearized as late as
+//! their return forces them to, and configurations already explored are
+//! remembered so they are never searched twice.
+
+mod history;
+mod search;
+
+use std::fmt;
+
+use serde_json::Value;
+
+use crate::history::Operation;
+pub use crate::history::{Event, Kind, Op};
+
+/// A non-linearizable history, narrowed down to the events of one key that
+/// cannot be linearized on their own, but can without any one operation
+/// which does not write a value the others observe.
+#[derive(Debug)]
+pub struct Violation {
+	pub key: Value,
+	pub events: Vec<Event>,
+}
+
+impl fmt::Display for Violation {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		writeln!(f, "Key {} is not linearizable:", self.key)?;
+
+		for event in &self.events {
+			writeln!(f, "  {}", serde_json::to_string(event).unwrap())?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Checks that some order of the operations, each taking effect between its
+/// invocation and completion, explains every value the history observed.
+pub fn check(history: &[Event]) -> Result<(), Violation> {
+	for (key, operations) in history::by_key(history) {
+		if search::linearizable(&operations) {
+			continue;
+		}
+
+		return Err(Violation {
+			key,
+			events: history::events(&shrink(operations)),
+		});
+	}
+
+	Ok(())
+}
+
+/// Drops every operation the violation does not need, one at a time. The
+/// writes behind values the rest observe stay, else any read of a written
+/// value would be a violation on its own.
+fn shrink(mut operations: Vec<Operation>) -> Vec<Operation> {
+	let mut i = 0;
+
+	while i < operations.len() {
+		let mut without = operations.clone();
+		without.remove(i);
+
+		if orphans(&without, &operations) || search::linearizable(&without) {
+			i += 1;
+		} else {
+			operations = without;
+		}
+	}
+
+	operations
+}
+
+/// Whether the operations left `without` the dropped one observe a value
+/// only the dropped one wrote.
+fn orphans(without: &[Operation], operations: &[Operation]) -> bool {
+	let before = history::written(operations);
+	let after = history::written(without);
+
+	history::observed(without)
+		.iter()
+		.any(|value| before.contains(value) && !after.contains(value))
+}
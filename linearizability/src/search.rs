@@ -0,0 +1,161 @@
+use std::collections::HashSet;
+
+use serde_json::Value;
+
+use crate::history::{Op, Operation};
+
+/// Where the search stands in the history, a doubly linked list of the call
+/// and return entries of the operations not linearized yet.
+struct Entries {
+	/// Operation of each entry, and whether it is its call or its return.
+	entries: Vec<(usize, bool)>,
+	prev: Vec<Option<usize>>,
+	next: Vec<Option<usize>>,
+	head: Option<usize>,
+	/// Call and return entry of each operation.
+	of: Vec<(usize, Option<usize>)>,
+}
+
+impl Entries {
+	fn new(operations: &[Operation]) -> Entries {
+		let mut timed: Vec<(usize, usize, bool)> = vec![];
+
+		for (i, operation) in operations.iter().enumerate() {
+			timed.push((operation.call, i, true));
+
+			if let Some(ret) = operation.ret {
+				timed.push((ret, i, false));
+			}
+		}
+
+		timed.sort();
+
+		let entries: Vec<(usize, bool)> = timed.iter().map(|(_, i, call)| (*i, *call)).collect();
+		let len = entries.len();
+		let mut of = vec![(0, None); operations.len()];
+
+		for (e, (i, call)) in entries.iter().enumerate() {
+			if *call {
+				of[*i].0 = e;
+			} else {
+				of[*i].1 = Some(e);
+			}
+		}
+
+		Entries {
+			entries,
+			prev: (0..len).map(|e| e.checked_sub(1)).collect(),
+			next: (0..len).map(|e| Some(e + 1).filter(|n| *n < len)).collect(),
+			head: (len > 0).then_some(0),
+			of,
+		}
+	}
+
+	fn unlink(&mut self, e: usize) {
+		match self.prev[e] {
+			Some(prev) => self.next[prev] = self.next[e],
+			None => self.head = self.next[e],
+		}
+
+		if let Some(next) = self.next[e] {
+			self.prev[next] = self.prev[e];
+		}
+	}
+
+	/// Puts back an entry, whose neighbours are the ones it was unlinked from.
+	fn relink(&mut self, e: usize) {
+		match self.prev[e] {
+			Some(prev) => self.next[prev] = Some(e),
+			None => self.head = Some(e),
+		}
+
+		if let Some(next) = self.next[e] {
+			self.prev[next] = Some(e);
+		}
+	}
+
+	/// Takes an operation out of the history once it is linearized.
+	fn lift(&mut self, i: usize) {
+		let (call, ret) = self.of[i];
+
+		self.unlink(call);
+		if let Some(ret) = ret {
+			self.unlink(ret);
+		}
+	}
+
+	/// Undoes [`Entries::lift`], in reverse so each entry finds its neighbours.
+	fn unlift(&mut self, i: usize) {
+		let (call, ret) = self.of[i];
+
+		if let Some(ret) = ret {
+			self.relink(ret);
+		}
+		self.relink(call);
+	}
+}
+
+/// The register after applying `op`, `None` if `op` cannot take effect on it.
+/// A register which was never written holds `null`.
+fn apply(register: &Value, op: &Op) -> Option<Value> {
+	match op {
+		Op::Read { value, .. } => (value == register).then(|| register.clone()),
+		Op::Write { value, .. } => Some(value.clone()),
+		Op::Cas { from, to, .. } => (from == register).then(|| to.clone()),
+	}
+}
+
+/// Searches for an order of the operations of one key, each taking effect
+/// between its call and return, which explains what they observed.
+///
+/// Each step linearizes the first operation whose call comes before any
+/// return still in the history. Reaching a return means the operation it
+/// belongs to should have taken effect already, so the search backtracks.
+/// Operations which never returned are free to be left out.
+pub(crate) fn linearizable(operations: &[Operation]) -> bool {
+	let mut entries = Entries::new(operations);
+	let mut linearized = vec![0u64; operations.len().div_ceil(64)];
+	let mut register = Value::Null;
+	let mut stack: Vec<(usize, Value)> = vec![];
+	// Operations linearized and the register they left, already explored
+	let mut seen: HashSet<(Vec<u64>, String)> = HashSet::new();
+
+	let mut entry = entries.head;
+
+	while entries.head.is_some() {
+		// Only operations which never returned are left
+		let Some(e) = entry else {
+			return true;
+		};
+
+		let (i, call) = entries.entries[e];
+
+		if call {
+			if let Some(next) = apply(&register, &operations[i].op) {
+				linearized[i / 64] |= 1 << (i % 64);
+
+				if seen.insert((linearized.clone(), next.to_string())) {
+					stack.push((i, std::mem::replace(&mut register, next)));
+					entries.lift(i);
+					entry = entries.head;
+					continue;
+				}
+
+				linearized[i / 64] &= !(1 << (i % 64));
+			}
+
+			entry = entries.next[e];
+		} else {
+			let Some((i, previous)) = stack.pop() else {
+				return false;
+			};
+
+			linearized[i / 64] &= !(1 << (i % 64));
+			register = previous;
+			entries.unlift(i);
+			entry = entries.next[entries.of[i].0];
+		}
+	}
+
+	true
+}
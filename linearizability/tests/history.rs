@@ -0,0 +1,202 @@
+//! Checks histories with known answers, and that a violation is narrowed down
+//! to the operations which cause it.
+
+use linearizability::{check, Event, Op};
+use serde_json::{json, Value};
+
+fn read(value: Value) -> Op {
+	Op::Read {
+		key: json!("x"),
+		value,
+	}
+}
+
+fn write(value: u64) -> Op {
+	Op::Write {
+		key: json!("x"),
+		value: json!(value),
+	}
+}
+
+fn cas(from: u64, to: u64) -> Op {
+	Op::Cas {
+		key: json!("x"),
+		from: json!(from),
+		to: json!(to),
+	}
+}
+
+#[test]
+fn sequential_history_is_linearizable() {
+	let history = [
+		Event::invoke(0, write(1)),
+		Event::ok(0, write(1)),
+		Event::invoke(0, read(Value::Null)),
+		Event::ok(0, read(json!(1))),
+		Event::invoke(0, cas(1, 2)),
+		Event::ok(0, cas(1, 2)),
+		Event::invoke(0, read(Value::Null)),
+		Event::ok(0, read(json!(2))),
+	];
+
+	check(&history).unwrap();
+}
+
+#[test]
+fn concurrent_operations_take_effect_in_any_order() {
+	// The read overlaps both writes, so it may see either
+	let history = [
+		Event::invoke(0, write(1)),
+		Event::invoke(1, read(Value::Null)),
+		Event::invoke(2, write(2)),
+		Event::ok(0, write(1)),
+		Event::ok(2, write(2)),
+		Event::ok(1, read(json!(1))),
+	];
+
+	check(&history).unwrap();
+}
+
+#[test]
+fn stale_read_is_a_violation() {
+	let history = [
+		Event::invoke(0, write(1)),
+		Event::ok(0, write(1)),
+		Event::invoke(1, read(Value::Null)),
+		Event::ok(1, read(json!(1))),
+		Event::invoke(0, write(2)),
+		Event::ok(0, write(2)),
+		Event::invoke(2, write(3)),
+		Event::invoke(1, read(Value::Null)),
+		Event::ok(1, read(json!(1))),
+		Event::ok(2, write(3)),
+	];
+
+	let violation = check(&history).unwrap_err();
+
+	assert_eq!(violation.key, json!("x"));
+	// The first read and the concurrent write play no part in it
+	assert_eq!(
+		violation.events,
+		[
+			Event::invoke(0, write(1)),
+			Event::ok(0, write(1)),
+			Event::invoke(0, write(2)),
+			Event::ok(0, write(2)),
+			Event::invoke(1, read(Value::Null)),
+			Event::ok(1, read(json!(1))),
+		]
+	);
+}
+
+#[test]
+fn indeterminate_operations_may_or_may_not_take_effect() {
+	let took_effect = [
+		Event::invoke(0, write(1)),
+		Event::info(0, write(1)),
+		Event::invoke(1, read(Value::Null)),
+		Event::ok(1, read(json!(1))),
+	];
+	let did_not = [
+		Event::invoke(0, write(1)),
+		Event::info(0, write(1)),
+		Event::invoke(1, read(Value::Null)),
+		Event::ok(1, read(Value::Null)),
+	];
+
+	check(&took_effect).unwrap();
+	check(&did_not).unwrap();
+}
+
+#[test]
+fn failed_operations_never_take_effect() {
+	let history = [
+		Event::invoke(0, write(1)),
+		Event::ok(0, write(1)),
+		Event::invoke(1, cas(2, 3)),
+		Event::fail(1, cas(2, 3)),
+		Event::invoke(1, write(4)),
+		Event::fail(1, write(4)),
+		Event::invoke(0, read(Value::Null)),
+		Event::ok(0, read(json!(4))),
+	];
+
+	let violation = check(&history).unwrap_err();
+
+	assert_eq!(
+		violation.events,
+		[
+			Event::invoke(0, read(Value::Null)),
+			Event::ok(0, read(json!(4))),
+		]
+	);
+}
+
+#[test]
+fn keys_are_checked_independently() {
+	let history: Vec<Event> = serde_json::from_str(
+		r#"[
+			{"process":0,"type":"invoke","f":"write","key":"a","value":1},
+			{"process":1,"type":"invoke","f":"write","key":"b","value":1},
+			{"process":0,"type":"ok","f":"write","key":"a","value":1},
+			{"process":1,"type":"ok","f":"write","key":"b","value":1},
+			{"process":0,"type":"invoke","f":"cas","key":"a","from":1,"to":2},
+			{"process":1,"type":"invoke","f":"read","key":"b"},
+			{"process":0,"type":"ok","f":"cas","key":"a","from":1,"to":2},
+			{"process":1,"type":"ok","f":"read","key":"b","value":2}
+		]"#,
+	)
+	.unwrap();
+
+	let violation = check(&history).unwrap_err();
+
+	// Nothing ever wrote what the read saw, the write before it is no excuse
+	assert_eq!(violation.key, json!("b"));
+	assert_eq!(
+		violation.events,
+		[
+			Event::invoke(
+				1,
+				Op::Read {
+					key: json!("b"),
+					value: Value::Null
+				}
+			),
+			Event::ok(
+				1,
+				Op::Read {
+					key: json!("b"),
+					value: json!(2)
+				}
+			),
+		]
+	);
+}
+
+#[test]
+fn many_concurrent_processes_are_searched_quickly() {
+	// Every process writes then reads its own value, all overlapping, which
+	// explodes without remembering explored configurations
+	let processes = 12;
+	let mut history = vec![];
+
+	for p in 0..processes {
+		history.push(Event::invoke(p, write(p)));
+	}
+	for p in 0..processes {
+		history.push(Event::ok(p, write(p)));
+	}
+	for p in 0..processes {
+		history.push(Event::invoke(p, read(Value::Null)));
+	}
+	for p in 0..processes {
+		history.push(Event::ok(p, read(json!(processes - 1))));
+	}
+
+	check(&history).unwrap();
+
+	history.push(Event::invoke(0, read(Value::Null)));
+	history.push(Event::ok(0, read(json!(0))));
+
+	assert!(check(&history).is_err());
+}
@@ -0,0 +1,81 @@
+//! Logging shared by the Maelstrom nodes.
+//!
+//! Stdout carries the protocol, so logs go to stderr. Each node wraps the
+//! handling of a message in a span of its own, named after the message's
+//! [`header`].
+
+use std::io;
+
+use serde::Serialize;
+use serde_json::Value;
+use tracing_subscriber::EnvFilter;
+
+/// Sends logs to stderr, filtered by an `EnvFilter` directive such as `debug`.
+pub fn init(filter: &str) {
+	tracing_subscriber::fmt()
+		.with_env_filter(EnvFilter::new(filter))
+		.with_writer(io::stderr)
+		.with_ansi(false)
+		.init();
+}
+
+/// Checks an `EnvFilter` directive, for use as a clap `value_parser` so a
+/// typo in `--log-level` or `RUST_LOG` fails at startup.
+pub fn parse_filter(filter: &str) -> Result<String, String> {
+	EnvFilter::try_new(filter)
+		.map(|_| filter.to_string())
+		.map_err(|e| e.to_string())
+}
+
+/// The `type` tag and `msg_id` of a message body, as serde writes them, so
+/// no node has to list its message types a second time.
+pub fn header(body: &impl Serialize) -> (String, Option<u64>) {
+	let Ok(Value::Object(fields)) = serde_json::to_value(body) else {
+		return (String::new(), None);
+	};
+
+	let body_type = fields
+		.get("type")
+		.and_then(Value::as_str)
+		.unwrap_or_default()
+		.to_string();
+
+	(body_type, fields.get("msg_id").and_then(Value::as_u64))
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+
+	#[derive(Serialize)]
+	#[serde(tag = "type", rename_all = "snake_case")]
+	enum Body {
+		Echo { msg_id: u64 },
+		EchoOk { in_reply_to: u64 },
+	}
+
+	#[test]
+	fn header_comes_from_the_serde_tag() {
+		assert_eq!(
+			header(&Body::Echo { msg_id: 3 }),
+			("echo".to_string(), Some(3))
+		);
+		assert_eq!(
+			header(&Body::EchoOk { in_reply_to: 3 }),
+			("echo_ok".to_string(), None)
+		);
+	}
+
+	#[test]
+	fn filters_are_checked() {
+		assert_eq!(parse_filter("lin_kv=debug"), Ok("lin_kv=debug".to_string()));
+		assert!(parse_filter("lin_kv=loud").is_err());
+	}
+
+	#[test]
+	fn bodies_which_are_not_objects_have_no_header() {
+		assert_eq!(header(&json!("echo")), (String::new(), None));
+	}
+}
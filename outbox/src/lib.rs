@@ -0,0 +1,318 @@
+//! Reliable delivery of gossip batches between broadcast nodes.
+//!
+//! Each batch sent to a peer gets a sequence number and stays in the
+//! [`Outbox`] until the peer acknowledges it, cumulatively or selectively.
+//! Batches nobody acknowledged are handed back for retransmission, less and
+//! less often while a peer stays silent. Each batch also carries the sender's
+//! base, below which the peer acknowledged everything, so a peer which lost
+//! its inbox in a restart does not wait for batches which are never resent.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+/// Gossip rounds after which an unacknowledged batch is sent again.
+const RETRANSMIT_ROUNDS: u64 = 3;
+/// The wait doubles with every retransmission, up to `RETRANSMIT_ROUNDS << MAX_BACKOFF`
+/// rounds. A partitioned peer is still probed now and then, so it catches up
+/// once it heals.
+const MAX_BACKOFF: u32 = 4;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Batch {
+	messages: Vec<u64>,
+	sent_round: u64,
+	retransmissions: u32,
+}
+
+impl Batch {
+	fn is_due(&self, round: u64) -> bool {
+		round - self.sent_round >= RETRANSMIT_ROUNDS << self.retransmissions.min(MAX_BACKOFF)
+	}
+}
+
+/// Batches sent to a single peer, numbered so the peer can acknowledge them.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct PeerQueue {
+	next_seq: u64,
+	unacked: BTreeMap<u64, Batch>,
+}
+
+/// Batches received from a single peer.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct PeerInbox {
+	/// Every batch up to and including this sequence number arrived.
+	cumulative: u64,
+	/// Batches above `cumulative` which arrived out of order.
+	selective: BTreeSet<u64>,
+}
+
+/// Per-peer sequencing, acknowledgement and retransmission of gossip batches.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Outbox {
+	queues: HashMap<String, PeerQueue>,
+	inboxes: HashMap<String, PeerInbox>,
+	round: u64,
+}
+
+impl Outbox {
+	pub fn next_round(&mut self) {
+		self.round += 1;
+	}
+
+	/// Queues a batch for the peer and returns its sequence number.
+	pub fn send(&mut self, peer: &str, messages: Vec<u64>) -> u64 {
+		let queue = self.queues.entry(peer.to_string()).or_default();
+		queue.next_seq += 1;
+
+		queue.unacked.insert(
+			queue.next_seq,
+			Batch {
+				messages,
+				sent_round: self.round,
+				retransmissions: 0,
+			},
+		);
+
+		queue.next_seq
+	}
+
+	/// Every batch which was not acknowledged in time, as `(peer, seq, messages)`.
+	pub fn get_retransmissions(&mut self) -> Vec<(String, u64, Vec<u64>)> {
+		let mut retransmissions = vec![];
+
+		for (peer, queue) in self.queues.iter_mut() {
+			for (seq, batch) in queue.unacked.iter_mut() {
+				if batch.is_due(self.round) {
+					batch.sent_round = self.round;
+					batch.retransmissions += 1;
+					retransmissions.push((peer.clone(), *seq, batch.messages.clone()));
+				}
+			}
+		}
+
+		retransmissions
+	}
+
+	/// Every batch sent to the peer up to and including this sequence number
+	/// was acknowledged.
+	pub fn base(&self, peer: &str) -> u64 {
+		self.queues.get(peer).map_or(0, |queue| {
+			queue
+				.unacked
+				.keys()
+				.next()
+				.map_or(queue.next_seq, |seq| seq - 1)
+		})
+	}
+
+	/// Values sent to the peer which it did not acknowledge yet.
+	pub fn get_in_flight(&self, peer: &str) -> HashSet<u64> {
+		self.queues
+			.get(peer)
+			.map(|queue| {
+				queue
+					.unacked
+					.values()
+					.flat_map(|batch| batch.messages.iter().cloned())
+					.collect()
+			})
+			.unwrap_or_default()
+	}
+
+	/// Applies a cumulative and selective acknowledgement, returns the values the peer now has.
+	pub fn acknowledge(&mut self, peer: &str, ack: u64, sack: Vec<u64>) -> Vec<u64> {
+		let Some(queue) = self.queues.get_mut(peer) else {
+			return vec![];
+		};
+
+		let acked: Vec<u64> = queue
+			.unacked
+			.keys()
+			.filter(|seq| **seq <= ack || sack.contains(seq))
+			.cloned()
+			.collect();
+
+		acked
+			.into_iter()
+			.filter_map(|seq| queue.unacked.remove(&seq))
+			.flat_map(|batch| batch.messages)
+			.collect()
+	}
+
+	/// Records a batch from the peer, sent with the peer's `base`, and returns
+	/// the acknowledgement to send back.
+	pub fn receive(&mut self, peer: &str, seq: u64, base: u64) -> (u64, Vec<u64>) {
+		let inbox = self.inboxes.entry(peer.to_string()).or_default();
+
+		// We acknowledged those before, and forgot it if we restarted since
+		if base > inbox.cumulative {
+			inbox.cumulative = base;
+			inbox.selective = inbox.selective.split_off(&(base + 1));
+		}
+
+		if seq > inbox.cumulative {
+			inbox.selective.insert(seq);
+		}
+
+		while inbox.selective.remove(&(inbox.cumulative + 1)) {
+			inbox.cumulative += 1;
+		}
+
+		(inbox.cumulative, inbox.selective.iter().cloned().collect())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Rounds in which a batch sent in round 0 and never acknowledged goes out again.
+	fn retransmission_rounds(outbox: &mut Outbox, rounds: u64) -> Vec<u64> {
+		(1..=rounds)
+			.filter(|_| {
+				outbox.next_round();
+				!outbox.get_retransmissions().is_empty()
+			})
+			.collect()
+	}
+
+	#[test]
+	fn batches_are_numbered_per_peer() {
+		let mut outbox = Outbox::default();
+
+		assert_eq!(outbox.send("n2", vec![1]), 1);
+		assert_eq!(outbox.send("n2", vec![2]), 2);
+		assert_eq!(outbox.send("n3", vec![3]), 1);
+		assert_eq!(outbox.get_in_flight("n2"), HashSet::from([1, 2]));
+	}
+
+	#[test]
+	fn cumulative_ack_releases_every_batch_up_to_it() {
+		let mut outbox = Outbox::default();
+		for m in 1..=3 {
+			outbox.send("n2", vec![m]);
+		}
+
+		let mut acked = outbox.acknowledge("n2", 2, vec![]);
+		acked.sort();
+
+		assert_eq!(acked, [1, 2]);
+		assert_eq!(outbox.get_in_flight("n2"), HashSet::from([3]));
+	}
+
+	#[test]
+	fn selective_ack_releases_only_the_batches_named() {
+		let mut outbox = Outbox::default();
+		for m in 1..=3 {
+			outbox.send("n2", vec![m]);
+		}
+
+		assert_eq!(outbox.acknowledge("n2", 0, vec![3]), [3]);
+		assert_eq!(outbox.get_in_flight("n2"), HashSet::from([1, 2]));
+		assert!(outbox.acknowledge("n3", 5, vec![]).is_empty());
+	}
+
+	#[test]
+	fn out_of_order_batches_are_acknowledged_selectively() {
+		let mut outbox = Outbox::default();
+
+		assert_eq!(outbox.receive("n2", 2, 0), (0, vec![2]));
+		assert_eq!(outbox.receive("n2", 4, 0), (0, vec![2, 4]));
+		assert_eq!(outbox.receive("n2", 1, 0), (2, vec![4]));
+		assert_eq!(outbox.receive("n2", 3, 0), (4, vec![]));
+	}
+
+	#[test]
+	fn duplicate_batches_do_not_move_the_acknowledgement() {
+		let mut outbox = Outbox::default();
+		outbox.receive("n2", 1, 0);
+
+		assert_eq!(outbox.receive("n2", 1, 0), (1, vec![]));
+		assert_eq!(outbox.receive("n3", 1, 0), (1, vec![]));
+	}
+
+	#[test]
+	fn base_is_below_the_first_unacknowledged_batch() {
+		let mut outbox = Outbox::default();
+		assert_eq!(outbox.base("n2"), 0);
+
+		for m in 1..=3 {
+			outbox.send("n2", vec![m]);
+		}
+		outbox.acknowledge("n2", 1, vec![3]);
+		assert_eq!(outbox.base("n2"), 1);
+
+		outbox.acknowledge("n2", 2, vec![]);
+		assert_eq!(outbox.base("n2"), 3);
+	}
+
+	#[test]
+	fn a_restarted_receiver_picks_up_from_the_senders_base() {
+		let mut sender = Outbox::default();
+		let mut receiver = Outbox::default();
+
+		for m in 1..=500 {
+			let seq = sender.send("n2", vec![m]);
+			let (ack, sack) = receiver.receive("n1", seq, sender.base("n2"));
+			sender.acknowledge("n2", ack, sack);
+		}
+
+		// The receiver comes back without its inbox, batch 501 is lost
+		let mut receiver = Outbox::default();
+		sender.send("n2", vec![501]);
+		let seq = sender.send("n2", vec![502]);
+
+		let (ack, sack) = receiver.receive("n1", seq, sender.base("n2"));
+		assert_eq!((ack, sack.clone()), (500, vec![502]));
+		sender.acknowledge("n2", ack, sack);
+
+		for _ in 0..3 {
+			sender.next_round();
+
+			for (_, seq, _) in sender.get_retransmissions() {
+				let (ack, sack) = receiver.receive("n1", seq, sender.base("n2"));
+				assert_eq!((ack, sack.clone()), (502, vec![]));
+				sender.acknowledge("n2", ack, sack);
+			}
+		}
+
+		assert!(sender.get_in_flight("n2").is_empty());
+	}
+
+	#[test]
+	fn unacknowledged_batches_are_retransmitted_with_backoff() {
+		let mut outbox = Outbox::default();
+		outbox.send("n2", vec![1]);
+
+		assert_eq!(retransmission_rounds(&mut outbox, 100), [3, 9, 21, 45, 93]);
+	}
+
+	#[test]
+	fn backoff_is_capped_so_a_silent_peer_is_still_probed() {
+		let mut outbox = Outbox::default();
+		outbox.send("n2", vec![1]);
+		retransmission_rounds(&mut outbox, 93);
+
+		let gaps = retransmission_rounds(&mut outbox, 3 * 48);
+
+		assert_eq!(gaps, [48, 96, 144]);
+	}
+
+	#[test]
+	fn acknowledged_batches_are_not_retransmitted() {
+		let mut outbox = Outbox::default();
+		outbox.send("n2", vec![1]);
+		outbox.send("n2", vec![2]);
+		outbox.acknowledge("n2", 1, vec![]);
+
+		for _ in 0..3 {
+			outbox.next_round();
+		}
+
+		assert_eq!(
+			outbox.get_retransmissions(),
+			[("n2".to_string(), 2, vec![2])]
+		);
+	}
+}
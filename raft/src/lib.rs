@@ -0,0 +1,25 @@
+//! Raft consensus over the Maelstrom message layer.
+//!
+//! [`Raft`] is a deterministic state machine: it is fed received messages with
+//! [`Raft::step`] and the passing of time with [`Raft::tick`], and hands back
+//! the messages to send and the commands it applied. [`transport`] drives it
+//! from stdin/stdout under Maelstrom, [`sim`] from an in-process cluster with
+//! partitions and message loss.
+//!
+//! State lives in memory only, a node which restarts rejoins as a fresh one.
+
+// Stdout carries the Maelstrom protocol, only the transport may touch it.
+#![deny(clippy::print_stdout)]
+
+mod log;
+mod message;
+mod raft;
+pub mod sim;
+mod state_machine;
+pub mod transport;
+
+pub use crate::{
+	message::{Body, Entry, Message},
+	raft::{Applied, Config, NotLeader, Raft, Role},
+	state_machine::StateMachine,
+};
@@ -0,0 +1,110 @@
+use crate::message::Entry;
+
+/// The replicated log, indexed from 1. Entries up to `snapshot_index` were
+/// compacted into a snapshot of the state machine.
+#[derive(Clone, Debug)]
+pub(crate) struct Log<C> {
+	snapshot_index: u64,
+	snapshot_term: u64,
+	entries: Vec<Entry<C>>,
+}
+
+impl<C> Default for Log<C> {
+	fn default() -> Self {
+		Log {
+			snapshot_index: 0,
+			snapshot_term: 0,
+			entries: vec![],
+		}
+	}
+}
+
+impl<C: Clone> Log<C> {
+	pub(crate) fn snapshot_index(&self) -> u64 {
+		self.snapshot_index
+	}
+
+	pub(crate) fn snapshot_term(&self) -> u64 {
+		self.snapshot_term
+	}
+
+	/// Entries not compacted yet.
+	pub(crate) fn len(&self) -> usize {
+		self.entries.len()
+	}
+
+	pub(crate) fn last_index(&self) -> u64 {
+		self.snapshot_index + self.entries.len() as u64
+	}
+
+	pub(crate) fn last_term(&self) -> u64 {
+		self.entries.last().map_or(self.snapshot_term, |e| e.term)
+	}
+
+	/// The term of the entry at `index`, `None` if it is compacted or not there.
+	pub(crate) fn term_at(&self, index: u64) -> Option<u64> {
+		if index == self.snapshot_index {
+			return Some(self.snapshot_term);
+		}
+
+		self.get(index).map(|e| e.term)
+	}
+
+	pub(crate) fn get(&self, index: u64) -> Option<&Entry<C>> {
+		if index <= self.snapshot_index {
+			return None;
+		}
+
+		self.entries.get((index - self.snapshot_index - 1) as usize)
+	}
+
+	/// The first index of the term the entry at `index` belongs to, so a
+	/// leader can skip a whole conflicting term at once.
+	pub(crate) fn first_index_of_term(&self, index: u64) -> u64 {
+		let Some(term) = self.term_at(index) else {
+			return index;
+		};
+
+		let mut first = index;
+		while first > self.snapshot_index + 1 && self.term_at(first - 1) == Some(term) {
+			first -= 1;
+		}
+
+		first
+	}
+
+	pub(crate) fn append(&mut self, entry: Entry<C>) -> u64 {
+		self.entries.push(entry);
+		self.last_index()
+	}
+
+	/// Up to `max` entries starting at `index`, which must not be compacted.
+	pub(crate) fn entries_from(&self, index: u64, max: usize) -> Vec<Entry<C>> {
+		let start = (index - self.snapshot_index - 1) as usize;
+
+		self.entries.iter().skip(start).take(max).cloned().collect()
+	}
+
+	/// Removes the entry at `index` and everything after it.
+	pub(crate) fn truncate_from(&mut self, index: u64) {
+		self.entries
+			.truncate((index - self.snapshot_index - 1) as usize);
+	}
+
+	/// Drops the entries up to and including `index`, which a snapshot covers.
+	/// Entries after it stay if the log holds `index` with the same term.
+	pub(crate) fn compact(&mut self, index: u64, term: u64) {
+		if index <= self.snapshot_index {
+			return;
+		}
+
+		if self.term_at(index) == Some(term) {
+			self.entries.drain(..(index - self.snapshot_index) as usize);
+		} else {
+			self.entries.clear();
+		}
+
+		self.snapshot_index = index;
+		self.snapshot_term = term;
+	}
+}
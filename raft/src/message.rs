@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Message<B> {
+	pub src: String,
+	pub dest: String,
+	pub body: B,
+}
+
+/// Messages Raft nodes exchange among themselves, `C` is the command type of
+/// the replicated state machine.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Body<C> {
+	RequestVote {
+		term: u64,
+		candidate_id: String,
+		last_log_index: u64,
+		last_log_term: u64,
+	},
+	RequestVoteOk {
+		term: u64,
+		vote_granted: bool,
+	},
+	AppendEntries {
+		term: u64,
+		leader_id: String,
+		prev_log_index: u64,
+		prev_log_term: u64,
+		entries: Vec<Entry<C>>,
+		leader_commit: u64,
+	},
+	AppendEntriesOk {
+		term: u64,
+		success: bool,
+		/// On success the last index known to match the leader's log, on
+		/// failure the index the leader should try next to back off from.
+		match_index: u64,
+	},
+	InstallSnapshot {
+		term: u64,
+		leader_id: String,
+		last_included_index: u64,
+		last_included_term: u64,
+		/// The serialized state machine.
+		data: serde_json::Value,
+	},
+	InstallSnapshotOk {
+		term: u64,
+		match_index: u64,
+	},
+}
+
+/// A log entry, leaders append one without a command when they are elected
+/// so entries of earlier terms can commit.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Entry<C> {
+	pub term: u64,
+	pub command: Option<C>,
+}
+
+impl<C> Body<C> {
+	/// The `type` tag of the body, for logs.
+	pub fn kind(&self) -> &'static str {
+		match self {
+			Body::RequestVote { .. } => "request_vote",
+			Body::RequestVoteOk { .. } => "request_vote_ok",
+			Body::AppendEntries { .. } => "append_entries",
+			Body::AppendEntriesOk { .. } => "append_entries_ok",
+			Body::InstallSnapshot { .. } => "install_snapshot",
+			Body::InstallSnapshotOk { .. } => "install_snapshot_ok",
+		}
+	}
+
+	/// Every message carries the sender's term.
+	pub fn term(&self) -> u64 {
+		match self {
+			Body::RequestVote { term, .. }
+			| Body::RequestVoteOk { term, .. }
+			| Body::AppendEntries { term, .. }
+			| Body::AppendEntriesOk { term, .. }
+			| Body::InstallSnapshot { term, .. }
+			| Body::InstallSnapshotOk { term, .. } => *term,
+		}
+	}
+}
@@ -0,0 +1,659 @@
+use std::collections::{HashMap, HashSet};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tracing::{debug, info, warn};
+
+use crate::{
+	log::Log,
+	message::{Body, Entry, Message},
+	state_machine::StateMachine,
+};
+
+/// Timing and batching, in ticks of whatever drives the node.
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+	/// Fewest ticks without hearing from a leader before a follower stands for election.
+	pub election_timeout_min: u64,
+	/// Most ticks, each election picks a random timeout in between.
+	pub election_timeout_max: u64,
+	/// Ticks between `append_entries` a leader sends even when idle.
+	pub heartbeat_interval: u64,
+	/// Applied entries kept in the log before they are compacted into a snapshot.
+	pub snapshot_threshold: usize,
+	/// Most entries in a single `append_entries`.
+	pub max_batch: usize,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Config {
+			election_timeout_min: 10,
+			election_timeout_max: 20,
+			heartbeat_interval: 3,
+			snapshot_threshold: 1000,
+			max_batch: 100,
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Role {
+	Follower,
+	Candidate {
+		votes: HashSet<String>,
+	},
+	Leader {
+		/// Next entry to send to each peer.
+		next_index: HashMap<String, u64>,
+		/// Highest entry known to be replicated on each peer.
+		match_index: HashMap<String, u64>,
+	},
+}
+
+/// Proposals only succeed on the leader, `leader` is where to send them instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NotLeader {
+	pub leader: Option<String>,
+}
+
+/// A command which was committed and applied to the state machine.
+#[derive(Debug)]
+pub struct Applied<O> {
+	pub index: u64,
+	/// Term the entry was appended in. A proposal whose index was applied with
+	/// another term was overwritten by a later leader and never took effect.
+	pub term: u64,
+	pub output: O,
+}
+
+/// One Raft node.
+pub struct Raft<S: StateMachine> {
+	id: String,
+	peers: Vec<String>,
+	config: Config,
+	role: Role,
+	term: u64,
+	voted_for: Option<String>,
+	leader: Option<String>,
+	log: Log<S::Command>,
+	commit_index: u64,
+	last_applied: u64,
+	state: S,
+	/// The state machine as of the log's snapshot index.
+	snapshot: serde_json::Value,
+	elapsed: u64,
+	election_timeout: u64,
+	rng: StdRng,
+	outbox: Vec<Message<Body<S::Command>>>,
+	applied: Vec<Applied<S::Output>>,
+}
+
+impl<S: StateMachine> Raft<S> {
+	/// A follower in a cluster of `nodes`, which includes `id`. The seed only
+	/// picks election timeouts.
+	pub fn new(id: String, nodes: &[String], config: Config, seed: u64) -> Raft<S> {
+		let state = S::default();
+		let snapshot = serde_json::to_value(&state).expect("State machine must serialize");
+
+		let mut raft = Raft {
+			peers: nodes.iter().filter(|n| **n != id).cloned().collect(),
+			id,
+			config,
+			role: Role::Follower,
+			term: 0,
+			voted_for: None,
+			leader: None,
+			log: Log::default(),
+			commit_index: 0,
+			last_applied: 0,
+			state,
+			snapshot,
+			elapsed: 0,
+			election_timeout: 0,
+			rng: StdRng::seed_from_u64(seed),
+			outbox: vec![],
+			applied: vec![],
+		};
+		raft.reset_election_timer();
+
+		raft
+	}
+
+	pub fn id(&self) -> &str {
+		&self.id
+	}
+
+	pub fn role(&self) -> &Role {
+		&self.role
+	}
+
+	pub fn is_leader(&self) -> bool {
+		matches!(self.role, Role::Leader { .. })
+	}
+
+	/// The leader of the current term, if we heard from it.
+	pub fn leader(&self) -> Option<&str> {
+		self.leader.as_deref()
+	}
+
+	pub fn term(&self) -> u64 {
+		self.term
+	}
+
+	pub fn commit_index(&self) -> u64 {
+		self.commit_index
+	}
+
+	/// The term of the entry at `index`, `None` if it is not in the log yet or
+	/// lies inside the snapshot. Entries a snapshot from the leader covered are
+	/// applied without [`Raft::take_applied`] reporting them.
+	pub fn term_at(&self, index: u64) -> Option<u64> {
+		self.log.term_at(index)
+	}
+
+	/// The state machine with every committed command applied.
+	pub fn state(&self) -> &S {
+		&self.state
+	}
+
+	/// Messages to send, since the last call.
+	pub fn take_messages(&mut self) -> Vec<Message<Body<S::Command>>> {
+		std::mem::take(&mut self.outbox)
+	}
+
+	/// Commands applied to the state machine, since the last call.
+	pub fn take_applied(&mut self) -> Vec<Applied<S::Output>> {
+		std::mem::take(&mut self.applied)
+	}
+
+	/// Appends a command to the leader's log, returns its index and term. It
+	/// takes effect once [`Raft::take_applied`] reports that index with that term.
+	pub fn propose(&mut self, command: S::Command) -> Result<(u64, u64), NotLeader> {
+		if !self.is_leader() {
+			return Err(NotLeader {
+				leader: self.leader.clone(),
+			});
+		}
+
+		let index = self.log.append(Entry {
+			term: self.term,
+			command: Some(command),
+		});
+
+		self.advance_commit();
+		self.broadcast_append();
+
+		Ok((index, self.term))
+	}
+
+	/// Lets one unit of time pass: leaders send heartbeats, everyone else
+	/// stands for election once the leader went quiet for too long.
+	pub fn tick(&mut self) {
+		self.elapsed += 1;
+
+		if self.is_leader() {
+			if self.elapsed >= self.config.heartbeat_interval {
+				self.elapsed = 0;
+				self.broadcast_append();
+			}
+		} else if self.elapsed >= self.election_timeout {
+			self.start_election();
+		}
+	}
+
+	/// Handles a message from another node.
+	pub fn step(&mut self, from: &str, body: Body<S::Command>) {
+		if body.term() > self.term {
+			self.become_follower(body.term(), None);
+		}
+
+		match body {
+			Body::RequestVote {
+				term,
+				candidate_id,
+				last_log_index,
+				last_log_term,
+			} => self.receive_request_vote(term, candidate_id, last_log_index, last_log_term),
+			Body::RequestVoteOk { term, vote_granted } => {
+				self.receive_request_vote_ok(from, term, vote_granted)
+			}
+			Body::AppendEntries {
+				term,
+				leader_id,
+				prev_log_index,
+				prev_log_term,
+				entries,
+				leader_commit,
+			} => self.receive_append_entries(
+				term,
+				leader_id,
+				prev_log_index,
+				prev_log_term,
+				entries,
+				leader_commit,
+			),
+			Body::AppendEntriesOk {
+				term,
+				success,
+				match_index,
+			} => self.receive_append_entries_ok(from, term, success, match_index),
+			Body::InstallSnapshot {
+				term,
+				leader_id,
+				last_included_index,
+				last_included_term,
+				data,
+			} => self.receive_install_snapshot(
+				term,
+				leader_id,
+				last_included_index,
+				last_included_term,
+				data,
+			),
+			Body::InstallSnapshotOk { term, match_index } => {
+				self.receive_install_snapshot_ok(from, term, match_index)
+			}
+		}
+	}
+
+	fn send(&mut self, dest: &str, body: Body<S::Command>) {
+		self.outbox.push(Message {
+			src: self.id.clone(),
+			dest: dest.to_string(),
+			body,
+		});
+	}
+
+	fn quorum(&self) -> usize {
+		let nodes = self.peers.len() + 1;
+		nodes / 2 + 1
+	}
+
+	fn reset_election_timer(&mut self) {
+		self.elapsed = 0;
+		self.election_timeout = self
+			.rng
+			.gen_range(self.config.election_timeout_min..=self.config.election_timeout_max);
+	}
+
+	fn become_follower(&mut self, term: u64, leader: Option<String>) {
+		if term > self.term {
+			self.term = term;
+			self.voted_for = None;
+		}
+
+		if self.is_leader() {
+			info!(term, "Stepping down");
+		}
+
+		self.role = Role::Follower;
+		self.leader = leader;
+	}
+
+	fn start_election(&mut self) {
+		self.term += 1;
+		self.voted_for = Some(self.id.clone());
+		self.leader = None;
+		self.role = Role::Candidate {
+			votes: HashSet::from([self.id.clone()]),
+		};
+		self.reset_election_timer();
+
+		debug!(term = self.term, "Standing for election");
+
+		let body = Body::RequestVote {
+			term: self.term,
+			candidate_id: self.id.clone(),
+			last_log_index: self.log.last_index(),
+			last_log_term: self.log.last_term(),
+		};
+
+		for peer in self.peers.clone() {
+			self.send(&peer, body.clone());
+		}
+
+		if self.quorum() == 1 {
+			self.become_leader();
+		}
+	}
+
+	fn become_leader(&mut self) {
+		info!(term = self.term, "Elected leader");
+
+		let next = self.log.last_index() + 1;
+		self.role = Role::Leader {
+			next_index: self.peers.iter().map(|p| (p.clone(), next)).collect(),
+			match_index: self.peers.iter().map(|p| (p.clone(), 0)).collect(),
+		};
+		self.leader = Some(self.id.clone());
+		self.elapsed = 0;
+
+		// Entries of earlier terms only commit along with one of our own
+		self.log.append(Entry {
+			term: self.term,
+			command: None,
+		});
+		self.advance_commit();
+		self.broadcast_append();
+	}
+
+	fn broadcast_append(&mut self) {
+		for peer in self.peers.clone() {
+			self.send_append(&peer);
+		}
+	}
+
+	/// Sends the peer the entries it is missing, or our snapshot if they were
+	/// compacted away.
+	fn send_append(&mut self, peer: &str) {
+		let Role::Leader { next_index, .. } = &self.role else {
+			return;
+		};
+		let next = next_index[peer];
+
+		let body = if next <= self.log.snapshot_index() {
+			Body::InstallSnapshot {
+				term: self.term,
+				leader_id: self.id.clone(),
+				last_included_index: self.log.snapshot_index(),
+				last_included_term: self.log.snapshot_term(),
+				data: self.snapshot.clone(),
+			}
+		} else {
+			let prev_log_index = next - 1;
+
+			Body::AppendEntries {
+				term: self.term,
+				leader_id: self.id.clone(),
+				prev_log_index,
+				prev_log_term: self.log.term_at(prev_log_index).unwrap_or_default(),
+				entries: self.log.entries_from(next, self.config.max_batch),
+				leader_commit: self.commit_index,
+			}
+		};
+
+		self.send(peer, body);
+	}
+
+	fn receive_request_vote(
+		&mut self,
+		term: u64,
+		candidate: String,
+		last_log_index: u64,
+		last_log_term: u64,
+	) {
+		let up_to_date =
+			(last_log_term, last_log_index) >= (self.log.last_term(), self.log.last_index());
+		let vote_granted = term == self.term
+			&& up_to_date
+			&& self.voted_for.as_ref().is_none_or(|v| *v == candidate);
+
+		if vote_granted {
+			self.voted_for = Some(candidate.clone());
+			self.reset_election_timer();
+		}
+
+		self.send(
+			&candidate,
+			Body::RequestVoteOk {
+				term: self.term,
+				vote_granted,
+			},
+		);
+	}
+
+	fn receive_request_vote_ok(&mut self, from: &str, term: u64, vote_granted: bool) {
+		let quorum = self.quorum();
+
+		let Role::Candidate { votes } = &mut self.role else {
+			return;
+		};
+
+		if term != self.term || !vote_granted {
+			return;
+		}
+
+		votes.insert(from.to_string());
+
+		if votes.len() >= quorum {
+			self.become_leader();
+		}
+	}
+
+	fn receive_append_entries(
+		&mut self,
+		term: u64,
+		leader: String,
+		prev_log_index: u64,
+		prev_log_term: u64,
+		entries: Vec<Entry<S::Command>>,
+		leader_commit: u64,
+	) {
+		if term < self.term {
+			let body = Body::AppendEntriesOk {
+				term: self.term,
+				success: false,
+				match_index: 0,
+			};
+			self.send(&leader, body);
+			return;
+		}
+
+		self.become_follower(term, Some(leader.clone()));
+		self.reset_election_timer();
+
+		// Entries we compacted are committed, and so match the leader's
+		let skip = self.log.snapshot_index().saturating_sub(prev_log_index);
+		let prev_log_index = prev_log_index + skip;
+		let prev_log_term = match skip {
+			0 => prev_log_term,
+			_ => self.log.snapshot_term(),
+		};
+		let entries: Vec<_> = entries.into_iter().skip(skip as usize).collect();
+
+		if prev_log_index > self.log.last_index() {
+			let body = Body::AppendEntriesOk {
+				term: self.term,
+				success: false,
+				match_index: self.log.last_index(),
+			};
+			self.send(&leader, body);
+			return;
+		}
+
+		if self.log.term_at(prev_log_index) != Some(prev_log_term) {
+			let conflict = self.log.first_index_of_term(prev_log_index);
+			let body = Body::AppendEntriesOk {
+				term: self.term,
+				success: false,
+				match_index: conflict.saturating_sub(1),
+			};
+			self.send(&leader, body);
+			return;
+		}
+
+		let match_index = prev_log_index + entries.len() as u64;
+
+		for (i, entry) in entries.into_iter().enumerate() {
+			let index = prev_log_index + 1 + i as u64;
+
+			match self.log.term_at(index) {
+				Some(term) if term == entry.term => continue,
+				Some(_) => {
+					debug!(index, "Dropping entries which conflict with the leader");
+					self.log.truncate_from(index);
+				}
+				None => {}
+			}
+
+			self.log.append(entry);
+		}
+
+		// Only what we know matches the leader's log is committed
+		let commit_index = leader_commit.min(match_index);
+
+		if commit_index > self.commit_index {
+			self.commit_index = commit_index;
+			self.apply();
+		}
+
+		let body = Body::AppendEntriesOk {
+			term: self.term,
+			success: true,
+			match_index,
+		};
+		self.send(&leader, body);
+	}
+
+	fn receive_append_entries_ok(&mut self, from: &str, term: u64, success: bool, index: u64) {
+		let Role::Leader {
+			next_index,
+			match_index,
+		} = &mut self.role
+		else {
+			return;
+		};
+
+		if term != self.term || !next_index.contains_key(from) {
+			return;
+		}
+
+		if success {
+			let matched = match_index.get_mut(from).unwrap();
+			*matched = (*matched).max(index);
+			next_index.insert(from.to_string(), *matched + 1);
+			let more = *matched < self.log.last_index();
+
+			self.advance_commit();
+
+			if more {
+				self.send_append(from);
+			}
+		} else {
+			let next = next_index.get_mut(from).unwrap();
+			*next = (index + 1).min(*next - 1).max(1);
+
+			self.send_append(from);
+		}
+	}
+
+	fn receive_install_snapshot(
+		&mut self,
+		term: u64,
+		leader: String,
+		index: u64,
+		snapshot_term: u64,
+		data: serde_json::Value,
+	) {
+		if term < self.term {
+			let body = Body::InstallSnapshotOk {
+				term: self.term,
+				match_index: 0,
+			};
+			self.send(&leader, body);
+			return;
+		}
+
+		self.become_follower(term, Some(leader.clone()));
+		self.reset_election_timer();
+
+		if index > self.commit_index {
+			match serde_json::from_value(data.clone()) {
+				Ok(state) => {
+					info!(index, "Installing snapshot from {}", leader);
+
+					self.state = state;
+					self.snapshot = data;
+					self.log.compact(index, snapshot_term);
+					self.commit_index = index;
+					self.last_applied = index;
+				}
+				Err(e) => {
+					warn!("Dropping snapshot which does not deserialize: {}", e);
+					return;
+				}
+			}
+		}
+
+		let body = Body::InstallSnapshotOk {
+			term: self.term,
+			match_index: index,
+		};
+		self.send(&leader, body);
+	}
+
+	fn receive_install_snapshot_ok(&mut self, from: &str, term: u64, index: u64) {
+		let Role::Leader {
+			next_index,
+			match_index,
+		} = &mut self.role
+		else {
+			return;
+		};
+
+		if term != self.term || !next_index.contains_key(from) {
+			return;
+		}
+
+		let matched = match_index.get_mut(from).unwrap();
+		*matched = (*matched).max(index);
+		next_index.insert(from.to_string(), *matched + 1);
+
+		self.advance_commit();
+		self.send_append(from);
+	}
+
+	/// Commits the highest entry of our term a quorum has replicated, along with
+	/// everything before it.
+	fn advance_commit(&mut self) {
+		let Role::Leader { match_index, .. } = &self.role else {
+			return;
+		};
+
+		let mut matched: Vec<u64> = match_index.values().cloned().collect();
+		matched.push(self.log.last_index());
+		matched.sort_unstable_by(|a, b| b.cmp(a));
+
+		let replicated = matched[self.quorum() - 1];
+
+		if replicated > self.commit_index && self.log.term_at(replicated) == Some(self.term) {
+			self.commit_index = replicated;
+			self.apply();
+		}
+	}
+
+	fn apply(&mut self) {
+		while self.last_applied < self.commit_index {
+			self.last_applied += 1;
+
+			let entry = self
+				.log
+				.get(self.last_applied)
+				.expect("Committed entries are in the log")
+				.clone();
+
+			if let Some(command) = entry.command {
+				let output = self.state.apply(&command);
+
+				self.applied.push(Applied {
+					index: self.last_applied,
+					term: entry.term,
+					output,
+				});
+			}
+		}
+
+		if self.log.len() > self.config.snapshot_threshold {
+			self.snapshot =
+				serde_json::to_value(&self.state).expect("State machine must serialize");
+
+			let term = self.log.term_at(self.last_applied).unwrap_or_default();
+			self.log.compact(self.last_applied, term);
+
+			debug!(
+				index = self.last_applied,
+				"Compacted the log into a snapshot"
+			);
+		}
+	}
+}
@@ -0,0 +1,196 @@
+//! An in-process cluster for tests: nodes exchange messages through a
+//! simulated network which can be partitioned and lose messages, and time
+//! only passes when the test ticks it. Runs are reproducible from their seed.
+
+use std::collections::{BTreeMap, HashMap};
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::{
+	message::{Body, Message},
+	raft::{Applied, Config, NotLeader, Raft},
+	state_machine::StateMachine,
+};
+
+pub struct Cluster<S: StateMachine> {
+	nodes: BTreeMap<String, Raft<S>>,
+	in_flight: Vec<Message<Body<S::Command>>>,
+	/// Partition each node is in, nodes only reach nodes in the same one.
+	partitions: HashMap<String, usize>,
+	/// Chance of losing each message.
+	loss: f64,
+	rng: StdRng,
+	/// Commands each node applied, in order.
+	applied: BTreeMap<String, Vec<Applied<S::Output>>>,
+	/// The leader elected in each term.
+	leaders: BTreeMap<u64, String>,
+}
+
+impl<S: StateMachine> Cluster<S> {
+	/// A cluster of nodes `n1` to `n{size}`.
+	pub fn new(size: usize, config: Config, seed: u64) -> Cluster<S> {
+		let ids: Vec<String> = (1..=size).map(|i| format!("n{}", i)).collect();
+
+		Cluster {
+			nodes: ids
+				.iter()
+				.enumerate()
+				.map(|(i, id)| {
+					(
+						id.clone(),
+						Raft::new(id.clone(), &ids, config, seed + i as u64),
+					)
+				})
+				.collect(),
+			in_flight: vec![],
+			partitions: HashMap::new(),
+			loss: 0.0,
+			rng: StdRng::seed_from_u64(seed),
+			applied: ids.iter().map(|id| (id.clone(), vec![])).collect(),
+			leaders: BTreeMap::new(),
+		}
+	}
+
+	pub fn ids(&self) -> Vec<String> {
+		self.nodes.keys().cloned().collect()
+	}
+
+	pub fn node(&self, id: &str) -> &Raft<S> {
+		&self.nodes[id]
+	}
+
+	/// Commands the node applied so far, in order.
+	pub fn applied(&self, id: &str) -> &[Applied<S::Output>] {
+		&self.applied[id]
+	}
+
+	pub fn set_loss(&mut self, loss: f64) {
+		self.loss = loss;
+	}
+
+	/// Splits the cluster, nodes left out of every group are cut off on their own.
+	pub fn partition(&mut self, groups: &[&[&str]]) {
+		self.partitions.clear();
+
+		for (i, group) in groups.iter().enumerate() {
+			for id in group.iter() {
+				self.partitions.insert(id.to_string(), i);
+			}
+		}
+
+		for (i, id) in self.nodes.keys().enumerate() {
+			self.partitions
+				.entry(id.clone())
+				.or_insert(groups.len() + i);
+		}
+	}
+
+	pub fn heal(&mut self) {
+		self.partitions.clear();
+	}
+
+	fn connected(&self, a: &str, b: &str) -> bool {
+		self.partitions.get(a) == self.partitions.get(b)
+	}
+
+	/// The leader of the highest term, if any node believes it leads.
+	pub fn leader(&self) -> Option<String> {
+		self.nodes
+			.values()
+			.filter(|n| n.is_leader())
+			.max_by_key(|n| n.term())
+			.map(|n| n.id().to_string())
+	}
+
+	/// Proposes a command on a node, as a client connected to it would.
+	pub fn propose(&mut self, id: &str, command: S::Command) -> Result<(u64, u64), NotLeader> {
+		let result = self.nodes.get_mut(id).unwrap().propose(command);
+		self.collect();
+		result
+	}
+
+	/// Ticks every node once, then delivers the messages that were in flight
+	/// in random order.
+	pub fn tick(&mut self) {
+		for node in self.nodes.values_mut() {
+			node.tick();
+		}
+
+		self.collect();
+		self.deliver();
+	}
+
+	pub fn run(&mut self, ticks: u64) {
+		for _ in 0..ticks {
+			self.tick();
+		}
+	}
+
+	/// Ticks until some node leads, or `ticks` ran out.
+	pub fn elect(&mut self, ticks: u64) -> Option<String> {
+		for _ in 0..ticks {
+			if let Some(leader) = self.leader() {
+				return Some(leader);
+			}
+
+			self.tick();
+		}
+
+		self.leader()
+	}
+
+	fn deliver(&mut self) {
+		let mut messages = std::mem::take(&mut self.in_flight);
+		messages.shuffle(&mut self.rng);
+
+		for message in messages {
+			if !self.connected(&message.src, &message.dest) || self.rng.gen_bool(self.loss) {
+				continue;
+			}
+
+			self.nodes
+				.get_mut(&message.dest)
+				.unwrap()
+				.step(&message.src, message.body);
+		}
+
+		self.collect();
+	}
+
+	/// Gathers what the nodes sent and applied, and records who led which term.
+	fn collect(&mut self) {
+		for (id, node) in self.nodes.iter_mut() {
+			self.in_flight.extend(node.take_messages());
+			self.applied
+				.get_mut(id)
+				.unwrap()
+				.extend(node.take_applied());
+
+			if node.is_leader() {
+				let leader = self.leaders.entry(node.term()).or_insert(id.clone());
+				assert_eq!(leader, id, "Two leaders in term {}", node.term());
+			}
+		}
+	}
+
+	/// Checks that no two nodes applied different entries at the same index.
+	/// Entries with the same index and term are the same entry.
+	pub fn check_applied(&self) -> Result<(), String> {
+		let mut terms: HashMap<u64, (u64, &str)> = HashMap::new();
+
+		for (id, applied) in &self.applied {
+			for a in applied {
+				let (term, other) = *terms.entry(a.index).or_insert((a.term, id));
+
+				if term != a.term {
+					return Err(format!(
+						"{} applied index {} from term {}, {} from term {}",
+						other, a.index, term, id, a.term
+					));
+				}
+			}
+		}
+
+		Ok(())
+	}
+}
@@ -0,0 +1,15 @@
+use std::fmt::Debug;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// The state Raft replicates. Every node applies the same commands in the
+/// same order, so `apply` has to be deterministic.
+///
+/// Snapshots serialize the whole state machine, a lagging node is sent one
+/// when the entries it misses were already compacted away.
+pub trait StateMachine: Default + Serialize + DeserializeOwned {
+	type Command: Clone + Debug + Serialize + DeserializeOwned;
+	type Output: Debug;
+
+	fn apply(&mut self, command: &Self::Command) -> Self::Output;
+}
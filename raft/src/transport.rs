@@ -0,0 +1,166 @@
+//! Drives a node from Maelstrom: messages arrive as JSON lines on stdin and
+//! leave as JSON lines on stdout, with a tick every interval in between.
+
+use std::{
+	io::{self, BufRead, Stdout, Write},
+	sync::mpsc::{self, Receiver, RecvTimeoutError},
+	thread,
+	time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::warn;
+
+use crate::message::{Body, Message};
+
+/// The node's place in the cluster, from Maelstrom's `init`.
+#[derive(Clone, Debug)]
+pub struct Node {
+	pub id: String,
+	pub node_ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Control {
+	Init {
+		msg_id: u64,
+		node_id: String,
+		node_ids: Vec<String>,
+	},
+	InitOk {
+		in_reply_to: u64,
+	},
+}
+
+/// Raft messages are told apart from client requests by their `type`, so
+/// the client protocol `R` must not reuse Raft's message types.
+#[derive(Deserialize)]
+#[serde(untagged)]
+#[serde(bound = "C: DeserializeOwned, R: DeserializeOwned")]
+enum Incoming<C, R> {
+	Raft(Message<Body<C>>),
+	Client(Message<R>),
+}
+
+pub enum Event<C, R> {
+	/// A message from another Raft node.
+	Raft(Message<Body<C>>),
+	/// A request from a client, or a reply from a Maelstrom service.
+	Client(Message<R>),
+	/// One tick interval passed.
+	Tick,
+}
+
+pub struct Transport<C, R> {
+	events: Receiver<Incoming<C, R>>,
+	interval: Duration,
+	next_tick: Instant,
+	stdout: Stdout,
+}
+
+impl<C, R> Transport<C, R>
+where
+	C: DeserializeOwned + Send + 'static,
+	R: DeserializeOwned + Send + 'static,
+{
+	/// Waits for `init`, answers it and starts reading the rest of stdin on a
+	/// thread of its own.
+	pub fn connect(interval: Duration) -> (Transport<C, R>, Node) {
+		let mut lines = io::stdin().lock().lines();
+
+		let init = lines
+			.next()
+			.expect("Stdin closed before init")
+			.expect("Could not read init");
+		let init: Message<Control> = serde_json::from_str(&init).expect("First message is init");
+
+		let Control::Init {
+			msg_id,
+			node_id,
+			node_ids,
+		} = init.body
+		else {
+			panic!("First message is init");
+		};
+
+		let mut transport = Transport {
+			events: spawn_reader(),
+			interval,
+			next_tick: Instant::now() + interval,
+			stdout: io::stdout(),
+		};
+
+		transport.send(&Message {
+			src: node_id.clone(),
+			dest: init.src,
+			body: Control::InitOk {
+				in_reply_to: msg_id,
+			},
+		});
+
+		let node = Node {
+			id: node_id,
+			node_ids,
+		};
+
+		(transport, node)
+	}
+
+	/// The next message, or a tick once the interval passed. `None` once
+	/// stdin is closed.
+	pub fn recv(&mut self) -> Option<Event<C, R>> {
+		let timeout = self.next_tick.saturating_duration_since(Instant::now());
+
+		// Ticks come first, a busy node must not miss its heartbeats
+		let incoming = if timeout.is_zero() {
+			Err(RecvTimeoutError::Timeout)
+		} else {
+			self.events.recv_timeout(timeout)
+		};
+
+		match incoming {
+			Ok(Incoming::Raft(message)) => Some(Event::Raft(message)),
+			Ok(Incoming::Client(message)) => Some(Event::Client(message)),
+			Err(RecvTimeoutError::Timeout) => {
+				self.next_tick = Instant::now() + self.interval;
+				Some(Event::Tick)
+			}
+			Err(RecvTimeoutError::Disconnected) => None,
+		}
+	}
+
+	pub fn send<B: Serialize>(&mut self, message: &Message<B>) {
+		let line = serde_json::to_string(message).unwrap();
+		writeln!(self.stdout, "{}", line).unwrap();
+		self.stdout.flush().unwrap();
+	}
+}
+
+fn spawn_reader<C, R>() -> Receiver<Incoming<C, R>>
+where
+	C: DeserializeOwned + Send + 'static,
+	R: DeserializeOwned + Send + 'static,
+{
+	let (sender, receiver) = mpsc::channel();
+
+	thread::spawn(move || {
+		for line in io::stdin().lock().lines() {
+			let Ok(line) = line else {
+				return;
+			};
+
+			match serde_json::from_str(&line) {
+				Ok(message) => {
+					if sender.send(message).is_err() {
+						return;
+					}
+				}
+				Err(e) => warn!("Malformed message {:?}: {}", line, e),
+			}
+		}
+	});
+
+	receiver
+}
@@ -0,0 +1,182 @@
+//! Runs clusters in the simulator through elections, partitions, message loss
+//! and log compaction, and checks that every node applies the same commands.
+
+use std::collections::BTreeMap;
+
+use raft::{sim::Cluster, Config, StateMachine};
+use serde::{Deserialize, Serialize};
+
+/// Appends each command, so two nodes with equal state applied the same ones.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+struct Journal(Vec<u64>);
+
+impl StateMachine for Journal {
+	type Command = u64;
+	type Output = usize;
+
+	fn apply(&mut self, command: &u64) -> usize {
+		self.0.push(*command);
+		self.0.len()
+	}
+}
+
+const ELECTION_TICKS: u64 = 100;
+
+fn assert_converged(cluster: &Cluster<Journal>) {
+	cluster.check_applied().unwrap();
+
+	let states: BTreeMap<String, &Journal> = cluster
+		.ids()
+		.into_iter()
+		.map(|id| {
+			let state = cluster.node(&id).state();
+			(id, state)
+		})
+		.collect();
+
+	let first = states.values().next().unwrap();
+	assert!(
+		states.values().all(|s| s == first),
+		"Nodes diverged: {:?}",
+		states
+	);
+}
+
+#[test]
+fn elects_a_leader_and_replicates_commands() {
+	let mut cluster = Cluster::<Journal>::new(5, Config::default(), 1);
+	let leader = cluster.elect(ELECTION_TICKS).expect("No leader elected");
+
+	for command in 1..=10 {
+		cluster.propose(&leader, command).unwrap();
+	}
+	cluster.run(20);
+
+	assert_converged(&cluster);
+	assert_eq!(cluster.node("n1").state().0, (1..=10).collect::<Vec<_>>());
+}
+
+#[test]
+fn followers_point_to_the_leader() {
+	let mut cluster = Cluster::<Journal>::new(3, Config::default(), 2);
+	let leader = cluster.elect(ELECTION_TICKS).expect("No leader elected");
+	cluster.run(10);
+
+	let follower = cluster.ids().into_iter().find(|id| *id != leader).unwrap();
+	let refused = cluster.propose(&follower, 1).unwrap_err();
+
+	assert_eq!(refused.leader.as_deref(), Some(leader.as_str()));
+}
+
+#[test]
+fn minority_cannot_commit_and_majority_moves_on() {
+	let mut cluster = Cluster::<Journal>::new(5, Config::default(), 3);
+	let old = cluster.elect(ELECTION_TICKS).expect("No leader elected");
+	cluster.propose(&old, 1).unwrap();
+	cluster.run(10);
+
+	let others: Vec<String> = cluster.ids().into_iter().filter(|id| *id != old).collect();
+	let minority = [old.as_str(), others[0].as_str()];
+	let majority = [others[1].as_str(), others[2].as_str(), others[3].as_str()];
+	cluster.partition(&[&minority, &majority]);
+
+	// The old leader still accepts commands, but cannot commit them
+	let (index, _) = cluster.propose(&old, 2).unwrap();
+	cluster.run(ELECTION_TICKS);
+	assert!(cluster.node(&old).commit_index() < index);
+
+	let new = cluster.leader().unwrap();
+	assert!(majority.contains(&new.as_str()), "{} leads", new);
+	cluster.propose(&new, 3).unwrap();
+	cluster.run(10);
+
+	// Once healed, the old leader steps down and drops its uncommitted command
+	cluster.heal();
+	cluster.run(ELECTION_TICKS);
+
+	assert_converged(&cluster);
+	assert_eq!(cluster.node(&old).state().0, [1, 3]);
+}
+
+#[test]
+fn lagging_follower_catches_up_from_a_snapshot() {
+	let config = Config {
+		snapshot_threshold: 5,
+		..Config::default()
+	};
+	let mut cluster = Cluster::<Journal>::new(3, config, 4);
+	let leader = cluster.elect(ELECTION_TICKS).expect("No leader elected");
+
+	let lagging = cluster.ids().into_iter().find(|id| *id != leader).unwrap();
+	let rest: Vec<String> = cluster
+		.ids()
+		.into_iter()
+		.filter(|id| *id != lagging)
+		.collect();
+	cluster.partition(&[&[rest[0].as_str(), rest[1].as_str()]]);
+
+	for command in 1..=50 {
+		let leader = cluster.elect(ELECTION_TICKS).expect("No leader elected");
+		cluster.propose(&leader, command).unwrap();
+		cluster.run(2);
+	}
+
+	cluster.heal();
+	cluster.run(ELECTION_TICKS);
+
+	assert_converged(&cluster);
+	assert_eq!(
+		cluster.node(&lagging).state().0,
+		(1..=50).collect::<Vec<_>>()
+	);
+
+	// The snapshot applied the first commands without reporting them
+	assert!(cluster.applied(&lagging).iter().all(|a| a.index > 1));
+	assert_eq!(cluster.node(&lagging).term_at(1), None);
+}
+
+#[test]
+fn random_partitions_and_loss_never_diverge() {
+	for seed in 0..20 {
+		let config = Config {
+			snapshot_threshold: 20,
+			..Config::default()
+		};
+		let mut cluster = Cluster::<Journal>::new(5, config, seed);
+		cluster.set_loss(0.1);
+
+		let ids = cluster.ids();
+		let mut command = 0;
+
+		for round in 0..30 {
+			// Reshuffle the network every few rounds
+			if round % 5 == 0 {
+				let split = (seed as usize + round) % ids.len();
+				let (a, b) = ids.split_at(split);
+				let a: Vec<&str> = a.iter().map(String::as_str).collect();
+				let b: Vec<&str> = b.iter().map(String::as_str).collect();
+				cluster.partition(&[&a, &b]);
+			}
+
+			for id in &ids {
+				command += 1;
+				let _ = cluster.propose(id, command);
+			}
+
+			cluster.run(5);
+		}
+
+		cluster.heal();
+		cluster.set_loss(0.0);
+		let leader = cluster.elect(ELECTION_TICKS).expect("No leader elected");
+		cluster.propose(&leader, 0).unwrap();
+		cluster.run(ELECTION_TICKS);
+
+		assert_converged(&cluster);
+		assert!(
+			cluster.node(&leader).state().0.contains(&0),
+			"Seed {}",
+			seed
+		);
+	}
+}
@@ -0,0 +1,76 @@
+//! Gossip topologies the broadcast nodes can build for themselves, instead
+//! of taking the one Maelstrom sends.
+
+use std::collections::HashMap;
+
+/// Every node is a neighbour of every other node.
+pub fn full(nodes: &[String]) -> HashMap<String, Vec<String>> {
+	nodes
+		.iter()
+		.map(|node| {
+			let others = nodes.iter().filter(|n| *n != node).cloned().collect();
+			(node.clone(), others)
+		})
+		.collect()
+}
+
+/// Links each node to its parent and children in a `fanout`-ary tree, ordered
+/// by node number so every node builds the same one.
+pub fn tree(nodes: &[String], fanout: usize) -> HashMap<String, Vec<String>> {
+	let mut sorted = nodes.to_vec();
+	sorted.sort_by_key(|node| {
+		node.trim_start_matches(|c: char| !c.is_ascii_digit())
+			.parse::<u64>()
+			.unwrap_or(u64::MAX)
+	});
+
+	let mut topology: HashMap<String, Vec<String>> = HashMap::new();
+
+	for (i, node) in sorted.iter().enumerate().skip(1) {
+		let parent = &sorted[(i - 1) / fanout];
+
+		topology
+			.entry(parent.clone())
+			.or_default()
+			.push(node.clone());
+		topology
+			.entry(node.clone())
+			.or_default()
+			.push(parent.clone());
+	}
+
+	topology
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn nodes(count: usize) -> Vec<String> {
+		// Out of order, and n10 sorts before n2 as a string
+		(1..=count).rev().map(|i| format!("n{}", i)).collect()
+	}
+
+	#[test]
+	fn full_links_everyone_but_the_node_itself() {
+		let topology = full(&nodes(3));
+
+		assert_eq!(topology.len(), 3);
+		assert_eq!(topology["n2"], ["n3", "n1"]);
+	}
+
+	#[test]
+	fn tree_orders_nodes_by_number() {
+		let topology = tree(&nodes(10), 3);
+
+		assert_eq!(topology["n1"], ["n2", "n3", "n4"]);
+		assert_eq!(topology["n2"], ["n1", "n5", "n6", "n7"]);
+		assert_eq!(topology["n3"], ["n1", "n8", "n9", "n10"]);
+		assert_eq!(topology["n10"], ["n3"]);
+	}
+
+	#[test]
+	fn a_single_node_has_no_neighbours() {
+		assert!(tree(&nodes(1), 3).is_empty());
+	}
+}